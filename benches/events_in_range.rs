@@ -0,0 +1,80 @@
+//! Run with `cargo +nightly bench --features nightly`.
+
+#![feature(test)]
+
+extern crate test;
+
+use daw::midi::{MidiEvent, MidiEventData, MidiEventsBlockList, NoteEvent, Time};
+use test::{black_box, Bencher};
+
+/// The length of a block at 120 BPM, 48 kHz and 512 frames, near enough.
+const BLOCK_LENGTH: Time = 0.02;
+const NOTES: usize = 100_000;
+
+fn note_event(time: Time, on: bool) -> MidiEvent {
+    let note = NoteEvent {
+        note: 60,
+        velocity: 100,
+    };
+    MidiEvent {
+        time,
+        delta_frames: 0,
+        channel: 0,
+        data: if on {
+            MidiEventData::NoteOn { note }
+        } else {
+            MidiEventData::NoteOff { note }
+        },
+    }
+}
+
+/// Sixteenth notes lasting two bars, so each block sees events from notes
+/// that started long before it.
+fn sixteenths() -> MidiEventsBlockList {
+    let mut list = MidiEventsBlockList::new();
+    for i in 0..NOTES {
+        let time = i as Time * 0.25;
+        list.insert_event(note_event(time, true));
+        list.insert_event(note_event(time + 8., false));
+    }
+    list
+}
+
+#[bench]
+fn one_block(b: &mut Bencher) {
+    let list = sixteenths();
+    let end = NOTES as Time * 0.25;
+    let mut t = 0.;
+
+    b.iter(|| {
+        let events = list.events_in_range(t, t + BLOCK_LENGTH);
+        t = (t + BLOCK_LENGTH) % end;
+        black_box(events)
+    });
+}
+
+#[bench]
+fn one_block_into_a_reused_buffer(b: &mut Bencher) {
+    let list = sixteenths();
+    let end = NOTES as Time * 0.25;
+    let mut events = Vec::with_capacity(64);
+    let mut t = 0.;
+
+    b.iter(|| {
+        events.clear();
+        list.events_in_range_into(t, t + BLOCK_LENGTH, &mut events);
+        t = (t + BLOCK_LENGTH) % end;
+        black_box(events.len())
+    });
+}
+
+#[bench]
+fn inserting(b: &mut Bencher) {
+    let mut list = MidiEventsBlockList::new();
+    let mut t = 0.;
+
+    b.iter(|| {
+        t += 0.25;
+        black_box(list.insert_event(note_event(t, true)))
+    });
+}
@@ -1,24 +1,41 @@
-use std::borrow::{Borrow, BorrowMut};
 use std::os::raw::c_void;
 use std::path::{Path, PathBuf};
-use std::process;
 use std::sync::{Arc, Mutex};
-use std::{env, fs};
+use std::fs;
 
 use async_trait::async_trait;
-use vst::api::{Event, Events};
-use vst::buffer::AudioBuffer;
+use serde::{Deserialize, Serialize};
+use vst::api::Event;
 use vst::editor::Editor;
 use vst::host::{Dispatch, Host, HostBuffer, PluginInstance, PluginLoader};
-use vst::plugin::Plugin;
+use vst::plugin::{Plugin, PluginParameters};
 
 use crate::midi::{MidiEvent, Time};
 
-use super::{Buffer, *};
+use super::{
+    effects::{compressor::Compressor, delay::Delay, eq::Eq, limiter::Limiter, reverb::Reverb},
+    sampler::{Kit, Sampler},
+    synth::Synth,
+    Buffer, *,
+};
+
+pub type ParamId = u32;
+
+/// Describes one of a processor's parameters. Plugin parameters are always
+/// normalised to 0..1, while native processors use their own units.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParamDescriptor {
+    pub id: ParamId,
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub unit: String,
+}
 
 #[async_trait]
-pub trait AudioProcessor {
-    fn show_gui(&mut self, window_id: *mut c_void) -> Result<(), String> {
+pub trait AudioProcessor: Send {
+    fn show_gui(&mut self, _window_id: *mut c_void) -> Result<(), String> {
         Ok(())
     }
     fn hide_gui(&mut self) {}
@@ -36,6 +53,31 @@ pub trait AudioProcessor {
 
     fn suspend(&mut self) {}
     fn resume(&mut self) {}
+    /// Frames by which the output lags the input, e.g. for lookahead.
+    fn latency(&self) -> usize {
+        0
+    }
+    fn params(&self) -> Vec<ParamDescriptor> {
+        vec![]
+    }
+    fn get_param(&self, _id: ParamId) -> Option<f32> {
+        None
+    }
+    /// Values outside the parameter's range are clamped.
+    fn set_param(&mut self, _id: ParamId, _value: f32) {}
+    /// The current value the way the processor would show it, e.g. "-6.0 dB".
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        self.get_param(id).map(|value| format!("{:.2}", value))
+    }
+    /// Changes the processor made itself since the last call, e.g. from its
+    /// own editor.
+    fn take_param_changes(&mut self) -> Vec<(ParamId, f32)> {
+        vec![]
+    }
+    /// Audio to key off instead of the main input, e.g. for ducking.
+    fn set_sidechain(&mut self, _input: Option<Buffer>) {}
+    /// The project tempo in beats per minute, for tempo synced processors.
+    fn set_tempo(&mut self, _tempo: f32) {}
     fn change_sample_rate(&mut self, rate: SampleRate);
     fn change_block_size(&mut self, size: BlockSize);
 }
@@ -43,20 +85,14 @@ pub trait AudioProcessor {
 pub fn scan_plugin_dir(path: &PathBuf) -> Result<Vec<PluginDescription>, String> {
     let mut plugins = vec![];
 
-    for entry in fs::read_dir(path).map_err(|e| e.to_string())? {
-        if let Ok(entry) = entry {
-            let path = entry.path();
-            if path.is_file() {
-                if let Some(plugin) = read_potential_plugin_file(path) {
-                    plugins.push(plugin);
-                }
-            } else if path.is_dir() {
-                plugins.extend(
-                    scan_plugin_dir(&path)
-                        .map_err(|s| s.to_string())?
-                        .into_iter(),
-                );
+    for entry in fs::read_dir(path).map_err(|e| e.to_string())?.flatten() {
+        let path = entry.path();
+        if path.is_file() {
+            if let Some(plugin) = read_potential_plugin_file(path) {
+                plugins.push(plugin);
             }
+        } else if path.is_dir() {
+            plugins.extend(scan_plugin_dir(&path)?);
         }
     }
 
@@ -64,7 +100,7 @@ pub fn scan_plugin_dir(path: &PathBuf) -> Result<Vec<PluginDescription>, String>
 }
 
 fn read_potential_plugin_file(path: PathBuf) -> Option<PluginDescription> {
-    let mut file = fs::File::open(&path).ok()?;
+    fs::File::open(&path).ok()?;
 
     let name = path.file_name()?.to_string_lossy().to_string();
 
@@ -78,6 +114,7 @@ fn read_potential_plugin_file(path: PathBuf) -> Option<PluginDescription> {
     })
 }
 
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct PluginDescription {
     pub name: String,
     pub path: PathBuf,
@@ -86,18 +123,108 @@ pub struct PluginDescription {
 }
 
 impl PluginDescription {
-    pub fn load(&self, a: &Audio) -> Box<dyn AudioProcessor> {
-        Box::new(match self.type_ {
-            PluginType::Vst2 => load_vst2_plugin(&self.path, &a),
-            _ => unimplemented!(),
+    pub fn from_path(path: &Path, instrument: bool) -> Result<Self, String> {
+        if !path.is_file() {
+            return Err(format!("No plugin at '{}'", path.display()));
+        }
+
+        let name = path
+            .file_stem()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Ok(Self {
+            name,
+            path: path.to_path_buf(),
+            type_: PluginType::Vst2,
+            instrument,
         })
     }
+
+    pub fn builtin(plugin: BuiltinPlugin) -> Self {
+        Self {
+            name: plugin.name().to_string(),
+            path: PathBuf::new(),
+            type_: PluginType::Builtin(plugin),
+            instrument: plugin.is_instrument(),
+        }
+    }
+
+    pub fn load(&self, a: &Audio) -> Result<Box<dyn AudioProcessor>, String> {
+        match self.type_ {
+            PluginType::Vst2 => Ok(Box::new(load_vst2_plugin(&self.path, a)?)),
+            PluginType::Builtin(plugin) => Ok(plugin.load(a)),
+            PluginType::Vst3 => Err(format!("'{}' is a VST3 plugin, which can't be loaded yet", self.name)),
+            PluginType::Unknown => Err(format!("'{}' isn't a plugin that can be loaded", self.name)),
+        }
+    }
 }
 
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum PluginType {
     Unknown,
     Vst2,
     Vst3,
+    Builtin(BuiltinPlugin),
+}
+
+/// Processors that ship with the DAW rather than being loaded from a file.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum BuiltinPlugin {
+    Synth,
+    Sampler,
+    Eq,
+    Compressor,
+    Delay,
+    Reverb,
+    Limiter,
+}
+
+impl BuiltinPlugin {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "synth" => Some(BuiltinPlugin::Synth),
+            "sampler" => Some(BuiltinPlugin::Sampler),
+            "eq" => Some(BuiltinPlugin::Eq),
+            "compressor" => Some(BuiltinPlugin::Compressor),
+            "delay" => Some(BuiltinPlugin::Delay),
+            "reverb" => Some(BuiltinPlugin::Reverb),
+            "limiter" => Some(BuiltinPlugin::Limiter),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuiltinPlugin::Synth => "Synth",
+            BuiltinPlugin::Sampler => "Sampler",
+            BuiltinPlugin::Eq => "EQ",
+            BuiltinPlugin::Compressor => "Compressor",
+            BuiltinPlugin::Delay => "Delay",
+            BuiltinPlugin::Reverb => "Reverb",
+            BuiltinPlugin::Limiter => "Limiter",
+        }
+    }
+
+    pub fn is_instrument(&self) -> bool {
+        matches!(self, BuiltinPlugin::Synth | BuiltinPlugin::Sampler)
+    }
+
+    fn load(&self, a: &Audio) -> Box<dyn AudioProcessor> {
+        let (sample_rate, block_size) = (a.sample_rate.get_copy(), a.block_size.get_copy());
+
+        match self {
+            BuiltinPlugin::Synth => Box::new(Synth::new(sample_rate, block_size)),
+            BuiltinPlugin::Sampler => {
+                Box::new(Sampler::new(&Kit::default(), sample_rate, block_size, &mut vec![]))
+            },
+            BuiltinPlugin::Eq => Box::new(Eq::new(sample_rate, block_size)),
+            BuiltinPlugin::Compressor => Box::new(Compressor::new(sample_rate, block_size)),
+            BuiltinPlugin::Delay => Box::new(Delay::new(sample_rate, block_size)),
+            BuiltinPlugin::Reverb => Box::new(Reverb::new(sample_rate, block_size)),
+            BuiltinPlugin::Limiter => Box::new(Limiter::new(sample_rate, block_size)),
+        }
+    }
 }
 
 struct Vst2 {
@@ -106,8 +233,22 @@ struct Vst2 {
     host_buffer: HostBuffer<FrameValue>,
     output: Buffer,
     editor: Option<Box<dyn Editor>>,
+    latency: usize,
+    parameters: Arc<dyn PluginParameters>,
+    /// Parameter values straight after loading.
+    defaults: Vec<f32>,
+    /// Filled by the plugin through `Vst2Host::automate`.
+    changes: Arc<Mutex<Vec<(ParamId, f32)>>>,
+    /// Reused every block, so sending events doesn't allocate.
+    midi_events: Vec<vst::api::MidiEvent>,
+    events_object: Box<Vst2Events<MAX_EVENTS>>,
 }
 
+// VST2 plugins are created on the UI thread and then processed on the audio
+// thread, which the format allows. The editor is only opened and closed from
+// the UI thread, before the plugin is handed over.
+unsafe impl Send for Vst2 {}
+
 #[derive(PartialEq, Eq)]
 enum Vst2State {
     Suspended,
@@ -123,6 +264,7 @@ impl AudioProcessor for Vst2 {
     fn change_block_size(&mut self, size: BlockSize) {
         self.suspend();
         self.plugin_instance.set_block_size(size);
+        self.output = Buffer::new_non_reactive(2, size as usize);
     }
 
     fn show_gui(&mut self, window_id: *mut c_void) -> Result<(), String> {
@@ -131,7 +273,7 @@ impl AudioProcessor for Vst2 {
             .get_editor()
             .ok_or_else(|| "Plugin has no editor".to_string())?;
 
-        editor.open(window_id as *mut std::ffi::c_void);
+        editor.open(window_id);
 
         self.editor = Some(editor);
 
@@ -162,16 +304,68 @@ impl AudioProcessor for Vst2 {
         self.state = Vst2State::Resumed;
     }
 
+    fn latency(&self) -> usize {
+        self.latency
+    }
+
+    fn params(&self) -> Vec<ParamDescriptor> {
+        self.defaults
+            .iter()
+            .enumerate()
+            .map(|(i, default)| ParamDescriptor {
+                id: i as ParamId,
+                name: self.parameters.get_parameter_name(i as i32),
+                min: 0.,
+                max: 1.,
+                default: *default,
+                unit: self.parameters.get_parameter_label(i as i32),
+            })
+            .collect()
+    }
+
+    fn get_param(&self, id: ParamId) -> Option<f32> {
+        if (id as usize) < self.defaults.len() {
+            Some(self.parameters.get_parameter(id as i32))
+        } else {
+            None
+        }
+    }
+
+    fn set_param(&mut self, id: ParamId, value: f32) {
+        if (id as usize) < self.defaults.len() {
+            self.parameters.set_parameter(id as i32, value.clamp(0., 1.));
+        }
+    }
+
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        if (id as usize) >= self.defaults.len() {
+            return None;
+        }
+
+        let text = self.parameters.get_parameter_text(id as i32);
+        let label = self.parameters.get_parameter_label(id as i32);
+        Some(format!("{} {}", text, label).trim().to_string())
+    }
+
+    fn take_param_changes(&mut self) -> Vec<(ParamId, f32)> {
+        // The plugin may be calling back from its editor on another thread,
+        // in which case the changes are picked up next time.
+        match self.changes.try_lock() {
+            Ok(mut changes) => changes.drain(..).collect(),
+            Err(_) => vec![],
+        }
+    }
+
     fn process(&mut self, midi_events: Option<&Vec<MidiEvent>>, input: Buffer, _t: Time) -> Buffer {
         self.resume();
         self.plugin_instance.start_process();
 
         if let Some(midi_events) = midi_events {
-            process_vst2_midi_events_list(&self.plugin_instance, midi_events);
+            self.send_midi_events(midi_events);
         }
 
-        let inputs_buf = input.data.as_ref().borrow();
-        let mut outputs_buf = self.output.data.as_ref().borrow_mut();
+        let inputs_buf = input.data.borrow();
+        let mut outputs_buf = self.output.data.borrow_mut();
         let mut audio_buffer = self.host_buffer.bind(&inputs_buf, &mut outputs_buf);
 
         self.plugin_instance.process(&mut audio_buffer);
@@ -181,46 +375,39 @@ impl AudioProcessor for Vst2 {
     }
 }
 
-#[allow(dead_code)]
-struct Vst2Host;
+struct Vst2Host {
+    changes: Arc<Mutex<Vec<(ParamId, f32)>>>,
+}
 
 impl Host for Vst2Host {
     fn automate(&self, index: i32, value: f32) {
-        println!("Parameter {} had its value changed to {}", index, value);
+        if let Ok(mut changes) = self.changes.lock() {
+            changes.push((index as ParamId, value));
+        }
     }
 }
 
-fn midi_event_to_vst2_event(midi_event: &MidiEvent) -> *mut Event {
-    let mut event = vst::api::MidiEvent {
+fn midi_event_to_vst2_event(midi_event: &MidiEvent) -> vst::api::MidiEvent {
+    vst::api::MidiEvent {
         event_type: vst::api::EventType::Midi,
-        byte_size: 0,
-        delta_frames: 0,
+        byte_size: std::mem::size_of::<vst::api::MidiEvent>() as i32,
+        delta_frames: midi_event.delta_frames,
         flags: 0,
         note_length: 0,
         note_offset: 0,
-        midi_data: [0; 3],
+        midi_data: midi_event.to_bytes(),
         detune: 0,
         note_off_velocity: 0,
         _reserved1: 0,
         _reserved2: 0,
         _midi_reserved: 0,
-    };
-
-    event.midi_data[0] = midi_event.status_byte();
-
-    let note = midi_event.note().unwrap();
-
-    event.midi_data[1] = note.note as u8;
-    event.midi_data[2] = note.velocity as u8;
-
-    println!("event.midi_data: {:?}", event.midi_data);
-
-    let event: Event = unsafe { std::mem::transmute(event) };
-    let event = Box::into_raw(Box::new(event));
-
-    event
+    }
 }
 
+/// Events handed to a plugin in one go. Blocks with more are sent in several
+/// batches.
+const MAX_EVENTS: usize = 512;
+
 #[repr(C)]
 pub struct Vst2Events<const L: usize> {
     pub num_events: i32,
@@ -230,85 +417,92 @@ pub struct Vst2Events<const L: usize> {
     pub events: [*mut Event; L],
 }
 
+impl<const L: usize> Default for Vst2Events<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const L: usize> Vst2Events<L> {
-    pub fn new(len: usize) -> Self {
+    pub fn new() -> Self {
         Self {
-            num_events: len as i32,
+            num_events: 0,
             _reserved: 0,
             events: [std::ptr::null_mut(); L],
         }
     }
 }
 
-fn process_vst2_midi_events_list(plugin_instance: &PluginInstance, midi_events: &Vec<MidiEvent>) {
-    let mut events: Vec<*mut Event> = midi_events
-        .into_iter()
-        .map(|e| midi_event_to_vst2_event(e))
-        .collect();
-
-    let num_events = events.len();
-
-    const MAX_EVENTS: usize = 100;
-
-    let mut events_object = Vst2Events::<MAX_EVENTS>::new(num_events);
-
-    if num_events > MAX_EVENTS {
-        // Alloc on heap instead
-        todo!();
-    }
-
-    for (i, event) in events.iter().enumerate() {
-        events_object.events[i] = *event;
+impl Vst2 {
+    /// Hands the plugin `midi_events` for the coming block, using storage
+    /// allocated when the plugin was loaded. Each batch overwrites the last,
+    /// so this relies on plugins copying events during `ProcessEvents`, as
+    /// the common plugin frameworks do.
+    fn send_midi_events(&mut self, midi_events: &[MidiEvent]) {
+        for batch in midi_events.chunks(MAX_EVENTS) {
+            self.midi_events.clear();
+            self.midi_events
+                .extend(batch.iter().map(midi_event_to_vst2_event));
+
+            for (pointer, event) in self
+                .events_object
+                .events
+                .iter_mut()
+                .zip(self.midi_events.iter_mut())
+            {
+                *pointer = event as *mut vst::api::MidiEvent as *mut Event;
+            }
+            self.events_object.num_events = batch.len() as i32;
+
+            self.plugin_instance.dispatch(
+                vst::plugin::OpCode::ProcessEvents,
+                0,
+                0,
+                &mut *self.events_object as *mut Vst2Events<MAX_EVENTS> as *mut c_void,
+                0.0,
+            );
+        }
     }
-
-    plugin_instance.dispatch(
-        vst::plugin::OpCode::ProcessEvents,
-        0,
-        0,
-        &events_object as *const _ as *mut _,
-        0.0,
-    );
 }
 
-fn load_vst2_plugin(path: &Path, a: &Audio) -> Vst2 {
-    let host = Arc::new(Mutex::new(Vst2Host));
+fn load_vst2_plugin(path: &Path, a: &Audio) -> Result<Vst2, String> {
+    let changes = Arc::new(Mutex::new(vec![]));
+    let host = Arc::new(Mutex::new(Vst2Host {
+        changes: changes.clone(),
+    }));
 
     let mut loader = PluginLoader::load(path, Arc::clone(&host))
-        .unwrap_or_else(|e| panic!("Failed to load plugin: {}", e));
+        .map_err(|e| format!("Failed to load plugin '{}': {}", path.display(), e))?;
 
-    let mut instance = loader.instance().unwrap();
+    let mut instance = loader
+        .instance()
+        .map_err(|e| format!("Failed to start plugin '{}': {}", path.display(), e))?;
 
     let info = instance.get_info();
 
-    println!(
-        "Loaded '{}':\n\t\
-         Vendor: {}\n\t\
-         Presets: {}\n\t\
-         Parameters: {}\n\t\
-         VST ID: {}\n\t\
-         Version: {}\n\t\
-         Initial Delay: {} samples",
-        info.name,
-        info.vendor,
-        info.presets,
-        info.parameters,
-        info.unique_id,
-        info.version,
-        info.initial_delay
-    );
-
     instance.init();
 
     instance.set_sample_rate(a.sample_rate.get_copy());
     instance.set_block_size(a.block_size.get_copy());
 
-    let output = Buffer::new(2, &a.block_size);
+    let output = Buffer::new_non_reactive(2, a.block_size.get_copy() as usize);
+
+    let parameters = instance.get_parameter_object();
+    let defaults = (0..info.parameters)
+        .map(|i| parameters.get_parameter(i))
+        .collect();
 
-    Vst2 {
+    Ok(Vst2 {
         plugin_instance: instance,
         state: Vst2State::Suspended,
         host_buffer: HostBuffer::new(2, 2),
         output,
         editor: None,
-    }
+        latency: info.initial_delay.max(0) as usize,
+        parameters,
+        defaults,
+        changes,
+        midi_events: Vec::with_capacity(MAX_EVENTS),
+        events_object: Box::new(Vst2Events::new()),
+    })
 }
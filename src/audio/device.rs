@@ -1,43 +1,90 @@
-use sdl2::{
-    audio::{AudioCallback, AudioDevice},
-    Sdl,
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use super::{Audio, BlockSize};
+use sdl2::audio::{AudioCallback, AudioDevice};
+
+use super::{
+    realtime::SharedEngine,
+    wav::{WavFormat, WavWriter},
+    Audio, BlockSize, SampleRate,
+};
 
 pub trait Device {
     fn get_name(&self) -> String;
-    fn open(&mut self) {}
+    fn open(&mut self) -> Result<(), String> {
+        Ok(())
+    }
     fn close(&mut self) {}
+    /// Problems since the last call, for the status line.
+    fn take_errors(&mut self) -> Vec<String> {
+        vec![]
+    }
 }
 
-const SDL_CHANNELS: u8 = 2;
+#[derive(Clone, PartialEq, Debug)]
+pub enum DeviceKind {
+    Sdl,
+    Null,
+    WavFile(PathBuf),
+}
 
-impl AudioCallback for SDLAudioDeviceCallback {
-    type Channel = f32;
+impl DeviceKind {
+    /// `sdl`, `null` or `wav <path>` (also accepts `wav:<path>`).
+    pub fn from_str(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (name, arg) = s
+            .split_once([' ', ':'])
+            .map(|(name, arg)| (name, arg.trim()))
+            .unwrap_or((s, ""));
 
-    fn callback(&mut self, out: &mut [f32]) {
-        // println!("{}", out.len());
+        match (name, arg) {
+            ("sdl", "") => Some(DeviceKind::Sdl),
+            ("null", "") => Some(DeviceKind::Null),
+            ("wav", path) if !path.is_empty() => Some(DeviceKind::WavFile(PathBuf::from(path))),
+            _ => None,
+        }
+    }
+}
+
+/// Creates and opens a device of the given kind driving `engine`.
+pub fn open_device(
+    kind: &DeviceKind,
+    engine: SharedEngine,
+    a: &Audio,
+) -> Result<Box<dyn Device>, String> {
+    let mut device: Box<dyn Device> = match kind {
+        DeviceKind::Sdl => Box::new(SDLAudioDevice::new(a, engine)?),
+        DeviceKind::Null => Box::new(NullDevice::new(a, engine)),
+        DeviceKind::WavFile(path) => Box::new(WavFileDevice::new(a, engine, path.clone())),
+    };
 
-        // for (channel_idx, channel) in out.chunks_mut((self.block_size / SDL_CHANNELS as i64) as usize).enumerate() {
-        //     // if channel_idx == 0 {
-        //     //     continue;
-        //     // }
+    device.open()?;
+    Ok(device)
+}
 
-        //     println!("{}", channel_idx);
+const SDL_CHANNELS: u8 = 2;
 
-        //     for (i, sample) in channel.iter_mut().enumerate() {
-        //         *sample = (i as f32 / 44100.0 * 440.0 * 2.0 * 3.141592).sin();
-        //     }
-        // }
+impl AudioCallback for SDLAudioDeviceCallback {
+    type Channel = f32;
 
-        // self.device.process(out);
+    fn callback(&mut self, out: &mut [f32]) {
+        // The lock is only ever contended while devices are being swapped.
+        match self.engine.try_lock() {
+            Ok(mut engine) => engine.fill_interleaved(out, SDL_CHANNELS as usize),
+            Err(_) => out.fill(0.),
+        }
     }
 }
 
 pub struct SDLAudioDeviceCallback {
-    // pub device: Box<dyn Device>,
-    pub block_size: BlockSize,
+    pub engine: SharedEngine,
 }
 
 pub struct SDLAudioDevice {
@@ -45,33 +92,28 @@ pub struct SDLAudioDevice {
 }
 
 impl SDLAudioDevice {
-    pub fn new(sdl_context: &Sdl, a: &Audio) -> Self {
-        let audio_subsystem = sdl_context.audio().unwrap();
-
-        let block_size = a.block_size.get_copy();
-        let block_size = 1024;
+    pub fn new(a: &Audio, engine: SharedEngine) -> Result<Self, String> {
+        let audio_subsystem = a
+            .sdl_audio
+            .as_ref()
+            .ok_or_else(|| "SDL audio is not available".to_string())?;
 
         let desired_spec = sdl2::audio::AudioSpecDesired {
             freq: Some(a.sample_rate.get_copy() as i32),
             channels: Some(SDL_CHANNELS),
-            samples: Some(block_size as u16),
+            samples: Some(a.block_size.get_copy() as u16),
         };
 
-        let device = audio_subsystem
-            .open_playback(None, &desired_spec, |spec| {
-                println!("spec: {:?}", spec);
-                SDLAudioDeviceCallback {
-                    block_size,
-                    // device: Box::new(device),
-                }
-            })
-            .unwrap();
-
-        device.resume();
+        let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
+            if let Ok(mut engine) = engine.lock() {
+                engine.set_format(spec.freq as SampleRate, spec.samples as BlockSize);
+            }
+            SDLAudioDeviceCallback { engine }
+        })?;
 
-        Self {
+        Ok(Self {
             device: Box::new(device),
-        }
+        })
     }
 }
 
@@ -80,7 +122,209 @@ impl Device for SDLAudioDevice {
         "SDL".to_string()
     }
 
-    fn open(&mut self) {
+    fn open(&mut self) -> Result<(), String> {
         self.device.resume();
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.device.pause();
+    }
+}
+
+/// Pulls blocks from the engine on a background thread at the rate a real
+/// sound card would, handing each one to `sink`.
+struct TimerThread {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TimerThread {
+    fn new() -> Self {
+        Self {
+            running: Arc::new(AtomicBool::new(false)),
+            handle: None,
+        }
+    }
+
+    fn start<F>(&mut self, engine: SharedEngine, sample_rate: SampleRate, block_size: BlockSize, mut sink: F)
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        if self.handle.is_some() {
+            return;
+        }
+
+        if let Ok(mut engine) = engine.lock() {
+            engine.set_format(sample_rate, block_size);
+        }
+
+        let running = self.running.clone();
+        running.store(true, Ordering::SeqCst);
+
+        let block_duration = Duration::from_secs_f64(block_size as f64 / sample_rate as f64);
+        let mut buffer = vec![0.; block_size as usize * 2];
+
+        self.handle = Some(thread::spawn(move || {
+            let mut next = Instant::now();
+
+            while running.load(Ordering::SeqCst) {
+                match engine.lock() {
+                    Ok(mut engine) => engine.fill_interleaved(&mut buffer, 2),
+                    Err(_) => buffer.fill(0.),
+                }
+
+                sink(&buffer);
+
+                next += block_duration;
+                if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+        }));
+    }
+
+    fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Drives the engine without producing any sound, for machines with no sound
+/// card.
+pub struct NullDevice {
+    engine: SharedEngine,
+    sample_rate: SampleRate,
+    block_size: BlockSize,
+    thread: TimerThread,
+}
+
+impl NullDevice {
+    pub fn new(a: &Audio, engine: SharedEngine) -> Self {
+        Self {
+            engine,
+            sample_rate: a.sample_rate.get_copy(),
+            block_size: a.block_size.get_copy(),
+            thread: TimerThread::new(),
+        }
+    }
+}
+
+impl Device for NullDevice {
+    fn get_name(&self) -> String {
+        "Null".to_string()
+    }
+
+    fn open(&mut self) -> Result<(), String> {
+        self.thread
+            .start(self.engine.clone(), self.sample_rate, self.block_size, |_| ());
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.thread.stop();
+    }
+}
+
+impl Drop for NullDevice {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Plays in real time like `NullDevice`, but writes everything the engine
+/// produces to a WAV file. The file is finalised when the device is closed.
+pub struct WavFileDevice {
+    engine: SharedEngine,
+    path: PathBuf,
+    sample_rate: SampleRate,
+    block_size: BlockSize,
+    thread: TimerThread,
+    writer: Option<Arc<Mutex<WavWriter>>>,
+    errors: Arc<Mutex<Vec<String>>>,
+}
+
+impl WavFileDevice {
+    pub fn new(a: &Audio, engine: SharedEngine, path: PathBuf) -> Self {
+        Self {
+            engine,
+            path,
+            sample_rate: a.sample_rate.get_copy(),
+            block_size: a.block_size.get_copy(),
+            thread: TimerThread::new(),
+            writer: None,
+            errors: Arc::new(Mutex::new(vec![])),
+        }
+    }
+}
+
+impl Device for WavFileDevice {
+    fn get_name(&self) -> String {
+        format!("WAV file ({})", self.path.display())
+    }
+
+    fn open(&mut self) -> Result<(), String> {
+        if self.writer.is_some() {
+            return Ok(());
+        }
+
+        let writer = WavWriter::create(&self.path, 2, self.sample_rate, WavFormat::Float32)?;
+        let writer = Arc::new(Mutex::new(writer));
+
+        let sink_writer = writer.clone();
+        let errors = self.errors.clone();
+        let mut failing = false;
+        self.thread
+            .start(self.engine.clone(), self.sample_rate, self.block_size, move |samples| {
+                if let Ok(mut writer) = sink_writer.lock() {
+                    match writer.write_interleaved(samples) {
+                        // Only the first of a run of failures is reported,
+                        // otherwise there'd be one every block.
+                        Err(e) if !failing => {
+                            failing = true;
+                            if let Ok(mut errors) = errors.lock() {
+                                errors.push(format!("Failed to write audio: {}", e));
+                            }
+                        }
+                        Err(_) => (),
+                        Ok(()) => failing = false,
+                    }
+                }
+            });
+
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.thread.stop();
+
+        let writer = match self.writer.take().and_then(|w| Arc::try_unwrap(w).ok()) {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        if let Ok(writer) = writer.into_inner() {
+            if let Err(e) = writer.finish() {
+                if let Ok(mut errors) = self.errors.lock() {
+                    errors.push(format!("Failed to finish '{}': {}", self.path.display(), e));
+                }
+            }
+        }
+    }
+
+    fn take_errors(&mut self) -> Vec<String> {
+        self.errors
+            .lock()
+            .map(|mut errors| std::mem::take(&mut *errors))
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for WavFileDevice {
+    fn drop(&mut self) {
+        self.close();
     }
 }
@@ -0,0 +1,164 @@
+use crate::midi::{MidiEvent, Time};
+
+use super::{
+    super::{
+        audio_processor::{AudioProcessor, ParamDescriptor, ParamId},
+        BlockSize, Buffer, SampleRate,
+    },
+    db_to_gain, gain_to_db, smoothing_coefficient, ParamInfo, Params,
+};
+
+const THRESHOLD: usize = 0;
+const RATIO: usize = 1;
+const ATTACK: usize = 2;
+const RELEASE: usize = 3;
+const KNEE: usize = 4;
+const MAKEUP: usize = 5;
+
+static PARAMS: [ParamInfo; 6] = [
+    ParamInfo {
+        name: "threshold",
+        min: -60.,
+        max: 0.,
+        default: -18.,
+        unit: "dB",
+    },
+    ParamInfo {
+        name: "ratio",
+        min: 1.,
+        max: 20.,
+        default: 4.,
+        unit: ":1",
+    },
+    ParamInfo {
+        name: "attack",
+        min: 0.1,
+        max: 200.,
+        default: 10.,
+        unit: "ms",
+    },
+    ParamInfo {
+        name: "release",
+        min: 5.,
+        max: 2000.,
+        default: 100.,
+        unit: "ms",
+    },
+    ParamInfo {
+        name: "knee",
+        min: 0.,
+        max: 24.,
+        default: 6.,
+        unit: "dB",
+    },
+    ParamInfo {
+        name: "makeup",
+        min: 0.,
+        max: 24.,
+        default: 0.,
+        unit: "dB",
+    },
+];
+
+/// Feed-forward compressor. Gain reduction is worked out from the sidechain
+/// input when one is connected, otherwise from the signal itself.
+pub struct Compressor {
+    pub params: Params,
+    sample_rate: SampleRate,
+    sidechain: Option<Buffer>,
+    /// Current gain reduction in dB, always zero or negative.
+    reduction: f32,
+    output: Buffer,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: SampleRate, block_size: BlockSize) -> Self {
+        Self {
+            params: Params::new(&PARAMS),
+            sample_rate,
+            sidechain: None,
+            reduction: 0.,
+            output: Buffer::new_non_reactive(2, block_size as usize),
+        }
+    }
+
+    /// Static curve: how many dB to reduce a level by.
+    fn gain_reduction(&self, level_db: f32) -> f32 {
+        let threshold = self.params.get(THRESHOLD);
+        let slope = 1. / self.params.get(RATIO) - 1.;
+        let knee = self.params.get(KNEE);
+        let over = level_db - threshold;
+
+        if knee > 0. && over.abs() <= knee / 2. {
+            slope * (over + knee / 2.).powi(2) / (2. * knee)
+        } else if over > 0. {
+            slope * over
+        } else {
+            0.
+        }
+    }
+}
+
+impl AudioProcessor for Compressor {
+    fn process(&mut self, _events: Option<&Vec<MidiEvent>>, input: Buffer, _t: Time) -> Buffer {
+        let attack = smoothing_coefficient(self.params.get(ATTACK), self.sample_rate);
+        let release = smoothing_coefficient(self.params.get(RELEASE), self.sample_rate);
+        let makeup = self.params.get(MAKEUP);
+
+        let key = self.sidechain.clone().unwrap_or_else(|| input.clone());
+        let key = key.data.borrow();
+        let input = input.data.borrow();
+        let mut output = self.output.data.borrow_mut();
+
+        let num_frames = input.first().map(|c| c.len()).unwrap_or(0);
+
+        for i in 0..num_frames {
+            let peak = key
+                .iter()
+                .map(|channel| channel.get(i).cloned().unwrap_or(0.).abs())
+                .fold(0., f32::max);
+
+            let target = self.gain_reduction(gain_to_db(peak));
+            // More reduction is an attack, less is a release.
+            let coefficient = if target < self.reduction { attack } else { release };
+            self.reduction = target + coefficient * (self.reduction - target);
+
+            let gain = db_to_gain(self.reduction + makeup);
+
+            for (in_channel, out_channel) in input.iter().zip(output.iter_mut()) {
+                out_channel[i] = in_channel[i] * gain;
+            }
+        }
+
+        drop(output);
+        self.output.clone()
+    }
+
+    fn set_sidechain(&mut self, input: Option<Buffer>) {
+        self.sidechain = input;
+    }
+
+    fn params(&self) -> Vec<ParamDescriptor> {
+        self.params.descriptors()
+    }
+
+    fn get_param(&self, id: ParamId) -> Option<f32> {
+        self.params.value(id)
+    }
+
+    fn set_param(&mut self, id: ParamId, value: f32) {
+        self.params.set(id as usize, value);
+    }
+
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        self.params.display(id)
+    }
+
+    fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.sample_rate = rate;
+    }
+
+    fn change_block_size(&mut self, size: BlockSize) {
+        self.output = Buffer::new_non_reactive(2, size as usize);
+    }
+}
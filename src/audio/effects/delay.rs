@@ -0,0 +1,157 @@
+use crate::midi::{MidiEvent, Time};
+
+use super::{
+    super::{
+        audio_processor::{AudioProcessor, ParamDescriptor, ParamId},
+        BlockSize, Buffer, SampleRate,
+    },
+    ParamInfo, Params,
+};
+
+const TIME: usize = 0;
+const FEEDBACK: usize = 1;
+const MIX: usize = 2;
+const PING_PONG: usize = 3;
+
+/// Longest delay in seconds, which bounds the buffer size.
+const MAX_SECONDS: f32 = 8.;
+
+static PARAMS: [ParamInfo; 4] = [
+    ParamInfo {
+        name: "time",
+        min: 1. / 16.,
+        max: 4.,
+        default: 0.75,
+        unit: "beats",
+    },
+    ParamInfo {
+        name: "feedback",
+        min: 0.,
+        max: 0.95,
+        default: 0.4,
+        unit: "",
+    },
+    ParamInfo {
+        name: "mix",
+        min: 0.,
+        max: 1.,
+        default: 0.3,
+        unit: "",
+    },
+    ParamInfo {
+        name: "ping_pong",
+        min: 0.,
+        max: 1.,
+        default: 0.,
+        unit: "",
+    },
+];
+
+/// Echo whose time is set in beats and follows the project tempo.
+pub struct Delay {
+    pub params: Params,
+    sample_rate: SampleRate,
+    tempo: f32,
+    /// A ring buffer per channel.
+    lines: Vec<Vec<f32>>,
+    pos: usize,
+    output: Buffer,
+}
+
+impl Delay {
+    pub fn new(sample_rate: SampleRate, block_size: BlockSize) -> Self {
+        let mut delay = Self {
+            params: Params::new(&PARAMS),
+            sample_rate,
+            tempo: 120.,
+            lines: vec![],
+            pos: 0,
+            output: Buffer::new_non_reactive(2, block_size as usize),
+        };
+        delay.allocate();
+        delay
+    }
+
+    fn allocate(&mut self) {
+        let length = (MAX_SECONDS * self.sample_rate) as usize + 1;
+        self.lines = vec![vec![0.; length]; 2];
+        self.pos = 0;
+    }
+
+    fn delay_frames(&self) -> usize {
+        let seconds = self.params.get(TIME) * 60. / self.tempo.max(1.);
+        let frames = (seconds.min(MAX_SECONDS) * self.sample_rate) as usize;
+        frames.clamp(1, self.lines[0].len() - 1)
+    }
+}
+
+impl AudioProcessor for Delay {
+    fn process(&mut self, _events: Option<&Vec<MidiEvent>>, input: Buffer, _t: Time) -> Buffer {
+        let delay = self.delay_frames();
+        let feedback = self.params.get(FEEDBACK);
+        let mix = self.params.get(MIX);
+        let ping_pong = self.params.get(PING_PONG) >= 0.5;
+
+        let input = input.data.borrow();
+        let mut output = self.output.data.borrow_mut();
+        let length = self.lines[0].len();
+        let num_frames = input.first().map(|c| c.len()).unwrap_or(0);
+
+        for i in 0..num_frames {
+            let read = (self.pos + length - delay) % length;
+
+            let dry_left = input[0][i];
+            let dry_right = input.get(1).map(|c| c[i]).unwrap_or(dry_left);
+            let wet_left = self.lines[0][read];
+            let wet_right = self.lines[1][read];
+
+            // Ping pong feeds each side's echoes into the other side.
+            let (into_left, into_right) = if ping_pong {
+                ((dry_left + dry_right) * 0.5 + wet_right * feedback, wet_left * feedback)
+            } else {
+                (dry_left + wet_left * feedback, dry_right + wet_right * feedback)
+            };
+
+            self.lines[0][self.pos] = into_left;
+            self.lines[1][self.pos] = into_right;
+            self.pos = (self.pos + 1) % length;
+
+            output[0][i] = dry_left * (1. - mix) + wet_left * mix;
+            if let Some(channel) = output.get_mut(1) {
+                channel[i] = dry_right * (1. - mix) + wet_right * mix;
+            }
+        }
+
+        drop(output);
+        self.output.clone()
+    }
+
+    fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo;
+    }
+
+    fn params(&self) -> Vec<ParamDescriptor> {
+        self.params.descriptors()
+    }
+
+    fn get_param(&self, id: ParamId) -> Option<f32> {
+        self.params.value(id)
+    }
+
+    fn set_param(&mut self, id: ParamId, value: f32) {
+        self.params.set(id as usize, value);
+    }
+
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        self.params.display(id)
+    }
+
+    fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.sample_rate = rate;
+        self.allocate();
+    }
+
+    fn change_block_size(&mut self, size: BlockSize) {
+        self.output = Buffer::new_non_reactive(2, size as usize);
+    }
+}
@@ -0,0 +1,283 @@
+use std::f32::consts::PI;
+
+use crate::midi::{MidiEvent, Time};
+
+use super::{
+    super::{
+        audio_processor::{AudioProcessor, ParamDescriptor, ParamId},
+        BlockSize, Buffer, SampleRate,
+    },
+    db_to_gain, ParamInfo, Params,
+};
+
+#[derive(Clone, Copy)]
+enum BandType {
+    LowShelf,
+    Peak,
+    HighShelf,
+}
+
+const BANDS: [BandType; 4] = [
+    BandType::LowShelf,
+    BandType::Peak,
+    BandType::Peak,
+    BandType::HighShelf,
+];
+
+/// Frequency, gain and Q for each band in `BANDS`, then the output gain.
+static PARAMS: [ParamInfo; 13] = [
+    ParamInfo {
+        name: "band1_freq",
+        min: 20.,
+        max: 20_000.,
+        default: 100.,
+        unit: "Hz",
+    },
+    ParamInfo {
+        name: "band1_gain",
+        min: -24.,
+        max: 24.,
+        default: 0.,
+        unit: "dB",
+    },
+    ParamInfo {
+        name: "band1_q",
+        min: 0.1,
+        max: 18.,
+        default: 0.707,
+        unit: "",
+    },
+    ParamInfo {
+        name: "band2_freq",
+        min: 20.,
+        max: 20_000.,
+        default: 500.,
+        unit: "Hz",
+    },
+    ParamInfo {
+        name: "band2_gain",
+        min: -24.,
+        max: 24.,
+        default: 0.,
+        unit: "dB",
+    },
+    ParamInfo {
+        name: "band2_q",
+        min: 0.1,
+        max: 18.,
+        default: 0.707,
+        unit: "",
+    },
+    ParamInfo {
+        name: "band3_freq",
+        min: 20.,
+        max: 20_000.,
+        default: 2_500.,
+        unit: "Hz",
+    },
+    ParamInfo {
+        name: "band3_gain",
+        min: -24.,
+        max: 24.,
+        default: 0.,
+        unit: "dB",
+    },
+    ParamInfo {
+        name: "band3_q",
+        min: 0.1,
+        max: 18.,
+        default: 0.707,
+        unit: "",
+    },
+    ParamInfo {
+        name: "band4_freq",
+        min: 20.,
+        max: 20_000.,
+        default: 8_000.,
+        unit: "Hz",
+    },
+    ParamInfo {
+        name: "band4_gain",
+        min: -24.,
+        max: 24.,
+        default: 0.,
+        unit: "dB",
+    },
+    ParamInfo {
+        name: "band4_q",
+        min: 0.1,
+        max: 18.,
+        default: 0.707,
+        unit: "",
+    },
+    ParamInfo {
+        name: "output",
+        min: -24.,
+        max: 24.,
+        default: 0.,
+        unit: "dB",
+    },
+];
+
+/// Normalised coefficients for a transposed direct form II biquad.
+#[derive(Clone, Copy, Default)]
+struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// From the RBJ audio EQ cookbook.
+    fn new(band: BandType, freq: f32, gain_db: f32, q: f32, sample_rate: SampleRate) -> Self {
+        let freq = freq.min(sample_rate * 0.49);
+        let a = 10f32.powf(gain_db / 40.);
+        let w0 = 2. * PI * freq / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+
+        let (b0, b1, b2, a0, a1, a2) = match band {
+            BandType::Peak => (
+                1. + alpha * a,
+                -2. * cos,
+                1. - alpha * a,
+                1. + alpha / a,
+                -2. * cos,
+                1. - alpha / a,
+            ),
+            BandType::LowShelf => {
+                let s = 2. * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.) - (a - 1.) * cos + s),
+                    2. * a * ((a - 1.) - (a + 1.) * cos),
+                    a * ((a + 1.) - (a - 1.) * cos - s),
+                    (a + 1.) + (a - 1.) * cos + s,
+                    -2. * ((a - 1.) + (a + 1.) * cos),
+                    (a + 1.) + (a - 1.) * cos - s,
+                )
+            }
+            BandType::HighShelf => {
+                let s = 2. * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.) + (a - 1.) * cos + s),
+                    -2. * a * ((a - 1.) + (a + 1.) * cos),
+                    a * ((a + 1.) + (a - 1.) * cos - s),
+                    (a + 1.) - (a - 1.) * cos + s,
+                    2. * ((a - 1.) - (a + 1.) * cos),
+                    (a + 1.) - (a - 1.) * cos - s,
+                )
+            }
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    fn next(&mut self, c: &Coefficients, input: f32) -> f32 {
+        let output = c.b0 * input + self.z1;
+        self.z1 = c.b1 * input - c.a1 * output + self.z2;
+        self.z2 = c.b2 * input - c.a2 * output;
+        output
+    }
+}
+
+/// Four band parametric EQ: a low shelf, two peaks and a high shelf.
+pub struct Eq {
+    pub params: Params,
+    sample_rate: SampleRate,
+    coefficients: [Coefficients; 4],
+    /// Filter state per channel then band.
+    filters: Vec<[Biquad; 4]>,
+    output: Buffer,
+}
+
+impl Eq {
+    pub fn new(sample_rate: SampleRate, block_size: BlockSize) -> Self {
+        Self {
+            params: Params::new(&PARAMS),
+            sample_rate,
+            coefficients: [Coefficients::default(); 4],
+            filters: vec![[Biquad::default(); 4]; 2],
+            output: Buffer::new_non_reactive(2, block_size as usize),
+        }
+    }
+
+    fn update_coefficients(&mut self) {
+        for (i, band) in BANDS.iter().enumerate() {
+            self.coefficients[i] = Coefficients::new(
+                *band,
+                self.params.get(i * 3),
+                self.params.get(i * 3 + 1),
+                self.params.get(i * 3 + 2),
+                self.sample_rate,
+            );
+        }
+    }
+}
+
+impl AudioProcessor for Eq {
+    fn process(&mut self, _events: Option<&Vec<MidiEvent>>, input: Buffer, _t: Time) -> Buffer {
+        if self.params.take_changed() {
+            self.update_coefficients();
+        }
+
+        let gain = db_to_gain(self.params.get(12));
+        let input = input.data.borrow();
+        let mut output = self.output.data.borrow_mut();
+
+        for (c, (in_channel, out_channel)) in input.iter().zip(output.iter_mut()).enumerate() {
+            let filters = &mut self.filters[c.min(1)];
+
+            for (i, o) in in_channel.iter().zip(out_channel.iter_mut()) {
+                let mut sample = *i;
+                for (filter, coefficients) in filters.iter_mut().zip(&self.coefficients) {
+                    sample = filter.next(coefficients, sample);
+                }
+                *o = sample * gain;
+            }
+        }
+
+        drop(output);
+        self.output.clone()
+    }
+
+    fn params(&self) -> Vec<ParamDescriptor> {
+        self.params.descriptors()
+    }
+
+    fn get_param(&self, id: ParamId) -> Option<f32> {
+        self.params.value(id)
+    }
+
+    fn set_param(&mut self, id: ParamId, value: f32) {
+        self.params.set(id as usize, value);
+    }
+
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        self.params.display(id)
+    }
+
+    fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.sample_rate = rate;
+        self.update_coefficients();
+    }
+
+    fn change_block_size(&mut self, size: BlockSize) {
+        self.output = Buffer::new_non_reactive(2, size as usize);
+    }
+}
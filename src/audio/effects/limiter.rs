@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+
+use crate::midi::{MidiEvent, Time};
+
+use super::{
+    super::{
+        audio_processor::{AudioProcessor, ParamDescriptor, ParamId},
+        BlockSize, Buffer, SampleRate,
+    },
+    db_to_gain, smoothing_coefficient, ParamInfo, Params,
+};
+
+const CEILING: usize = 0;
+const RELEASE: usize = 1;
+
+/// How far ahead peaks are seen coming.
+const LOOKAHEAD_SECONDS: f32 = 0.005;
+
+static PARAMS: [ParamInfo; 2] = [
+    ParamInfo {
+        name: "ceiling",
+        min: -24.,
+        max: 0.,
+        default: -0.3,
+        unit: "dB",
+    },
+    ParamInfo {
+        name: "release",
+        min: 1.,
+        max: 1000.,
+        default: 50.,
+        unit: "ms",
+    },
+];
+
+/// Lookahead brickwall limiter. The output never goes over the ceiling, at
+/// the cost of `latency` frames of delay.
+pub struct Limiter {
+    pub params: Params,
+    sample_rate: SampleRate,
+    lookahead: usize,
+    /// Delayed input, one ring buffer per channel.
+    delay: Vec<Vec<f32>>,
+    pos: usize,
+    /// (frame, gain needed) pairs with increasing gains, giving the smallest
+    /// gain needed over the lookahead window.
+    window: VecDeque<(u64, f32)>,
+    frame: u64,
+    gain: f32,
+    output: Buffer,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: SampleRate, block_size: BlockSize) -> Self {
+        let mut limiter = Self {
+            params: Params::new(&PARAMS),
+            sample_rate,
+            lookahead: 0,
+            delay: vec![],
+            pos: 0,
+            window: VecDeque::new(),
+            frame: 0,
+            gain: 1.,
+            output: Buffer::new_non_reactive(2, block_size as usize),
+        };
+        limiter.allocate();
+        limiter
+    }
+
+    fn allocate(&mut self) {
+        self.lookahead = ((LOOKAHEAD_SECONDS * self.sample_rate) as usize).max(1);
+        self.delay = vec![vec![0.; self.lookahead]; 2];
+        self.pos = 0;
+        self.window = VecDeque::with_capacity(self.lookahead + 1);
+        self.frame = 0;
+        self.gain = 1.;
+    }
+}
+
+impl AudioProcessor for Limiter {
+    fn process(&mut self, _events: Option<&Vec<MidiEvent>>, input: Buffer, _t: Time) -> Buffer {
+        let ceiling = db_to_gain(self.params.get(CEILING));
+        let release = smoothing_coefficient(self.params.get(RELEASE), self.sample_rate);
+        // Fast enough to be nearly there by the time the peak comes out.
+        let attack = smoothing_coefficient(
+            self.lookahead as f32 / self.sample_rate * 1000. / 4.,
+            self.sample_rate,
+        );
+
+        let input = input.data.borrow();
+        let mut output = self.output.data.borrow_mut();
+        let num_frames = input.first().map(|c| c.len()).unwrap_or(0);
+
+        for i in 0..num_frames {
+            let left = input[0][i];
+            let right = input.get(1).map(|c| c[i]).unwrap_or(left);
+
+            let peak = left.abs().max(right.abs());
+            let needed = if peak > ceiling { ceiling / peak } else { 1. };
+
+            while matches!(self.window.back(), Some((_, g)) if *g >= needed) {
+                self.window.pop_back();
+            }
+            self.window.push_back((self.frame, needed));
+            // Keep the frame coming out of the delay line this time round.
+            while matches!(self.window.front(), Some((f, _)) if *f + (self.lookahead as u64) < self.frame)
+            {
+                self.window.pop_front();
+            }
+
+            let target = self.window.front().map(|(_, g)| *g).unwrap_or(1.);
+            let coefficient = if target < self.gain { attack } else { release };
+            self.gain = target + coefficient * (self.gain - target);
+
+            let delayed_left = self.delay[0][self.pos];
+            let delayed_right = self.delay[1][self.pos];
+            self.delay[0][self.pos] = left;
+            self.delay[1][self.pos] = right;
+            self.pos = (self.pos + 1) % self.lookahead;
+            self.frame += 1;
+
+            // The smoothing can lag behind a sudden peak, so clamp as well to
+            // keep the ceiling a hard limit.
+            let limit = |s: f32| (s * self.gain).clamp(-ceiling, ceiling);
+
+            output[0][i] = limit(delayed_left);
+            if let Some(channel) = output.get_mut(1) {
+                channel[i] = limit(delayed_right);
+            }
+        }
+
+        drop(output);
+        self.output.clone()
+    }
+
+    fn latency(&self) -> usize {
+        self.lookahead
+    }
+
+    fn params(&self) -> Vec<ParamDescriptor> {
+        self.params.descriptors()
+    }
+
+    fn get_param(&self, id: ParamId) -> Option<f32> {
+        self.params.value(id)
+    }
+
+    fn set_param(&mut self, id: ParamId, value: f32) {
+        self.params.set(id as usize, value);
+    }
+
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        self.params.display(id)
+    }
+
+    fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.sample_rate = rate;
+        self.allocate();
+    }
+
+    fn change_block_size(&mut self, size: BlockSize) {
+        self.output = Buffer::new_non_reactive(2, size as usize);
+    }
+}
@@ -0,0 +1,97 @@
+pub mod compressor;
+pub mod delay;
+pub mod eq;
+pub mod limiter;
+pub mod reverb;
+
+use super::audio_processor::{ParamDescriptor, ParamId};
+
+/// Describes one of a native processor's parameters.
+pub struct ParamInfo {
+    pub name: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub unit: &'static str,
+}
+
+/// Current values for a native processor's fixed list of parameters.
+/// Effects read them at the start of each block, so changes take effect on
+/// the next one.
+pub struct Params {
+    info: &'static [ParamInfo],
+    values: Vec<f32>,
+    /// Set by `set`, for effects that cache values derived from parameters.
+    changed: bool,
+}
+
+impl Params {
+    pub fn new(info: &'static [ParamInfo]) -> Self {
+        Self {
+            info,
+            values: info.iter().map(|p| p.default).collect(),
+            changed: true,
+        }
+    }
+
+    pub fn descriptors(&self) -> Vec<ParamDescriptor> {
+        self.info
+            .iter()
+            .enumerate()
+            .map(|(i, p)| ParamDescriptor {
+                id: i as ParamId,
+                name: p.name.to_string(),
+                min: p.min,
+                max: p.max,
+                default: p.default,
+                unit: p.unit.to_string(),
+            })
+            .collect()
+    }
+
+    pub fn get(&self, index: usize) -> f32 {
+        self.values[index]
+    }
+
+    pub fn value(&self, id: ParamId) -> Option<f32> {
+        self.values.get(id as usize).cloned()
+    }
+
+    pub fn display(&self, id: ParamId) -> Option<String> {
+        let info = self.info.get(id as usize)?;
+        let value = self.values[id as usize];
+
+        Some(match info.unit {
+            "" => format!("{:.2}", value),
+            ":1" => format!("{:.1}:1", value),
+            unit => format!("{:.1} {}", value, unit),
+        })
+    }
+
+    /// Clamps `value` to the parameter's range.
+    pub fn set(&mut self, index: usize, value: f32) {
+        if let Some(info) = self.info.get(index) {
+            self.values[index] = value.clamp(info.min, info.max);
+            self.changed = true;
+        }
+    }
+
+    /// Whether anything was set since the last call.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+pub fn gain_to_db(gain: f32) -> f32 {
+    20. * gain.max(1e-9).log10()
+}
+
+/// Coefficient for a one pole smoother that covers most of the distance to
+/// its target in `ms` milliseconds.
+pub fn smoothing_coefficient(ms: f32, sample_rate: f32) -> f32 {
+    (-1. / (ms.max(0.01) * 0.001 * sample_rate)).exp()
+}
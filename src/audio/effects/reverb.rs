@@ -0,0 +1,205 @@
+use crate::midi::{MidiEvent, Time};
+
+use super::{
+    super::{
+        audio_processor::{AudioProcessor, ParamDescriptor, ParamId},
+        BlockSize, Buffer, SampleRate,
+    },
+    ParamInfo, Params,
+};
+
+const SIZE: usize = 0;
+const DAMPING: usize = 1;
+const WIDTH: usize = 2;
+const MIX: usize = 3;
+
+static PARAMS: [ParamInfo; 4] = [
+    ParamInfo {
+        name: "size",
+        min: 0.,
+        max: 1.,
+        default: 0.6,
+        unit: "",
+    },
+    ParamInfo {
+        name: "damping",
+        min: 0.,
+        max: 1.,
+        default: 0.5,
+        unit: "",
+    },
+    ParamInfo {
+        name: "width",
+        min: 0.,
+        max: 1.,
+        default: 1.,
+        unit: "",
+    },
+    ParamInfo {
+        name: "mix",
+        min: 0.,
+        max: 1.,
+        default: 0.25,
+        unit: "",
+    },
+];
+
+/// Comb and all-pass lengths in frames at 44.1kHz, from Freeverb.
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+/// Added to the right channel's lengths so the sides decorrelate.
+const STEREO_SPREAD: usize = 23;
+const INPUT_GAIN: f32 = 0.015;
+
+struct Comb {
+    buffer: Vec<f32>,
+    pos: usize,
+    filter_state: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.; length.max(1)],
+            pos: 0,
+            filter_state: 0.,
+        }
+    }
+
+    fn next(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.pos];
+        self.filter_state = output * (1. - damping) + self.filter_state * damping;
+        self.buffer[self.pos] = input + self.filter_state * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct AllPass {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl AllPass {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.; length.max(1)],
+            pos: 0,
+        }
+    }
+
+    fn next(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.pos];
+        self.buffer[self.pos] = input + buffered * 0.5;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+struct Channel {
+    combs: Vec<Comb>,
+    allpasses: Vec<AllPass>,
+}
+
+impl Channel {
+    fn new(sample_rate: SampleRate, spread: usize) -> Self {
+        let scale = |length: usize| ((length + spread) as f32 * sample_rate / 44_100.) as usize;
+
+        Self {
+            combs: COMB_LENGTHS.iter().map(|l| Comb::new(scale(*l))).collect(),
+            allpasses: ALLPASS_LENGTHS.iter().map(|l| AllPass::new(scale(*l))).collect(),
+        }
+    }
+
+    fn next(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let mut output = 0.;
+        for comb in &mut self.combs {
+            output += comb.next(input, feedback, damping);
+        }
+        for allpass in &mut self.allpasses {
+            output = allpass.next(output);
+        }
+        output
+    }
+}
+
+/// Freeverb style algorithmic reverb: parallel damped combs into series
+/// all-passes for each side.
+pub struct Reverb {
+    pub params: Params,
+    sample_rate: SampleRate,
+    left: Channel,
+    right: Channel,
+    output: Buffer,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: SampleRate, block_size: BlockSize) -> Self {
+        Self {
+            params: Params::new(&PARAMS),
+            sample_rate,
+            left: Channel::new(sample_rate, 0),
+            right: Channel::new(sample_rate, STEREO_SPREAD),
+            output: Buffer::new_non_reactive(2, block_size as usize),
+        }
+    }
+}
+
+impl AudioProcessor for Reverb {
+    fn process(&mut self, _events: Option<&Vec<MidiEvent>>, input: Buffer, _t: Time) -> Buffer {
+        let feedback = 0.7 + self.params.get(SIZE) * 0.28;
+        let damping = self.params.get(DAMPING) * 0.4;
+        let width = self.params.get(WIDTH);
+        let mix = self.params.get(MIX);
+
+        let wet1 = mix * (width / 2. + 0.5);
+        let wet2 = mix * ((1. - width) / 2.);
+
+        let input = input.data.borrow();
+        let mut output = self.output.data.borrow_mut();
+        let num_frames = input.first().map(|c| c.len()).unwrap_or(0);
+
+        for i in 0..num_frames {
+            let dry_left = input[0][i];
+            let dry_right = input.get(1).map(|c| c[i]).unwrap_or(dry_left);
+            let mono = (dry_left + dry_right) * INPUT_GAIN;
+
+            let left = self.left.next(mono, feedback, damping);
+            let right = self.right.next(mono, feedback, damping);
+
+            output[0][i] = dry_left * (1. - mix) + left * wet1 + right * wet2;
+            if let Some(channel) = output.get_mut(1) {
+                channel[i] = dry_right * (1. - mix) + right * wet1 + left * wet2;
+            }
+        }
+
+        drop(output);
+        self.output.clone()
+    }
+
+    fn params(&self) -> Vec<ParamDescriptor> {
+        self.params.descriptors()
+    }
+
+    fn get_param(&self, id: ParamId) -> Option<f32> {
+        self.params.value(id)
+    }
+
+    fn set_param(&mut self, id: ParamId, value: f32) {
+        self.params.set(id as usize, value);
+    }
+
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        self.params.display(id)
+    }
+
+    fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.sample_rate = rate;
+        self.left = Channel::new(rate, 0);
+        self.right = Channel::new(rate, STEREO_SPREAD);
+    }
+
+    fn change_block_size(&mut self, size: BlockSize) {
+        self.output = Buffer::new_non_reactive(2, size as usize);
+    }
+}
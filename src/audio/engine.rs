@@ -0,0 +1,1116 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    automation::{AutomationLane, AutomationTarget},
+    midi::{MidiEvent, MidiEventData, MidiEventsBlockList, NoteEvent, Time},
+    midi_output::{MidiOutputSettings, OutputMessage, OutputPort},
+    midi_sync::{self, ClockOutput, CLOCKS_PER_BEAT, CONTINUE, START, STOP},
+    mixer::MixerSettings,
+    project::{Project, TimeSignature},
+    routing::{topological_order, BusId, Send},
+    tempo_map::{MeterMap, TempoMap, MAX_TEMPO, MIN_TEMPO},
+    track::{Insert, Instrument, Track, TrackId},
+};
+
+use super::{
+    audio_processor::{AudioProcessor, ParamDescriptor, ParamId},
+    graph::{send_gains, BusNode, RoutingGraph},
+    latency::Compensation,
+    metronome::{Metronome, MetronomeSettings},
+    Audio, BlockSize, Buffer, ParamChange, ProcessorGroup, ProcessorId, ProcessorSlot, SampleRate,
+    SlotSettings,
+};
+
+/// Frames of rounding error allowed when working out which frame a time lands
+/// on, so something exactly on a frame isn't put on the one before.
+const FRAME_TOLERANCE: f64 = 1e-6;
+
+/// Events a track can be given in one block before the buffer they're
+/// gathered in has to grow on the audio thread.
+const BLOCK_EVENTS_CAPACITY: usize = 1024;
+
+/// Beat lines fetched each block to find its clicks. No block spans this
+/// many.
+const CLICK_LOOKAHEAD: usize = 16;
+
+/// Walks the timeline one block at a time, feeding each track's notes to its
+/// instrument and summing the results.
+pub struct Engine {
+    pub tracks: Vec<EngineTrack>,
+    pub sample_rate: SampleRate,
+    pub block_size: BlockSize,
+    /// Tempo at the start of the current block.
+    pub tempo: f32,
+    pub tempo_map: TempoMap,
+    /// Where the metronome's beats and downbeats fall.
+    pub meter_map: MeterMap,
+    pub metronome: Metronome,
+    /// `external_tempo` as last applied. It holds for a whole block, rather
+    /// than following `tempo_map`.
+    followed_tempo: Option<f32>,
+    /// `(start, end)` of the loop while looping. Playback jumps back to the
+    /// start on the frame the end is reached.
+    pub loop_range: Option<(Time, Time)>,
+    /// The track live input is played through, if any.
+    pub monitored_track: Option<TrackId>,
+    /// Live input waiting for the next block. Played at its start.
+    pub live_events: Vec<MidiEvent>,
+    /// Where tracks with a MIDI output send their notes. `None` when
+    /// rendering offline.
+    pub midi_out: Option<Sender<OutputMessage>>,
+    /// Set while sending MIDI clock to other devices.
+    pub clock_output: Option<ClockOutput>,
+    /// Tempo of the clock being followed, which overrides everything else.
+    pub external_tempo: Option<f32>,
+    /// Whether devices following the clock have been told to play.
+    clock_running: bool,
+    /// Where the next block should start if playback carries straight on.
+    clock_next: Time,
+    /// Return buses fed by track sends.
+    pub graph: RoutingGraph,
+    /// Everything passes through here after the tracks and buses are summed.
+    pub master: ProcessorGroup,
+    /// Frames everything is delayed by so the slowest path lines up with
+    /// the rest. Updated by `compensate_latency`.
+    pub latency: usize,
+    /// Each track's output for the current block, for sidechain inputs.
+    track_outputs: HashMap<TrackId, Buffer>,
+    output: Buffer,
+    silence: Buffer,
+    /// Reused every block so the audio thread doesn't allocate.
+    block_events: Vec<MidiEvent>,
+    beat_lines: Vec<(Time, bool)>,
+}
+
+/// The timeline a block plays, from `start` to `end` unless it reaches the
+/// loop end first and carries on from the loop start.
+struct BlockSpan {
+    start: Time,
+    end: Time,
+    wrap: Option<Wrap>,
+}
+
+impl BlockSpan {
+    /// `(start, end, first frame)` of the part before any wrap, then the
+    /// part after.
+    fn parts(&self) -> impl Iterator<Item = (Time, Time, i32)> {
+        [
+            Some((self.start, self.end, 0)),
+            self.wrap.map(|wrap| (wrap.start, wrap.end, wrap.frame)),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Wrap {
+    /// First frame of the block after the jump.
+    frame: i32,
+    start: Time,
+    end: Time,
+}
+
+pub struct EngineTrack {
+    pub track_id: TrackId,
+    /// Its id is the project instrument's, so parameter changes can find it.
+    pub instrument: Option<ProcessorSlot>,
+    /// Effects fed by the instrument's output.
+    pub inserts: ProcessorGroup,
+    pub events: MidiEventsBlockList,
+    pub mixer: MixerSettings,
+    pub sends: Vec<Send>,
+    pub automation: Vec<AutomationLane>,
+    pub midi_output: Option<MidiOutputSettings>,
+    compensation: Compensation,
+    /// `(channel, note)` of every note sounding.
+    held_notes: Vec<(u8, u32)>,
+    release_held_notes: bool,
+}
+
+impl Engine {
+    pub fn new(sample_rate: SampleRate, block_size: BlockSize, tempo_map: TempoMap) -> Self {
+        Self {
+            tracks: vec![],
+            sample_rate,
+            block_size,
+            tempo: tempo_map.initial_tempo(),
+            tempo_map,
+            meter_map: MeterMap::new(TimeSignature::common()),
+            metronome: Metronome::new(&MetronomeSettings::default(), sample_rate, &mut vec![]),
+            followed_tempo: None,
+            loop_range: None,
+            monitored_track: None,
+            live_events: vec![],
+            midi_out: None,
+            clock_output: None,
+            external_tempo: None,
+            clock_running: false,
+            clock_next: 0.,
+            graph: RoutingGraph::new(),
+            master: ProcessorGroup::new(),
+            latency: 0,
+            track_outputs: HashMap::new(),
+            output: Buffer::new_non_reactive(2, block_size as usize),
+            silence: Buffer::new_non_reactive(2, block_size as usize),
+            block_events: Vec::with_capacity(BLOCK_EVENTS_CAPACITY),
+            beat_lines: Vec::with_capacity(CLICK_LOOKAHEAD),
+        }
+    }
+
+    /// Processors that fail to load are left out, with why added to `errors`.
+    pub fn from_project(project: &Project, a: &Audio, errors: &mut Vec<String>) -> Self {
+        let mut engine = Self::new(
+            a.sample_rate.get_copy(),
+            a.block_size.get_copy(),
+            project.tempo_map.get_copy(),
+        );
+        engine.meter_map = project.meter_map.get_copy();
+        engine.metronome = Metronome::new(&project.metronome.get_copy(), engine.sample_rate, errors);
+
+        let mut tracks: Vec<&Track> = project.tracks.tracks.values().collect();
+        tracks.sort_by_key(|t| t.uid);
+
+        for track in tracks {
+            engine.tracks.push(EngineTrack::from_track(track, a, errors));
+        }
+
+        for bus in project.buses.values() {
+            engine.graph.add_bus(BusNode::from_bus(bus, a, errors));
+        }
+
+        engine.update_routing();
+        engine
+    }
+
+    /// Where the block starting at `block_start` ends, which is back inside
+    /// the loop if it wraps.
+    pub fn block_end(&self, block_start: Time) -> Time {
+        let span = self.block_span(block_start);
+        match span.wrap {
+            Some(wrap) => wrap.end,
+            None => span.end,
+        }
+    }
+
+    /// The part of the timeline the block starting at `block_start` plays.
+    fn block_span(&self, block_start: Time) -> BlockSpan {
+        let seconds = self.block_size as f64 / self.sample_rate as f64;
+        let end = self.advance(block_start, seconds);
+
+        let (loop_start, loop_end) = match self.loop_range {
+            Some((loop_start, loop_end)) if block_start < loop_end && end > loop_end => {
+                (loop_start, loop_end)
+            }
+            _ => {
+                return BlockSpan {
+                    start: block_start,
+                    end,
+                    wrap: None,
+                }
+            }
+        };
+
+        // The first frame at or after the loop end plays the loop start.
+        let to_loop_end = self.seconds_between(block_start, loop_end) * self.sample_rate as f64;
+        let frame = (to_loop_end - FRAME_TOLERANCE).ceil() as i64;
+        let frame = frame.clamp(0, self.block_size) as i32;
+        let remaining = (self.block_size - frame as i64) as f64 / self.sample_rate as f64;
+
+        BlockSpan {
+            start: block_start,
+            end: loop_end,
+            wrap: Some(Wrap {
+                frame,
+                start: loop_start,
+                end: self.advance(loop_start, remaining),
+            }),
+        }
+    }
+
+    fn advance(&self, from: Time, seconds: f64) -> Time {
+        match self.followed_tempo {
+            Some(tempo) => from + seconds_to_beats(seconds, tempo),
+            None => self
+                .tempo_map
+                .seconds_to_beats(self.tempo_map.beats_to_seconds(from) + seconds),
+        }
+    }
+
+    fn seconds_between(&self, from: Time, to: Time) -> f64 {
+        match self.followed_tempo {
+            Some(tempo) => beats_to_seconds(to - from, tempo),
+            None => self.tempo_map.beats_to_seconds(to) - self.tempo_map.beats_to_seconds(from),
+        }
+    }
+
+    /// Frame within the block starting at `block_start` at which `t` lands.
+    fn frame_offset(&self, block_start: Time, t: Time) -> i32 {
+        let seconds = self.seconds_between(block_start, t);
+        let frame = (seconds * self.sample_rate as f64 + FRAME_TOLERANCE).floor() as i64;
+        frame.clamp(0, self.block_size - 1) as i32
+    }
+
+    /// Everything track `i` should hear this block, into `events`: note offs
+    /// for notes cut short, then its events in `span`. Keeps the track's held
+    /// notes up to date.
+    fn track_events(
+        &mut self,
+        i: usize,
+        span: &BlockSpan,
+        playing: bool,
+        events: &mut Vec<MidiEvent>,
+    ) {
+        events.clear();
+        self.tracks[i].take_note_releases(span.start, events);
+
+        if self.monitored_track == Some(self.tracks[i].track_id) {
+            events.append(&mut self.live_events);
+        }
+
+        if playing {
+            let first = events.len();
+            self.tracks[i].events.events_in_range_into(span.start, span.end, events);
+            for event in &mut events[first..] {
+                event.delta_frames = self.frame_offset(span.start, event.time);
+            }
+        }
+
+        self.tracks[i].update_held_notes(events);
+
+        let wrap = match span.wrap {
+            Some(wrap) if playing => wrap,
+            _ => return,
+        };
+
+        // Notes still sounding at the loop end would never get their note
+        // offs otherwise.
+        self.tracks[i].release_held_notes = true;
+        let wrapped = events.len();
+        self.tracks[i].take_note_releases(wrap.start, events);
+        let last_frame = self.block_size as i32 - 1;
+
+        for event in &mut events[wrapped..] {
+            event.delta_frames = wrap.frame.min(last_frame);
+        }
+
+        let first = events.len();
+        self.tracks[i].events.events_in_range_into(wrap.start, wrap.end, events);
+        for event in &mut events[first..] {
+            let offset = self.frame_offset(wrap.start, event.time);
+            event.delta_frames = (wrap.frame + offset).min(last_frame);
+        }
+
+        self.tracks[i].update_held_notes(&events[wrapped..]);
+    }
+
+    /// Processes one block starting at `block_start`. When `playing` is false
+    /// the timeline is ignored but processors still run so tails ring out.
+    pub fn process_block(&mut self, block_start: Time, playing: bool) -> Buffer {
+        self.follow_tempo(block_start);
+
+        let span = self.block_span(block_start);
+        let block_sent_at = Instant::now();
+        let any_solo = self.tracks.iter().any(|t| t.mixer.solo);
+
+        for channel in self.output.data.borrow_mut().iter_mut() {
+            channel.fill(0.);
+        }
+        self.graph.begin_block();
+        let mut events = std::mem::take(&mut self.block_events);
+
+        for i in 0..self.tracks.len() {
+            self.track_events(i, &span, playing, &mut events);
+            self.send_midi_output(i, &events, block_sent_at);
+            let track = &mut self.tracks[i];
+
+            let mixer = if playing {
+                track.automate(block_start)
+            } else {
+                track.mixer
+            };
+
+            let instrument = match &mut track.instrument {
+                Some(slot) => &mut slot.processor,
+                None => continue,
+            };
+
+            let track_output = instrument.process(Some(&events), self.silence.clone(), block_start);
+            track.inserts.feed_sidechains(&self.track_outputs);
+            let track_output = track.inserts.process(Some(&events), track_output, block_start);
+            self.track_outputs.insert(track.track_id, track_output.clone());
+
+            // Silent tracks are still processed so their state keeps up.
+            let audible = mixer.is_audible(any_solo);
+
+            let delayed = track.compensation.master.process(&track_output);
+            if audible {
+                let (left, right) = mixer.channel_gains();
+                mix_into_with_gains(&self.output, &delayed, &[left, right]);
+            }
+
+            for (i, send) in track.sends.iter().enumerate() {
+                let delayed = track.compensation.send(i).process(&track_output);
+                if audible {
+                    let gains = send_gains(send, &mixer);
+                    self.graph.send_into(send.target, &delayed, &gains);
+                }
+            }
+        }
+
+        self.block_events = events;
+        // Nothing's monitoring it.
+        self.live_events.clear();
+
+        self.graph.feed_sidechains(&self.track_outputs);
+        self.graph.process(block_start, &self.output);
+
+        if playing && self.metronome.enabled {
+            self.schedule_clicks(&span);
+        }
+
+        self.send_clock(&span, playing, block_sent_at);
+
+        let output = self.master.process(None, self.output.clone(), block_start);
+        self.metronome.process(&output);
+        output
+    }
+
+    /// Schedules track `i`'s events on its MIDI output, late by the engine's
+    /// latency and the output's own offset so they line up with the audio.
+    fn send_midi_output(&self, i: usize, events: &[MidiEvent], block_start: Instant) {
+        let (midi_out, output) = match (&self.midi_out, &self.tracks[i].midi_output) {
+            (Some(midi_out), Some(output)) => (midi_out, output),
+            _ => return,
+        };
+
+        for event in events {
+            let frames = event.delta_frames as usize + self.latency;
+            let seconds = frames as f64 / self.sample_rate as f64 + output.offset_ms as f64 / 1000.;
+
+            let mut event = event.clone();
+            event.channel = output.channel;
+
+            let _ = midi_out.send(OutputMessage::Send {
+                port: OutputPort::Track(self.tracks[i].track_id),
+                due: block_start + Duration::from_secs_f64(seconds.max(0.)),
+                bytes: event.to_bytes(),
+            });
+        }
+    }
+
+    /// Sends clock, time code and transport messages for `span` to devices
+    /// following this one, late by the engine's latency like everything else.
+    fn send_clock(&mut self, span: &BlockSpan, playing: bool, block_start: Instant) {
+        let (midi_out, output) = match (&self.midi_out, &self.clock_output) {
+            (Some(midi_out), Some(output)) => (midi_out, output),
+            _ => {
+                self.clock_running = false;
+                return;
+            }
+        };
+
+        let offset = self.latency as f64 / self.sample_rate as f64 + output.port.offset_ms as f64 / 1000.;
+        let send = |frame: f64, bytes: [u8; 3]| {
+            let seconds = frame / self.sample_rate as f64 + offset;
+            let _ = midi_out.send(OutputMessage::Send {
+                port: OutputPort::Sync,
+                due: block_start + Duration::from_secs_f64(seconds.max(0.)),
+                bytes,
+            });
+        };
+
+        if !playing {
+            if self.clock_running {
+                send(0., [STOP, 0, 0]);
+                self.clock_running = false;
+            }
+            return;
+        }
+
+        // Starting, jumping and looping all reposition the followers.
+        let relocate = |frame: f64, t: Time, running: bool| {
+            if running {
+                send(frame, [STOP, 0, 0]);
+            }
+            if t == 0. {
+                send(frame, [START, 0, 0]);
+            } else {
+                send(frame, midi_sync::song_position(t));
+                send(frame, [CONTINUE, 0, 0]);
+            }
+        };
+
+        if !self.clock_running || self.clock_next != span.start {
+            relocate(0., span.start, self.clock_running);
+        }
+
+        for (i, (start, end, first_frame)) in span.parts().enumerate() {
+            if i > 0 {
+                relocate(first_frame as f64, start, true);
+            }
+
+            let mut clock = (start * CLOCKS_PER_BEAT).ceil();
+            while clock / CLOCKS_PER_BEAT < end {
+                let seconds = self.seconds_between(start, clock / CLOCKS_PER_BEAT);
+                send(first_frame as f64 + seconds * self.sample_rate as f64, [midi_sync::CLOCK, 0, 0]);
+                clock += 1.;
+            }
+
+            if let Some(rate) = output.mtc {
+                let quarters_per_second = rate.fps() as f64 * 4.;
+                let start_seconds = self.tempo_map.beats_to_seconds(start);
+                let end_seconds = start_seconds + self.seconds_between(start, end);
+
+                let mut quarter = (start_seconds * quarters_per_second).ceil();
+                while quarter / quarters_per_second < end_seconds {
+                    let seconds = quarter / quarters_per_second - start_seconds;
+                    send(
+                        first_frame as f64 + seconds * self.sample_rate as f64,
+                        midi_sync::quarter_frame(quarter as u64, rate),
+                    );
+                    quarter += 1.;
+                }
+            }
+        }
+
+        self.clock_running = true;
+        self.clock_next = self.block_end(span.start);
+    }
+
+    /// Schedules a click for every beat in `span`, late by the engine's
+    /// latency so they line up with the tracks.
+    fn schedule_clicks(&mut self, span: &BlockSpan) {
+        for (start, end, first_frame) in span.parts() {
+            self.meter_map
+                .beat_lines_into(start, CLICK_LOOKAHEAD, &mut self.beat_lines);
+            for &(t, downbeat) in self.beat_lines.iter().take_while(|(t, _)| *t < end) {
+                let frame = first_frame + self.frame_offset(start, t);
+                self.metronome.schedule(frame as usize + self.latency, downbeat);
+            }
+        }
+    }
+
+    /// Clicks one block of a count-in that has `remaining` beats to go before
+    /// playback starts at `at`, at the tempo and time signature there.
+    /// Returns the beats left after the block.
+    pub fn count_in(&mut self, at: Time, remaining: Time) -> Time {
+        let time_signature = self.meter_map.time_signature_at(at);
+        let unit = time_signature.beat_length();
+        let tempo = self.tempo_map.tempo_at(at);
+        let block_beats = seconds_to_beats(self.block_size as f64 / self.sample_rate as f64, tempo);
+
+        // Beats are counted back from `at`. The one at `at` itself is left
+        // to playback.
+        let mut beat = (remaining / unit).floor() as i64;
+        while beat >= 1 && remaining - beat as Time * unit < block_beats {
+            let seconds = beats_to_seconds(remaining - beat as Time * unit, tempo);
+            let frame = (seconds * self.sample_rate as f64 + FRAME_TOLERANCE).floor() as usize;
+            let downbeat = beat % time_signature.numerator().max(1) as i64 == 0;
+
+            self.metronome.schedule(frame + self.latency, downbeat);
+            beat -= 1;
+        }
+
+        remaining - block_beats
+    }
+
+    /// Sends note offs for every sounding note on the next block, e.g. when
+    /// the transport stops or jumps.
+    pub fn release_all_notes(&mut self) {
+        for track in &mut self.tracks {
+            track.release_held_notes = true;
+        }
+    }
+
+    /// Brings everything derived from the set of processors and how they're
+    /// connected up to date. Call whenever processors or routing change.
+    pub fn update_routing(&mut self) {
+        self.order_tracks();
+        self.set_tempo(self.tempo);
+        self.compensate_latency();
+    }
+
+    /// Sorts tracks so that any track keying a sidechain is processed before
+    /// the tracks using it. Tracks keying each other in a cycle go last, so
+    /// they and any tracks they key get their output from the previous block.
+    fn order_tracks(&mut self) {
+        let mut edges: HashMap<TrackId, Vec<TrackId>> =
+            self.tracks.iter().map(|t| (t.track_id, vec![])).collect();
+
+        for track in &self.tracks {
+            for source in track.inserts.sidechain_sources() {
+                if let Some(targets) = edges.get_mut(&source) {
+                    targets.push(track.track_id);
+                }
+            }
+        }
+
+        let (order, cyclic) = topological_order(&edges);
+
+        self.tracks.sort_by_key(|t| {
+            order
+                .iter()
+                .chain(&cyclic)
+                .position(|id| *id == t.track_id)
+        });
+        self.track_outputs.retain(|id, _| edges.contains_key(id));
+    }
+
+    /// Follows the external clock if there is one, and the tempo map
+    /// otherwise.
+    fn follow_tempo(&mut self, t: Time) {
+        self.followed_tempo = self
+            .external_tempo
+            .map(|tempo| tempo.clamp(MIN_TEMPO, MAX_TEMPO));
+        let tempo = self
+            .followed_tempo
+            .unwrap_or_else(|| self.tempo_map.tempo_at(t));
+
+        if tempo != self.tempo {
+            self.set_tempo(tempo);
+        }
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo;
+        for track in &mut self.tracks {
+            track.set_tempo(tempo);
+        }
+        self.graph.set_tempo(tempo);
+        self.master.set_tempo(tempo);
+    }
+
+    /// Works out how late each track and bus is because of its processors'
+    /// latency and delays the others to match, at every bus input and at the
+    /// master bus.
+    pub fn compensate_latency(&mut self) {
+        let track_latencies: Vec<usize> = self.tracks.iter().map(|t| t.latency()).collect();
+
+        // How late the latest signal arriving at each bus is.
+        let mut bus_inputs: HashMap<BusId, usize> = HashMap::new();
+        for (track, latency) in self.tracks.iter().zip(&track_latencies) {
+            for send in &track.sends {
+                let input = bus_inputs.entry(send.target).or_insert(0);
+                *input = (*input).max(*latency);
+            }
+        }
+
+        let bus_outputs = self.graph.latencies(&mut bus_inputs);
+
+        self.latency = track_latencies
+            .iter()
+            .chain(bus_outputs.values())
+            .cloned()
+            .max()
+            .unwrap_or(0);
+
+        for (track, latency) in self.tracks.iter_mut().zip(track_latencies) {
+            let sends: Vec<usize> = track
+                .sends
+                .iter()
+                .map(|s| bus_inputs.get(&s.target).cloned().unwrap_or(latency).saturating_sub(latency))
+                .collect();
+
+            track.compensation.set(self.latency - latency, &sends);
+        }
+
+        self.graph.compensate(self.latency, &bus_inputs, &bus_outputs);
+    }
+
+    fn processor_groups(&self) -> impl Iterator<Item = &ProcessorGroup> {
+        self.tracks
+            .iter()
+            .map(|t| &t.inserts)
+            .chain(self.graph.processor_groups())
+            .chain(std::iter::once(&self.master))
+    }
+
+    fn processor_groups_mut(&mut self) -> impl Iterator<Item = &mut ProcessorGroup> {
+        self.tracks
+            .iter_mut()
+            .map(|t| &mut t.inserts)
+            .chain(self.graph.processor_groups_mut())
+            .chain(std::iter::once(&mut self.master))
+    }
+
+    pub fn set_param(&mut self, change: ParamChange) {
+        for slot in self.tracks.iter_mut().filter_map(|t| t.instrument.as_mut()) {
+            if slot.id == change.processor {
+                slot.processor.set_param(change.param, change.value);
+                return;
+            }
+        }
+
+        for group in self.processor_groups_mut() {
+            if let Some(slot) = group.slot_mut(change.processor) {
+                slot.processor.set_param(change.param, change.value);
+                return;
+            }
+        }
+    }
+
+    /// Parameters of every instrument and insert, so the UI thread can look
+    /// them up without reaching into the processors.
+    pub fn param_descriptors(&self) -> HashMap<ProcessorId, Vec<ParamDescriptor>> {
+        self.tracks
+            .iter()
+            .filter_map(|t| t.instrument.as_ref())
+            .map(|slot| (slot.id, slot.processor.params()))
+            .chain(self.processor_groups().flat_map(|group| group.param_descriptors()))
+            .collect()
+    }
+
+    /// Adds changes processors made themselves to `changes`.
+    pub fn collect_param_changes(&mut self, changes: &mut Vec<ParamChange>) {
+        for slot in self.tracks.iter_mut().filter_map(|t| t.instrument.as_mut()) {
+            slot.collect_param_changes(changes);
+        }
+
+        for group in self.processor_groups_mut() {
+            group.collect_param_changes(changes);
+        }
+    }
+
+    pub fn track_mut(&mut self, track_id: TrackId) -> Option<&mut EngineTrack> {
+        self.tracks.iter_mut().find(|t| t.track_id == track_id)
+    }
+
+    pub fn remove_track(&mut self, track_id: TrackId) -> Option<EngineTrack> {
+        let i = self.tracks.iter().position(|t| t.track_id == track_id)?;
+        Some(self.tracks.remove(i))
+    }
+
+    pub fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.sample_rate = rate;
+        for track in &mut self.tracks {
+            track.change_sample_rate(rate);
+        }
+        self.graph.change_sample_rate(rate);
+        self.master.change_sample_rate(rate);
+        self.metronome.change_sample_rate(rate);
+    }
+
+    pub fn change_block_size(&mut self, size: BlockSize) {
+        self.block_size = size;
+        self.output = Buffer::new_non_reactive(2, size as usize);
+        self.silence = Buffer::new_non_reactive(2, size as usize);
+        for track in &mut self.tracks {
+            track.change_block_size(size);
+        }
+        self.graph.change_block_size(size);
+        self.master.change_block_size(size);
+    }
+}
+
+impl EngineTrack {
+    pub fn from_track(track: &Track, a: &Audio, errors: &mut Vec<String>) -> Self {
+        let instrument = load_instrument(track.instrument(), a, errors);
+
+        let mut inserts = ProcessorGroup::new();
+        inserts.rearrange(
+            &insert_order(&track.inserts),
+            insert_slots(&track.inserts, &[], a, errors),
+        );
+
+        Self {
+            track_id: track.uid,
+            instrument,
+            inserts,
+            events: track.events(),
+            mixer: track.mixer.get_copy(),
+            sends: track.sends.clone(),
+            automation: track.automation.clone(),
+            midi_output: track.midi_output.clone(),
+            compensation: Compensation::new(),
+            held_notes: vec![],
+            release_held_notes: false,
+        }
+    }
+
+    /// Frames by which the track's output lags its notes.
+    pub fn latency(&self) -> usize {
+        let instrument = self.instrument.as_ref().map(|i| i.processor.latency()).unwrap_or(0);
+        instrument + self.inserts.latency()
+    }
+
+    /// Applies the track's lanes at `t` to its inserts, returning its mixer
+    /// settings with any gain and pan lanes applied.
+    fn automate(&mut self, t: Time) -> MixerSettings {
+        let mut mixer = self.mixer;
+
+        for lane in &self.automation {
+            let value = match lane.value_at(t) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            match lane.target {
+                AutomationTarget::Gain => mixer.gain_db = value,
+                AutomationTarget::Pan => mixer.pan = value,
+                AutomationTarget::Param { insert, param } => {
+                    if let Some(slot) = self.inserts.slot_mut(insert) {
+                        slot.processor.set_param(param, value);
+                    }
+                }
+                AutomationTarget::InstrumentParam { param } => {
+                    if let Some(slot) = &mut self.instrument {
+                        slot.processor.set_param(param, value);
+                    }
+                }
+            }
+        }
+
+        mixer
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        if let Some(instrument) = &mut self.instrument {
+            instrument.processor.set_tempo(tempo);
+        }
+        self.inserts.set_tempo(tempo);
+    }
+
+    pub fn change_sample_rate(&mut self, rate: SampleRate) {
+        if let Some(instrument) = &mut self.instrument {
+            instrument.processor.change_sample_rate(rate);
+        }
+        self.inserts.change_sample_rate(rate);
+    }
+
+    pub fn change_block_size(&mut self, size: BlockSize) {
+        if let Some(instrument) = &mut self.instrument {
+            instrument.processor.change_block_size(size);
+        }
+        self.inserts.change_block_size(size);
+    }
+
+    /// Appends note offs at `t` for every held note if they're due to be
+    /// released.
+    fn take_note_releases(&mut self, t: Time, events: &mut Vec<MidiEvent>) {
+        if !self.release_held_notes {
+            return;
+        }
+
+        self.release_held_notes = false;
+
+        events.extend(self.held_notes.drain(..).map(|(channel, note)| MidiEvent {
+            time: t,
+            delta_frames: 0,
+            channel,
+            data: MidiEventData::NoteOff {
+                note: NoteEvent { note, velocity: 0 },
+            },
+        }));
+    }
+
+    fn update_held_notes(&mut self, events: &[MidiEvent]) {
+        for event in events {
+            match event.data {
+                MidiEventData::NoteOn { note } => self.held_notes.push((event.channel, note.note)),
+                MidiEventData::NoteOff { note } => {
+                    let held = (event.channel, note.note);
+                    if let Some(i) = self.held_notes.iter().position(|n| *n == held) {
+                        self.held_notes.remove(i);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// The settings of an insert chain's slots in order.
+pub fn insert_order(inserts: &[Insert]) -> Vec<SlotSettings> {
+    inserts
+        .iter()
+        .map(|insert| SlotSettings {
+            id: insert.id,
+            bypass: insert.bypass,
+            sidechain: insert.sidechain,
+        })
+        .collect()
+}
+
+/// The saved parameter values of an insert chain.
+pub fn insert_params(inserts: &[Insert]) -> Vec<ParamChange> {
+    inserts
+        .iter()
+        .flat_map(|insert| saved_params(insert.id, &insert.params))
+        .collect()
+}
+
+/// The saved parameter values of a track's instrument and inserts.
+pub fn track_params(track: &Track) -> Vec<ParamChange> {
+    let mut params = insert_params(&track.inserts);
+    if let Some(instrument) = track.instrument() {
+        params.extend(saved_params(instrument.id, &instrument.params));
+    }
+    params
+}
+
+fn saved_params(
+    processor: ProcessorId,
+    params: &BTreeMap<ParamId, f32>,
+) -> impl Iterator<Item = ParamChange> + '_ {
+    params.iter().map(move |(param, value)| ParamChange {
+        processor,
+        param: *param,
+        value: *value,
+    })
+}
+
+/// Loads a track's instrument with its saved parameter values. If it fails the
+/// track is left silent, with why added to `errors`.
+pub fn load_instrument(
+    instrument: Option<&Instrument>,
+    a: &Audio,
+    errors: &mut Vec<String>,
+) -> Option<ProcessorSlot> {
+    let instrument = instrument?;
+    let mut processor = match instrument.load(a, errors) {
+        Ok(processor) => processor,
+        Err(e) => {
+            errors.push(e);
+            return None;
+        }
+    };
+
+    for (param, value) in &instrument.params {
+        processor.set_param(*param, *value);
+    }
+
+    Some(ProcessorSlot {
+        id: instrument.id,
+        processor,
+        bypass: false,
+        sidechain: None,
+    })
+}
+
+/// Loads the processors for every insert not already in `known`, with their
+/// saved parameter values. Inserts that fail to load are skipped, with why
+/// added to `errors`.
+pub fn insert_slots(
+    inserts: &[Insert],
+    known: &[SlotSettings],
+    a: &Audio,
+    errors: &mut Vec<String>,
+) -> Vec<ProcessorSlot> {
+    inserts
+        .iter()
+        .filter(|insert| !known.iter().any(|slot| slot.id == insert.id))
+        .filter_map(|insert| {
+            let mut processor = match insert.plugin.load(a) {
+                Ok(processor) => processor,
+                Err(e) => {
+                    errors.push(e);
+                    return None;
+                }
+            };
+
+            for (param, value) in &insert.params {
+                processor.set_param(*param, *value);
+            }
+
+            Some(ProcessorSlot {
+                id: insert.id,
+                processor,
+                bypass: insert.bypass,
+                sidechain: insert.sidechain,
+            })
+        })
+        .collect()
+}
+
+/// Adds `src` into `dest`, scaling each channel by the matching gain.
+pub fn mix_into_with_gains(dest: &Buffer, src: &Buffer, gains: &[f32]) {
+    if dest.uid == src.uid {
+        return;
+    }
+
+    let src = src.data.borrow();
+    let mut dest = dest.data.borrow_mut();
+
+    for ((dest_channel, src_channel), gain) in dest.iter_mut().zip(src.iter()).zip(gains) {
+        for (d, s) in dest_channel.iter_mut().zip(src_channel.iter()) {
+            *d += *s * gain;
+        }
+    }
+}
+
+pub fn beats_to_seconds(beats: Time, tempo: f32) -> f64 {
+    beats * 60. / tempo as f64
+}
+
+pub fn seconds_to_beats(seconds: f64, tempo: f32) -> Time {
+    seconds * tempo as f64 / 60.
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tempo_map::TempoChange;
+
+    use super::*;
+
+    const SAMPLE_RATE: SampleRate = 48_000.;
+    const BLOCK_SIZE: BlockSize = 512;
+
+    /// Renders `blocks` blocks from the start the way an offline render
+    /// does, returning the left channel.
+    fn render(engine: &mut Engine, blocks: usize) -> Vec<f32> {
+        let mut output = vec![];
+
+        for block in 0..blocks {
+            let seconds = (block * BLOCK_SIZE as usize) as f64 / SAMPLE_RATE as f64;
+            let t = engine.tempo_map.seconds_to_beats(seconds);
+            output.extend_from_slice(&engine.process_block(t, true).data.borrow()[0]);
+        }
+
+        output
+    }
+
+    /// Frames where sound starts after at least a frame of silence. Beeps
+    /// start on a zero crossing, so that's the frame before the first
+    /// non-zero one.
+    fn onsets(output: &[f32]) -> Vec<usize> {
+        (1..output.len())
+            .filter(|&i| output[i] != 0. && output[i - 1] == 0. && (i < 2 || output[i - 2] == 0.))
+            .map(|i| i - 1)
+            .collect()
+    }
+
+    /// Outputs a single frame at 1 for each note on, `latency` frames late,
+    /// the way a lookahead plugin would.
+    struct Impulses {
+        latency: usize,
+        /// Frames from the start of the next block.
+        pending: Vec<usize>,
+        output: Buffer,
+    }
+
+    impl AudioProcessor for Impulses {
+        fn process(&mut self, events: Option<&Vec<MidiEvent>>, _input: Buffer, _t: Time) -> Buffer {
+            for event in events.into_iter().flatten().filter(|e| e.is_note_on()) {
+                self.pending.push(event.delta_frames as usize + self.latency);
+            }
+
+            let mut output = self.output.data.borrow_mut();
+            let num_frames = output[0].len();
+            for channel in output.iter_mut() {
+                channel.fill(0.);
+                for frame in self.pending.iter().filter(|f| **f < num_frames) {
+                    channel[*frame] = 1.;
+                }
+            }
+
+            self.pending.retain(|f| *f >= num_frames);
+            for frame in &mut self.pending {
+                *frame -= num_frames;
+            }
+
+            drop(output);
+            self.output.clone()
+        }
+
+        fn latency(&self) -> usize {
+            self.latency
+        }
+
+        fn change_sample_rate(&mut self, _rate: SampleRate) {}
+
+        fn change_block_size(&mut self, _size: BlockSize) {}
+    }
+
+    /// A track with a note at beat 1 played by `Impulses`.
+    fn impulse_track(track_id: TrackId, latency: usize) -> EngineTrack {
+        let mut events = MidiEventsBlockList::new();
+        events.insert_event(MidiEvent {
+            time: 1.,
+            delta_frames: 0,
+            channel: 0,
+            data: MidiEventData::NoteOn {
+                note: NoteEvent {
+                    note: 60,
+                    velocity: 100,
+                },
+            },
+        });
+
+        EngineTrack {
+            track_id,
+            instrument: Some(ProcessorSlot {
+                id: track_id as ProcessorId,
+                processor: Box::new(Impulses {
+                    latency,
+                    pending: vec![],
+                    output: Buffer::new_non_reactive(2, BLOCK_SIZE as usize),
+                }),
+                bypass: false,
+                sidechain: None,
+            }),
+            inserts: ProcessorGroup::new(),
+            events,
+            mixer: MixerSettings::default(),
+            sends: vec![],
+            automation: vec![],
+            midi_output: None,
+            compensation: Compensation::new(),
+            held_notes: vec![],
+            release_held_notes: false,
+        }
+    }
+
+    #[test]
+    fn tracks_with_different_latencies_line_up() {
+        let mut engine = Engine::new(SAMPLE_RATE, BLOCK_SIZE, TempoMap::new(120.));
+        // 300 frames carries the late track's note over a block boundary.
+        engine.tracks.push(impulse_track(1, 300));
+        engine.tracks.push(impulse_track(2, 0));
+        engine.update_routing();
+
+        assert_eq!(engine.latency, 300);
+
+        let output = render(&mut engine, 50);
+        let sounding: Vec<usize> = (0..output.len()).filter(|i| output[*i] != 0.).collect();
+        let (left, _) = MixerSettings::default().channel_gains();
+
+        assert_eq!(sounding, vec![24_300]);
+        assert_eq!(output[24_300], 2. * left);
+    }
+
+    #[test]
+    fn clicks_land_on_the_frames_of_their_beats() {
+        let mut engine = Engine::new(SAMPLE_RATE, BLOCK_SIZE, TempoMap::new(120.));
+        engine.metronome.enabled = true;
+
+        // A beat is 24000 frames. Beat 0 starts a block, beat 1 is 448
+        // frames into block 46 and rings on past its end, and beat 2 is 384
+        // frames into block 93.
+        let output = render(&mut engine, 100);
+
+        assert_eq!(onsets(&output), vec![0, 24_000, 48_000]);
+        assert!(output[24_064..24_100].iter().any(|s| *s != 0.));
+    }
+
+    #[test]
+    fn clicks_follow_tempo_changes() {
+        let mut tempo_map = TempoMap::new(120.);
+        tempo_map.insert(TempoChange {
+            time: 1.,
+            tempo: 90.,
+            ramp: false,
+        });
+        let mut engine = Engine::new(SAMPLE_RATE, BLOCK_SIZE, tempo_map);
+        engine.metronome.enabled = true;
+
+        // Beat 2 comes 2/3 of a second after beat 1.
+        let output = render(&mut engine, 120);
+
+        assert_eq!(onsets(&output), vec![0, 24_000, 56_000]);
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+
+use crate::{
+    midi::Time,
+    mixer::{db_to_linear, MixerSettings},
+    routing::{topological_order, Bus, BusId, Send},
+    track::TrackId,
+};
+
+use super::{
+    audio_processor::AudioProcessor,
+    engine::{insert_order, insert_slots, mix_into_with_gains},
+    latency::Compensation,
+    Audio, BlockSize, Buffer, ProcessorGroup, SampleRate,
+};
+
+/// The output of a node for block number `block`, so a node feeding several
+/// others is only processed once per block. Blocks are counted rather than
+/// keyed on their time, which stands still while stopped or counting in.
+pub struct Memo {
+    block: Option<u64>,
+    output: Option<Buffer>,
+}
+
+impl Memo {
+    fn new() -> Self {
+        Self {
+            block: None,
+            output: None,
+        }
+    }
+
+    fn get(&self, block: u64) -> Option<Buffer> {
+        match self.block {
+            Some(b) if b == block => self.output.clone(),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, block: u64, output: Buffer) {
+        self.block = Some(block);
+        self.output = Some(output);
+    }
+}
+
+pub struct BusNode {
+    pub bus_id: BusId,
+    pub processors: ProcessorGroup,
+    pub mixer: MixerSettings,
+    pub sends: Vec<Send>,
+    /// Sum of everything sent to this bus during the current block.
+    input: Buffer,
+    memo: Memo,
+    compensation: Compensation,
+}
+
+impl BusNode {
+    pub fn from_bus(bus: &Bus, a: &Audio, errors: &mut Vec<String>) -> Self {
+        let mut processors = ProcessorGroup::new();
+        processors.rearrange(
+            &insert_order(&bus.inserts),
+            insert_slots(&bus.inserts, &[], a, errors),
+        );
+
+        Self {
+            bus_id: bus.uid,
+            processors,
+            mixer: bus.mixer,
+            sends: bus.sends.clone(),
+            input: Buffer::new_non_reactive(2, a.block_size.get_copy() as usize),
+            memo: Memo::new(),
+            compensation: Compensation::new(),
+        }
+    }
+
+    fn process(&mut self, block: u64, t: Time) -> Buffer {
+        if let Some(output) = self.memo.get(block) {
+            return output;
+        }
+
+        let output = self.processors.process(None, self.input.clone(), t);
+        self.memo.set(block, output.clone());
+        output
+    }
+
+    pub fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.processors.change_sample_rate(rate);
+    }
+
+    pub fn change_block_size(&mut self, size: BlockSize) {
+        self.input = Buffer::new_non_reactive(2, size as usize);
+        self.memo = Memo::new();
+        self.processors.change_block_size(size);
+    }
+}
+
+/// Return buses and the sends between them. Tracks push audio into bus inputs
+/// with `send_into`, then `process` runs every bus in dependency order and
+/// sums them into the master bus.
+pub struct RoutingGraph {
+    buses: Vec<BusNode>,
+    /// Bus ids such that every bus comes after the buses sending to it.
+    order: Vec<BusId>,
+    /// Counts the blocks started with `begin_block`.
+    block: u64,
+}
+
+impl Default for RoutingGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RoutingGraph {
+    pub fn new() -> Self {
+        Self {
+            buses: vec![],
+            order: vec![],
+            block: 0,
+        }
+    }
+
+    /// Returns the bus with the same id it replaced, if any.
+    pub fn add_bus(&mut self, bus: BusNode) -> Option<BusNode> {
+        let old = self.remove_bus(bus.bus_id);
+        self.buses.push(bus);
+        self.update_order();
+        old
+    }
+
+    pub fn remove_bus(&mut self, bus_id: BusId) -> Option<BusNode> {
+        let i = self.buses.iter().position(|b| b.bus_id == bus_id)?;
+        let bus = self.buses.remove(i);
+        self.update_order();
+        Some(bus)
+    }
+
+    pub fn bus_mut(&mut self, bus_id: BusId) -> Option<&mut BusNode> {
+        self.buses.iter_mut().find(|b| b.bus_id == bus_id)
+    }
+
+    pub fn set_sends(&mut self, bus_id: BusId, sends: Vec<Send>) {
+        if let Some(bus) = self.bus_mut(bus_id) {
+            bus.sends = sends;
+        }
+        self.update_order();
+    }
+
+    /// Recomputes the processing order. Buses caught in a cycle are left out
+    /// so they go silent rather than feeding back forever. The controller
+    /// reports them, see `cyclic_buses`.
+    fn update_order(&mut self) {
+        let edges: HashMap<BusId, Vec<BusId>> = self
+            .buses
+            .iter()
+            .map(|bus| (bus.bus_id, bus.sends.iter().map(|s| s.target).collect()))
+            .collect();
+
+        self.order = topological_order(&edges).0;
+    }
+
+    /// Clears every bus input ready for a new block.
+    pub fn begin_block(&mut self) {
+        self.block += 1;
+        for bus in &mut self.buses {
+            for channel in bus.input.data.borrow_mut().iter_mut() {
+                channel.fill(0.);
+            }
+        }
+    }
+
+    pub fn send_into(&self, bus_id: BusId, src: &Buffer, gains: &[f32]) {
+        if let Some(bus) = self.buses.iter().find(|b| b.bus_id == bus_id) {
+            mix_into_with_gains(&bus.input, src, gains);
+        }
+    }
+
+    pub fn feed_sidechains(&mut self, track_outputs: &HashMap<TrackId, Buffer>) {
+        for bus in &mut self.buses {
+            bus.processors.feed_sidechains(track_outputs);
+        }
+    }
+
+    /// Processes every bus for the block starting at `t`, mixing their
+    /// outputs into `master`.
+    pub fn process(&mut self, t: Time, master: &Buffer) {
+        let block = self.block;
+
+        for i in 0..self.order.len() {
+            let bus_id = self.order[i];
+
+            let output = match self.bus_mut(bus_id) {
+                Some(bus) => bus.process(block, t),
+                None => continue,
+            };
+
+            let bus = self.bus_mut(bus_id).unwrap();
+            // Soloing tracks shouldn't silence the returns they feed.
+            let audible = bus.mixer.is_audible(false);
+
+            let delayed = bus.compensation.master.process(&output);
+            if audible {
+                let (left, right) = bus.mixer.channel_gains();
+                mix_into_with_gains(master, &delayed, &[left, right]);
+            }
+
+            for i in 0..bus.sends.len() {
+                let bus = self.bus_mut(bus_id).unwrap();
+                let send = bus.sends[i];
+                let mixer = bus.mixer;
+                let delayed = bus.compensation.send(i).process(&output);
+
+                if audible {
+                    self.send_into(send.target, &delayed, &send_gains(&send, &mixer));
+                }
+            }
+        }
+    }
+
+    /// Takes how late the signal reaching each bus from tracks is, and fills
+    /// in how late it is from other buses. Returns how late each bus's output
+    /// is.
+    pub fn latencies(&self, inputs: &mut HashMap<BusId, usize>) -> HashMap<BusId, usize> {
+        let mut outputs = HashMap::new();
+
+        for bus_id in &self.order {
+            let bus = match self.buses.iter().find(|b| b.bus_id == *bus_id) {
+                Some(bus) => bus,
+                None => continue,
+            };
+
+            let output = inputs.get(bus_id).cloned().unwrap_or(0) + bus.processors.latency();
+            outputs.insert(*bus_id, output);
+
+            for send in &bus.sends {
+                let input = inputs.entry(send.target).or_insert(0);
+                *input = (*input).max(output);
+            }
+        }
+
+        outputs
+    }
+
+    /// Sets each bus's delays from the results of `latencies`.
+    pub fn compensate(
+        &mut self,
+        master: usize,
+        inputs: &HashMap<BusId, usize>,
+        outputs: &HashMap<BusId, usize>,
+    ) {
+        for bus in &mut self.buses {
+            let output = outputs.get(&bus.bus_id).cloned().unwrap_or(0);
+
+            let sends: Vec<usize> = bus
+                .sends
+                .iter()
+                .map(|s| inputs.get(&s.target).cloned().unwrap_or(output).saturating_sub(output))
+                .collect();
+
+            bus.compensation.set(master.saturating_sub(output), &sends);
+        }
+    }
+
+    pub fn processor_groups(&self) -> impl Iterator<Item = &ProcessorGroup> {
+        self.buses.iter().map(|bus| &bus.processors)
+    }
+
+    pub fn processor_groups_mut(&mut self) -> impl Iterator<Item = &mut ProcessorGroup> {
+        self.buses.iter_mut().map(|bus| &mut bus.processors)
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        for bus in &mut self.buses {
+            bus.processors.set_tempo(tempo);
+        }
+    }
+
+    pub fn change_sample_rate(&mut self, rate: SampleRate) {
+        for bus in &mut self.buses {
+            bus.change_sample_rate(rate);
+        }
+    }
+
+    pub fn change_block_size(&mut self, size: BlockSize) {
+        for bus in &mut self.buses {
+            bus.change_block_size(size);
+        }
+    }
+}
+
+/// Left and right gains for a send from a strip with the given fader.
+pub fn send_gains(send: &Send, source: &MixerSettings) -> [f32; 2] {
+    let level = db_to_linear(send.level_db);
+
+    if send.pre_fader {
+        [level, level]
+    } else {
+        let (left, right) = source.channel_gains();
+        [left * level, right * level]
+    }
+}
@@ -0,0 +1,172 @@
+use super::{Buffer, FrameValue};
+
+/// Holds audio back by a fixed number of frames.
+pub struct DelayLine {
+    delay: usize,
+    /// One ring buffer of `delay` frames per channel.
+    history: Vec<Vec<FrameValue>>,
+    pos: usize,
+    output: Option<Buffer>,
+}
+
+impl Default for DelayLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DelayLine {
+    pub fn new() -> Self {
+        Self {
+            delay: 0,
+            history: vec![],
+            pos: 0,
+            output: None,
+        }
+    }
+
+    pub fn set_delay(&mut self, delay: usize) {
+        if delay == self.delay {
+            return;
+        }
+
+        self.delay = delay;
+        self.history.clear();
+        self.pos = 0;
+    }
+
+    pub fn process(&mut self, input: &Buffer) -> Buffer {
+        if self.delay == 0 {
+            return input.clone();
+        }
+
+        let input = input.data.borrow();
+        let num_channels = input.len();
+        let num_frames = input.first().map(|c| c.len()).unwrap_or(0);
+
+        if self.history.len() != num_channels {
+            self.history = vec![vec![0.; self.delay]; num_channels];
+            self.pos = 0;
+        }
+
+        let output = match &self.output {
+            Some(output)
+                if output.data.borrow().len() == num_channels
+                    && output.data.borrow()[0].len() == num_frames =>
+            {
+                output.clone()
+            }
+            _ => {
+                let output = Buffer::new_non_reactive(num_channels, num_frames);
+                self.output = Some(output.clone());
+                output
+            }
+        };
+
+        let mut out = output.data.borrow_mut();
+
+        for i in 0..num_frames {
+            for c in 0..num_channels {
+                out[c][i] = self.history[c][self.pos];
+                self.history[c][self.pos] = input[c][i];
+            }
+            self.pos = (self.pos + 1) % self.delay;
+        }
+
+        drop(out);
+        output
+    }
+}
+
+/// Delays for one strip's output on its way to the master bus and to each of
+/// its sends, so it arrives in step with everything else there.
+pub struct Compensation {
+    pub master: DelayLine,
+    sends: Vec<DelayLine>,
+}
+
+impl Default for Compensation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compensation {
+    pub fn new() -> Self {
+        Self {
+            master: DelayLine::new(),
+            sends: vec![],
+        }
+    }
+
+    pub fn set(&mut self, master: usize, sends: &[usize]) {
+        self.master.set_delay(master);
+        self.sends.resize_with(sends.len(), DelayLine::new);
+
+        for (line, delay) in self.sends.iter_mut().zip(sends) {
+            line.set_delay(*delay);
+        }
+    }
+
+    /// The delay line for the send at `index`, added if the sends have changed
+    /// since the last `set`.
+    pub fn send(&mut self, index: usize) -> &mut DelayLine {
+        if index >= self.sends.len() {
+            self.sends.resize_with(index + 1, DelayLine::new);
+        }
+
+        &mut self.sends[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(frames: &[FrameValue]) -> Buffer {
+        Buffer::from_data(vec![frames.to_vec(), frames.iter().map(|f| -f).collect()])
+    }
+
+    fn frames(buffer: &Buffer) -> Vec<FrameValue> {
+        buffer.data.borrow()[0].clone()
+    }
+
+    #[test]
+    fn delays_across_blocks() {
+        let mut line = DelayLine::new();
+        line.set_delay(3);
+
+        assert_eq!(frames(&line.process(&block(&[1., 2.]))), vec![0., 0.]);
+        assert_eq!(frames(&line.process(&block(&[3., 4.]))), vec![0., 1.]);
+
+        let output = line.process(&block(&[5., 6.]));
+        assert_eq!(frames(&output), vec![2., 3.]);
+        assert_eq!(output.data.borrow()[1], vec![-2., -3.]);
+    }
+
+    #[test]
+    fn changing_the_delay_forgets_held_audio() {
+        let mut line = DelayLine::new();
+        assert_eq!(frames(&line.process(&block(&[1., 2.]))), vec![1., 2.]);
+
+        line.set_delay(1);
+        assert_eq!(frames(&line.process(&block(&[1., 2.]))), vec![0., 1.]);
+
+        line.set_delay(2);
+        assert_eq!(frames(&line.process(&block(&[3., 4.]))), vec![0., 0.]);
+    }
+
+    #[test]
+    fn compensates_master_and_each_send_separately() {
+        let mut compensation = Compensation::new();
+        compensation.set(1, &[0, 2]);
+
+        let input = block(&[1., 2., 3.]);
+        assert_eq!(frames(&compensation.master.process(&input)), vec![0., 1., 2.]);
+        assert_eq!(frames(&compensation.send(0).process(&input)), vec![1., 2., 3.]);
+        assert_eq!(frames(&compensation.send(1).process(&input)), vec![0., 0., 1.]);
+
+        // A send added since the last `set` passes audio straight through.
+        assert_eq!(frames(&compensation.send(3).process(&input)), vec![1., 2., 3.]);
+    }
+}
@@ -0,0 +1,172 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mixer::db_to_linear;
+
+use super::{
+    sampler::sample_at,
+    wav::{read_wav, WavData},
+    Buffer, FrameValue, SampleRate,
+};
+
+const BEEP_SECONDS: f32 = 0.03;
+const BEEP_HZ: f32 = 1000.;
+const ACCENT_BEEP_HZ: f32 = 1500.;
+/// Clicks other than the downbeat are this much quieter.
+const OFF_BEAT_DB: f32 = -6.;
+const MAX_CLICKS: usize = 8;
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ClickSound {
+    Beep,
+    /// A WAV file, played at its own pitch on every beat.
+    Sample(PathBuf),
+}
+
+/// Saved with the project.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MetronomeSettings {
+    pub enabled: bool,
+    pub level_db: f32,
+    pub sound: ClickSound,
+    /// Bars of clicks before recording starts. The count-in clicks even when
+    /// the metronome is off.
+    pub count_in_bars: u32,
+}
+
+impl Default for MetronomeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level_db: -6.,
+            sound: ClickSound::Beep,
+            count_in_bars: 1,
+        }
+    }
+}
+
+struct Click {
+    /// Frames left before it starts.
+    delay: usize,
+    /// Frames into the sound, in the sample's own frames for samples.
+    position: f64,
+    accent: bool,
+}
+
+/// Plays clicks the engine schedules, on top of everything else.
+pub struct Metronome {
+    pub enabled: bool,
+    gain: f32,
+    sample: Option<WavData>,
+    sample_rate: SampleRate,
+    clicks: Vec<Click>,
+}
+
+impl Metronome {
+    /// Falls back to the beep, with why added to `errors`, if the sample
+    /// can't be read.
+    pub fn new(
+        settings: &MetronomeSettings,
+        sample_rate: SampleRate,
+        errors: &mut Vec<String>,
+    ) -> Self {
+        let sample = match &settings.sound {
+            ClickSound::Beep => None,
+            ClickSound::Sample(path) => match read_wav(path) {
+                Ok(sample) => Some(sample),
+                Err(e) => {
+                    errors.push(format!("Metronome: {}", e));
+                    None
+                }
+            },
+        };
+
+        Self {
+            enabled: settings.enabled,
+            gain: db_to_linear(settings.level_db),
+            sample,
+            sample_rate,
+            clicks: Vec::with_capacity(MAX_CLICKS),
+        }
+    }
+
+    pub fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.sample_rate = rate;
+        self.clicks.clear();
+    }
+
+    /// Starts a click `frame` frames into the next block processed, or later
+    /// blocks if it's further out than that.
+    pub fn schedule(&mut self, frame: usize, accent: bool) {
+        if self.clicks.len() >= MAX_CLICKS {
+            self.clicks.remove(0);
+        }
+
+        self.clicks.push(Click {
+            delay: frame,
+            position: 0.,
+            accent,
+        });
+    }
+
+    /// Adds the clicks sounding during the block to `output`.
+    pub fn process(&mut self, output: &Buffer) {
+        if self.clicks.is_empty() {
+            return;
+        }
+
+        let mut output = output.data.borrow_mut();
+        let num_frames = output.first().map(|c| c.len()).unwrap_or(0);
+
+        for click in &mut self.clicks {
+            let gain = if click.accent {
+                self.gain
+            } else {
+                self.gain * db_to_linear(OFF_BEAT_DB)
+            };
+
+            for i in 0..num_frames {
+                if click.delay > 0 {
+                    click.delay -= 1;
+                    continue;
+                }
+
+                let value = match &self.sample {
+                    Some(sample) => {
+                        let value = sample_at(&sample.channels[0], click.position);
+                        click.position += sample.sample_rate as f64 / self.sample_rate as f64;
+                        value
+                    }
+                    None => {
+                        let value = beep_at(click.position as f32, click.accent, self.sample_rate);
+                        click.position += 1.;
+                        value
+                    }
+                };
+
+                for channel in output.iter_mut() {
+                    channel[i] += value * gain;
+                }
+            }
+        }
+
+        let length = match &self.sample {
+            Some(sample) => sample.channels[0].len() as f64,
+            None => (BEEP_SECONDS * self.sample_rate) as f64,
+        };
+        self.clicks.retain(|click| click.position < length);
+    }
+}
+
+/// A short sine with a quick decay.
+fn beep_at(frame: f32, accent: bool, sample_rate: SampleRate) -> FrameValue {
+    let length = BEEP_SECONDS * sample_rate;
+    if frame >= length {
+        return 0.;
+    }
+
+    let hz = if accent { ACCENT_BEEP_HZ } else { BEEP_HZ };
+    let envelope = (1. - frame / length).powi(2);
+    (std::f32::consts::TAU * hz * frame / sample_rate).sin() * envelope
+}
@@ -1,15 +1,29 @@
-use std::{rc::Rc, sync::{atomic::AtomicU64, Arc, Mutex}};
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicU64, Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
 
 use crate::{
     midi::{self, Time},
+    track::TrackId,
     ui::reactive::Reactive,
-    utils::{free, rc_ref_cell, RcRefCell},
 };
 
-use self::audio_processor::AudioProcessor;
+use self::audio_processor::{AudioProcessor, ParamDescriptor, ParamId};
 
 pub mod audio_processor;
 pub mod device;
+pub mod effects;
+pub mod engine;
+pub mod graph;
+pub mod latency;
+pub mod metronome;
+pub mod queue;
+pub mod realtime;
+pub mod render;
+pub mod sampler;
+pub mod synth;
+pub mod wav;
 
 pub type SampleRate = f32;
 pub type BlockSize = i64;
@@ -17,92 +31,92 @@ pub type FrameValue = f32;
 
 pub struct Audio {
     pub device: Option<Box<dyn device::Device>>,
+    /// Needed to open SDL devices. `None` when SDL audio failed to initialise.
+    pub sdl_audio: Option<sdl2::AudioSubsystem>,
     pub output_processor: Option<Box<dyn AudioProcessor>>,
     pub sample_rate: Reactive<SampleRate>,
     pub block_size: Reactive<BlockSize>,
     pub engine_output_buf: Buffer,
+    /// Problems from devices that have since been closed, for the status line.
+    messages: Vec<String>,
 }
 
 impl Default for Audio {
     fn default() -> Self {
         Self {
             device: None,
+            sdl_audio: None,
             output_processor: None,
             sample_rate: Reactive::new(44100.0),
             block_size: Reactive::new(512),
-            engine_output_buf: Buffer::new(2, &Reactive::new(512)),
+            engine_output_buf: Buffer::new_non_reactive(2, 512),
+            messages: vec![],
         }
     }
 }
 
-static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+impl Audio {
+    /// Closes the current device, if any, and opens a new one driving `engine`.
+    pub fn set_device(
+        &mut self,
+        kind: &device::DeviceKind,
+        engine: realtime::SharedEngine,
+    ) -> Result<(), String> {
+        if let Some(mut device) = self.device.take() {
+            device.close();
+            self.messages.extend(device.take_errors());
+        }
 
-pub struct Buffer {
-    pub uid: u64,
-    count: *mut usize,
-    pub data: RcRefCell<Vec<Vec<FrameValue>>>,
-    pub on_drop: Rc<dyn Fn()>,
-}
+        self.device = Some(device::open_device(kind, engine, self)?);
 
-impl Clone for Buffer {
-    fn clone(&self) -> Self {
-        unsafe {
-            *self.count += 1;
-        }
+        Ok(())
+    }
 
-        Self {
-            uid: self.uid,
-            count: self.count,
-            data: self.data.clone(),
-            on_drop: self.on_drop.clone(),
+    /// Messages for the status line since the last call.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        let mut messages = std::mem::take(&mut self.messages);
+        if let Some(device) = &mut self.device {
+            messages.extend(device.take_errors());
         }
+        messages
     }
 }
 
-impl Buffer {
-    fn new(num_channels: usize, num_frames: &Reactive<BlockSize>) -> Self {
-        let uid = ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
 
-        let num_frames = num_frames.clone();
-
-        let data = rc_ref_cell(vec![
-            vec![0.0; num_frames.get_copy() as usize];
-            num_channels
-        ]);
-
-        let sub = {
-            let data = data.clone();
-            num_frames.subscribe(Box::new(move |new_num_frames| {
-                data.swap(&rc_ref_cell(vec![
-                    vec![0.0; *new_num_frames as usize];
-                    num_channels
-                ]));
-            }))
-        };
-
-        let clean_up = {
-            let num_frames = num_frames.clone();
-            move || {
-                num_frames.unsubscribe(sub);
-            }
-        };
+/// Samples shared by a buffer and its clones. A buffer is only used by one
+/// thread at a time, so a borrow overlapping a mutable one is a bug and
+/// panics, as with a `RefCell`.
+#[derive(Clone)]
+pub struct BufferData(Arc<RwLock<Vec<Vec<FrameValue>>>>);
 
-        Self {
-            uid,
-            count: Box::into_raw(Box::new(1)),
-            data,
-            on_drop: Rc::new(clean_up),
-        }
+impl BufferData {
+    pub fn borrow(&self) -> RwLockReadGuard<'_, Vec<Vec<FrameValue>>> {
+        self.0.try_read().expect("Buffer is already mutably borrowed")
+    }
+
+    pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, Vec<Vec<FrameValue>>> {
+        self.0.try_write().expect("Buffer is already borrowed")
     }
+}
+
+#[derive(Clone)]
+pub struct Buffer {
+    pub uid: u64,
+    pub data: BufferData,
+}
 
+impl Buffer {
     fn new_non_reactive(num_channels: usize, num_frames: usize) -> Self {
+        Self::from_data(vec![vec![0.0; num_frames]; num_channels])
+    }
+
+    fn from_data(data: Vec<Vec<FrameValue>>) -> Self {
         let uid = ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
         Self {
             uid,
-            count: Box::into_raw(Box::new(1)),
-            data: rc_ref_cell(vec![vec![0.0; num_frames]; num_channels]),
-            on_drop: Rc::new(|| ()),
+            data: BufferData(Arc::new(RwLock::new(data))),
         }
     }
 
@@ -113,126 +127,180 @@ impl Buffer {
     }
 }
 
-impl Drop for Buffer {
-    fn drop(&mut self) {
-        let count = unsafe {
-            *self.count -= 1;
-            *self.count
-        };
-
-        if count == 0 {
-            unsafe { free(self.count) };
-
-            (*self.on_drop)();
-        }
-    }
-}
-
 #[derive(Clone)]
 pub struct ThreadSafeBuffer {
     pub data: Vec<Vec<FrameValue>>,
 }
 
 impl ThreadSafeBuffer {
-    fn new(num_channels: usize, num_frames: usize) -> Self {
-        Self {
-            data: vec![vec![0.0; num_frames]; num_channels],
-        }
+    pub fn to_buffer(&self) -> Buffer {
+        Buffer::from_data(self.data.clone())
     }
+}
 
-    pub fn to_buffer(&self) -> Buffer {
-        let buf = Buffer::new_non_reactive(self.data.len(), self.data[0].len());
-        buf.data.swap(&rc_ref_cell(self.data.clone()));
-        buf
+
+pub type ProcessorId = u64;
+
+pub struct ProcessorSlot {
+    pub id: ProcessorId,
+    pub processor: Box<dyn AudioProcessor>,
+    pub bypass: bool,
+    /// Track whose output is fed to the processor's sidechain input.
+    pub sidechain: Option<TrackId>,
+}
+
+impl ProcessorSlot {
+    /// Adds changes the processor made itself to `changes`.
+    pub fn collect_param_changes(&mut self, changes: &mut Vec<ParamChange>) {
+        for (param, value) in self.processor.take_param_changes() {
+            changes.push(ParamChange {
+                processor: self.id,
+                param,
+                value,
+            });
+        }
     }
 }
 
+/// Everything about a slot that can change without reloading its processor.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SlotSettings {
+    pub id: ProcessorId,
+    pub bypass: bool,
+    pub sidechain: Option<TrackId>,
+}
+
+/// A parameter of the processor in the slot with id `processor`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ParamChange {
+    pub processor: ProcessorId,
+    pub param: ParamId,
+    pub value: f32,
+}
 
+/// Processors run one after another, each getting the previous one's output.
 pub struct ProcessorGroup {
     pub uid: u64,
-    pub processors: Vec<Box<dyn AudioProcessor>>,
-    pub output: Buffer,
+    pub processors: Vec<ProcessorSlot>,
+}
+
+impl Default for ProcessorGroup {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProcessorGroup {
-    fn new() -> Self {
+    pub fn new() -> Self {
         let uid = ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
         Self {
             uid,
             processors: vec![],
-            output: Buffer::new_non_reactive(2, 512)
         }
     }
 
-    fn add_processor(&mut self, mut new_processor: Box<dyn AudioProcessor>, a: &Audio) {
-        self.processors.push(new_processor);
+    pub fn add_processor(&mut self, id: ProcessorId, processor: Box<dyn AudioProcessor>) {
+        self.processors.push(ProcessorSlot {
+            id,
+            processor,
+            bypass: false,
+            sidechain: None,
+        });
     }
-}
 
-impl AudioProcessor for ProcessorGroup {
-    fn change_sample_rate(&mut self, rate: SampleRate) {
-        for processor in &mut self.processors {
-            processor.change_sample_rate(rate);
+    /// Reorders the group to match `order`, taking processors not already in
+    /// the group from `new`. Anything not mentioned in `order` is returned, so
+    /// it can be dropped off the audio thread.
+    pub fn rearrange(&mut self, order: &[SlotSettings], new: Vec<ProcessorSlot>) -> Vec<ProcessorSlot> {
+        let mut available: Vec<ProcessorSlot> = self.processors.drain(..).collect();
+        available.extend(new);
+
+        for settings in order {
+            if let Some(i) = available.iter().position(|slot| slot.id == settings.id) {
+                let mut slot = available.swap_remove(i);
+                slot.bypass = settings.bypass;
+                slot.sidechain = settings.sidechain;
+                self.processors.push(slot);
+            }
         }
+
+        available
     }
 
-    fn change_block_size(&mut self, size: BlockSize) {
-        for processor in &mut self.processors {
-            processor.change_block_size(size);
+    /// Hands each processor with a sidechain the current block's output of
+    /// its source track.
+    pub fn feed_sidechains(&mut self, track_outputs: &HashMap<TrackId, Buffer>) {
+        for slot in &mut self.processors {
+            if let Some(track_id) = slot.sidechain {
+                slot.processor.set_sidechain(track_outputs.get(&track_id).cloned());
+            }
         }
     }
 
-    fn show_gui(&mut self, _window_id: *mut std::ffi::c_void) -> Result<(), String> {
-        Ok(())
+    pub fn slot_mut(&mut self, id: ProcessorId) -> Option<&mut ProcessorSlot> {
+        self.processors.iter_mut().find(|slot| slot.id == id)
     }
 
+    pub fn param_descriptors(&self) -> impl Iterator<Item = (ProcessorId, Vec<ParamDescriptor>)> + '_ {
+        self.processors.iter().map(|slot| (slot.id, slot.processor.params()))
+    }
 
-    fn hide_gui(&mut self) {}
-
-    fn process(&mut self, _midi_events: std::option::Option<&Vec<midi::MidiEvent>>, mut input: Buffer, t: Time) -> Buffer {
-        for processor in &mut self.processors {
-            input = processor.process(None, input, t);
+    /// Adds changes processors in the group made themselves to `changes`.
+    pub fn collect_param_changes(&mut self, changes: &mut Vec<ParamChange>) {
+        for slot in &mut self.processors {
+            slot.collect_param_changes(changes);
         }
-        input.clone()
     }
 
+    /// Tracks feeding a sidechain somewhere in the group.
+    pub fn sidechain_sources(&self) -> impl Iterator<Item = TrackId> + '_ {
+        self.processors.iter().filter_map(|slot| slot.sidechain)
+    }
 }
 
-// pub struct Memo {
-//     last_t: Time,
-//     output: Buffer,
-// }
-
-
-// // Note: Tracks count as sends...
-// pub struct Send {
-//     inputs: AddNode,
-//     processors: ProcessorGroup,
-//     memo: Memo,
-// }
+impl AudioProcessor for ProcessorGroup {
+    fn change_sample_rate(&mut self, rate: SampleRate) {
+        for slot in &mut self.processors {
+            slot.processor.change_sample_rate(rate);
+        }
+    }
 
-// impl Send {
-//     async fn process(&mut self, t: Time) -> ThreadSafeBuffer {
+    fn change_block_size(&mut self, size: BlockSize) {
+        for slot in &mut self.processors {
+            slot.processor.change_block_size(size);
+        }
+    }
 
-//         if self.memo.last_t == t {
-//             return self.memo.output.to_thread_safe();
-//         }
+    fn set_tempo(&mut self, tempo: f32) {
+        for slot in &mut self.processors {
+            slot.processor.set_tempo(tempo);
+        }
+    }
 
-//         let output = self.processors.process(events, input.to_buffer(), t);
+    fn show_gui(&mut self, _window_id: *mut std::ffi::c_void) -> Result<(), String> {
+        Ok(())
+    }
 
-//         self.memo.output = output.clone();
-//         self.memo.last_t = t;
 
-//         output.to_thread_safe()
-//     }
-// }
+    fn hide_gui(&mut self) {}
 
-// pub struct AddNode(Option<Vec<Arc<Mutex<Send>>>>);
+    fn latency(&self) -> usize {
+        self.processors
+            .iter()
+            .filter(|slot| !slot.bypass)
+            .map(|slot| slot.processor.latency())
+            .sum()
+    }
 
-// impl AddNode {
-//     fn process(rt: &mut Runtime, input: ThreadSafeBuffer, t: Time) -> ThreadSafeBuffer {
+    fn process(&mut self, midi_events: std::option::Option<&Vec<midi::MidiEvent>>, mut input: Buffer, t: Time) -> Buffer {
+        for slot in &mut self.processors {
+            if slot.bypass {
+                continue;
+            }
 
+            input = slot.processor.process(midi_events, input, t);
+        }
+        input.clone()
+    }
 
-//         output
-//     }
-// }
\ No newline at end of file
+}
@@ -0,0 +1,981 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    automation::{AutomationLane, AutomationTarget},
+    clip::ClipState,
+    global::PlayingState,
+    midi::{MidiEvent, MidiEventsBlockList, Time},
+    midi_sync::ClockOutput,
+    midi_output::{self, spawn_output_thread, MidiOutputSettings, OutputMessage, OutputPort},
+    mixer::MixerSettings,
+    project::Project,
+    routing::{self, Bus, BusId},
+    tempo_map::{MeterMap, TempoMap},
+    track::{Instrument, Track, TrackId},
+};
+
+use super::{
+    audio_processor::ParamDescriptor,
+    engine::{
+        insert_order, insert_params, insert_slots, load_instrument, track_params, Engine, EngineTrack,
+    },
+    graph::BusNode,
+    metronome::{Metronome, MetronomeSettings},
+    queue::{queue, Consumer, Producer},
+    Audio, BlockSize, ParamChange, ProcessorId, ProcessorSlot, SampleRate, SlotSettings,
+};
+
+/// Parameter changes the audio thread can report before the UI thread
+/// catches up. Any more wait for the next block.
+const PARAM_QUEUE_SIZE: usize = 1024;
+
+/// Messages from the UI thread to the audio thread.
+pub enum EngineCommand {
+    /// Starts after `count_in` beats of clicks.
+    Play { from: Time, count_in: Time },
+    Stop,
+    Seek(Time),
+    SetTempoMap(TempoMap),
+    /// `(start, end)` while looping.
+    SetLoop(Option<(Time, Time)>),
+    SetMeterMap(MeterMap),
+    SetMetronome(Metronome),
+    SetMonitoredTrack(Option<TrackId>),
+    SetClockOutput(Option<ClockOutput>),
+    SetExternalTempo(Option<f32>),
+    AddTrack(EngineTrack),
+    RemoveTrack(TrackId),
+    SetTrackEvents {
+        track_id: TrackId,
+        events: MidiEventsBlockList,
+    },
+    SetTrackMixer {
+        track_id: TrackId,
+        mixer: MixerSettings,
+    },
+    SetTrackInstrument {
+        track_id: TrackId,
+        instrument: Option<ProcessorSlot>,
+    },
+    /// Reorders a track's inserts, with any processors the engine doesn't
+    /// have yet in `new`.
+    SetTrackInserts {
+        track_id: TrackId,
+        order: Vec<SlotSettings>,
+        new: Vec<ProcessorSlot>,
+    },
+    SetTrackSends {
+        track_id: TrackId,
+        sends: Vec<routing::Send>,
+    },
+    SetTrackAutomation {
+        track_id: TrackId,
+        lanes: Vec<AutomationLane>,
+    },
+    SetTrackMidiOutput {
+        track_id: TrackId,
+        output: Option<MidiOutputSettings>,
+    },
+    AddBus(BusNode),
+    RemoveBus(BusId),
+    SetBusMixer {
+        bus_id: BusId,
+        mixer: MixerSettings,
+    },
+    SetBusInserts {
+        bus_id: BusId,
+        order: Vec<SlotSettings>,
+        new: Vec<ProcessorSlot>,
+    },
+    SetBusSends {
+        bus_id: BusId,
+        sends: Vec<routing::Send>,
+    },
+    SetParams(Vec<ParamChange>),
+}
+
+/// Things the audio thread has finished with. They're sent back to be dropped
+/// on the UI thread, as freeing them can take a while and plugins may do
+/// anything when they're dropped.
+#[allow(dead_code)]
+pub enum Garbage {
+    Track(EngineTrack),
+    Bus(BusNode),
+    Instrument(ProcessorSlot),
+    Slots(Vec<ProcessorSlot>),
+    Events(MidiEventsBlockList),
+    Metronome(Metronome),
+}
+
+/// The audio thread's half. Lives inside the device callback and never blocks:
+/// commands are polled with `try_recv` and the playhead is published through
+/// an atomic.
+pub struct RealtimeEngine {
+    engine: Engine,
+    commands: Receiver<EngineCommand>,
+    /// Notes played live, straight from the MIDI input thread.
+    live_input: Receiver<MidiEvent>,
+    position: Arc<AtomicU64>,
+    handled: Arc<AtomicU64>,
+    playing: bool,
+    player_time: Time,
+    /// Beats of count-in left before the timeline starts moving.
+    count_in: Time,
+    /// Interleaved frames from the last block that didn't fit in the device
+    /// buffer.
+    leftover: Vec<f32>,
+    leftover_pos: usize,
+    /// Parameter changes made by processors themselves, e.g. from their
+    /// editors, reported back to the UI thread.
+    param_changes: Producer<ParamChange>,
+    pending_param_changes: Vec<ParamChange>,
+    garbage: Sender<Garbage>,
+}
+
+/// Shared between whichever device is currently driving the engine. Devices
+/// only `try_lock` from real-time callbacks, and the lock is otherwise only
+/// taken while devices are being swapped.
+pub type SharedEngine = Arc<Mutex<RealtimeEngine>>;
+
+struct KnownTrack {
+    mixer: MixerSettings,
+    clips: Vec<ClipState>,
+    /// Without its parameter values, which are in `params` so changing them
+    /// doesn't reload it.
+    instrument: Option<Instrument>,
+    inserts: Vec<SlotSettings>,
+    params: Vec<ParamChange>,
+    sends: Vec<routing::Send>,
+    automation: Vec<AutomationLane>,
+    midi_output: Option<MidiOutputSettings>,
+}
+
+impl KnownTrack {
+    fn from_track(track: &Track) -> Self {
+        Self {
+            mixer: track.mixer.get_copy(),
+            clips: track.clips().iter().map(|clip| clip.state()).collect(),
+            instrument: track.instrument().map(|instrument| Instrument {
+                params: BTreeMap::new(),
+                ..instrument.clone()
+            }),
+            inserts: insert_order(&track.inserts),
+            params: track_params(track),
+            sends: track.sends.clone(),
+            automation: track.automation.clone(),
+            midi_output: track.midi_output.clone(),
+        }
+    }
+}
+
+struct KnownBus {
+    mixer: MixerSettings,
+    inserts: Vec<SlotSettings>,
+    params: Vec<ParamChange>,
+    sends: Vec<routing::Send>,
+}
+
+impl KnownBus {
+    fn from_bus(bus: &Bus) -> Self {
+        Self {
+            mixer: bus.mixer,
+            inserts: insert_order(&bus.inserts),
+            params: insert_params(&bus.inserts),
+            sends: bus.sends.clone(),
+        }
+    }
+}
+
+/// The UI thread's half.
+pub struct EngineController {
+    realtime: SharedEngine,
+    commands: Sender<EngineCommand>,
+    live_input: Sender<MidiEvent>,
+    midi_out: Sender<OutputMessage>,
+    position: Arc<AtomicU64>,
+    handled: Arc<AtomicU64>,
+    sent: u64,
+    param_changes: Consumer<ParamChange>,
+    garbage: Receiver<Garbage>,
+    /// Problems to tell the user about, e.g. plugins that failed to load.
+    messages: Vec<String>,
+    /// Parameters of every processor the engine has been sent.
+    param_descriptors: HashMap<ProcessorId, Vec<ParamDescriptor>>,
+    /// Tracks the engine has, with what it was last sent for each.
+    known_tracks: HashMap<TrackId, KnownTrack>,
+    known_buses: HashMap<BusId, KnownBus>,
+    last_tempo_map: TempoMap,
+    last_loop_range: Option<(Time, Time)>,
+    last_meter_map: MeterMap,
+    last_metronome: MetronomeSettings,
+    last_monitored_track: Option<TrackId>,
+    last_external_tempo: Option<f32>,
+    was_playing: bool,
+    last_reported_time: Time,
+}
+
+impl RealtimeEngine {
+    /// Called once the device has settled on a format.
+    pub fn set_format(&mut self, sample_rate: SampleRate, block_size: BlockSize) {
+        if sample_rate != self.engine.sample_rate {
+            self.engine.change_sample_rate(sample_rate);
+        }
+
+        if block_size != self.engine.block_size {
+            self.engine.change_block_size(block_size);
+        }
+
+        self.engine.update_routing();
+    }
+
+    fn handle_commands(&mut self) {
+        let mut changed = false;
+
+        while let Ok(command) = self.commands.try_recv() {
+            changed = true;
+
+            match command {
+                EngineCommand::Play { from, count_in } => {
+                    self.player_time = from;
+                    self.count_in = count_in;
+                    self.playing = true;
+                }
+                EngineCommand::Stop => {
+                    self.playing = false;
+                    self.count_in = 0.;
+                    self.engine.release_all_notes();
+                }
+                EngineCommand::Seek(t) => {
+                    self.player_time = t;
+                    self.engine.release_all_notes();
+                }
+                EngineCommand::SetTempoMap(tempo_map) => self.engine.tempo_map = tempo_map,
+                EngineCommand::SetLoop(loop_range) => self.engine.loop_range = loop_range,
+                EngineCommand::SetMeterMap(meter_map) => self.engine.meter_map = meter_map,
+                EngineCommand::SetMonitoredTrack(track_id) => {
+                    self.engine.release_all_notes();
+                    self.engine.monitored_track = track_id;
+                }
+                EngineCommand::SetClockOutput(output) => self.engine.clock_output = output,
+                EngineCommand::SetExternalTempo(tempo) => self.engine.external_tempo = tempo,
+                EngineCommand::SetMetronome(mut metronome) => {
+                    metronome.change_sample_rate(self.engine.sample_rate);
+                    let old = std::mem::replace(&mut self.engine.metronome, metronome);
+                    self.dispose(Garbage::Metronome(old));
+                }
+                EngineCommand::AddTrack(mut track) => {
+                    track.change_sample_rate(self.engine.sample_rate);
+                    track.change_block_size(self.engine.block_size);
+                    if let Some(old) = self.engine.remove_track(track.track_id) {
+                        self.dispose(Garbage::Track(old));
+                    }
+                    self.engine.tracks.push(track);
+                }
+                EngineCommand::RemoveTrack(track_id) => {
+                    if let Some(old) = self.engine.remove_track(track_id) {
+                        self.dispose(Garbage::Track(old));
+                    }
+                }
+                EngineCommand::SetTrackEvents { track_id, events } => {
+                    if let Some(track) = self.engine.track_mut(track_id) {
+                        let old = std::mem::replace(&mut track.events, events);
+                        self.dispose(Garbage::Events(old));
+                    }
+                }
+                EngineCommand::SetTrackMixer { track_id, mixer } => {
+                    if let Some(track) = self.engine.track_mut(track_id) {
+                        track.mixer = mixer;
+                    }
+                }
+                EngineCommand::SetTrackInstrument {
+                    track_id,
+                    mut instrument,
+                } => {
+                    if let Some(slot) = &mut instrument {
+                        slot.processor.change_sample_rate(self.engine.sample_rate);
+                        slot.processor.change_block_size(self.engine.block_size);
+                    }
+
+                    self.engine.release_all_notes();
+                    if let Some(track) = self.engine.track_mut(track_id) {
+                        if let Some(old) = std::mem::replace(&mut track.instrument, instrument) {
+                            self.dispose(Garbage::Instrument(old));
+                        }
+                    }
+                }
+                EngineCommand::SetTrackInserts {
+                    track_id,
+                    order,
+                    mut new,
+                } => {
+                    for slot in &mut new {
+                        slot.processor.change_sample_rate(self.engine.sample_rate);
+                        slot.processor.change_block_size(self.engine.block_size);
+                    }
+
+                    if let Some(track) = self.engine.track_mut(track_id) {
+                        let removed = track.inserts.rearrange(&order, new);
+                        self.dispose(Garbage::Slots(removed));
+                    }
+                }
+                EngineCommand::SetTrackSends { track_id, sends } => {
+                    if let Some(track) = self.engine.track_mut(track_id) {
+                        track.sends = sends;
+                    }
+                }
+                EngineCommand::SetTrackAutomation { track_id, lanes } => {
+                    if let Some(track) = self.engine.track_mut(track_id) {
+                        track.automation = lanes;
+                    }
+                }
+                EngineCommand::SetTrackMidiOutput { track_id, output } => {
+                    if let Some(track) = self.engine.track_mut(track_id) {
+                        track.midi_output = output;
+                    }
+                }
+                EngineCommand::AddBus(mut bus) => {
+                    bus.change_sample_rate(self.engine.sample_rate);
+                    bus.change_block_size(self.engine.block_size);
+                    if let Some(old) = self.engine.graph.add_bus(bus) {
+                        self.dispose(Garbage::Bus(old));
+                    }
+                }
+                EngineCommand::RemoveBus(bus_id) => {
+                    if let Some(old) = self.engine.graph.remove_bus(bus_id) {
+                        self.dispose(Garbage::Bus(old));
+                    }
+                }
+                EngineCommand::SetBusMixer { bus_id, mixer } => {
+                    if let Some(bus) = self.engine.graph.bus_mut(bus_id) {
+                        bus.mixer = mixer;
+                    }
+                }
+                EngineCommand::SetBusInserts {
+                    bus_id,
+                    order,
+                    mut new,
+                } => {
+                    for slot in &mut new {
+                        slot.processor.change_sample_rate(self.engine.sample_rate);
+                        slot.processor.change_block_size(self.engine.block_size);
+                    }
+
+                    if let Some(bus) = self.engine.graph.bus_mut(bus_id) {
+                        let removed = bus.processors.rearrange(&order, new);
+                        self.dispose(Garbage::Slots(removed));
+                    }
+                }
+                EngineCommand::SetBusSends { bus_id, sends } => {
+                    self.engine.graph.set_sends(bus_id, sends);
+                }
+                EngineCommand::SetParams(changes) => {
+                    for change in changes {
+                        self.engine.set_param(change);
+                    }
+                }
+            }
+
+            self.handled.fetch_add(1, Ordering::Release);
+        }
+
+        if changed {
+            self.engine.update_routing();
+        }
+    }
+
+    fn dispose(&self, garbage: Garbage) {
+        // If the controller is gone there's nowhere better to drop it.
+        let _ = self.garbage.send(garbage);
+    }
+
+    /// Fills an interleaved device buffer, processing as many engine blocks as
+    /// needed.
+    pub fn fill_interleaved(&mut self, out: &mut [f32], num_channels: usize) {
+        self.handle_commands();
+
+        let mut written = 0;
+
+        while written < out.len() {
+            if self.leftover_pos >= self.leftover.len() {
+                self.render_block(num_channels);
+            }
+
+            let n = (out.len() - written).min(self.leftover.len() - self.leftover_pos);
+            out[written..written + n]
+                .copy_from_slice(&self.leftover[self.leftover_pos..self.leftover_pos + n]);
+
+            written += n;
+            self.leftover_pos += n;
+        }
+
+        self.position
+            .store(self.player_time.to_bits(), Ordering::Release);
+    }
+
+    fn render_block(&mut self, num_channels: usize) {
+        self.engine.live_events.extend(self.live_input.try_iter());
+
+        let counting_in = self.playing && self.count_in > 0.;
+        if counting_in {
+            self.count_in = self.engine.count_in(self.player_time, self.count_in);
+        }
+
+        let playing = self.playing && !counting_in;
+        let output = self.engine.process_block(self.player_time, playing);
+
+        self.engine.collect_param_changes(&mut self.pending_param_changes);
+        let mut sent = 0;
+        for change in &self.pending_param_changes {
+            if self.param_changes.push(*change).is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        self.pending_param_changes.drain(..sent);
+
+        if playing {
+            self.player_time = self.engine.block_end(self.player_time);
+        }
+
+        let data = output.data.borrow();
+        let num_frames = self.engine.block_size as usize;
+
+        self.leftover.clear();
+        self.leftover_pos = 0;
+
+        for i in 0..num_frames {
+            for c in 0..num_channels {
+                let sample = data
+                    .get(c.min(data.len().saturating_sub(1)))
+                    .and_then(|channel| channel.get(i))
+                    .cloned()
+                    .unwrap_or(0.);
+
+                self.leftover.push(sample);
+            }
+        }
+    }
+}
+
+impl EngineController {
+    /// `engine` should have been built from `project`, e.g. with
+    /// `Engine::from_project`.
+    pub fn new(mut engine: Engine, project: &Project) -> Self {
+        let (sender, receiver) = channel();
+        let (param_sender, param_receiver) = queue(PARAM_QUEUE_SIZE);
+        let (live_sender, live_receiver) = channel();
+        let (garbage_sender, garbage_receiver) = channel();
+        let position = Arc::new(AtomicU64::new(0f64.to_bits()));
+        let handled = Arc::new(AtomicU64::new(0));
+        let midi_out = spawn_output_thread();
+        engine.midi_out = Some(midi_out.clone());
+
+        let known_tracks = engine
+            .tracks
+            .iter()
+            .filter_map(|t| project.tracks.tracks.get(&t.track_id))
+            .map(|track| (track.uid, KnownTrack::from_track(track)))
+            .collect();
+        let known_buses = project
+            .buses
+            .values()
+            .map(|bus| (bus.uid, KnownBus::from_bus(bus)))
+            .collect();
+        let last_tempo_map = engine.tempo_map.clone();
+        let last_loop_range = engine.loop_range;
+        let last_meter_map = engine.meter_map.clone();
+        let last_metronome = project.metronome.get_copy();
+        let param_descriptors = engine.param_descriptors();
+
+        let realtime = RealtimeEngine {
+            engine,
+            commands: receiver,
+            live_input: live_receiver,
+            position: position.clone(),
+            handled: handled.clone(),
+            playing: false,
+            player_time: 0.,
+            count_in: 0.,
+            leftover: vec![],
+            leftover_pos: 0,
+            param_changes: param_sender,
+            pending_param_changes: Vec::with_capacity(PARAM_QUEUE_SIZE),
+            garbage: garbage_sender,
+        };
+
+        let mut controller = EngineController {
+            realtime: Arc::new(Mutex::new(realtime)),
+            commands: sender,
+            live_input: live_sender,
+            midi_out,
+            position,
+            handled,
+            sent: 0,
+            param_changes: param_receiver,
+            garbage: garbage_receiver,
+            messages: vec![],
+            param_descriptors,
+            known_tracks,
+            known_buses,
+            last_tempo_map,
+            last_loop_range,
+            last_meter_map,
+            last_metronome,
+            last_monitored_track: None,
+            last_external_tempo: None,
+            was_playing: false,
+            last_reported_time: 0.,
+        };
+
+        for track in project.tracks.tracks.values() {
+            controller.connect_midi_output(OutputPort::Track(track.uid), &track.midi_output);
+        }
+
+        controller
+    }
+
+    /// Opens the port a track's notes or the clock go out on, closing the one
+    /// used before. Failures are reported but don't stop playback.
+    fn connect_midi_output(&mut self, port: OutputPort, output: &Option<MidiOutputSettings>) {
+        let message = match output {
+            Some(settings) => match midi_output::connect(settings) {
+                Ok(connection) => OutputMessage::Connect { port, connection },
+                Err(e) => {
+                    self.messages.push(format!("MIDI output: {}", e));
+                    OutputMessage::Disconnect(port)
+                }
+            },
+            None => OutputMessage::Disconnect(port),
+        };
+
+        let _ = self.midi_out.send(message);
+    }
+
+    /// Starts or stops sending MIDI clock.
+    pub fn set_clock_output(&mut self, output: Option<ClockOutput>) {
+        self.connect_midi_output(OutputPort::Sync, &output.as_ref().map(|o| o.port.clone()));
+        self.send(EngineCommand::SetClockOutput(output));
+    }
+
+    /// Plays at the tempo of a clock being followed instead of the
+    /// project's.
+    pub fn set_external_tempo(&mut self, tempo: Option<f32>) {
+        if tempo != self.last_external_tempo {
+            self.last_external_tempo = tempo;
+            self.send(EngineCommand::SetExternalTempo(tempo));
+        }
+    }
+
+    /// Where to send notes played live so they're heard on the monitored
+    /// track.
+    /// Messages for the status line since the last call.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.messages)
+    }
+
+    pub fn live_input(&self) -> Sender<MidiEvent> {
+        self.live_input.clone()
+    }
+
+    /// The audio thread's half, for handing to a device.
+    pub fn realtime(&self) -> SharedEngine {
+        self.realtime.clone()
+    }
+
+    /// Parameters of the processor for the insert with id `processor`, once
+    /// it's been loaded.
+    pub fn params(&self, processor: ProcessorId) -> Option<&Vec<ParamDescriptor>> {
+        self.param_descriptors.get(&processor)
+    }
+
+    fn remember_params(&mut self, slots: &[ProcessorSlot]) {
+        for slot in slots {
+            self.param_descriptors.insert(slot.id, slot.processor.params());
+        }
+    }
+
+    /// The changes that take the engine from the `known` parameter values to
+    /// `current`, going back to the default for any no longer set.
+    fn param_diff(&self, known: &[ParamChange], current: &[ParamChange]) -> Vec<ParamChange> {
+        let mut changes: Vec<ParamChange> = current
+            .iter()
+            .filter(|change| !known.contains(change))
+            .cloned()
+            .collect();
+
+        for old in known {
+            let still_set = current
+                .iter()
+                .any(|c| c.processor == old.processor && c.param == old.param);
+            let default = self
+                .params(old.processor)
+                .and_then(|params| params.iter().find(|p| p.id == old.param))
+                .map(|p| p.default);
+
+            if let (false, Some(default)) = (still_set, default) {
+                changes.push(ParamChange {
+                    value: default,
+                    ..*old
+                });
+            }
+        }
+
+        changes
+    }
+
+    /// The values parameters driven by `lanes` were given by hand in
+    /// `params`, or their defaults, to put back once the lanes stop.
+    fn manual_params(
+        &self,
+        lanes: &[&AutomationLane],
+        params: &[ParamChange],
+        instrument: Option<&Instrument>,
+    ) -> Vec<ParamChange> {
+        lanes
+            .iter()
+            .filter_map(|lane| match lane.target {
+                AutomationTarget::Param { insert, param } => Some((insert, param)),
+                AutomationTarget::InstrumentParam { param } => Some((instrument?.id, param)),
+                _ => None,
+            })
+            .filter_map(|(processor, param)| {
+                let value = match params
+                    .iter()
+                    .find(|p| p.processor == processor && p.param == param)
+                {
+                    Some(manual) => manual.value,
+                    None => self.params(processor)?.iter().find(|p| p.id == param)?.default,
+                };
+
+                Some(ParamChange {
+                    processor,
+                    param,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// Saves changes processors made themselves into the project, without
+    /// sending them back.
+    fn receive_param_changes(&mut self, project: &mut Project) {
+        while let Some(change) = self.param_changes.pop() {
+            if let Some(params) = project.params_mut(change.processor) {
+                params.insert(change.param, change.value);
+            }
+
+            let owns = |inserts: &[SlotSettings], instrument: Option<&Instrument>| {
+                inserts.iter().any(|slot| slot.id == change.processor)
+                    || instrument.map(|i| i.id) == Some(change.processor)
+            };
+            let known = self
+                .known_tracks
+                .values_mut()
+                .map(|t| (owns(&t.inserts, t.instrument.as_ref()), &mut t.params))
+                .chain(self.known_buses.values_mut().map(|b| (owns(&b.inserts, None), &mut b.params)));
+
+            for (owned, params) in known {
+                if owned {
+                    params.retain(|p| !(p.processor == change.processor && p.param == change.param));
+                    params.push(change);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, command: EngineCommand) {
+        if self.commands.send(command).is_ok() {
+            self.sent += 1;
+        }
+    }
+
+    fn sync_buses(&mut self, project: &Project, a: &Audio) {
+        let removed: Vec<BusId> = self
+            .known_buses
+            .keys()
+            .filter(|id| !project.buses.contains_key(id))
+            .cloned()
+            .collect();
+
+        let mut rerouted = !removed.is_empty();
+
+        for bus_id in removed {
+            self.known_buses.remove(&bus_id);
+            self.send(EngineCommand::RemoveBus(bus_id));
+        }
+
+        for (bus_id, bus) in &project.buses {
+            let current = KnownBus::from_bus(bus);
+
+            match self.known_buses.remove(bus_id) {
+                None => {
+                    let node = BusNode::from_bus(bus, a, &mut self.messages);
+                    self.remember_params(&node.processors.processors);
+                    self.send(EngineCommand::AddBus(node));
+                    rerouted = true;
+                }
+                Some(known) => {
+                    if known.mixer != current.mixer {
+                        self.send(EngineCommand::SetBusMixer {
+                            bus_id: *bus_id,
+                            mixer: current.mixer,
+                        });
+                    }
+
+                    if known.inserts != current.inserts {
+                        let new = insert_slots(&bus.inserts, &known.inserts, a, &mut self.messages);
+                        self.remember_params(&new);
+                        self.send(EngineCommand::SetBusInserts {
+                            bus_id: *bus_id,
+                            order: current.inserts.clone(),
+                            new,
+                        });
+                    }
+
+                    let changes = self.param_diff(&known.params, &current.params);
+                    if !changes.is_empty() {
+                        self.send(EngineCommand::SetParams(changes));
+                    }
+
+                    if known.sends != current.sends {
+                        self.send(EngineCommand::SetBusSends {
+                            bus_id: *bus_id,
+                            sends: current.sends.clone(),
+                        });
+                        rerouted = true;
+                    }
+                }
+            }
+
+            self.known_buses.insert(*bus_id, current);
+        }
+
+        if rerouted {
+            let names: Vec<&str> = routing::cyclic_buses(&project.buses)
+                .iter()
+                .map(|bus_id| project.buses[bus_id].name.as_str())
+                .collect();
+
+            if !names.is_empty() {
+                self.messages.push(format!(
+                    "Buses {} send to each other in a cycle and were muted",
+                    names.join(", ")
+                ));
+            }
+        }
+    }
+
+    /// Pushes project changes to the audio thread and pulls the playhead
+    /// back. Call once per frame from the UI thread.
+    pub fn sync(
+        &mut self,
+        project: &mut Project,
+        playing_state: &PlayingState,
+        monitored_track: Option<TrackId>,
+        a: &Audio,
+    ) {
+        self.receive_param_changes(project);
+        // Dropped here rather than on the audio thread.
+        for _ in self.garbage.try_iter() {}
+
+        if monitored_track != self.last_monitored_track {
+            self.last_monitored_track = monitored_track;
+            self.send(EngineCommand::SetMonitoredTrack(monitored_track));
+        }
+
+        let track_ids: Vec<TrackId> = project.tracks.tracks.keys().cloned().collect();
+
+        let removed: Vec<TrackId> = self
+            .known_tracks
+            .keys()
+            .filter(|id| !track_ids.contains(id))
+            .cloned()
+            .collect();
+
+        for track_id in removed {
+            self.known_tracks.remove(&track_id);
+            self.connect_midi_output(OutputPort::Track(track_id), &None);
+            self.send(EngineCommand::RemoveTrack(track_id));
+        }
+
+        for track_id in &track_ids {
+            let track = &project.tracks[*track_id];
+            let current = KnownTrack::from_track(track);
+
+            match self.known_tracks.remove(track_id) {
+                None => {
+                    let engine_track = EngineTrack::from_track(track, a, &mut self.messages);
+                    self.remember_params(&engine_track.inserts.processors);
+                    if let Some(slot) = &engine_track.instrument {
+                        self.remember_params(std::slice::from_ref(slot));
+                    }
+                    if track.midi_output.is_some() {
+                        self.connect_midi_output(OutputPort::Track(*track_id), &track.midi_output);
+                    }
+                    self.send(EngineCommand::AddTrack(engine_track));
+                }
+                Some(known) => {
+                    // Only the tracks whose clips changed are rebuilt.
+                    if known.clips != current.clips {
+                        self.send(EngineCommand::SetTrackEvents {
+                            track_id: *track_id,
+                            events: track.events(),
+                        });
+                    }
+
+                    if known.mixer != current.mixer {
+                        self.send(EngineCommand::SetTrackMixer {
+                            track_id: *track_id,
+                            mixer: current.mixer,
+                        });
+                    }
+
+                    if known.instrument != current.instrument {
+                        let instrument = load_instrument(track.instrument(), a, &mut self.messages);
+                        if let Some(slot) = &instrument {
+                            self.remember_params(std::slice::from_ref(slot));
+                        }
+                        self.send(EngineCommand::SetTrackInstrument {
+                            track_id: *track_id,
+                            instrument,
+                        });
+                    }
+
+                    if known.inserts != current.inserts {
+                        let new = insert_slots(&track.inserts, &known.inserts, a, &mut self.messages);
+                        self.remember_params(&new);
+                        self.send(EngineCommand::SetTrackInserts {
+                            track_id: *track_id,
+                            order: current.inserts.clone(),
+                            new,
+                        });
+                    }
+
+                    let changes = self.param_diff(&known.params, &current.params);
+                    if !changes.is_empty() {
+                        self.send(EngineCommand::SetParams(changes));
+                    }
+
+                    if known.sends != current.sends {
+                        self.send(EngineCommand::SetTrackSends {
+                            track_id: *track_id,
+                            sends: current.sends.clone(),
+                        });
+                    }
+
+                    if known.automation != current.automation {
+                        self.send(EngineCommand::SetTrackAutomation {
+                            track_id: *track_id,
+                            lanes: current.automation.clone(),
+                        });
+
+                        let dropped: Vec<&AutomationLane> = known
+                            .automation
+                            .iter()
+                            .filter(|lane| !current.automation.iter().any(|l| l.target == lane.target))
+                            .collect();
+                        let restored = self.manual_params(&dropped, &current.params, current.instrument.as_ref());
+                        if !restored.is_empty() {
+                            self.send(EngineCommand::SetParams(restored));
+                        }
+                    }
+
+                    if known.midi_output != current.midi_output {
+                        self.connect_midi_output(OutputPort::Track(*track_id), &current.midi_output);
+                        self.send(EngineCommand::SetTrackMidiOutput {
+                            track_id: *track_id,
+                            output: current.midi_output.clone(),
+                        });
+                    }
+                }
+            }
+
+            self.known_tracks.insert(*track_id, current);
+        }
+
+        self.sync_buses(project, a);
+
+        let tempo_map = project.tempo_map.get_copy();
+        if tempo_map != self.last_tempo_map {
+            self.last_tempo_map = tempo_map.clone();
+            self.send(EngineCommand::SetTempoMap(tempo_map));
+        }
+
+        let meter_map = project.meter_map.get_copy();
+        if meter_map != self.last_meter_map {
+            self.last_meter_map = meter_map.clone();
+            self.send(EngineCommand::SetMeterMap(meter_map));
+        }
+
+        let metronome = project.metronome.get_copy();
+        if metronome != self.last_metronome {
+            let loaded = Metronome::new(&metronome, a.sample_rate.get_copy(), &mut self.messages);
+            self.last_metronome = metronome;
+            self.send(EngineCommand::SetMetronome(loaded));
+        }
+
+        let loop_range = project.loop_region.get_copy().active();
+        if loop_range != self.last_loop_range {
+            self.last_loop_range = loop_range;
+            self.send(EngineCommand::SetLoop(loop_range));
+        }
+
+        let player_time = project.player_time.get_copy();
+        let moved_by_user = player_time != self.last_reported_time;
+        let is_playing = playing_state.is_playing();
+
+        if is_playing && !self.was_playing {
+            let count_in = match playing_state {
+                PlayingState::Recording => {
+                    let bar = self
+                        .last_meter_map
+                        .time_signature_at(player_time)
+                        .beats_per_measure();
+                    self.last_metronome.count_in_bars as Time * bar
+                }
+                _ => 0.,
+            };
+
+            self.send(EngineCommand::Play {
+                from: player_time,
+                count_in,
+            });
+        } else if !is_playing && self.was_playing {
+            self.send(EngineCommand::Stop);
+
+            let restored: Vec<ParamChange> = self
+                .known_tracks
+                .values()
+                .flat_map(|known| {
+                    let lanes: Vec<&AutomationLane> = known.automation.iter().collect();
+                    self.manual_params(&lanes, &known.params, known.instrument.as_ref())
+                })
+                .collect();
+            if !restored.is_empty() {
+                self.send(EngineCommand::SetParams(restored));
+            }
+        } else if moved_by_user {
+            self.send(EngineCommand::Seek(player_time));
+        }
+
+        self.was_playing = is_playing;
+        self.last_reported_time = player_time;
+
+        // Only trust the reported position once the engine has caught up with
+        // everything we've sent, otherwise a seek would snap back.
+        if is_playing && self.handled.load(Ordering::Acquire) == self.sent {
+            let engine_time = f64::from_bits(self.position.load(Ordering::Acquire));
+            if engine_time != player_time {
+                project.player_time <<= engine_time;
+                self.last_reported_time = engine_time;
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+
+use crate::project::Project;
+
+use super::{
+    engine::Engine,
+    wav::{WavFormat, WavWriter},
+    Audio,
+};
+
+pub struct RenderOptions {
+    pub format: WavFormat,
+    /// Extra time rendered after the last note so release tails aren't cut off.
+    pub tail_seconds: f64,
+    /// Mixes in the metronome, with the project's sound and level, whether
+    /// or not it's switched on.
+    pub metronome: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            format: WavFormat::Int24,
+            tail_seconds: 2.,
+            metronome: false,
+        }
+    }
+}
+
+/// Renders the whole project to a WAV file as fast as the processors allow.
+/// Doesn't touch the audio device so it works headless.
+pub fn render_project(
+    project: &Project,
+    a: &Audio,
+    path: &Path,
+    options: &RenderOptions,
+) -> Result<(), String> {
+    let mut errors = vec![];
+    let mut engine = Engine::from_project(project, a, &mut errors);
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    engine.metronome.enabled = options.metronome;
+
+    let sample_rate = engine.sample_rate;
+    let block_size = engine.block_size as usize;
+
+    let end_seconds = engine.tempo_map.beats_to_seconds(project.end_time());
+    let tail_frames = (options.tail_seconds * sample_rate as f64).ceil() as usize;
+    let total_frames = (end_seconds * sample_rate as f64).ceil() as usize + tail_frames;
+
+    let mut writer = WavWriter::create(path, 2, sample_rate, options.format)?;
+
+    // Everything comes out `engine.latency` frames late, so render that much
+    // extra and drop it from the start.
+    let mut to_skip = engine.latency;
+    let mut frames_rendered: usize = 0;
+    let mut frames_written = 0;
+
+    while frames_written < total_frames {
+        // Worked out from the frame count each time, as adding up block
+        // lengths in beats would drift.
+        let seconds = frames_rendered as f64 / sample_rate as f64;
+        let t = engine.tempo_map.seconds_to_beats(seconds);
+
+        let output = engine.process_block(t, true);
+        frames_rendered += block_size;
+
+        let skipped = to_skip.min(block_size);
+        to_skip -= skipped;
+
+        let num_frames = (block_size - skipped).min(total_frames - frames_written);
+        writer.write_frames(&output.data.borrow(), skipped, num_frames)?;
+
+        frames_written += num_frames;
+    }
+
+    writer.finish()
+}
+
+/// `daw --render <project> <output.wav> [16|24|32f] [click]`
+pub fn render_from_args(args: &[String]) -> Result<(), String> {
+    let usage = || "Usage: daw --render <project> <output.wav> [16|24|32f] [click]".to_string();
+
+    let project_path = args.first().ok_or_else(usage)?;
+    let output_path = args.get(1).ok_or_else(usage)?;
+
+    let mut options = RenderOptions::default();
+    for arg in &args[2..] {
+        match arg.as_str() {
+            "click" => options.metronome = true,
+            format => options.format = WavFormat::from_str(format).ok_or_else(usage)?,
+        }
+    }
+
+    let mut project = Project::new();
+    project.load(&PathBuf::from(project_path))?;
+
+    let audio = Audio::default();
+
+    render_project(&project, &audio, &PathBuf::from(output_path), &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi::Note;
+
+    use super::*;
+
+    /// The body of the first chunk called `id` in a RIFF file.
+    fn chunk<'a>(bytes: &'a [u8], id: &[u8]) -> &'a [u8] {
+        let mut i = 12;
+        while i + 8 <= bytes.len() {
+            let size = u32::from_le_bytes(bytes[i + 4..i + 8].try_into().unwrap()) as usize;
+            if &bytes[i..i + 4] == id {
+                return &bytes[i + 8..i + 8 + size];
+            }
+            i += 8 + size + size % 2;
+        }
+        panic!("No {:?} chunk", String::from_utf8_lossy(id));
+    }
+
+    #[test]
+    fn renders_a_project_to_a_wav_file() {
+        let mut project = Project::new();
+        let (track_id, clip_id) = project.first_clip().unwrap();
+        project.tracks[track_id].push_note(
+            clip_id,
+            Note {
+                note: 60,
+                velocity: 100,
+                start: 0.,
+                length: 1.,
+            },
+        );
+
+        let audio = Audio::default();
+        let options = RenderOptions {
+            format: WavFormat::Int16,
+            tail_seconds: 0.5,
+            metronome: false,
+        };
+        let path = std::env::temp_dir().join(format!("render_{}.wav", std::process::id()));
+        let result = render_project(&project, &audio, &path, &options);
+        let bytes = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+        result.unwrap();
+        let bytes = bytes.unwrap();
+
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(&bytes[8..12], b"WAVE");
+
+        let fmt = chunk(&bytes, b"fmt ");
+        let channels = u16::from_le_bytes([fmt[2], fmt[3]]) as usize;
+        let sample_rate = u32::from_le_bytes(fmt[4..8].try_into().unwrap());
+        let bits = u16::from_le_bytes([fmt[14], fmt[15]]) as usize;
+        assert_eq!(channels, 2);
+        assert_eq!(sample_rate, 44100);
+        assert_eq!(bits, 16);
+
+        let seconds = project.tempo_map.get_copy().beats_to_seconds(project.end_time());
+        let expected = (seconds * 44100.).ceil() as usize + 22050;
+        let data = chunk(&bytes, b"data");
+        assert_eq!(data.len() / (channels * bits / 8), expected);
+        assert!(data.iter().any(|b| *b != 0));
+    }
+}
@@ -0,0 +1,423 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    midi::{MidiEvent, MidiEventData, Time},
+    mixer::MixerSettings,
+};
+
+use super::{
+    audio_processor::{AudioProcessor, ParamDescriptor, ParamId},
+    effects::{db_to_gain, ParamInfo, Params},
+    wav::{read_wav, WavData},
+    BlockSize, Buffer, FrameValue, SampleRate,
+};
+
+const GAIN: usize = 0;
+const TUNE: usize = 1;
+const VELOCITY_SENSITIVITY: usize = 2;
+const RELEASE: usize = 3;
+
+/// Tuning applies to notes started after it changes. Release is how long
+/// gated pads take to fade out after their note ends.
+static PARAMS: [ParamInfo; 4] = [
+    ParamInfo {
+        name: "gain",
+        min: -60.,
+        max: 12.,
+        default: 0.,
+        unit: "dB",
+    },
+    ParamInfo {
+        name: "tune",
+        min: -24.,
+        max: 24.,
+        default: 0.,
+        unit: "st",
+    },
+    ParamInfo {
+        name: "velocity_sensitivity",
+        min: 0.,
+        max: 1.,
+        default: 1.,
+        unit: "",
+    },
+    ParamInfo {
+        name: "release",
+        min: 1.,
+        max: 2000.,
+        default: 5.,
+        unit: "ms",
+    },
+];
+
+const MAX_VOICES: usize = 32;
+/// Seconds taken to fade out choked voices so they don't click.
+const CHOKE_SECONDS: f32 = 0.005;
+
+/// One sample and the notes and velocities that trigger it.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Pad {
+    pub path: PathBuf,
+    pub low_note: u32,
+    pub high_note: u32,
+    /// The note that plays the sample at its original pitch.
+    pub root_note: u32,
+    /// Pads sharing notes but with different velocity ranges act as layers.
+    pub low_velocity: u32,
+    pub high_velocity: u32,
+    /// Plays to the end regardless of note offs.
+    pub one_shot: bool,
+    /// Starting a pad cuts off any other pad in the same group, e.g. open
+    /// and closed hi-hats.
+    pub choke_group: Option<u32>,
+    pub gain_db: f32,
+    pub pan: f32,
+}
+
+impl Pad {
+    pub fn new(path: PathBuf, note: u32) -> Self {
+        Self {
+            path,
+            low_note: note,
+            high_note: note,
+            root_note: note,
+            low_velocity: 0,
+            high_velocity: 127,
+            one_shot: true,
+            choke_group: None,
+            gain_db: 0.,
+            pan: 0.,
+        }
+    }
+
+    fn matches(&self, note: u32, velocity: u32) -> bool {
+        (self.low_note..=self.high_note).contains(&note)
+            && (self.low_velocity..=self.high_velocity).contains(&velocity)
+    }
+
+    fn channel_gains(&self) -> (f32, f32) {
+        MixerSettings {
+            gain_db: self.gain_db,
+            pan: self.pan,
+            ..MixerSettings::default()
+        }
+        .channel_gains()
+    }
+}
+
+/// The sampler's settings, saved with the track's instrument.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct Kit {
+    pub pads: Vec<Pad>,
+}
+
+struct LoadedPad {
+    pad: Pad,
+    sample: WavData,
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    pad: usize,
+    note: u32,
+    /// Position in the sample, in the sample's own frames.
+    position: f64,
+    /// Sample frames to advance per output frame, covering both pitch and
+    /// any difference in sample rate.
+    step: f64,
+    gain: f32,
+    /// How much `fade` drops each frame. Zero until the voice is released
+    /// or choked.
+    fade_step: f32,
+    fade: f32,
+}
+
+/// Plays WAV files mapped across note and velocity ranges.
+pub struct Sampler {
+    pub params: Params,
+    pads: Vec<LoadedPad>,
+    voices: Vec<Voice>,
+    sample_rate: SampleRate,
+    output: Buffer,
+}
+
+impl Sampler {
+    /// Loads every pad's sample. Pads whose files can't be read are skipped,
+    /// with why added to `errors`, rather than failing the whole kit.
+    pub fn new(
+        kit: &Kit,
+        sample_rate: SampleRate,
+        block_size: BlockSize,
+        errors: &mut Vec<String>,
+    ) -> Self {
+        let pads = kit
+            .pads
+            .iter()
+            .filter_map(|pad| match read_wav(&pad.path) {
+                Ok(sample) => Some(LoadedPad {
+                    pad: pad.clone(),
+                    sample,
+                }),
+                Err(e) => {
+                    errors.push(format!("Sampler: {}", e));
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            params: Params::new(&PARAMS),
+            pads,
+            voices: Vec::with_capacity(MAX_VOICES),
+            sample_rate,
+            output: Buffer::new_non_reactive(2, block_size as usize),
+        }
+    }
+
+    /// `fade_step` for a fade lasting `seconds`.
+    fn fade_step(&self, seconds: f32) -> f32 {
+        1. / (seconds * self.sample_rate).max(1.)
+    }
+
+    fn note_on(&mut self, note: u32, velocity: u32) {
+        let choke_step = self.fade_step(CHOKE_SECONDS);
+        let tune = self.params.get(TUNE) as f64;
+        let sensitivity = self.params.get(VELOCITY_SENSITIVITY);
+
+        for i in 0..self.pads.len() {
+            let loaded = &self.pads[i];
+            if !loaded.pad.matches(note, velocity) {
+                continue;
+            }
+
+            if let Some(group) = loaded.pad.choke_group {
+                for voice in &mut self.voices {
+                    if self.pads[voice.pad].pad.choke_group == Some(group) {
+                        voice.fade_step = voice.fade_step.max(choke_step);
+                    }
+                }
+            }
+
+            let pitch = 2f64.powf((note as f64 - loaded.pad.root_note as f64 + tune) / 12.);
+            let rate = loaded.sample.sample_rate as f64 / self.sample_rate as f64;
+
+            if self.voices.len() >= MAX_VOICES {
+                self.voices.remove(0);
+            }
+
+            self.voices.push(Voice {
+                pad: i,
+                note,
+                position: 0.,
+                step: pitch * rate,
+                gain: 1. - sensitivity + sensitivity * velocity.min(127) as f32 / 127.,
+                fade_step: 0.,
+                fade: 1.,
+            });
+        }
+    }
+
+    fn note_off(&mut self, note: u32) {
+        let release_step = self.fade_step(self.params.get(RELEASE) / 1000.);
+
+        for voice in &mut self.voices {
+            if voice.note == note && !self.pads[voice.pad].pad.one_shot && voice.fade_step == 0. {
+                voice.fade_step = release_step;
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: &MidiEvent) {
+        match event.data {
+            MidiEventData::NoteOn { note } if note.velocity == 0 => self.note_off(note.note),
+            MidiEventData::NoteOn { note } => self.note_on(note.note, note.velocity),
+            MidiEventData::NoteOff { note } => self.note_off(note.note),
+            _ => (),
+        }
+    }
+}
+
+/// Linearly interpolated sample at a fractional position.
+pub fn sample_at(channel: &[FrameValue], position: f64) -> FrameValue {
+    let i = position as usize;
+    let frac = (position - i as f64) as f32;
+
+    let a = channel.get(i).cloned().unwrap_or(0.);
+    let b = channel.get(i + 1).cloned().unwrap_or(0.);
+
+    a + (b - a) * frac
+}
+
+impl AudioProcessor for Sampler {
+    fn process(&mut self, events: Option<&Vec<MidiEvent>>, _input: Buffer, _t: Time) -> Buffer {
+        let mut events: Vec<&MidiEvent> = events.map(|e| e.iter().collect()).unwrap_or_default();
+        events.sort_by_key(|e| e.delta_frames);
+        let mut events = events.into_iter().peekable();
+
+        let output_buf = self.output.clone();
+        let mut output = output_buf.data.borrow_mut();
+        let num_frames = output.first().map(|c| c.len()).unwrap_or(0);
+        let level = db_to_gain(self.params.get(GAIN));
+
+        for i in 0..num_frames {
+            while let Some(event) = events.next_if(|e| e.delta_frames as usize <= i) {
+                self.handle_event(event);
+            }
+
+            let (mut left, mut right) = (0., 0.);
+
+            for voice in &mut self.voices {
+                let loaded = &self.pads[voice.pad];
+                let channels = &loaded.sample.channels;
+
+                let l = sample_at(&channels[0], voice.position);
+                let r = channels
+                    .get(1)
+                    .map(|c| sample_at(c, voice.position))
+                    .unwrap_or(l);
+
+                let (pad_left, pad_right) = loaded.pad.channel_gains();
+                let gain = voice.gain * voice.fade * level;
+
+                left += l * pad_left * gain;
+                right += r * pad_right * gain;
+
+                voice.position += voice.step;
+                voice.fade -= voice.fade_step;
+            }
+
+            let pads = &self.pads;
+            self.voices.retain(|v| {
+                v.fade > 0. && v.position < pads[v.pad].sample.channels[0].len() as f64
+            });
+
+            output[0][i] = left;
+            if let Some(channel) = output.get_mut(1) {
+                channel[i] = right;
+            }
+        }
+
+        for event in events {
+            self.handle_event(event);
+        }
+
+        drop(output);
+        output_buf
+    }
+
+    fn params(&self) -> Vec<ParamDescriptor> {
+        self.params.descriptors()
+    }
+
+    fn get_param(&self, id: ParamId) -> Option<f32> {
+        self.params.value(id)
+    }
+
+    fn set_param(&mut self, id: ParamId, value: f32) {
+        self.params.set(id as usize, value);
+    }
+
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        self.params.display(id)
+    }
+
+    fn change_sample_rate(&mut self, rate: SampleRate) {
+        // Voices already playing keep their old speed until they finish.
+        self.sample_rate = rate;
+    }
+
+    fn change_block_size(&mut self, size: BlockSize) {
+        self.output = Buffer::new_non_reactive(2, size as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi::NoteEvent;
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 64;
+
+    /// A sampler with one gated pad on note 60 playing a second of 1s.
+    fn sampler(params: &[(usize, f32)]) -> Sampler {
+        let pad = Pad {
+            one_shot: false,
+            ..Pad::new(PathBuf::new(), 60)
+        };
+        let mut sampler =
+            Sampler::new(&Kit::default(), 48_000., BLOCK_SIZE as BlockSize, &mut vec![]);
+        sampler.pads.push(LoadedPad {
+            pad,
+            sample: WavData {
+                channels: vec![vec![1.; 48_000]],
+                sample_rate: 48_000.,
+            },
+        });
+        for (id, value) in params {
+            sampler.set_param(*id as ParamId, *value);
+        }
+        sampler
+    }
+
+    fn event(velocity: u32, on: bool) -> Vec<MidiEvent> {
+        let note = NoteEvent { note: 60, velocity };
+        vec![MidiEvent {
+            time: 0.,
+            delta_frames: 0,
+            channel: 0,
+            data: if on {
+                MidiEventData::NoteOn { note }
+            } else {
+                MidiEventData::NoteOff { note }
+            },
+        }]
+    }
+
+    /// The left channel's last frame in the block.
+    fn level(sampler: &mut Sampler, events: Vec<MidiEvent>) -> f32 {
+        let input = Buffer::new_non_reactive(2, BLOCK_SIZE);
+        let output = sampler.process(Some(&events), input, 0.);
+        let level = output.data.borrow()[0][BLOCK_SIZE - 1];
+        level
+    }
+
+    #[test]
+    fn gain_and_velocity_sensitivity_scale_notes() {
+        let left = Pad::new(PathBuf::new(), 60).channel_gains().0;
+
+        let mut full = sampler(&[(GAIN, -6.)]);
+        assert!((level(&mut full, event(127, true)) - left * db_to_gain(-6.)).abs() < 1e-4);
+
+        let mut soft = sampler(&[]);
+        assert!((level(&mut soft, event(64, true)) - left * 64. / 127.).abs() < 1e-4);
+
+        let mut insensitive = sampler(&[(VELOCITY_SENSITIVITY, 0.)]);
+        assert!((level(&mut insensitive, event(64, true)) - left).abs() < 1e-4);
+    }
+
+    #[test]
+    fn release_sets_how_long_gated_notes_fade() {
+        let mut short = sampler(&[]);
+        let mut long = sampler(&[(RELEASE, 1000.)]);
+
+        let mut tails = vec![];
+        for sampler in [&mut short, &mut long] {
+            level(sampler, event(127, true));
+            level(sampler, event(0, false));
+            // Past the default release's 240 frames.
+            for _ in 0..4 {
+                level(sampler, vec![]);
+            }
+            tails.push(level(sampler, vec![]));
+        }
+
+        assert!(short.voices.is_empty());
+        assert_eq!(tails[0], 0.);
+        assert!(tails[1] > 0.9 * Pad::new(PathBuf::new(), 60).channel_gains().0);
+        assert_eq!(long.get_param(RELEASE as ParamId), Some(1000.));
+        assert_eq!(long.params().len(), PARAMS.len());
+    }
+}
@@ -0,0 +1,743 @@
+use std::f32::consts::PI;
+
+use crate::midi::{MidiEvent, MidiEventData, Time};
+
+use super::{
+    audio_processor::{AudioProcessor, ParamDescriptor, ParamId},
+    effects::{ParamInfo, Params},
+    BlockSize, Buffer, SampleRate,
+};
+
+const OSC1: usize = 0;
+const OSC2: usize = 1;
+const OSC2_DETUNE: usize = 2;
+const OSC_MIX: usize = 3;
+const CUTOFF: usize = 4;
+const RESONANCE: usize = 5;
+const FILTER_ENV_AMOUNT: usize = 6;
+const AMP_ATTACK: usize = 7;
+const FILTER_ATTACK: usize = 11;
+const VELOCITY_SENSITIVITY: usize = 15;
+const VOICES: usize = 16;
+const GAIN: usize = 17;
+
+/// Voices are allocated up front so changing the count doesn't allocate on
+/// the audio thread.
+const MAX_VOICES: usize = 32;
+
+/// Waveforms are numbered in `Waveform::ALL` order. Envelopes are attack,
+/// decay, sustain and release, amp then filter.
+static PARAMS: [ParamInfo; 18] = [
+    ParamInfo {
+        name: "osc1",
+        min: 0.,
+        max: 3.,
+        default: 0.,
+        unit: "",
+    },
+    ParamInfo {
+        name: "osc2",
+        min: 0.,
+        max: 3.,
+        default: 1.,
+        unit: "",
+    },
+    ParamInfo {
+        name: "osc2_detune",
+        min: -12.,
+        max: 12.,
+        default: 0.07,
+        unit: "st",
+    },
+    ParamInfo {
+        name: "osc_mix",
+        min: 0.,
+        max: 1.,
+        default: 0.3,
+        unit: "",
+    },
+    ParamInfo {
+        name: "cutoff",
+        min: 20.,
+        max: 20_000.,
+        default: 2000.,
+        unit: "Hz",
+    },
+    ParamInfo {
+        name: "resonance",
+        min: 0.,
+        max: 1.,
+        default: 0.2,
+        unit: "",
+    },
+    ParamInfo {
+        name: "filter_env_amount",
+        min: 0.,
+        max: 8.,
+        default: 2.,
+        unit: "oct",
+    },
+    ParamInfo {
+        name: "amp_attack",
+        min: 0.,
+        max: 10_000.,
+        default: 5.,
+        unit: "ms",
+    },
+    ParamInfo {
+        name: "amp_decay",
+        min: 0.,
+        max: 10_000.,
+        default: 300.,
+        unit: "ms",
+    },
+    ParamInfo {
+        name: "amp_sustain",
+        min: 0.,
+        max: 1.,
+        default: 0.7,
+        unit: "",
+    },
+    ParamInfo {
+        name: "amp_release",
+        min: 0.,
+        max: 10_000.,
+        default: 250.,
+        unit: "ms",
+    },
+    ParamInfo {
+        name: "filter_attack",
+        min: 0.,
+        max: 10_000.,
+        default: 10.,
+        unit: "ms",
+    },
+    ParamInfo {
+        name: "filter_decay",
+        min: 0.,
+        max: 10_000.,
+        default: 400.,
+        unit: "ms",
+    },
+    ParamInfo {
+        name: "filter_sustain",
+        min: 0.,
+        max: 1.,
+        default: 0.3,
+        unit: "",
+    },
+    ParamInfo {
+        name: "filter_release",
+        min: 0.,
+        max: 10_000.,
+        default: 300.,
+        unit: "ms",
+    },
+    ParamInfo {
+        name: "velocity_sensitivity",
+        min: 0.,
+        max: 1.,
+        default: 0.8,
+        unit: "",
+    },
+    ParamInfo {
+        name: "voices",
+        min: 1.,
+        max: MAX_VOICES as f32,
+        default: 16.,
+        unit: "",
+    },
+    ParamInfo {
+        name: "gain",
+        min: 0.,
+        max: 1.,
+        default: 0.25,
+        unit: "",
+    },
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Waveform {
+    Saw,
+    Square,
+    Sine,
+    Noise,
+}
+
+impl Waveform {
+    const ALL: [Waveform; 4] = [Waveform::Saw, Waveform::Square, Waveform::Sine, Waveform::Noise];
+
+    fn from_param(value: f32) -> Self {
+        Self::ALL[(value.round().max(0.) as usize).min(Self::ALL.len() - 1)]
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Adsr {
+    /// Seconds.
+    pub attack: f32,
+    /// Seconds.
+    pub decay: f32,
+    /// Level from 0 to 1.
+    pub sustain: f32,
+    /// Seconds.
+    pub release: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SynthPatch {
+    pub osc1: Waveform,
+    pub osc2: Waveform,
+    /// Semitones.
+    pub osc2_detune: f32,
+    /// 0 is only the first oscillator, 1 only the second.
+    pub osc_mix: f32,
+    /// Hz.
+    pub cutoff: f32,
+    /// 0 to 1, self oscillating near 1.
+    pub resonance: f32,
+    /// Octaves the filter envelope opens the cutoff by.
+    pub filter_env_amount: f32,
+    pub amp_env: Adsr,
+    pub filter_env: Adsr,
+    /// 0 ignores velocity, 1 scales the level by it fully.
+    pub velocity_sensitivity: f32,
+    pub voices: usize,
+    pub gain: f32,
+}
+
+impl Adsr {
+    /// Reads the four parameters from `first` on, with times in ms.
+    fn from_params(params: &Params, first: usize) -> Self {
+        Self {
+            attack: params.get(first) / 1000.,
+            decay: params.get(first + 1) / 1000.,
+            sustain: params.get(first + 2),
+            release: params.get(first + 3) / 1000.,
+        }
+    }
+}
+
+impl SynthPatch {
+    fn from_params(params: &Params) -> Self {
+        Self {
+            osc1: Waveform::from_param(params.get(OSC1)),
+            osc2: Waveform::from_param(params.get(OSC2)),
+            osc2_detune: params.get(OSC2_DETUNE),
+            osc_mix: params.get(OSC_MIX),
+            cutoff: params.get(CUTOFF),
+            resonance: params.get(RESONANCE),
+            filter_env_amount: params.get(FILTER_ENV_AMOUNT),
+            amp_env: Adsr::from_params(params, AMP_ATTACK),
+            filter_env: Adsr::from_params(params, FILTER_ATTACK),
+            velocity_sensitivity: params.get(VELOCITY_SENSITIVITY),
+            voices: params.get(VOICES).round() as usize,
+            gain: params.get(GAIN),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy)]
+struct Envelope {
+    stage: Stage,
+    level: f32,
+    /// Level when the release started.
+    release_from: f32,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            stage: Stage::Idle,
+            level: 0.,
+            release_from: 0.,
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.stage = Stage::Attack;
+    }
+
+    fn release(&mut self) {
+        if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+            self.release_from = self.level;
+        }
+    }
+
+    /// Advances by one frame. Segments are linear and restart from the
+    /// current level so retriggering doesn't click.
+    fn next(&mut self, adsr: &Adsr, dt: f32) -> f32 {
+        let step = |seconds: f32, range: f32| dt * range / seconds.max(dt);
+
+        match self.stage {
+            Stage::Idle => self.level = 0.,
+            Stage::Attack => {
+                self.level += step(adsr.attack, 1.);
+                if self.level >= 1. {
+                    self.level = 1.;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= step(adsr.decay, 1. - adsr.sustain);
+                if self.level <= adsr.sustain {
+                    self.level = adsr.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => self.level = adsr.sustain,
+            Stage::Release => {
+                self.level -= step(adsr.release, self.release_from);
+                if self.level <= 0. {
+                    self.level = 0.;
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+
+        self.level
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Oscillator {
+    phase: f32,
+    /// State for the noise generator, seeded per voice so renders repeat.
+    seed: u32,
+}
+
+impl Oscillator {
+    fn new(seed: u32) -> Self {
+        Self {
+            phase: 0.,
+            seed: seed.max(1),
+        }
+    }
+
+    fn next(&mut self, waveform: Waveform, increment: f32) -> f32 {
+        let t = self.phase;
+        self.phase += increment;
+        if self.phase >= 1. {
+            self.phase -= 1.;
+        }
+
+        match waveform {
+            Waveform::Saw => 2. * t - 1. - poly_blep(t, increment),
+            Waveform::Square => {
+                let naive = if t < 0.5 { 1. } else { -1. };
+                naive + poly_blep(t, increment) - poly_blep((t + 0.5) % 1., increment)
+            }
+            Waveform::Sine => (2. * PI * t).sin(),
+            Waveform::Noise => {
+                // xorshift32
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 17;
+                self.seed ^= self.seed << 5;
+                self.seed as f32 / u32::MAX as f32 * 2. - 1.
+            }
+        }
+    }
+}
+
+/// Smooths the discontinuity of a naive saw or square to cut down aliasing.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.
+    } else if t > 1. - dt {
+        let t = (t - 1.) / dt;
+        t * t + t + t + 1.
+    } else {
+        0.
+    }
+}
+
+/// Two pole state variable low pass.
+#[derive(Clone, Copy)]
+struct Filter {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Filter {
+    fn new() -> Self {
+        Self {
+            ic1eq: 0.,
+            ic2eq: 0.,
+        }
+    }
+
+    fn next(&mut self, input: f32, cutoff: f32, resonance: f32, sample_rate: SampleRate) -> f32 {
+        let cutoff = cutoff.clamp(20., sample_rate * 0.45);
+        let g = (PI * cutoff / sample_rate).tan();
+        let k = 2. - 2. * resonance.clamp(0., 0.98);
+
+        let a1 = 1. / (1. + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+
+        self.ic1eq = 2. * v1 - self.ic1eq;
+        self.ic2eq = 2. * v2 - self.ic2eq;
+
+        v2
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    note: u32,
+    velocity: f32,
+    /// Released voices keep sounding until their envelope finishes.
+    held: bool,
+    /// When the voice was started, for stealing the oldest.
+    started: u64,
+    osc1: Oscillator,
+    osc2: Oscillator,
+    filter: Filter,
+    amp_env: Envelope,
+    filter_env: Envelope,
+}
+
+impl Voice {
+    fn new(seed: u32) -> Self {
+        Self {
+            note: 0,
+            velocity: 0.,
+            held: false,
+            started: 0,
+            osc1: Oscillator::new(seed),
+            osc2: Oscillator::new(seed.wrapping_mul(31).wrapping_add(17)),
+            filter: Filter::new(),
+            amp_env: Envelope::new(),
+            filter_env: Envelope::new(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.amp_env.stage != Stage::Idle
+    }
+
+    /// `bend` is in semitones.
+    fn next(&mut self, patch: &SynthPatch, bend: f32, sample_rate: SampleRate) -> f32 {
+        let dt = 1. / sample_rate;
+        let note = self.note as f32 + bend;
+        let frequency = note_frequency(note);
+        let frequency2 = note_frequency(note + patch.osc2_detune);
+
+        let mix = patch.osc_mix.clamp(0., 1.);
+        let osc = self.osc1.next(patch.osc1, frequency * dt) * (1. - mix)
+            + self.osc2.next(patch.osc2, frequency2 * dt) * mix;
+
+        let filter_env = self.filter_env.next(&patch.filter_env, dt);
+        let cutoff = patch.cutoff * 2f32.powf(filter_env * patch.filter_env_amount);
+        let filtered = self.filter.next(osc, cutoff, patch.resonance, sample_rate);
+
+        let amp_env = self.amp_env.next(&patch.amp_env, dt);
+
+        filtered * amp_env * self.velocity
+    }
+}
+
+/// Semitones a full pitch bend moves notes by.
+const PITCH_BEND_RANGE: f32 = 2.;
+
+fn note_frequency(note: f32) -> f32 {
+    440. * 2f32.powf((note - 69.) / 12.)
+}
+
+/// A polyphonic subtractive synth: two oscillators into a resonant low pass,
+/// each with its own envelope. Holds no randomness beyond fixed seeds, so the
+/// same notes always render the same audio.
+pub struct Synth {
+    pub params: Params,
+    /// Worked out from `params` whenever one is set.
+    patch: SynthPatch,
+    /// Always `MAX_VOICES` long, only the first `patch.voices` play.
+    voices: Vec<Voice>,
+    sample_rate: SampleRate,
+    output: Buffer,
+    /// Counts note ons, for stealing the oldest voice.
+    notes_started: u64,
+    /// Semitones, from the pitch wheel.
+    bend: f32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: SampleRate, block_size: BlockSize) -> Self {
+        let params = Params::new(&PARAMS);
+
+        Self {
+            patch: SynthPatch::from_params(&params),
+            params,
+            voices: (1..=MAX_VOICES as u32)
+                .map(|seed| Voice::new(seed.wrapping_mul(2_654_435_761)))
+                .collect(),
+            sample_rate,
+            output: Buffer::new_non_reactive(2, block_size as usize),
+            notes_started: 0,
+            bend: 0.,
+        }
+    }
+
+    fn voices(&mut self) -> &mut [Voice] {
+        &mut self.voices[..self.patch.voices]
+    }
+
+    fn note_on(&mut self, note: u32, velocity: u32) {
+        let sensitivity = self.patch.velocity_sensitivity.clamp(0., 1.);
+        let velocity = 1. - sensitivity + sensitivity * (velocity.min(127) as f32 / 127.);
+
+        let index = self.pick_voice();
+        let started = self.notes_started;
+        let voice = &mut self.voices()[index];
+
+        voice.note = note;
+        voice.velocity = velocity;
+        voice.held = true;
+        voice.started = started;
+        voice.amp_env.trigger();
+        voice.filter_env.trigger();
+
+        self.notes_started += 1;
+    }
+
+    fn note_off(&mut self, note: u32) {
+        for voice in self.voices() {
+            if voice.held && voice.note == note {
+                voice.held = false;
+                voice.amp_env.release();
+                voice.filter_env.release();
+            }
+        }
+    }
+
+    /// A free voice if there is one, otherwise the quietest released voice,
+    /// otherwise the oldest.
+    fn pick_voice(&self) -> usize {
+        let voices = &self.voices[..self.patch.voices];
+
+        if let Some(i) = voices.iter().position(|v| !v.is_active()) {
+            return i;
+        }
+
+        let released = voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| !v.held)
+            .min_by(|(_, a), (_, b)| a.amp_env.level.total_cmp(&b.amp_env.level));
+
+        if let Some((i, _)) = released {
+            return i;
+        }
+
+        voices
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, v)| v.started)
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+
+    fn handle_event(&mut self, event: &MidiEvent) {
+        match event.data {
+            MidiEventData::NoteOn { note } if note.velocity == 0 => self.note_off(note.note),
+            MidiEventData::NoteOn { note } => self.note_on(note.note, note.velocity),
+            MidiEventData::NoteOff { note } => self.note_off(note.note),
+            MidiEventData::PitchBend { value } => {
+                self.bend = value as f32 / 8192. * PITCH_BEND_RANGE;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl AudioProcessor for Synth {
+    fn process(&mut self, events: Option<&Vec<MidiEvent>>, _input: Buffer, _t: Time) -> Buffer {
+        let mut events: Vec<&MidiEvent> = events.map(|e| e.iter().collect()).unwrap_or_default();
+        // Stable, so events at the same frame keep their order.
+        events.sort_by_key(|e| e.delta_frames);
+        let mut events = events.into_iter().peekable();
+
+        let output_buf = self.output.clone();
+        let mut output = output_buf.data.borrow_mut();
+        let num_frames = output.first().map(|c| c.len()).unwrap_or(0);
+
+        for i in 0..num_frames {
+            while let Some(event) = events.next_if(|e| e.delta_frames as usize <= i) {
+                self.handle_event(event);
+            }
+
+            let mut sample = 0.;
+            let (patch, bend, sample_rate) = (self.patch, self.bend, self.sample_rate);
+            for voice in self.voices() {
+                if voice.is_active() {
+                    sample += voice.next(&patch, bend, sample_rate);
+                }
+            }
+            sample *= self.patch.gain;
+
+            for channel in output.iter_mut() {
+                channel[i] = sample;
+            }
+        }
+
+        // Events past the end of the block still need handling.
+        for event in events {
+            self.handle_event(event);
+        }
+
+        drop(output);
+        output_buf
+    }
+
+    fn params(&self) -> Vec<ParamDescriptor> {
+        self.params.descriptors()
+    }
+
+    fn get_param(&self, id: ParamId) -> Option<f32> {
+        self.params.value(id)
+    }
+
+    /// Voices dropped by lowering the voice count are silenced.
+    fn set_param(&mut self, id: ParamId, value: f32) {
+        self.params.set(id as usize, value);
+        self.patch = SynthPatch::from_params(&self.params);
+
+        for voice in &mut self.voices[self.patch.voices..] {
+            voice.held = false;
+            voice.amp_env = Envelope::new();
+            voice.filter_env = Envelope::new();
+        }
+    }
+
+    fn param_display(&self, id: ParamId) -> Option<String> {
+        self.params.display(id)
+    }
+
+    fn change_sample_rate(&mut self, rate: SampleRate) {
+        self.sample_rate = rate;
+    }
+
+    fn change_block_size(&mut self, size: BlockSize) {
+        self.output = Buffer::new_non_reactive(2, size as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi::NoteEvent;
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 64;
+
+    fn note(note: u32, velocity: u32, on: bool) -> MidiEventData {
+        let note = NoteEvent { note, velocity };
+        if on {
+            MidiEventData::NoteOn { note }
+        } else {
+            MidiEventData::NoteOff { note }
+        }
+    }
+
+    /// Renders `frames` frames of the left channel, with each event at its
+    /// frame.
+    fn render(
+        params: &[(usize, f32)],
+        events: &[(usize, MidiEventData)],
+        frames: usize,
+    ) -> Vec<f32> {
+        let mut synth = Synth::new(48_000., BLOCK_SIZE as BlockSize);
+        for (id, value) in params {
+            synth.set_param(*id as ParamId, *value);
+        }
+
+        let input = Buffer::new_non_reactive(2, BLOCK_SIZE);
+        let mut output = vec![];
+
+        for start in (0..frames).step_by(BLOCK_SIZE) {
+            let block: Vec<MidiEvent> = events
+                .iter()
+                .filter(|(frame, _)| (start..start + BLOCK_SIZE).contains(frame))
+                .map(|(frame, data)| MidiEvent {
+                    time: 0.,
+                    delta_frames: (frame - start) as i32,
+                    channel: 0,
+                    data: data.clone(),
+                })
+                .collect();
+
+            let buffer = synth.process(Some(&block), input.clone(), 0.);
+            output.extend_from_slice(&buffer.data.borrow()[0]);
+        }
+
+        output
+    }
+
+    /// FNV-1a over the render as 16 bit samples, so tiny float differences
+    /// don't matter.
+    fn checksum(samples: &[f32]) -> u64 {
+        samples.iter().fold(0xcbf2_9ce4_8422_2325, |hash, sample| {
+            let pcm = (sample.clamp(-1., 1.) * 32767.).round() as i16;
+            pcm.to_le_bytes()
+                .iter()
+                .fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100_0000_01b3))
+        })
+    }
+
+    fn phrase() -> Vec<(usize, MidiEventData)> {
+        vec![
+            (0, note(60, 100, true)),
+            (100, note(64, 80, true)),
+            (1000, note(67, 127, true)),
+            // Steals a voice, as only two play.
+            (1500, note(72, 60, true)),
+            (2000, MidiEventData::PitchBend { value: 4096 }),
+            (3000, note(60, 0, false)),
+            (3000, note(64, 0, false)),
+            (4000, note(67, 0, false)),
+            (4001, note(72, 0, false)),
+        ]
+    }
+
+    fn patch() -> Vec<(usize, f32)> {
+        vec![(OSC2, 3.), (VOICES, 2.), (AMP_ATTACK, 1.), (FILTER_ENV_AMOUNT, 3.)]
+    }
+
+    #[test]
+    fn renders_the_same_notes_the_same_way() {
+        let first = render(&patch(), &phrase(), 9600);
+        let second = render(&patch(), &phrase(), 9600);
+
+        assert!(first.iter().any(|s| s.abs() > 0.01));
+        assert!(first.iter().zip(&second).all(|(a, b)| a.to_bits() == b.to_bits()));
+        assert_eq!(checksum(&first), 6_192_595_882_576_707_381);
+    }
+
+    #[test]
+    fn params_change_the_patch() {
+        let mut synth = Synth::new(48_000., BLOCK_SIZE as BlockSize);
+        synth.set_param(CUTOFF as ParamId, 100_000.);
+        synth.set_param(OSC1 as ParamId, 2.);
+        synth.set_param(VOICES as ParamId, 4.4);
+
+        assert_eq!(synth.get_param(CUTOFF as ParamId), Some(20_000.));
+        assert_eq!(synth.patch.osc1, Waveform::Sine);
+        assert_eq!(synth.patch.voices, 4);
+        assert_eq!(synth.params().len(), PARAMS.len());
+    }
+}
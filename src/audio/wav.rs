@@ -0,0 +1,276 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use super::{FrameValue, SampleRate};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WavFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl WavFormat {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "16" => Some(WavFormat::Int16),
+            "24" => Some(WavFormat::Int24),
+            "32" | "32f" | "float" => Some(WavFormat::Float32),
+            _ => None,
+        }
+    }
+
+    fn bytes_per_sample(&self) -> u16 {
+        match self {
+            WavFormat::Int16 => 2,
+            WavFormat::Int24 => 3,
+            WavFormat::Float32 => 4,
+        }
+    }
+
+    fn format_tag(&self) -> u16 {
+        match self {
+            WavFormat::Float32 => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// Streams interleaved frames to a WAV file. The chunk sizes in the header
+/// are filled in by `finish`.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    format: WavFormat,
+    num_channels: u16,
+    frames_written: u32,
+    data_size_pos: u64,
+    fact_pos: Option<u64>,
+}
+
+impl WavWriter {
+    pub fn create(
+        path: &Path,
+        num_channels: u16,
+        sample_rate: SampleRate,
+        format: WavFormat,
+    ) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut file = BufWriter::new(file);
+
+        let sample_rate = sample_rate as u32;
+        let block_align = num_channels * format.bytes_per_sample();
+        let byte_rate = sample_rate * block_align as u32;
+        let is_float = format == WavFormat::Float32;
+
+        let mut header = vec![];
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&(if is_float { 18u32 } else { 16u32 }).to_le_bytes());
+        header.extend_from_slice(&format.format_tag().to_le_bytes());
+        header.extend_from_slice(&num_channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&(format.bytes_per_sample() * 8).to_le_bytes());
+
+        let mut fact_pos = None;
+        if is_float {
+            // Non PCM formats need a cbSize and a fact chunk.
+            header.extend_from_slice(&0u16.to_le_bytes());
+            header.extend_from_slice(b"fact");
+            header.extend_from_slice(&4u32.to_le_bytes());
+            fact_pos = Some(header.len() as u64);
+            header.extend_from_slice(&0u32.to_le_bytes());
+        }
+
+        header.extend_from_slice(b"data");
+        let data_size_pos = header.len() as u64;
+        header.extend_from_slice(&0u32.to_le_bytes());
+
+        file.write_all(&header).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            file,
+            format,
+            num_channels,
+            frames_written: 0,
+            data_size_pos,
+            fact_pos,
+        })
+    }
+
+    /// Writes `num_frames` frames starting at `first_frame` from
+    /// non-interleaved channel buffers. Channels missing from `channels` are
+    /// written as silence.
+    pub fn write_frames(
+        &mut self,
+        channels: &[Vec<FrameValue>],
+        first_frame: usize,
+        num_frames: usize,
+    ) -> Result<(), String> {
+        let mut bytes =
+            Vec::with_capacity(num_frames * self.num_channels as usize * self.format.bytes_per_sample() as usize);
+
+        for i in first_frame..first_frame + num_frames {
+            for c in 0..self.num_channels as usize {
+                let sample = channels
+                    .get(c)
+                    .and_then(|channel| channel.get(i))
+                    .cloned()
+                    .unwrap_or(0.);
+
+                self.encode_sample(&mut bytes, sample);
+            }
+        }
+
+        self.file.write_all(&bytes).map_err(|e| e.to_string())?;
+        self.frames_written += num_frames as u32;
+        Ok(())
+    }
+
+    /// Writes interleaved samples laid out the same way as the file.
+    pub fn write_interleaved(&mut self, samples: &[FrameValue]) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(samples.len() * self.format.bytes_per_sample() as usize);
+
+        for sample in samples {
+            self.encode_sample(&mut bytes, *sample);
+        }
+
+        self.file.write_all(&bytes).map_err(|e| e.to_string())?;
+        self.frames_written += (samples.len() / self.num_channels as usize) as u32;
+        Ok(())
+    }
+
+    fn encode_sample(&self, bytes: &mut Vec<u8>, sample: FrameValue) {
+        match self.format {
+            WavFormat::Int16 => {
+                let s = (sample.clamp(-1., 1.) * i16::MAX as f32).round() as i16;
+                bytes.extend_from_slice(&s.to_le_bytes());
+            }
+            WavFormat::Int24 => {
+                let s = (sample.clamp(-1., 1.) * 8_388_607.).round() as i32;
+                bytes.extend_from_slice(&s.to_le_bytes()[..3]);
+            }
+            WavFormat::Float32 => {
+                bytes.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        let data_size =
+            self.frames_written * self.num_channels as u32 * self.format.bytes_per_sample() as u32;
+        let riff_size = self.data_size_pos as u32 + 4 + data_size - 8;
+
+        let mut patch = |pos: u64, value: u32| -> Result<(), String> {
+            self.file
+                .seek(SeekFrom::Start(pos))
+                .map_err(|e| e.to_string())?;
+            self.file
+                .write_all(&value.to_le_bytes())
+                .map_err(|e| e.to_string())
+        };
+
+        patch(4, riff_size)?;
+        patch(self.data_size_pos, data_size)?;
+
+        if let Some(fact_pos) = self.fact_pos {
+            patch(fact_pos, self.frames_written)?;
+        }
+
+        self.file.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// A WAV file decoded to one `Vec` per channel.
+pub struct WavData {
+    pub channels: Vec<Vec<FrameValue>>,
+    pub sample_rate: SampleRate,
+}
+
+/// Reads 8, 16, 24 or 32 bit PCM and 32 or 64 bit float WAV files.
+pub fn read_wav(path: &Path) -> Result<WavData, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(format!("{} isn't a WAV file", path.display()));
+    }
+
+    let u16_at = |pos: usize| u16::from_le_bytes([bytes[pos], bytes[pos + 1]]);
+    let u32_at = |pos: usize| {
+        u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+    };
+
+    let mut format = None;
+    let mut data = None;
+    let mut pos = 12;
+
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let size = u32_at(pos + 4) as usize;
+        let body = pos + 8;
+        let end = (body + size).min(bytes.len());
+
+        if id == b"fmt " && size >= 16 {
+            let mut tag = u16_at(body);
+            // WAVE_FORMAT_EXTENSIBLE keeps the real tag in the sub format.
+            if tag == 0xFFFE && size >= 26 {
+                tag = u16_at(body + 24);
+            }
+            format = Some((tag, u16_at(body + 2), u32_at(body + 4), u16_at(body + 14)));
+        } else if id == b"data" {
+            data = Some(&bytes[body..end]);
+        }
+
+        // Chunks are padded to an even length.
+        pos = body + size + (size & 1);
+    }
+
+    let (tag, num_channels, sample_rate, bits) =
+        format.ok_or_else(|| format!("{} has no format chunk", path.display()))?;
+    let data = data.ok_or_else(|| format!("{} has no data chunk", path.display()))?;
+
+    let bytes_per_sample = (bits as usize).div_ceil(8);
+    let decode: fn(&[u8]) -> FrameValue = match (tag, bits) {
+        (1, 8) => |b| (b[0] as f32 - 128.) / 128.,
+        (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32_768.,
+        (1, 24) => |b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.,
+        (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.,
+        (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        (3, 64) => |b| {
+            f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32
+        },
+        _ => {
+            return Err(format!(
+                "{} uses an unsupported format ({} bit, tag {})",
+                path.display(),
+                bits,
+                tag
+            ))
+        }
+    };
+
+    let num_channels = num_channels.max(1) as usize;
+    let mut channels = vec![vec![]; num_channels];
+
+    for (i, sample) in data.chunks_exact(bytes_per_sample).enumerate() {
+        channels[i % num_channels].push(decode(sample));
+    }
+
+    // Drop a trailing partial frame.
+    let num_frames = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    for channel in &mut channels {
+        channel.truncate(num_frames);
+    }
+
+    Ok(WavData {
+        channels,
+        sample_rate: sample_rate as SampleRate,
+    })
+}
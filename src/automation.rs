@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::audio_processor::ParamId,
+    midi::Time,
+    mixer::{MAX_GAIN_DB, MIN_GAIN_DB},
+    track::{Insert, InsertId},
+};
+
+/// What an automation lane drives.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum AutomationTarget {
+    Gain,
+    Pan,
+    /// A parameter of one of the lane's track's inserts. In project files
+    /// `insert` is the insert's position in the chain instead, as ids aren't
+    /// saved.
+    Param { insert: InsertId, param: ParamId },
+    /// A parameter of the lane's track's instrument.
+    InstrumentParam { param: ParamId },
+}
+
+impl AutomationTarget {
+    /// The range of values for targets that don't depend on a processor.
+    pub fn fixed_range(&self) -> Option<(f32, f32)> {
+        match self {
+            AutomationTarget::Gain => Some((MIN_GAIN_DB, MAX_GAIN_DB)),
+            AutomationTarget::Pan => Some((-1., 1.)),
+            AutomationTarget::Param { .. } | AutomationTarget::InstrumentParam { .. } => None,
+        }
+    }
+}
+
+/// How a lane's value moves from one breakpoint to the next.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Curve {
+    Linear,
+    /// Equal ratios in equal times, which suits frequencies and tempos. Falls
+    /// back to easing in when the values aren't both positive.
+    Exponential,
+    /// Holds the value until the next breakpoint.
+    Step,
+}
+
+impl Curve {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear" => Some(Curve::Linear),
+            "exp" | "exponential" => Some(Curve::Exponential),
+            "step" => Some(Curve::Step),
+            _ => None,
+        }
+    }
+
+    /// The value a fraction `x` of the way from `from` to `to`.
+    fn interpolate(&self, from: f32, to: f32, x: f32) -> f32 {
+        match self {
+            Curve::Linear => from + (to - from) * x,
+            Curve::Exponential if from > 0. && to > 0. => from * (to / from).powf(x),
+            Curve::Exponential => from + (to - from) * x * x,
+            Curve::Step => from,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Breakpoint {
+    pub time: Time,
+    pub value: f32,
+    /// Shape of the segment leading to the next breakpoint.
+    pub curve: Curve,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct AutomationLane {
+    pub target: AutomationTarget,
+    /// Sorted by time.
+    pub points: Vec<Breakpoint>,
+}
+
+impl AutomationLane {
+    pub fn new(target: AutomationTarget) -> Self {
+        Self {
+            target,
+            points: vec![],
+        }
+    }
+
+    /// The lane's value at `t`, holding the first and last breakpoints'
+    /// values before and after them. `None` if the lane is empty.
+    pub fn value_at(&self, t: Time) -> Option<f32> {
+        let next = self.points.partition_point(|p| p.time <= t);
+
+        if next == 0 {
+            return self.points.first().map(|p| p.value);
+        }
+
+        let from = &self.points[next - 1];
+        let to = match self.points.get(next) {
+            Some(to) => to,
+            None => return Some(from.value),
+        };
+
+        let x = ((t - from.time) / (to.time - from.time)) as f32;
+        Some(from.curve.interpolate(from.value, to.value, x))
+    }
+
+    /// Adds a breakpoint after any others at the same time, returning its
+    /// index.
+    pub fn add_point(&mut self, point: Breakpoint) -> usize {
+        let index = self.points.partition_point(|p| p.time <= point.time);
+        self.points.insert(index, point);
+        index
+    }
+
+    /// Swaps insert ids in param targets for positions in `inserts`, for
+    /// saving. `None` if the insert is gone.
+    pub fn to_file(&self, inserts: &[Insert]) -> Option<Self> {
+        let target = match self.target {
+            AutomationTarget::Param { insert, param } => AutomationTarget::Param {
+                insert: inserts.iter().position(|i| i.id == insert)? as InsertId,
+                param,
+            },
+            target => target,
+        };
+
+        Some(Self {
+            target,
+            points: self.points.clone(),
+        })
+    }
+
+    /// Undoes `to_file` once `inserts` have been loaded.
+    pub fn from_file(&self, inserts: &[Insert]) -> Option<Self> {
+        let target = match self.target {
+            AutomationTarget::Param { insert, param } => AutomationTarget::Param {
+                insert: inserts.get(insert as usize)?.id,
+                param,
+            },
+            target => target,
+        };
+
+        Some(Self {
+            target,
+            points: self.points.clone(),
+        })
+    }
+}
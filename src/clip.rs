@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::midi::{MidiClip, MidiEvent, MidiEventData, MidiEventsBlockList, NoteEvent, Time};
+
+static CLIP_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+pub type ClipId = u32;
+
+/// Length new clips are given. Clips made to fit their notes are rounded up
+/// to a multiple of it.
+pub const CLIP_LENGTH_STEP: Time = 16.;
+
+/// A clip placed on a track's timeline. Linked copies share their `midi`, so
+/// editing the notes of one edits all of them.
+#[derive(Clone)]
+pub struct TrackClip {
+    /// Only unique within a session, so not saved.
+    pub uid: ClipId,
+    /// Where the clip starts on the timeline.
+    pub start: Time,
+    /// Where in the notes the clip starts playing from, so its start can be
+    /// trimmed without moving them.
+    pub offset: Time,
+    pub length: Time,
+    /// Repeats the first `loop_length` beats of the notes to fill the clip.
+    pub loop_length: Option<Time>,
+    pub midi: MidiClip,
+}
+
+/// Everything about a clip that affects what it plays, to tell when a track's
+/// events need rebuilding.
+#[derive(Clone, PartialEq)]
+pub struct ClipState {
+    uid: ClipId,
+    start: Time,
+    offset: Time,
+    length: Time,
+    loop_length: Option<Time>,
+    revision: u64,
+}
+
+fn next_clip_id() -> ClipId {
+    CLIP_ID_COUNTER.fetch_add(1, Ordering::SeqCst)
+}
+
+impl TrackClip {
+    pub fn new(start: Time, length: Time, midi: MidiClip) -> Self {
+        Self {
+            uid: next_clip_id(),
+            start,
+            offset: 0.,
+            length,
+            loop_length: None,
+            midi,
+        }
+    }
+
+    pub fn empty(start: Time) -> Self {
+        Self::new(start, CLIP_LENGTH_STEP, MidiClip::new())
+    }
+
+    /// A clip at `start` long enough to play all of `midi`.
+    pub fn fitted(start: Time, midi: MidiClip) -> Self {
+        let length = fitted_length(midi.end_time());
+        Self::new(start, length, midi)
+    }
+
+    pub fn state(&self) -> ClipState {
+        ClipState {
+            uid: self.uid,
+            start: self.start,
+            offset: self.offset,
+            length: self.length,
+            loop_length: self.loop_length,
+            revision: self.midi.revision(),
+        }
+    }
+
+    pub fn end(&self) -> Time {
+        self.start + self.length
+    }
+
+    pub fn contains(&self, t: Time) -> bool {
+        self.start <= t && t < self.end()
+    }
+
+    /// Where in the notes timeline time `t` falls.
+    pub fn local_time(&self, t: Time) -> Time {
+        let local = t - self.start + self.offset;
+        match self.loop_length {
+            Some(loop_length) if loop_length > 0. => local.rem_euclid(loop_length),
+            _ => local,
+        }
+    }
+
+    /// Where notes at `local` in the clip first sound on the timeline.
+    pub fn timeline_time(&self, local: Time) -> Time {
+        local - self.offset + self.start
+    }
+
+    /// A copy at `start` that shares its notes with this one.
+    pub fn linked_copy(&self, start: Time) -> Self {
+        Self {
+            uid: next_clip_id(),
+            start,
+            ..self.clone()
+        }
+    }
+
+    /// A copy at `start` with notes of its own.
+    pub fn unlinked_copy(&self, start: Time) -> Self {
+        Self {
+            uid: next_clip_id(),
+            start,
+            midi: self.midi.duplicate(),
+            ..self.clone()
+        }
+    }
+
+    /// This clip lengthened so notes up to `local_end` play, if they don't
+    /// already. Looping clips are left alone.
+    pub fn grown_to_fit(&self, local_end: Time) -> Option<TrackClip> {
+        if self.loop_length.is_some() || local_end <= self.offset + self.length {
+            return None;
+        }
+
+        Some(TrackClip {
+            length: fitted_length(local_end - self.offset),
+            ..self.clone()
+        })
+    }
+
+    /// Ranges of the notes the clip plays, as `(start, end, shift)` where
+    /// `shift` moves a time in the notes onto the timeline.
+    fn windows(&self) -> Vec<(Time, Time, Time)> {
+        let end = self.offset + self.length;
+        let shift = self.start - self.offset;
+
+        let loop_length = match self.loop_length {
+            Some(loop_length) if loop_length > 0. => loop_length,
+            _ => return vec![(self.offset, end, shift)],
+        };
+
+        let mut windows = vec![];
+        let mut cycle = (self.offset / loop_length).floor() as i64;
+        while (cycle as Time) * loop_length < end {
+            let cycle_start = cycle as Time * loop_length;
+            let from = self.offset.max(cycle_start);
+            let to = end.min(cycle_start + loop_length);
+
+            if from < to {
+                windows.push((from - cycle_start, to - cycle_start, shift + cycle_start));
+            }
+            cycle += 1;
+        }
+
+        windows
+    }
+
+    /// Adds the events the clip plays to `events`, at their times on the
+    /// timeline. Notes cut off by the end of the clip or a loop are ended
+    /// there.
+    fn add_events(&self, events: &mut MidiEventsBlockList) {
+        let clip_events = self.midi.events();
+
+        for (start, end, shift) in self.windows() {
+            let mut sounding: HashMap<(u8, u32), u32> = HashMap::new();
+
+            for mut event in clip_events.events_in_range(start, end) {
+                match &event.data {
+                    MidiEventData::NoteOn { note } => {
+                        *sounding.entry((event.channel, note.note)).or_insert(0) += 1;
+                    }
+                    MidiEventData::NoteOff { note } => {
+                        match sounding.get_mut(&(event.channel, note.note)) {
+                            Some(count) if *count > 0 => *count -= 1,
+                            // Started before the window.
+                            _ => continue,
+                        }
+                    }
+                    _ => (),
+                }
+
+                event.time += shift;
+                events.insert_event(event);
+            }
+
+            for ((channel, note), count) in sounding {
+                for _ in 0..count {
+                    events.insert_event(MidiEvent {
+                        time: end + shift,
+                        delta_frames: 0,
+                        channel,
+                        data: MidiEventData::NoteOff {
+                            note: NoteEvent { note, velocity: 0 },
+                        },
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// `length` rounded up to a whole number of steps.
+pub fn fitted_length(length: Time) -> Time {
+    ((length / CLIP_LENGTH_STEP).ceil() * CLIP_LENGTH_STEP).max(CLIP_LENGTH_STEP)
+}
+
+/// Everything `clips` play, indexed by time on the timeline.
+pub fn arrange(clips: &[TrackClip]) -> MidiEventsBlockList {
+    let mut events = MidiEventsBlockList::new();
+    for clip in clips {
+        clip.add_events(&mut events);
+    }
+    events
+}
@@ -0,0 +1,1820 @@
+use std::{path::PathBuf, rc::Rc};
+
+use crate::{
+    audio::{
+        audio_processor::{BuiltinPlugin, ParamDescriptor, PluginDescription, PluginType},
+        device::DeviceKind,
+        metronome::ClickSound,
+        render::{render_project, RenderOptions},
+        sampler::{Kit, Pad},
+        wav::{read_wav, WavFormat},
+        Audio,
+    },
+    automation::{AutomationLane, AutomationTarget, Breakpoint, Curve},
+    clip::{ClipId, TrackClip},
+    global::{EditingContext, Globals},
+    midi::Time,
+    midi_input::MidiInputs,
+    midi_output::{self, MidiOutputSettings},
+    midi_sync::{ClockFollower, ClockOutput, MtcRate},
+    mixer::{MAX_GAIN_DB, MIN_GAIN_DB},
+    project::{Action, Region, TimeSignature},
+    project_file::PROJECT_FILE_EXTENSION,
+    recording::armed_track,
+    routing::{would_create_cycle, Bus, BusId, Send, StripId},
+    smf::{self, SmfFormat},
+    tempo_map::{MeterChange, TempoChange},
+    track::{Insert, InsertId, Instrument, TrackId, TrackType},
+    ui::{
+        arrangement::open_arrangement,
+        automation::lane_range,
+        mixer::{open_mixer, refresh_mixer, selected_strip, selected_track},
+        piano_roll::editing_clip,
+    },
+};
+
+pub type CommandCallback = Rc<dyn Fn(&mut Globals, &str) -> Result<(), String>>;
+
+/// A named action that can be run from the command palette. Anything typed
+/// after the name is passed to the callback as its argument string.
+pub struct Command {
+    pub name: &'static str,
+    pub description: &'static str,
+    callback: CommandCallback,
+}
+
+pub struct Commands {
+    commands: Vec<Command>,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self { commands: vec![] }
+    }
+
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        description: &'static str,
+        callback: CommandCallback,
+    ) {
+        self.commands.retain(|c| c.name != name);
+        self.commands.push(Command {
+            name,
+            description,
+            callback,
+        });
+    }
+
+    pub fn list(&self) -> Vec<(&'static str, &'static str)> {
+        self.commands
+            .iter()
+            .map(|c| (c.name, c.description))
+            .collect()
+    }
+
+    fn find(&self, name: &str) -> Option<CommandCallback> {
+        self.commands
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.callback.clone())
+    }
+}
+
+pub fn run_command(globals: &mut Globals, input: &str) -> Result<(), String> {
+    let input = input.trim();
+    let (name, args) = input.split_once(' ').unwrap_or((input, ""));
+
+    let callback = globals
+        .commands
+        .find(name)
+        .ok_or_else(|| format!("Unknown command '{}'", name))?;
+
+    callback(globals, args.trim())
+}
+
+/// Opens the command palette with some text already typed, e.g. a command
+/// name waiting for its argument.
+pub fn open_command_palette(globals: &mut Globals, prefill: &str) {
+    globals.command_palette_input <<= prefill.to_string();
+    globals.editor_context <<= EditingContext::CommandPallet;
+}
+
+pub fn universal_commands(globals: &mut Globals) {
+    globals.commands.register(
+        "save",
+        "Save the project",
+        Rc::new(|globals, args| {
+            if args.is_empty() {
+                save_project(globals);
+                Ok(())
+            } else {
+                save_project_as(globals, args)
+            }
+        }),
+    );
+
+    globals.commands.register(
+        "save-as",
+        "Save the project to a new file",
+        Rc::new(save_project_as),
+    );
+
+    globals.commands.register(
+        "open",
+        "Open a project file",
+        Rc::new(|globals, args| {
+            if args.is_empty() {
+                return Err("Usage: open <path>".to_string());
+            }
+
+            globals.loaded_project.load(&PathBuf::from(args))
+        }),
+    );
+
+    globals.commands.register(
+        "device",
+        "Switch audio device: sdl, null or wav <path>",
+        Rc::new(|globals, args| {
+            let kind = DeviceKind::from_str(args)
+                .ok_or_else(|| "Usage: device <sdl|null|wav <path>>".to_string())?;
+            let engine = globals
+                .engine
+                .as_ref()
+                .ok_or_else(|| "The audio engine isn't running".to_string())?
+                .realtime();
+
+            globals.audio.set_device(&kind, engine)?;
+            if let Some(device) = &globals.audio.device {
+                globals.status <<= format!("Audio device: {}", device.get_name());
+            }
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "mixer",
+        "Show the mixer",
+        Rc::new(|globals, _| {
+            open_mixer(globals);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "arrangement",
+        "Show every track and its clips",
+        Rc::new(|globals, _| {
+            open_arrangement(globals);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "instrument",
+        "Set the selected track's instrument: synth, sampler, <path> or none",
+        Rc::new(set_instrument),
+    );
+
+    globals.commands.register(
+        "pad-add",
+        "Add a sample to the selected track's sampler: <path> [note=36-38] [root=36] [vel=0-127] [mode=oneshot|gated] [choke=1] [gain=dB] [pan=-1..1]",
+        Rc::new(|globals, args| {
+            let (path, options) = split_pad_options(args);
+            if path.is_empty() {
+                return Err("Usage: pad-add <path> [options]".to_string());
+            }
+
+            edit_kit(globals, |kit| {
+                let note = (36..128)
+                    .find(|n| !kit.pads.iter().any(|p| p.low_note <= *n && *n <= p.high_note))
+                    .unwrap_or(36);
+
+                let mut pad = Pad::new(PathBuf::from(path), note);
+                for option in options {
+                    set_pad_option(&mut pad, option)?;
+                }
+
+                kit.pads.push(pad);
+                Ok(())
+            })
+        }),
+    );
+
+    globals.commands.register(
+        "pad-set",
+        "Change a sampler pad: <position> [note=..] [root=..] [vel=..] [mode=..] [choke=..|none] [gain=..] [pan=..]",
+        Rc::new(|globals, args| {
+            let (position, options) = args.split_once(' ').unwrap_or((args, ""));
+
+            edit_kit(globals, |kit| {
+                let index = pad_at(kit, position)?;
+                let pad = &mut kit.pads[index];
+                for option in options.split_whitespace() {
+                    set_pad_option(pad, option)?;
+                }
+                Ok(())
+            })
+        }),
+    );
+
+    globals.commands.register(
+        "pad-remove",
+        "Remove a pad from the selected track's sampler: <position>",
+        Rc::new(|globals, args| {
+            edit_kit(globals, |kit| {
+                kit.pads.remove(pad_at(kit, args)?);
+                Ok(())
+            })
+        }),
+    );
+
+    globals.commands.register(
+        "insert-add",
+        "Add an effect to the selected strip: <eq|compressor|delay|reverb|limiter|path> [position]",
+        Rc::new(add_insert),
+    );
+
+    globals.commands.register(
+        "insert-remove",
+        "Remove an effect from the selected strip: <position>",
+        Rc::new(|globals, args| {
+            let (strip, index) = insert_at(globals, args)?;
+            globals
+                .loaded_project
+                .perform_action(Action::RemoveInsert { strip, index });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "insert-move",
+        "Move an effect in the selected strip's chain: <from> <to>",
+        Rc::new(|globals, args| {
+            let (from, to) = args
+                .split_once(' ')
+                .ok_or_else(|| "Usage: insert-move <from> <to>".to_string())?;
+            let (strip, from) = insert_at(globals, from)?;
+            let (_, to) = insert_at(globals, to)?;
+            globals
+                .loaded_project
+                .perform_action(Action::MoveInsert { strip, from, to });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "insert-bypass",
+        "Toggle bypass on an effect in the selected strip: <position>",
+        Rc::new(|globals, args| {
+            let (strip, index) = insert_at(globals, args)?;
+            let bypass = !globals.loaded_project.inserts(strip)[index].bypass;
+            globals.loaded_project.perform_action(Action::SetInsertBypass {
+                strip,
+                index,
+                bypass,
+            });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "insert-sidechain",
+        "Key an effect in the selected strip from another track: <position> <track|none>",
+        Rc::new(|globals, args| {
+            let (position, source) = args
+                .split_once(' ')
+                .ok_or_else(|| "Usage: insert-sidechain <position> <track|none>".to_string())?;
+            let (strip, index) = insert_at(globals, position)?;
+
+            let sidechain = match source.trim() {
+                "none" => None,
+                name => Some(find_track(globals, name)?),
+            };
+
+            if sidechain.map(StripId::Track) == Some(strip) {
+                return Err("A track can't key its own effects".to_string());
+            }
+
+            globals.loaded_project.perform_action(Action::SetInsertSidechain {
+                strip,
+                index,
+                sidechain,
+            });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "insert-param",
+        "Set a parameter of an effect in the selected strip: <position> <param> <value|default>",
+        Rc::new(set_insert_param),
+    );
+
+    globals.commands.register(
+        "instrument-param",
+        "Set a parameter of the selected track's instrument: <param> <value|default>",
+        Rc::new(set_instrument_param),
+    );
+
+    globals.commands.register(
+        "lane-add",
+        "Automate the selected track: <gain|pan|instrument <param>|<insert position> <param>>",
+        Rc::new(add_lane),
+    );
+
+    globals.commands.register(
+        "lane-remove",
+        "Remove an automation lane from the selected track: <lane>",
+        Rc::new(|globals, args| {
+            let (track_id, index) = lane_at(globals, args)?;
+            globals
+                .loaded_project
+                .perform_action(Action::RemoveAutomationLane { track_id, index });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "lane-show",
+        "Show one of the selected track's automation lanes under the piano roll: <lane>",
+        Rc::new(|globals, args| {
+            let (_, index) = lane_at(globals, args)?;
+            globals.automation_lane = index;
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "point-add",
+        "Add a breakpoint to an automation lane: <lane> <beat> <value> [linear|exp|step]",
+        Rc::new(|globals, args| {
+            let usage = "Usage: point-add <lane> <beat> <value> [linear|exp|step]";
+            let args: Vec<&str> = args.split_whitespace().collect();
+            if args.len() < 3 {
+                return Err(usage.to_string());
+            }
+
+            let (track_id, lane) = lane_at(globals, args[0])?;
+            let point = parse_breakpoint(globals, track_id, lane, &args[1..], Curve::Linear)?;
+
+            globals.loaded_project.perform_action(Action::AddBreakpoint {
+                track_id,
+                lane,
+                point,
+            });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "point-remove",
+        "Remove a breakpoint from an automation lane: <lane> <point>",
+        Rc::new(|globals, args| {
+            let (lane, point) = args
+                .split_once(' ')
+                .ok_or_else(|| "Usage: point-remove <lane> <point>".to_string())?;
+            let (track_id, lane) = lane_at(globals, lane)?;
+            let index = point_at(globals, track_id, lane, point)?;
+
+            globals.loaded_project.perform_action(Action::RemoveBreakpoint {
+                track_id,
+                lane,
+                index,
+            });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "point-set",
+        "Change a breakpoint in an automation lane: <lane> <point> <beat> <value> [linear|exp|step]",
+        Rc::new(|globals, args| {
+            let usage = "Usage: point-set <lane> <point> <beat> <value> [linear|exp|step]";
+            let args: Vec<&str> = args.split_whitespace().collect();
+            if args.len() < 4 {
+                return Err(usage.to_string());
+            }
+
+            let (track_id, lane) = lane_at(globals, args[0])?;
+            let index = point_at(globals, track_id, lane, args[1])?;
+            let curve = globals.loaded_project.tracks[track_id].automation[lane].points[index].curve;
+            let point = parse_breakpoint(globals, track_id, lane, &args[2..], curve)?;
+
+            globals.loaded_project.perform_action(Action::ModifyBreakpoint {
+                track_id,
+                lane,
+                index,
+                point,
+            });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "tempo-set",
+        "Change the tempo from a beat on: <beat> <bpm> [ramp]",
+        Rc::new(|globals, args| {
+            let usage = "Usage: tempo-set <beat> <bpm> [ramp]";
+            let args: Vec<&str> = args.split_whitespace().collect();
+            if args.len() < 2 {
+                return Err(usage.to_string());
+            }
+
+            let time = parse_beat(args[0])?;
+            let tempo = args[1]
+                .parse::<f32>()
+                .map_err(|_| format!("'{}' isn't a tempo", args[1]))?;
+            let ramp = match args.get(2) {
+                None => false,
+                Some(&"ramp") => true,
+                Some(_) => return Err(usage.to_string()),
+            };
+
+            let mut tempo_map = globals.loaded_project.tempo_map.get_copy();
+            tempo_map.insert(TempoChange { time, tempo, ramp });
+            globals
+                .loaded_project
+                .perform_action(Action::SetTempoMap(tempo_map));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "tempo-remove",
+        "Remove a tempo change other than the first: <change>",
+        Rc::new(|globals, args| {
+            let mut tempo_map = globals.loaded_project.tempo_map.get_copy();
+            let index = change_at(args, tempo_map.changes().len())?;
+            tempo_map.remove(index);
+            globals
+                .loaded_project
+                .perform_action(Action::SetTempoMap(tempo_map));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "meter-set",
+        "Change the time signature from a bar on: <bar> <numerator>/<denominator>",
+        Rc::new(|globals, args| {
+            let (bar, time_signature) = args
+                .split_once(' ')
+                .ok_or_else(|| "Usage: meter-set <bar> <numerator>/<denominator>".to_string())?;
+
+            let bar = bar
+                .parse::<u32>()
+                .ok()
+                .filter(|bar| *bar >= 1)
+                .ok_or_else(|| format!("'{}' isn't a bar", bar))?;
+            let time_signature = parse_time_signature(time_signature.trim())?;
+
+            let mut meter_map = globals.loaded_project.meter_map.get_copy();
+            meter_map.insert(MeterChange {
+                bar,
+                time_signature,
+            });
+            globals
+                .loaded_project
+                .perform_action(Action::SetMeterMap(meter_map));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "meter-remove",
+        "Remove a time signature change other than the first: <change>",
+        Rc::new(|globals, args| {
+            let mut meter_map = globals.loaded_project.meter_map.get_copy();
+            let index = change_at(args, meter_map.changes().len())?;
+            meter_map.remove(index);
+            globals
+                .loaded_project
+                .perform_action(Action::SetMeterMap(meter_map));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "loop",
+        "Turn looping on or off, or loop between two beats: [on|off|<start> <end>]",
+        Rc::new(|globals, args| {
+            let usage = "Usage: loop [on|off|<start> <end>]";
+            let region = parse_region(globals.loaded_project.loop_region.get_copy(), args, usage)?;
+            globals
+                .loaded_project
+                .perform_action(Action::SetLoopRegion(region));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "punch",
+        "Turn punch recording on or off, or punch between two beats: [on|off|<in> <out>]",
+        Rc::new(|globals, args| {
+            let usage = "Usage: punch [on|off|<in> <out>]";
+            let region = parse_region(globals.loaded_project.punch_region.get_copy(), args, usage)?;
+            globals
+                .loaded_project
+                .perform_action(Action::SetPunchRegion(region));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "metronome",
+        "Turn the metronome on or off: [on|off]",
+        Rc::new(|globals, args| {
+            let enabled = match args {
+                "" => !globals.loaded_project.metronome.get_copy().enabled,
+                "on" => true,
+                "off" => false,
+                _ => return Err("Usage: metronome [on|off]".to_string()),
+            };
+
+            globals
+                .loaded_project
+                .metronome
+                .mutate(Box::new(move |metronome| metronome.enabled = enabled));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "metronome-level",
+        "Set the metronome's level: <dB>",
+        Rc::new(|globals, args| {
+            let level_db = args
+                .parse::<f32>()
+                .map_err(|_| "Usage: metronome-level <dB>".to_string())?
+                .clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+
+            globals
+                .loaded_project
+                .metronome
+                .mutate(Box::new(move |metronome| metronome.level_db = level_db));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "metronome-sound",
+        "Set the metronome's sound: <beep|path to a WAV file>",
+        Rc::new(|globals, args| {
+            let sound = match args {
+                "" => return Err("Usage: metronome-sound <beep|path>".to_string()),
+                "beep" => ClickSound::Beep,
+                path => {
+                    let path = PathBuf::from(path);
+                    read_wav(&path)?;
+                    ClickSound::Sample(path)
+                }
+            };
+
+            globals
+                .loaded_project
+                .metronome
+                .mutate(Box::new(move |metronome| metronome.sound = sound.clone()));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "count-in",
+        "Set how many bars of clicks play before recording starts: <bars>",
+        Rc::new(|globals, args| {
+            let bars = args
+                .parse::<u32>()
+                .map_err(|_| "Usage: count-in <bars>".to_string())?;
+
+            globals
+                .loaded_project
+                .metronome
+                .mutate(Box::new(move |metronome| metronome.count_in_bars = bars));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "midi-in",
+        "Listen to a MIDI input port: <port number|name|virtual|off>",
+        Rc::new(|globals, args| {
+            match args {
+                "" => {
+                    let ports = MidiInputs::port_names()?
+                        .iter()
+                        .enumerate()
+                        .map(|(i, name)| format!("{}: {}", i + 1, name))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let current = globals.midi_inputs.port_name().unwrap_or("none");
+
+                    Err(format!(
+                        "Usage: midi-in <port number|name|virtual|off> (listening to {}; ports: {})",
+                        current, ports
+                    ))
+                }
+                "off" => {
+                    globals.midi_inputs.disconnect();
+                    Ok(())
+                }
+                #[cfg(unix)]
+                "virtual" => globals.midi_inputs.connect_virtual(),
+                port => globals.midi_inputs.connect(port),
+            }
+        }),
+    );
+
+    globals.commands.register(
+        "midi-in-send",
+        "Feed a message to MIDI input as if it was played: <hex bytes>, e.g. 90 3c 64",
+        Rc::new(|globals, args| {
+            let bytes = args
+                .split_whitespace()
+                .map(|byte| u8::from_str_radix(byte, 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .ok()
+                .filter(|bytes| !bytes.is_empty() && bytes.len() <= 3)
+                .ok_or_else(|| "Usage: midi-in-send <hex bytes>".to_string())?;
+
+            globals.midi_inputs.source().send(0, &bytes);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "midi-out",
+        "Send the selected track's notes to a MIDI port: <port number|name|virtual [name]|off>",
+        Rc::new(|globals, args| {
+            let track_id = current_track(globals)?;
+            let current = globals.loaded_project.tracks[track_id].midi_output.clone();
+
+            let name = format!("daw-{}", globals.loaded_project.tracks[track_id].name);
+            let output = parse_output_port(args, current, name, "midi-out")?;
+
+            globals
+                .loaded_project
+                .perform_action(Action::SetTrackMidiOutput { track_id, output });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "midi-out-channel",
+        "Set the channel the selected track's MIDI output uses: <1-16>",
+        Rc::new(|globals, args| {
+            let channel = args
+                .parse::<u8>()
+                .ok()
+                .filter(|channel| (1..=16).contains(channel))
+                .ok_or_else(|| "Usage: midi-out-channel <1-16>".to_string())?;
+
+            edit_midi_output(globals, |output| output.channel = channel - 1)
+        }),
+    );
+
+    globals.commands.register(
+        "midi-out-offset",
+        "Send the selected track's MIDI output earlier or later: <ms>",
+        Rc::new(|globals, args| {
+            let offset_ms = args
+                .parse::<f32>()
+                .ok()
+                .filter(|offset| offset.is_finite())
+                .ok_or_else(|| "Usage: midi-out-offset <ms>".to_string())?;
+
+            edit_midi_output(globals, |output| output.offset_ms = offset_ms)
+        }),
+    );
+
+    globals.commands.register(
+        "clock-out",
+        "Send MIDI clock to a port: <port number|name|virtual [name]|off>",
+        Rc::new(|globals, args| {
+            if globals.clock_follower.is_some() {
+                return Err("Can't send clock while following it".to_string());
+            }
+
+            let current = globals.clock_output.as_ref().map(|output| output.port.clone());
+            let mtc = globals.clock_output.as_ref().and_then(|output| output.mtc);
+            let port = parse_output_port(args, current, "daw-clock".to_string(), "clock-out")?;
+
+            set_clock_output(globals, port.map(|port| ClockOutput { port, mtc }));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "mtc",
+        "Send MIDI Time Code along with the clock: <off|24|25|30>",
+        Rc::new(|globals, args| {
+            let mtc = match args {
+                "off" => None,
+                rate => Some(
+                    MtcRate::from_str(rate).ok_or_else(|| "Usage: mtc <off|24|25|30>".to_string())?,
+                ),
+            };
+
+            let mut output = globals
+                .clock_output
+                .clone()
+                .ok_or_else(|| "Clock output is off, turn it on with clock-out".to_string())?;
+            output.mtc = mtc;
+
+            set_clock_output(globals, Some(output));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "clock-in",
+        "Follow MIDI clock from the input port: [on|off]",
+        Rc::new(|globals, args| {
+            let follow = match args {
+                "" => globals.clock_follower.is_none(),
+                "on" => true,
+                "off" => false,
+                _ => return Err("Usage: clock-in [on|off]".to_string()),
+            };
+
+            if follow {
+                set_clock_output(globals, None);
+                globals.clock_follower = Some(ClockFollower::new());
+            } else {
+                globals.clock_follower = None;
+                if let Some(engine) = &mut globals.engine {
+                    engine.set_external_tempo(None);
+                }
+            }
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "arm",
+        "Record and monitor MIDI input on a track: [track name]",
+        Rc::new(|globals, args| {
+            let track_id = match args {
+                "" => current_track(globals)?,
+                name => find_track(globals, name)?,
+            };
+
+            globals.armed_track = Some(track_id);
+            if armed_track(globals).is_none() {
+                globals.armed_track = None;
+                return Err("Only MIDI tracks can be armed".to_string());
+            }
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "disarm",
+        "Stop recording and monitoring MIDI input",
+        Rc::new(|globals, _| {
+            globals.armed_track = None;
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "monitor",
+        "Hear MIDI input through the armed track: [on|off]",
+        Rc::new(|globals, args| {
+            globals.monitoring = match args {
+                "" => !globals.monitoring,
+                "on" => true,
+                "off" => false,
+                _ => return Err("Usage: monitor [on|off]".to_string()),
+            };
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "input-quantize",
+        "Snap recorded notes to a grid: <off|1/<division>|beats>",
+        Rc::new(|globals, args| {
+            globals.input_quantize = match args {
+                "off" => None,
+                grid => Some(parse_grid(grid)?),
+            };
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "clip-new",
+        "Add an empty clip to the selected track and open it: [start beat]",
+        Rc::new(|globals, args| {
+            let track_id = current_track(globals)?;
+            if globals.loaded_project.tracks[track_id].type_ != TrackType::Midi {
+                return Err("Clips can only go on MIDI tracks".to_string());
+            }
+
+            let start = match args {
+                "" => globals.loaded_project.player_time.get_copy(),
+                beat => parse_beat(beat)?,
+            };
+
+            let clip = TrackClip::empty(start);
+            globals.editing_clip = Some((track_id, clip.uid));
+            globals
+                .loaded_project
+                .perform_action(Action::AddClip { track_id, clip });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "clip-open",
+        "Open a clip of the selected track in the piano roll: [number]",
+        Rc::new(|globals, args| {
+            let (track_id, clip_id) = match args {
+                "" => {
+                    let track_id = current_track(globals)?;
+                    let t = globals.loaded_project.player_time.get_copy();
+                    let clip = globals.loaded_project.tracks[track_id]
+                        .clip_at(t)
+                        .ok_or_else(|| "No clip under the time cursor".to_string())?;
+                    (track_id, clip.uid)
+                }
+                position => clip_at(globals, position)?,
+            };
+
+            globals.editing_clip = Some((track_id, clip_id));
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "clip-move",
+        "Move the open clip: <start beat>",
+        Rc::new(|globals, args| {
+            let start = parse_beat(args)?;
+            edit_clip(globals, |clip| {
+                clip.start = start;
+                Ok(())
+            })
+        }),
+    );
+
+    globals.commands.register(
+        "clip-length",
+        "Set how long the open clip plays for: <beats>",
+        Rc::new(|globals, args| {
+            let length = parse_length(args)?;
+            edit_clip(globals, |clip| {
+                clip.length = length;
+                Ok(())
+            })
+        }),
+    );
+
+    globals.commands.register(
+        "clip-offset",
+        "Start the open clip this far into its notes: <beats>",
+        Rc::new(|globals, args| {
+            let offset = parse_beat(args)?;
+            edit_clip(globals, |clip| {
+                clip.offset = offset;
+                Ok(())
+            })
+        }),
+    );
+
+    globals.commands.register(
+        "clip-loop",
+        "Repeat the start of the open clip's notes: <beats|off>",
+        Rc::new(|globals, args| {
+            let loop_length = match args {
+                "off" => None,
+                beats => Some(parse_length(beats)?),
+            };
+            edit_clip(globals, |clip| {
+                clip.loop_length = loop_length;
+                Ok(())
+            })
+        }),
+    );
+
+    globals.commands.register(
+        "clip-link",
+        "Add a copy of the open clip sharing its notes: [start beat]",
+        Rc::new(|globals, args| {
+            let (track_id, clip) = current_clip(globals)?;
+            let start = copy_start(&clip, args)?;
+            globals.loaded_project.perform_action(Action::AddClip {
+                track_id,
+                clip: clip.linked_copy(start),
+            });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "clip-copy",
+        "Add a copy of the open clip with notes of its own: [start beat]",
+        Rc::new(|globals, args| {
+            let (track_id, clip) = current_clip(globals)?;
+            let start = copy_start(&clip, args)?;
+            globals.loaded_project.perform_action(Action::AddClip {
+                track_id,
+                clip: clip.unlinked_copy(start),
+            });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "clip-unlink",
+        "Give the open clip notes of its own, apart from its linked copies",
+        Rc::new(|globals, _| {
+            edit_clip(globals, |clip| {
+                clip.midi = clip.midi.duplicate();
+                Ok(())
+            })
+        }),
+    );
+
+    globals.commands.register(
+        "clip-delete",
+        "Remove the open clip",
+        Rc::new(|globals, _| {
+            let (track_id, clip) = current_clip(globals)?;
+            globals.loaded_project.perform_action(Action::RemoveClip {
+                track_id,
+                clip_id: clip.uid,
+            });
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "bus-add",
+        "Add a return bus: <name>",
+        Rc::new(|globals, args| {
+            if args.is_empty() {
+                return Err("Usage: bus-add <name>".to_string());
+            }
+
+            let bus = Bus::new(globals.loaded_project.next_bus_id(), args.to_string());
+            globals.loaded_project.perform_action(Action::AddBus(bus));
+            refresh_mixer(globals);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "bus-remove",
+        "Remove a return bus and every send to it: <name>",
+        Rc::new(|globals, args| {
+            remove_bus(globals, args)?;
+            refresh_mixer(globals);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "send-add",
+        "Send the selected strip to a bus: <bus> [level dB] [pre]",
+        Rc::new(|globals, args| {
+            add_send(globals, args)?;
+            refresh_mixer(globals);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "send-remove",
+        "Remove a send from the selected strip: <position>",
+        Rc::new(|globals, args| {
+            let (source, index) = send_at(globals, args)?;
+            globals
+                .loaded_project
+                .perform_action(Action::RemoveSend { source, index });
+            refresh_mixer(globals);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "send-level",
+        "Set the level of a send from the selected strip: <position> <dB>",
+        Rc::new(|globals, args| {
+            let (position, level) = args
+                .split_once(' ')
+                .ok_or_else(|| "Usage: send-level <position> <dB>".to_string())?;
+            let (source, index) = send_at(globals, position)?;
+            let level_db = level
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| format!("'{}' isn't a level in dB", level.trim()))?;
+
+            let mut send = globals.loaded_project.sends(source)[index];
+            send.level_db = level_db.clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+            globals.loaded_project.perform_action(Action::ModifySend {
+                source,
+                index,
+                send,
+            });
+            refresh_mixer(globals);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "send-pre",
+        "Toggle whether a send from the selected strip is pre-fader: <position>",
+        Rc::new(|globals, args| {
+            let (source, index) = send_at(globals, args)?;
+            let mut send = globals.loaded_project.sends(source)[index];
+            send.pre_fader = !send.pre_fader;
+            globals.loaded_project.perform_action(Action::ModifySend {
+                source,
+                index,
+                send,
+            });
+            refresh_mixer(globals);
+            Ok(())
+        }),
+    );
+
+    globals.commands.register(
+        "render",
+        "Bounce the project to a WAV file: <path> [16|24|32f] [click]",
+        Rc::new(render),
+    );
+
+    globals.commands.register(
+        "export-midi",
+        "Export MIDI tracks to a type 1 MIDI file",
+        Rc::new(|globals, args| export_midi(globals, args, SmfFormat::MultiTrack)),
+    );
+
+    globals.commands.register(
+        "export-midi-single",
+        "Export MIDI tracks merged into a type 0 MIDI file",
+        Rc::new(|globals, args| export_midi(globals, args, SmfFormat::SingleTrack)),
+    );
+
+    globals.commands.register(
+        "import-midi",
+        "Import a MIDI file as new tracks",
+        Rc::new(|globals, args| {
+            if args.is_empty() {
+                return Err("Usage: import-midi <path>".to_string());
+            }
+
+            smf::import(&mut globals.loaded_project, &PathBuf::from(args))
+        }),
+    );
+}
+
+fn current_track(globals: &Globals) -> Result<TrackId, String> {
+    selected_track(globals).ok_or_else(|| "No track selected".to_string())
+}
+
+fn current_strip(globals: &Globals) -> Result<StripId, String> {
+    selected_strip(globals).ok_or_else(|| "No track or bus selected".to_string())
+}
+
+fn find_track(globals: &Globals, name: &str) -> Result<TrackId, String> {
+    globals
+        .loaded_project
+        .tracks
+        .tracks
+        .values()
+        .find(|track| track.name == name)
+        .map(|track| track.uid)
+        .ok_or_else(|| format!("No track called '{}'", name))
+}
+
+fn current_clip(globals: &Globals) -> Result<(TrackId, TrackClip), String> {
+    editing_clip(globals).ok_or_else(|| "No clip open".to_string())
+}
+
+/// Applies `f` to a copy of the open clip and puts it back as one undoable
+/// step.
+fn edit_clip(
+    globals: &mut Globals,
+    f: impl FnOnce(&mut TrackClip) -> Result<(), String>,
+) -> Result<(), String> {
+    let (track_id, mut clip) = current_clip(globals)?;
+    f(&mut clip)?;
+    globals
+        .loaded_project
+        .perform_action(Action::ModifyClip { track_id, clip });
+    Ok(())
+}
+
+/// Where a copy of `clip` goes: the beat given, or straight after it.
+fn copy_start(clip: &TrackClip, args: &str) -> Result<Time, String> {
+    match args {
+        "" => Ok(clip.end()),
+        beat => parse_beat(beat),
+    }
+}
+
+fn find_bus(globals: &Globals, name: &str) -> Result<BusId, String> {
+    globals
+        .loaded_project
+        .buses
+        .values()
+        .find(|bus| bus.name == name)
+        .map(|bus| bus.uid)
+        .ok_or_else(|| format!("No bus called '{}'", name))
+}
+
+fn set_instrument(globals: &mut Globals, args: &str) -> Result<(), String> {
+    let track_id = current_track(globals)?;
+
+    let instrument = match args {
+        "" => return Err("Usage: instrument <synth|sampler|path|none>".to_string()),
+        "none" => None,
+        "synth" => Some(Instrument::synth()),
+        "sampler" => Some(Instrument::sampler(Kit::default())),
+        path => Some(Instrument::new(PluginDescription::from_path(
+            &PathBuf::from(path),
+            true,
+        )?)),
+    };
+
+    if globals.loaded_project.tracks[track_id].type_ != TrackType::Midi {
+        return Err("Only MIDI tracks have instruments".to_string());
+    }
+
+    // Fail here rather than when the engine picks it up, where the error
+    // can only be reported. Missing samples are reported then.
+    if let Some(instrument) = &instrument {
+        instrument.load(&globals.audio, &mut vec![])?;
+    }
+
+    globals
+        .loaded_project
+        .perform_action(Action::SetInstrument {
+            track_id,
+            instrument,
+        });
+    Ok(())
+}
+
+/// Reads `<port number|name|virtual [name]|off>` into where to send MIDI,
+/// keeping the other settings of `current`. Virtual ports are called
+/// `virtual_name` unless a name is given.
+fn parse_output_port(
+    args: &str,
+    current: Option<MidiOutputSettings>,
+    virtual_name: String,
+    command: &str,
+) -> Result<Option<MidiOutputSettings>, String> {
+    let port = match args.split_once(' ').unwrap_or((args, "")) {
+        ("", _) => {
+            let ports = midi_output::port_names()?
+                .iter()
+                .enumerate()
+                .map(|(i, name)| format!("{}: {}", i + 1, name))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            return Err(format!(
+                "Usage: {} <port number|name|virtual [name]|off> (ports: {})",
+                command, ports
+            ));
+        }
+        ("off", _) => return Ok(None),
+        ("virtual", name) => {
+            return Ok(Some(MidiOutputSettings {
+                port: match name.trim() {
+                    "" => virtual_name,
+                    name => name.to_string(),
+                },
+                virtual_port: true,
+                ..current.unwrap_or_default()
+            }))
+        }
+        _ => match args.parse::<usize>() {
+            Ok(position) => midi_output::port_names()?
+                .get(position.wrapping_sub(1))
+                .cloned()
+                .ok_or_else(|| format!("There's no MIDI output port {}", position))?,
+            Err(_) => args.to_string(),
+        },
+    };
+
+    Ok(Some(MidiOutputSettings {
+        port,
+        virtual_port: false,
+        ..current.unwrap_or_default()
+    }))
+}
+
+fn set_clock_output(globals: &mut Globals, output: Option<ClockOutput>) {
+    if let Some(engine) = &mut globals.engine {
+        engine.set_clock_output(output.clone());
+    }
+    globals.clock_output = output;
+}
+
+/// Changes the selected track's MIDI output as one undo step.
+fn edit_midi_output(
+    globals: &mut Globals,
+    f: impl FnOnce(&mut MidiOutputSettings),
+) -> Result<(), String> {
+    let track_id = current_track(globals)?;
+
+    let mut output = globals.loaded_project.tracks[track_id]
+        .midi_output
+        .clone()
+        .ok_or_else(|| "The selected track has no MIDI output".to_string())?;
+
+    f(&mut output);
+
+    globals.loaded_project.perform_action(Action::SetTrackMidiOutput {
+        track_id,
+        output: Some(output),
+    });
+    Ok(())
+}
+
+/// Changes the kit of the selected track's sampler as one undo step.
+fn edit_kit(
+    globals: &mut Globals,
+    f: impl FnOnce(&mut Kit) -> Result<(), String>,
+) -> Result<(), String> {
+    let track_id = current_track(globals)?;
+
+    let mut instrument = match globals.loaded_project.tracks[track_id].instrument() {
+        Some(instrument) if instrument.plugin.type_ == PluginType::Builtin(BuiltinPlugin::Sampler) => {
+            instrument.clone()
+        }
+        _ => return Err("The selected track isn't using the sampler".to_string()),
+    };
+
+    f(instrument.kit.get_or_insert_with(Kit::default))?;
+
+    globals.loaded_project.perform_action(Action::SetInstrument {
+        track_id,
+        instrument: Some(instrument),
+    });
+    Ok(())
+}
+
+/// Splits `pad-add` arguments into the path and the `key=value` options
+/// after it.
+fn split_pad_options(args: &str) -> (&str, Vec<&str>) {
+    let mut path = args.trim();
+    let mut options = vec![];
+
+    while let Some((rest, last)) = path.rsplit_once(' ') {
+        if !last.contains('=') {
+            break;
+        }
+        options.insert(0, last);
+        path = rest.trim_end();
+    }
+
+    (path, options)
+}
+
+/// Parses a 1 based pad position.
+fn pad_at(kit: &Kit, position: &str) -> Result<usize, String> {
+    let num_pads = kit.pads.len();
+
+    match position.trim().parse::<usize>() {
+        Ok(position) if position >= 1 && position <= num_pads => Ok(position - 1),
+        _ => Err(format!("Expected a pad position from 1 to {}", num_pads)),
+    }
+}
+
+fn set_pad_option(pad: &mut Pad, option: &str) -> Result<(), String> {
+    let (key, value) = option
+        .split_once('=')
+        .ok_or_else(|| format!("Expected key=value, got '{}'", option))?;
+
+    let number = |value: &str| -> Result<u32, String> {
+        value
+            .parse::<u32>()
+            .map_err(|_| format!("'{}' isn't a number", value))
+    };
+    let range = |value: &str| -> Result<(u32, u32), String> {
+        match value.split_once('-') {
+            Some((low, high)) => Ok((number(low)?, number(high)?)),
+            None => Ok((number(value)?, number(value)?)),
+        }
+    };
+    let float = |value: &str| -> Result<f32, String> {
+        value
+            .parse::<f32>()
+            .map_err(|_| format!("'{}' isn't a number", value))
+    };
+
+    match key {
+        "note" => {
+            let (low, high) = range(value)?;
+            if !(low..=high).contains(&pad.root_note) {
+                pad.root_note = low;
+            }
+            pad.low_note = low.min(127);
+            pad.high_note = high.min(127);
+        }
+        "root" => pad.root_note = number(value)?.min(127),
+        "vel" => {
+            let (low, high) = range(value)?;
+            pad.low_velocity = low.min(127);
+            pad.high_velocity = high.min(127);
+        }
+        "mode" => {
+            pad.one_shot = match value {
+                "oneshot" => true,
+                "gated" => false,
+                _ => return Err("mode is either oneshot or gated".to_string()),
+            }
+        }
+        "choke" => {
+            pad.choke_group = match value {
+                "none" => None,
+                value => Some(number(value)?),
+            }
+        }
+        "gain" => pad.gain_db = float(value)?.clamp(MIN_GAIN_DB, MAX_GAIN_DB),
+        "pan" => pad.pan = float(value)?.clamp(-1., 1.),
+        _ => return Err(format!("Unknown pad option '{}'", key)),
+    }
+
+    Ok(())
+}
+
+fn add_insert(globals: &mut Globals, args: &str) -> Result<(), String> {
+    let strip = current_strip(globals)?;
+    let num_inserts = globals.loaded_project.inserts(strip).len();
+
+    // Without a position it goes at the end.
+    let (path, index) = match args.rsplit_once(' ') {
+        Some((path, position)) => match position.parse::<usize>() {
+            Ok(position) if position >= 1 && position <= num_inserts + 1 => (path, position - 1),
+            Ok(_) => {
+                return Err(format!(
+                    "Expected an insert position from 1 to {}",
+                    num_inserts + 1
+                ))
+            }
+            Err(_) => (args, num_inserts),
+        },
+        None => (args, num_inserts),
+    };
+
+    if path.is_empty() {
+        return Err("Usage: insert-add <effect|path> [position]".to_string());
+    }
+
+    let plugin = match BuiltinPlugin::from_name(path) {
+        Some(builtin) if !builtin.is_instrument() => PluginDescription::builtin(builtin),
+        _ => PluginDescription::from_path(&PathBuf::from(path), false)?,
+    };
+
+    // See `set_instrument`.
+    plugin.load(&globals.audio)?;
+
+    globals.loaded_project.perform_action(Action::AddInsert {
+        strip,
+        index,
+        insert: Insert::new(plugin),
+    });
+    Ok(())
+}
+
+fn set_insert_param(globals: &mut Globals, args: &str) -> Result<(), String> {
+    let usage = || "Usage: insert-param <position> <param> <value|default>".to_string();
+
+    let (position, rest) = args.split_once(' ').ok_or_else(usage)?;
+    // Plugin parameter names can have spaces in them.
+    let (name, value) = rest.trim().rsplit_once(' ').ok_or_else(usage)?;
+    let (strip, index) = insert_at(globals, position)?;
+    let insert = &globals.loaded_project.inserts(strip)[index];
+    let param = find_param(globals, insert.id, &insert.plugin, name)?;
+    let value = param_value(&param, value)?;
+
+    globals.loaded_project.perform_action(Action::SetInsertParam {
+        strip,
+        index,
+        param: param.id,
+        value,
+    });
+    Ok(())
+}
+
+fn set_instrument_param(globals: &mut Globals, args: &str) -> Result<(), String> {
+    let usage = || "Usage: instrument-param <param> <value|default>".to_string();
+
+    let (name, value) = args.trim().rsplit_once(' ').ok_or_else(usage)?;
+    let track_id = current_track(globals)?;
+    let instrument = globals.loaded_project.tracks[track_id]
+        .instrument()
+        .ok_or_else(|| "The track has no instrument".to_string())?;
+    let param = find_param(globals, instrument.id, &instrument.plugin, name)?;
+    let value = param_value(&param, value)?;
+
+    globals.loaded_project.perform_action(Action::SetInstrumentParam {
+        track_id,
+        param: param.id,
+        value,
+    });
+    Ok(())
+}
+
+/// Parses a value for `param`, or "default" to go back to the plugin's.
+fn param_value(param: &ParamDescriptor, value: &str) -> Result<Option<f32>, String> {
+    match value {
+        "default" => Ok(None),
+        value => Ok(Some(
+            value
+                .parse::<f32>()
+                .map_err(|_| format!("'{}' isn't a number", value))?
+                .clamp(param.min, param.max),
+        )),
+    }
+}
+
+/// Looks up a parameter of a loaded instrument or insert by name or 1 based
+/// position.
+fn find_param(
+    globals: &Globals,
+    processor: InsertId,
+    plugin: &PluginDescription,
+    name: &str,
+) -> Result<ParamDescriptor, String> {
+    let params = globals
+        .engine
+        .as_ref()
+        .and_then(|engine| engine.params(processor))
+        .ok_or_else(|| format!("'{}' hasn't been loaded yet", plugin.name))?;
+
+    params
+        .iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name.trim()))
+        .or_else(|| {
+            let i = name.trim().parse::<usize>().ok()?;
+            params.get(i.checked_sub(1)?)
+        })
+        .cloned()
+        .ok_or_else(|| format!("'{}' has no parameter '{}'", plugin.name, name.trim()))
+}
+
+fn add_lane(globals: &mut Globals, args: &str) -> Result<(), String> {
+    let track_id = current_track(globals)?;
+
+    let target = match args.trim() {
+        "gain" => AutomationTarget::Gain,
+        "pan" => AutomationTarget::Pan,
+        // Tempo changes live in the tempo map, which can ramp between them.
+        "tempo" => return Err("Use tempo-set to change the tempo over time".to_string()),
+        args => {
+            let (position, name) = args.split_once(' ').ok_or_else(|| {
+                "Usage: lane-add <gain|pan|instrument <param>|<insert position> <param>>"
+                    .to_string()
+            })?;
+
+            if position == "instrument" {
+                let instrument = globals.loaded_project.tracks[track_id]
+                    .instrument()
+                    .ok_or_else(|| "The track has no instrument".to_string())?;
+                let param = find_param(globals, instrument.id, &instrument.plugin, name)?;
+
+                AutomationTarget::InstrumentParam { param: param.id }
+            } else {
+                let (strip, index) = insert_at(globals, position)?;
+                let insert = &globals.loaded_project.inserts(strip)[index];
+                let param = find_param(globals, insert.id, &insert.plugin, name)?;
+
+                AutomationTarget::Param {
+                    insert: insert.id,
+                    param: param.id,
+                }
+            }
+        }
+    };
+
+    let lanes = &globals.loaded_project.tracks[track_id].automation;
+    if lanes.iter().any(|lane| lane.target == target) {
+        return Err("The track already has a lane for that".to_string());
+    }
+
+    let index = lanes.len();
+    globals.loaded_project.perform_action(Action::AddAutomationLane {
+        track_id,
+        index,
+        lane: AutomationLane::new(target),
+    });
+    globals.automation_lane = index;
+    Ok(())
+}
+
+/// Parses a 1 based automation lane position on the selected track.
+fn lane_at(globals: &Globals, position: &str) -> Result<(TrackId, usize), String> {
+    let track_id = current_track(globals)?;
+    let num_lanes = globals.loaded_project.tracks[track_id].automation.len();
+
+    match position.trim().parse::<usize>() {
+        Ok(position) if position >= 1 && position <= num_lanes => Ok((track_id, position - 1)),
+        _ => Err(format!("Expected a lane from 1 to {}", num_lanes)),
+    }
+}
+
+/// Parses a 1 based breakpoint position in a lane.
+fn point_at(globals: &Globals, track_id: TrackId, lane: usize, position: &str) -> Result<usize, String> {
+    let num_points = globals.loaded_project.tracks[track_id].automation[lane].points.len();
+
+    match position.trim().parse::<usize>() {
+        Ok(position) if position >= 1 && position <= num_points => Ok(position - 1),
+        _ => Err(format!("Expected a point from 1 to {}", num_points)),
+    }
+}
+
+/// Parses `<beat> <value> [curve]`, clamping the value to the lane's range.
+/// `curve` is used when none is given.
+fn parse_breakpoint(
+    globals: &Globals,
+    track_id: TrackId,
+    lane: usize,
+    args: &[&str],
+    curve: Curve,
+) -> Result<Breakpoint, String> {
+    let time = parse_beat(args[0])?;
+    let value = args[1]
+        .parse::<f32>()
+        .map_err(|_| format!("'{}' isn't a number", args[1]))?;
+    let curve = match args.get(2) {
+        Some(name) => {
+            Curve::from_name(name).ok_or_else(|| format!("'{}' isn't linear, exp or step", name))?
+        }
+        None => curve,
+    };
+
+    let target = globals.loaded_project.tracks[track_id].automation[lane].target;
+    let (min, max) = lane_range(globals, track_id, target)
+        .ok_or_else(|| "The lane's insert hasn't been loaded yet".to_string())?;
+
+    Ok(Breakpoint {
+        time,
+        value: value.clamp(min, max),
+        curve,
+    })
+}
+
+fn parse_beat(beat: &str) -> Result<Time, String> {
+    beat.parse::<f64>()
+        .ok()
+        .filter(|t| *t >= 0.)
+        .ok_or_else(|| format!("'{}' isn't a beat", beat))
+}
+
+fn parse_length(beats: &str) -> Result<Time, String> {
+    beats
+        .parse::<f64>()
+        .ok()
+        .filter(|beats| beats.is_finite() && *beats > 0.)
+        .ok_or_else(|| format!("'{}' isn't a length in beats", beats))
+}
+
+/// A grid size in beats, written either as a note division like `1/16` or
+/// as beats.
+fn parse_grid(grid: &str) -> Result<Time, String> {
+    let beats = match grid.strip_prefix("1/") {
+        Some(division) => division.parse::<f64>().map(|division| 4. / division),
+        None => grid.parse::<f64>(),
+    };
+
+    beats
+        .ok()
+        .filter(|beats| beats.is_finite() && *beats > 0.)
+        .ok_or_else(|| format!("'{}' isn't a grid size", grid))
+}
+
+/// Applies `[on|off|<start> <end>]` to `region`, toggling it when there are
+/// no arguments. Setting the range turns the region on.
+fn parse_region(mut region: Region, args: &str, usage: &str) -> Result<Region, String> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+
+    match args.as_slice() {
+        [] => region.enabled = !region.enabled,
+        ["on"] => region.enabled = true,
+        ["off"] => region.enabled = false,
+        [start, end] => {
+            let (start, end) = (parse_beat(start)?, parse_beat(end)?);
+            if end <= start {
+                return Err("The end has to come after the start".to_string());
+            }
+
+            region = Region {
+                start,
+                end,
+                enabled: true,
+            };
+        }
+        _ => return Err(usage.to_string()),
+    }
+
+    Ok(region)
+}
+
+/// Parses a 1 based position in a tempo or meter map, which can't be the
+/// first change.
+fn change_at(position: &str, num_changes: usize) -> Result<usize, String> {
+    match position.trim().parse::<usize>() {
+        Ok(position) if position >= 2 && position <= num_changes => Ok(position - 1),
+        _ if num_changes < 2 => Err("There are no changes after the first".to_string()),
+        _ => Err(format!("Expected a change from 2 to {}", num_changes)),
+    }
+}
+
+fn parse_time_signature(text: &str) -> Result<TimeSignature, String> {
+    let invalid = || format!("'{}' isn't a time signature like 3/4", text);
+
+    let (numerator, denominator) = text.split_once('/').ok_or_else(invalid)?;
+    let numerator = numerator.parse::<u32>().map_err(|_| invalid())?;
+    let denominator = denominator.parse::<u32>().map_err(|_| invalid())?;
+
+    if numerator == 0 || numerator > 64 || !denominator.is_power_of_two() || denominator > 64 {
+        return Err(invalid());
+    }
+
+    Ok(TimeSignature::new(numerator, denominator))
+}
+
+/// Parses a 1 based insert position on the selected strip.
+fn insert_at(globals: &Globals, position: &str) -> Result<(StripId, usize), String> {
+    let strip = current_strip(globals)?;
+    let num_inserts = globals.loaded_project.inserts(strip).len();
+
+    match position.trim().parse::<usize>() {
+        Ok(position) if position >= 1 && position <= num_inserts => Ok((strip, position - 1)),
+        _ => Err(format!("Expected an insert position from 1 to {}", num_inserts)),
+    }
+}
+
+/// Parses a 1 based clip position on the selected track.
+fn clip_at(globals: &Globals, position: &str) -> Result<(TrackId, ClipId), String> {
+    let track_id = current_track(globals)?;
+    let clips = globals.loaded_project.tracks[track_id].clips();
+
+    match position.trim().parse::<usize>() {
+        Ok(position) if position >= 1 && position <= clips.len() => {
+            Ok((track_id, clips[position - 1].uid))
+        }
+        _ if clips.is_empty() => Err("The track has no clips".to_string()),
+        _ => Err(format!("Expected a clip from 1 to {}", clips.len())),
+    }
+}
+
+/// Parses a 1 based send position on the selected strip.
+fn send_at(globals: &Globals, position: &str) -> Result<(StripId, usize), String> {
+    let strip = current_strip(globals)?;
+    let num_sends = globals.loaded_project.sends(strip).len();
+
+    match position.trim().parse::<usize>() {
+        Ok(position) if position >= 1 && position <= num_sends => Ok((strip, position - 1)),
+        _ => Err(format!("Expected a send position from 1 to {}", num_sends)),
+    }
+}
+
+fn add_send(globals: &mut Globals, args: &str) -> Result<(), String> {
+    let source = current_strip(globals)?;
+    let mut args = args.split_whitespace();
+
+    let target = find_bus(
+        globals,
+        args.next()
+            .ok_or_else(|| "Usage: send-add <bus> [level dB] [pre]".to_string())?,
+    )?;
+
+    let mut send = Send {
+        target,
+        level_db: 0.,
+        pre_fader: false,
+    };
+
+    for arg in args {
+        if arg == "pre" {
+            send.pre_fader = true;
+        } else {
+            let level_db = arg
+                .parse::<f32>()
+                .map_err(|_| format!("'{}' isn't a level in dB", arg))?;
+            send.level_db = level_db.clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+        }
+    }
+
+    let project = &globals.loaded_project;
+
+    if source == StripId::Bus(target) || would_create_cycle(&project.buses, source, target) {
+        return Err("That send would feed the bus back into itself".to_string());
+    }
+
+    let index = project.sends(source).len();
+    globals
+        .loaded_project
+        .perform_action(Action::AddSend { source, index, send });
+    Ok(())
+}
+
+/// Removes a bus along with every send to it as one undo step.
+fn remove_bus(globals: &mut Globals, name: &str) -> Result<(), String> {
+    let bus_id = find_bus(globals, name)?;
+    let project = &globals.loaded_project;
+
+    let sources = project
+        .tracks
+        .tracks
+        .keys()
+        .map(|id| StripId::Track(*id))
+        .chain(project.buses.keys().map(|id| StripId::Bus(*id)));
+
+    let mut actions = vec![];
+
+    for source in sources {
+        // Remove from the back so earlier indices stay valid.
+        for (index, send) in project.sends(source).iter().enumerate().rev() {
+            if send.target == bus_id {
+                actions.push(Action::RemoveSend { source, index });
+            }
+        }
+    }
+
+    actions.push(Action::RemoveBus(bus_id));
+    globals.loaded_project.perform_action(Action::Group(actions));
+    Ok(())
+}
+
+fn render(globals: &mut Globals, args: &str) -> Result<(), String> {
+    let mut args = args.split_whitespace();
+    let path = args
+        .next()
+        .ok_or_else(|| "Usage: render <path> [16|24|32f] [click]".to_string())?;
+
+    let mut options = RenderOptions::default();
+    for arg in args {
+        match arg {
+            "click" => options.metronome = true,
+            format => {
+                options.format = WavFormat::from_str(format)
+                    .ok_or_else(|| format!("Unknown bit depth '{}'", format))?
+            }
+        }
+    }
+
+    let mut path = PathBuf::from(path);
+    if path.extension().is_none() {
+        path.set_extension("wav");
+    }
+
+    render_project(&globals.loaded_project, &Audio::default(), &path, &options)
+}
+
+fn export_midi(globals: &mut Globals, path: &str, format: SmfFormat) -> Result<(), String> {
+    if path.is_empty() {
+        return Err("Usage: export-midi <path>".to_string());
+    }
+
+    let mut path = PathBuf::from(path);
+    if path.extension().is_none() {
+        path.set_extension("mid");
+    }
+
+    smf::export(&globals.loaded_project, &path, format)
+}
+
+/// Saves to the project's current path, or asks for one if it has never been
+/// saved.
+pub fn save_project(globals: &mut Globals) {
+    let path = match &globals.loaded_project.path {
+        Some(path) => path.clone(),
+        None => {
+            open_command_palette(globals, "save-as ");
+            return;
+        }
+    };
+
+    if let Err(e) = globals.loaded_project.save(&path) {
+        globals.status <<= format!("Failed to save project: {}", e);
+    }
+}
+
+fn save_project_as(globals: &mut Globals, path: &str) -> Result<(), String> {
+    if path.is_empty() {
+        return Err("Usage: save-as <path>".to_string());
+    }
+
+    let mut path = PathBuf::from(path);
+    if path.extension().is_none() {
+        path.set_extension(PROJECT_FILE_EXTENSION);
+    }
+
+    globals.loaded_project.save(&path)
+}
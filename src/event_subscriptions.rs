@@ -1,10 +1,10 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, time::Instant};
 
 use sdl2::{mouse::MouseButton, sys::KeyCode, event::Event};
 
 use crate::{
     global::Globals,
-    ui::{bounding_box_ref_contains, p, p_c, BoundingBoxRef, ComputedDimensions, ComputedPosition},
+    ui::{bounding_box_ref_contains, p_c, BoundingBoxRef, ComputedPosition},
 };
 
 pub struct Subscriptions {
@@ -25,6 +25,12 @@ struct Subscription<T> {
     callback: SubscriptionCallback<T>,
 }
 
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Subscriptions {
     pub fn new() -> Self {
         Self {
@@ -127,8 +133,25 @@ pub struct Key {
 #[derive(Debug)]
 pub struct MidiInputEvent {
     pub channel: u8,
+    /// Milliseconds, from the input port's own clock.
     pub stamp: u32,
     pub message: [u8; 3],
+    pub received: Instant,
+}
+
+/// Passes a message from `MidiInputs` to everything subscribed to MIDI
+/// input.
+pub fn handle_midi_input(globals: &mut Globals, event: &MidiInputEvent) {
+    let callbacks_to_make: Vec<SubscriptionCallback<MidiInputEvent>> = globals
+        .subscriptions
+        .midi_input
+        .iter()
+        .map(|subscription| subscription.callback.clone())
+        .collect();
+
+    for callbacks in callbacks_to_make {
+        callbacks.borrow()(event, globals);
+    }
 }
 
 fn handle_mouse_button_down(globals: &mut Globals, event: &sdl2::event::Event) {
@@ -154,7 +177,7 @@ fn handle_mouse_button_down(globals: &mut Globals, event: &sdl2::event::Event) {
     }
 
     for callbacks in callbacks_to_make {
-        callbacks.borrow()(&button, globals);
+        callbacks.borrow()(button, globals);
     }
 }
 
@@ -185,8 +208,6 @@ fn handle_key_down(globals: &mut Globals, event: &sdl2::event::Event) {
     }
 
     if let Some(code) = code {
-        let code = code;
-
         for callbacks in callbacks_to_make {
             callbacks.borrow()(
                 &Key {
@@ -223,7 +244,7 @@ fn handle_text_input(globals: &mut Globals, event: &sdl2::event::Event) {
 
 fn handle_wheel(globals: &mut Globals, event: &Event) {
     let dir = match event {
-        Event::MouseWheel { precise_x, precise_y, .. } => (*precise_x as f32, *precise_y as f32),
+        Event::MouseWheel { precise_x, precise_y, .. } => (*precise_x, *precise_y),
         _ => return,
     };
 
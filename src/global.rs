@@ -1,14 +1,23 @@
 use std::collections::HashMap;
-use std::default;
 use std::rc::Rc;
 
 use glow::*;
 
+use crate::audio::realtime::EngineController;
+use crate::audio::Audio;
+use crate::clip::ClipId;
+use crate::commands::Commands;
 use crate::event_subscriptions::Subscriptions;
+use crate::midi::Time;
+use crate::midi_input::MidiInputs;
+use crate::midi_sync::{ClockFollower, ClockOutput};
 use crate::project::Project;
-use crate::selection::Selection;
+use crate::recording::Take;
 use crate::shortcuts::ShortcutsBuffer;
-use crate::ui::{gl::*, ComputedPosition};
+use crate::track::TrackId;
+use crate::ui::arrangement::ArrangementView;
+use crate::ui::automation::AutomationView;
+use crate::ui::ComputedPosition;
 use crate::ui::reactive::Reactive;
 use crate::ui::style::*;
 use crate::ui::text::Font;
@@ -17,10 +26,46 @@ use crate::ui::ComputedDimensions;
 pub struct Globals {
     pub loaded_project: Project,
     pub playing_state: PlayingState,
+    pub audio: Audio,
+    /// `None` when running without an audio device.
+    pub engine: Option<EngineController>,
+    pub midi_inputs: MidiInputs,
+    /// The track MIDI input is recorded into and monitored through.
+    pub armed_track: Option<TrackId>,
+    /// Whether MIDI input is heard through the armed track's instrument.
+    pub monitoring: bool,
+    /// Grid recorded notes are snapped to, in beats.
+    pub input_quantize: Option<Time>,
+    /// Notes recorded so far while recording.
+    pub take: Option<Take>,
+    /// Set while sending MIDI clock.
+    pub clock_output: Option<ClockOutput>,
+    /// Set while following MIDI clock from the input port.
+    pub clock_follower: Option<ClockFollower>,
+    /// The clip the piano roll shows.
+    pub editing_clip: Option<(TrackId, ClipId)>,
     pub viewport: Viewport,
     pub shortcuts_buffer: ShortcutsBuffer,
     pub editor_context: Reactive<EditingContext>,
     pub subscriptions: Subscriptions,
+    pub commands: Commands,
+    pub command_palette_input: Reactive<String>,
+    /// The last error or notice, shown in the top bar.
+    pub status: Reactive<String>,
+    pub mixer_lines: Reactive<Vec<String>>,
+    pub mixer_selected_track: usize,
+    pub automation_view: Reactive<AutomationView>,
+    /// Which of the selected track's lanes the lane view shows.
+    pub automation_lane: usize,
+    pub arrangement_view: Reactive<ArrangementView>,
+    /// Selected track and clip in the arrangement, as indices.
+    pub arrangement_track: usize,
+    pub arrangement_clip: usize,
+    /// Beat at the left edge of the arrangement.
+    pub arrangement_scroll: Time,
+    /// Set while the events of the frame that opened the arrangement are
+    /// handled, so the key that opened it isn't also taken by it.
+    pub arrangement_just_opened: bool,
     pub element_uniform_locations: HashMap<&'static str, UniformLocation>,
     pub texture_uniform_locations: HashMap<&'static str, UniformLocation>,
     pub colour_palette: ColourPalette,
@@ -71,6 +116,15 @@ impl Globals {
 
         Globals {
             playing_state: PlayingState::default(),
+            audio: Audio::default(),
+            engine: None,
+            midi_inputs: MidiInputs::new(),
+            armed_track: Some(0),
+            monitoring: true,
+            input_quantize: None,
+            take: None,
+            clock_output: None,
+            clock_follower: None,
             element_uniform_locations,
             texture_uniform_locations,
             shortcuts_buffer: ShortcutsBuffer::new(),
@@ -84,24 +138,48 @@ impl Globals {
             piano_roll_keyboard_width: 150.,
             loaded_project: Project::new(),
             subscriptions: Subscriptions::new(),
+            commands: Commands::new(),
+            command_palette_input: Reactive::new(String::new()),
+            status: Reactive::new(String::new()),
+            mixer_lines: Reactive::new(vec![]),
+            mixer_selected_track: 0,
+            automation_view: Reactive::new(AutomationView::default()),
+            automation_lane: 0,
+            arrangement_view: Reactive::new(ArrangementView::default()),
+            arrangement_track: 0,
+            arrangement_clip: 0,
+            arrangement_scroll: 0.,
+            arrangement_just_opened: false,
+            editing_clip: None,
             viewport: Viewport::default(),
             mouse_pos: ComputedPosition::origin(),
         }
     }
 }
 
-#[derive(Default)]
 pub struct Viewport {
     pub time_scroll: Reactive<f32>,
     pub h_zoom: Reactive<f32>,
     pub v_zoom: Reactive<f32>,
 }
 
+impl Default for Viewport {
+    fn default() -> Self {
+        Self {
+            time_scroll: Reactive::new(0.),
+            h_zoom: Reactive::new(35.),
+            v_zoom: Reactive::new(12.),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum EditingContext {
     PianoRoll,
     InputField(usize),
-    CommandPallet
+    CommandPallet,
+    Mixer,
+    Arrangement,
 }
 
 #[derive(Default, PartialEq, Eq, Clone, Debug)]
@@ -133,10 +211,12 @@ impl PlayingState {
 
 impl EditingContext {
     pub fn grabs_keyboard(&self) -> bool {
-        match self {
-            EditingContext::InputField(_) => true,
-            EditingContext::CommandPallet => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            EditingContext::InputField(_)
+                | EditingContext::CommandPallet
+                | EditingContext::Mixer
+                | EditingContext::Arrangement
+        )
     }
 }
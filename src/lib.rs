@@ -0,0 +1,34 @@
+//! Everything but the app's window and main loop, so benchmarks can use it
+//! too.
+
+#![allow(
+    clippy::missing_safety_doc,
+    clippy::new_ret_no_self,
+    clippy::should_implement_trait,
+    clippy::too_many_arguments,
+    clippy::type_complexity
+)]
+
+pub mod audio;
+pub mod automation;
+pub mod clip;
+pub mod commands;
+pub mod event_subscriptions;
+pub mod global;
+pub mod midi;
+pub mod midi_input;
+pub mod midi_output;
+pub mod midi_sync;
+pub mod mixer;
+pub mod project;
+pub mod project_file;
+pub mod recording;
+pub mod routing;
+pub mod selection;
+pub mod shortcuts;
+pub mod smf;
+pub mod tempo_map;
+pub mod track;
+pub mod ui;
+pub mod utils;
+// mod script;
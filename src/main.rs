@@ -1,41 +1,73 @@
 use std::{cell::RefCell, path::PathBuf, rc::Rc};
 
+use daw::{
+    audio, clip,
+    commands::universal_commands,
+    event_subscriptions::{handle_event_subscriptions, handle_midi_input},
+    global, midi_input, midi_sync, recording,
+    shortcuts,
+    track, ui,
+};
+
 use audio::{
-    audio_processor::{PluginDescription, PluginType},
+    engine::Engine,
+    device::DeviceKind,
+    realtime::EngineController,
     Audio,
 };
+use clip::ClipId;
 use element_creation_queue::fulfil_queue;
-use global::{EditingContext, Globals, PlayingState};
+use global::{EditingContext, Globals};
 use glow::*;
-use midi::*;
-use midir::{Ignore, MidiInput};
+use midi_input::MidiInputs;
+use midi_sync::follow_midi_clock;
+use recording::{armed_track, record_midi_input, update_recording};
 use sdl2::sys::{SDL_GetPerformanceCounter, SDL_GetPerformanceFrequency};
-use shortcuts::{k, universal_shortcuts};
+use shortcuts::universal_shortcuts;
 use top_bar::fb_topbar;
+use track::TrackId;
 use ui::{
+    arrangement::{fb_arrangement, refresh_arrangement},
+    automation::{fb_automation, refresh_automation_view, LANE_HEIGHT},
     command_palette::fb_command_palette,
+    mixer::fb_mixer,
     frame_buf::FrameBuf,
     gl::RENDER_MODE_SOLID,
-    style::{Colour, Style},
+    style::Colour,
     text::{Font, Text},
+    time_header::{fb_time_header, HEADER_HEIGHT},
     *,
 };
 
-use crate::{event_subscriptions::handle_event_subscriptions, shortcuts::key_from_symbol, audio::device::SDLAudioDevice};
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
 
-mod audio;
-mod event_subscriptions;
-mod global;
-mod midi;
-mod project;
-mod selection;
-mod shortcuts;
-mod track;
-mod ui;
-mod utils;
-// mod script;
+    if args.get(1).map(|a| a == "--render").unwrap_or(false) {
+        if let Err(e) = audio::render::render_from_args(&args[2..]) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut device_kind = DeviceKind::Sdl;
+    let mut project_path = None;
+
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        if arg == "--device" {
+            match rest.next().and_then(|kind| DeviceKind::from_str(kind)) {
+                Some(kind) => device_kind = kind,
+                None => {
+                    println!("Usage: daw [project] [--device sdl|null|wav:<path>]");
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            project_path = Some(arg.clone());
+        }
+    }
 
-fn main() {
     unsafe {
         let (gl, window, mut events_loop, _context, sdl) = gl::create_sdl2_context();
 
@@ -43,58 +75,6 @@ fn main() {
         let mut audio = Audio::default();
         // let mut instance = audio::load_vst2_plugin(&audio);
 
-        let plugin_desc = PluginDescription {
-            name: "".to_string(),
-            path: PathBuf::from("/home/jasper/dev/daw/ott/vital.so"),
-            type_: PluginType::Vst2,
-            instrument: true,
-        };
-
-        let mut plugin = plugin_desc.load(&audio);
-        plugin.show_gui(window_id as *mut std::ffi::c_void);
-
-        let events = vec![
-            MidiEvent {
-                time: 0.,
-                data: MidiEventData::NoteOn {
-                    note: NoteEvent {
-                        note: 60,
-                        velocity: 127,
-                    },
-                },
-            },
-            MidiEvent {
-                time: 0.,
-                data: MidiEventData::NoteOn {
-                    note: NoteEvent {
-                        note: 65,
-                        velocity: 127,
-                    },
-                },
-            },
-            MidiEvent {
-                time: 0.,
-                data: MidiEventData::NoteOn {
-                    note: NoteEvent {
-                        note: 69,
-                        velocity: 127,
-                    },
-                },
-            },
-            MidiEvent {
-                time: 0.,
-                data: MidiEventData::NoteOn {
-                    note: NoteEvent {
-                        note: 72,
-                        velocity: 127,
-                    },
-                },
-            },
-        ];
-
-
-        audio.device = Some(Box::new(SDLAudioDevice::new(&sdl, &audio)));
-
         let element_shader =
             gl::create_program(&gl, MAIN_VERTEX_SHADER_SOURCE, MAIN_FRAGMENT_SHADER_SOURCE);
         let texture_shader = gl::create_program(
@@ -136,33 +116,69 @@ fn main() {
         let mut globals = Globals::create(&gl, element_shader, texture_shader, screen_dims, font);
 
         universal_shortcuts(&mut globals);
+        universal_commands(&mut globals);
+        record_midi_input(&mut globals);
+        follow_midi_clock(&mut globals);
+
+        if let Some(path) = &project_path {
+            if let Err(e) = globals.loaded_project.load(&PathBuf::from(&path)) {
+                println!("Failed to open project '{}': {}", path, e);
+            }
+        }
 
-        let mut midi_in = MidiInput::new("midir reading input").unwrap();
-        midi_in.ignore(Ignore::None);
-        for port in midi_in.ports() {
-            println!("MIDI port: {}", midi_in.port_name(&port).unwrap());
+        let mut load_errors = vec![];
+        let mut engine = Engine::from_project(&globals.loaded_project, &audio, &mut load_errors);
+        globals.status <<= load_errors.join("; ");
+        for track in engine.tracks.iter_mut() {
+            if let Some(instrument) = &mut track.instrument {
+                let _ = instrument.processor.show_gui(window_id as *mut std::ffi::c_void);
+            }
         }
 
+        let engine_controller = EngineController::new(engine, &globals.loaded_project);
+
+        audio.sdl_audio = match sdl.audio() {
+            Ok(audio_subsystem) => Some(audio_subsystem),
+            Err(e) => {
+                println!("SDL audio is unavailable: {}", e);
+                None
+            }
+        };
+
+        if let Err(e) = audio.set_device(&device_kind, engine_controller.realtime()) {
+            println!("Failed to open audio device: {}", e);
+            if let Err(e) = audio.set_device(&DeviceKind::Null, engine_controller.realtime()) {
+                println!("Failed to open null audio device: {}", e);
+            }
+        }
+
+        globals.audio = audio;
+        globals.engine = Some(engine_controller);
+
+        globals
+            .midi_inputs
+            .set_monitor(globals.engine.as_ref().map(|e| e.live_input()));
+        for port in MidiInputs::port_names().unwrap_or_default() {
+            println!("MIDI port: {}", port);
+        }
+
+        // The piano roll sits between the time header and the lane view.
         let mut frame = FrameBuf::new(
             &gl,
             None,
-            p(0., 0.),
+            p(0., LANE_HEIGHT),
             Dimensions {
                 width: Size::FractionOfParent(1.),
-                height: Size::FractionOfParentWithOffset(1., -globals.top_bar_size),
+                height: Size::FractionOfParentWithOffset(
+                    1.,
+                    -globals.top_bar_size - HEADER_HEIGHT - LANE_HEIGHT,
+                ),
             },
             screen_dims,
         );
 
-        let root = piano_roll::e_piano_roll(
-            &gl,
-            &mut globals,
-            0,
-            frame.children_need_rerender.clone(),
-            frame.bounding_box.clone(),
-        );
-
-        frame.root_node = Some(root);
+        let mut shown_clip = None;
+        piano_roll::refresh_piano_roll(&gl, &mut globals, &mut frame, &mut shown_clip);
 
         gl.uniform_1_i32(
             Some(&globals.element_uniform_locations["mode"]),
@@ -172,22 +188,22 @@ fn main() {
         gl.clear_color(0.1, 0.2, 0.3, 1.0);
 
         let mut top_bar = fb_topbar(&gl, &mut globals, &screen_dims);
+        let mut arrangement = fb_arrangement(&gl, &mut globals, &screen_dims);
         let mut command_palette = fb_command_palette(&gl, &mut globals, &screen_dims);
-
-        let mut style = Style::default();
-        style.background_colour.r = 1.;
+        let mut mixer = fb_mixer(&gl, &mut globals, &screen_dims);
+        let mut automation = fb_automation(&gl, &mut globals, &screen_dims);
+        let mut time_header = fb_time_header(&gl, &mut globals, &screen_dims);
 
         let mut resize = true;
 
         let mut running = true;
 
-        let mut NOW = SDL_GetPerformanceCounter();
-        let mut LAST = 0;
+        let mut now = SDL_GetPerformanceCounter();
 
         while running {
-            LAST = NOW;
-            NOW = SDL_GetPerformanceCounter();
-            let delta_t = (NOW - LAST) as f64 / SDL_GetPerformanceFrequency() as f64;
+            let last = now;
+            now = SDL_GetPerformanceCounter();
+            let delta_t = (now - last) as f64 / SDL_GetPerformanceFrequency() as f64;
 
             main_loop(
                 &mut events_loop,
@@ -195,8 +211,13 @@ fn main() {
                 &mut resize,
                 &gl,
                 &mut frame,
+                &mut shown_clip,
                 &mut top_bar,
                 &mut command_palette,
+                &mut mixer,
+                &mut automation,
+                &mut time_header,
+                &mut arrangement,
                 &window,
                 &mut text,
                 &mut running,
@@ -207,6 +228,9 @@ fn main() {
         // root.cleanup(&gl);
         frame.cleanup(&gl);
         top_bar.cleanup(&gl);
+        automation.cleanup(&gl);
+        time_header.cleanup(&gl);
+        arrangement.cleanup(&gl);
 
         gl.delete_program(element_shader);
     }
@@ -228,14 +252,20 @@ fn get_window_id(window: &sdl2::video::Window) -> u64 {
     unsafe { wm_info.info.x11.window }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn main_loop(
     events_loop: &mut sdl2::EventPump,
     globals: &mut Globals,
     resize: &mut bool,
     gl: &Context,
     frame: &mut FrameBuf,
+    shown_clip: &mut Option<(TrackId, ClipId)>,
     top_bar: &mut FrameBuf,
     command_palette: &mut FrameBuf,
+    mixer: &mut FrameBuf,
+    automation: &mut FrameBuf,
+    time_header: &mut FrameBuf,
+    arrangement: &mut FrameBuf,
     window: &sdl2::video::Window,
     text: &mut Text,
     running: &mut bool,
@@ -252,30 +282,65 @@ fn main_loop(
         handle_event_subscriptions(globals, &event);
 
         if let sdl2::event::Event::Window {
-            timestamp,
-            window_id,
-            win_event,
+            win_event: sdl2::event::WindowEvent::Resized(_, _),
+            ..
         } = event
         {
-            if let sdl2::event::WindowEvent::Resized(_, _) = win_event {
-                *resize = true;
-            }
+            *resize = true;
         }
     }
+    globals.arrangement_just_opened = false;
 
     fulfil_queue(gl, globals);
 
-    if globals.playing_state.is_playing() {
-        // println!("{}", globals.loaded_project.player_time.get_copy());
-        let delta_beats: Time =
-            delta_t as Time * (globals.loaded_project.tempo.get_copy() as Time / 60.);
-        globals.loaded_project.player_time += delta_beats;
+    update_recording(globals);
+    for event in globals.midi_inputs.poll() {
+        handle_midi_input(globals, &event);
+    }
+
+    let monitored_track = armed_track(globals).filter(|_| globals.monitoring);
+    if let Some(engine) = &mut globals.engine {
+        engine.sync(
+            &mut globals.loaded_project,
+            &globals.playing_state,
+            monitored_track,
+            &globals.audio,
+        );
+
+        let mut messages = engine.take_messages();
+        messages.extend(globals.audio.take_messages());
+        if !messages.is_empty() {
+            globals.status <<= messages.join("; ");
+        }
+    } else if globals.playing_state.is_playing() {
+        let tempo_map = globals.loaded_project.tempo_map.get_copy();
+        let player_time = globals.loaded_project.player_time.get_copy();
+        let seconds = tempo_map.beats_to_seconds(player_time);
+        let mut next = tempo_map.seconds_to_beats(seconds + delta_t);
+
+        if let Some((start, end)) = globals.loaded_project.loop_region.get_copy().active() {
+            if player_time < end && next >= end {
+                next = start + (next - end).min(end - start);
+            }
+        }
+
+        globals.loaded_project.player_time <<= next;
+    }
+
+    refresh_automation_view(globals);
+    piano_roll::refresh_piano_roll(gl, globals, frame, shown_clip);
+
+    if globals.editor_context.get_copy() == EditingContext::Arrangement {
+        refresh_arrangement(globals);
     }
 
     if *resize {
         *resize = false;
         frame.children_need_rerender.replace(true);
         top_bar.children_need_rerender.replace(true);
+        automation.children_need_rerender.replace(true);
+        time_header.children_need_rerender.replace(true);
+        arrangement.children_need_rerender.replace(true);
     }
 
     let (width, height) = window.drawable_size();
@@ -303,10 +368,22 @@ fn main_loop(
 
     top_bar.render(gl, ComputedPosition::origin(), &*globals, &screen_dims);
 
+    automation.render(gl, ComputedPosition::origin(), &*globals, &screen_dims);
+
+    time_header.render(gl, ComputedPosition::origin(), &*globals, &screen_dims);
+
     if globals.editor_context.get_copy() == EditingContext::CommandPallet {
         command_palette.render(gl, ComputedPosition::origin(), &*globals, &screen_dims);
     }
 
+    if globals.editor_context.get_copy() == EditingContext::Mixer {
+        mixer.render(gl, ComputedPosition::origin(), &*globals, &screen_dims);
+    }
+
+    if globals.editor_context.get_copy() == EditingContext::Arrangement {
+        arrangement.render(gl, ComputedPosition::origin(), &*globals, &screen_dims);
+    }
+
     text.render(gl, ComputedPosition::origin(), &*globals, &screen_dims);
 
     window.gl_swap_window();
@@ -1,13 +1,20 @@
-use std::{ops::{Index, IndexMut}, collections::HashMap};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::{ui::{
-    reactive::Reactive,
+use serde::{Deserialize, Serialize};
+
+use crate::ui::{
+    reactive::{Reactive, ReactiveSubscriptionId},
     reactive_list::{ReactiveList, ReactiveListKey},
-}, audio::{SampleRate, BlockSize}};
+};
 
 pub type Time = f64;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Note {
     pub note: u32,
     pub velocity: u32,
@@ -15,26 +22,313 @@ pub struct Note {
     pub length: Time,
 }
 
+/// What a controller lane drives.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Controller {
+    ControlChange(u32),
+    /// -8192 to 8191, 0 is centred.
+    PitchBend,
+    ChannelAftertouch,
+    /// Pressure on one note.
+    PolyAftertouch(u32),
+    Program,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ControllerPoint {
+    pub time: Time,
+    pub value: i32,
+}
+
+/// Values a controller jumps to over time on one channel.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ControllerLane {
+    pub controller: Controller,
+    #[serde(default)]
+    pub channel: u8,
+    /// Sorted by time.
+    pub points: Vec<ControllerPoint>,
+}
+
+impl ControllerLane {
+    pub fn new(controller: Controller, channel: u8) -> Self {
+        Self {
+            controller,
+            channel,
+            points: vec![],
+        }
+    }
+
+    /// Adds `point`, replacing any point already at its time.
+    pub fn insert(&mut self, point: ControllerPoint) {
+        let i = self.points.partition_point(|p| p.time < point.time);
+        match self.points.get_mut(i) {
+            Some(existing) if existing.time == point.time => *existing = point,
+            _ => self.points.insert(i, point),
+        }
+    }
+
+    pub fn to_midi_events(&self) -> Vec<MidiEvent> {
+        self.points
+            .iter()
+            .map(|point| MidiEvent {
+                time: point.time,
+                delta_frames: 0,
+                channel: self.channel,
+                data: self.controller.event_data(point.value),
+            })
+            .collect()
+    }
+}
+
+impl Controller {
+    /// The controller an event moves and the value it moves it to.
+    pub fn of(data: &MidiEventData) -> Option<(Self, i32)> {
+        match *data {
+            MidiEventData::ControlChange { controller, value } => {
+                Some((Controller::ControlChange(controller), value as i32))
+            }
+            MidiEventData::PitchBend { value } => Some((Controller::PitchBend, value)),
+            MidiEventData::ChannelAftertouch { pressure } => {
+                Some((Controller::ChannelAftertouch, pressure as i32))
+            }
+            MidiEventData::PolyAftertouch { note, pressure } => {
+                Some((Controller::PolyAftertouch(note), pressure as i32))
+            }
+            MidiEventData::ProgramChange { program } => Some((Controller::Program, program as i32)),
+            MidiEventData::NoteOn { .. } | MidiEventData::NoteOff { .. } => None,
+        }
+    }
+
+    pub fn event_data(&self, value: i32) -> MidiEventData {
+        let value_7bit = value.clamp(0, 127) as u32;
+        match *self {
+            Controller::ControlChange(controller) => MidiEventData::ControlChange {
+                controller,
+                value: value_7bit,
+            },
+            Controller::PitchBend => MidiEventData::PitchBend {
+                value: value.clamp(-8192, 8191),
+            },
+            Controller::ChannelAftertouch => MidiEventData::ChannelAftertouch {
+                pressure: value_7bit,
+            },
+            Controller::PolyAftertouch(note) => MidiEventData::PolyAftertouch {
+                note,
+                pressure: value_7bit,
+            },
+            Controller::Program => MidiEventData::ProgramChange {
+                program: value_7bit,
+            },
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct MidiClip {
     pub notes: ReactiveList<Reactive<Note>>,
+    /// Shared between clones, like `notes`.
+    events: Rc<RefCell<ClipEvents>>,
+}
+
+static REVISION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn next_revision() -> u64 {
+    REVISION_COUNTER.fetch_add(1, Ordering::SeqCst)
+}
+
+/// A clip's events, kept up to date as its notes are added, changed and
+/// removed.
+struct ClipEvents {
+    events: MidiEventsBlockList,
+    /// Changed along with `events`.
+    revision: u64,
+    notes: HashMap<ReactiveListKey, NoteEvents>,
+    controllers: Vec<ControllerLane>,
+    controller_events: Vec<EventId>,
+}
+
+struct NoteEvents {
+    on: EventId,
+    off: EventId,
+    note: Reactive<Note>,
+    /// Watches the note for changes.
+    subscription: ReactiveSubscriptionId,
+}
+
+impl Default for MidiClip {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MidiClip {
     pub fn new() -> Self {
-        MidiClip {
-            notes: ReactiveList::new(),
-        }
+        let notes: ReactiveList<Reactive<Note>> = ReactiveList::new();
+        let events = Rc::new(RefCell::new(ClipEvents {
+            events: MidiEventsBlockList::new(),
+            revision: next_revision(),
+            notes: HashMap::new(),
+            controllers: vec![],
+            controller_events: vec![],
+        }));
+
+        let weak = Rc::downgrade(&events);
+        notes.subscribe_to_push(Box::new(move |key, note| {
+            if let Some(events) = weak.upgrade() {
+                ClipEvents::watch_note(&events, *key, note);
+            }
+        }));
+
+        let weak = Rc::downgrade(&events);
+        notes.subscribe_to_remove(Box::new(move |key, _| {
+            if let Some(events) = weak.upgrade() {
+                events.borrow_mut().remove_note(*key);
+            }
+        }));
+
+        MidiClip { notes, events }
     }
 
     pub fn get_note(&self, key: ReactiveListKey) -> Option<Reactive<Note>> {
         self.notes.get_copy_of_item(key)
     }
+
+    /// All the clip's events, indexed by time.
+    pub fn events(&self) -> MidiEventsBlockList {
+        self.events.borrow().events.clone()
+    }
+
+    /// Changes whenever the clip's events do. Unique across clips, so a clip
+    /// given different notes never keeps the same revision.
+    pub fn revision(&self) -> u64 {
+        self.events.borrow().revision
+    }
+
+    pub fn controllers(&self) -> Vec<ControllerLane> {
+        self.events.borrow().controllers.clone()
+    }
+
+    /// Whether the two are linked copies, sharing the same notes.
+    pub fn shares_notes_with(&self, other: &MidiClip) -> bool {
+        Rc::ptr_eq(&self.events, &other.events)
+    }
+
+    /// A copy with notes of its own, so editing one leaves the other alone.
+    pub fn duplicate(&self) -> MidiClip {
+        let mut clip = MidiClip::new();
+        for (_, note) in self.notes.copy_of_whole_list() {
+            clip.notes.push(Reactive::new(note.get_copy()));
+        }
+        clip.set_controllers(self.controllers());
+        clip
+    }
+
+    pub fn set_controllers(&mut self, lanes: Vec<ControllerLane>) {
+        let mut clip_events = self.events.borrow_mut();
+        clip_events.revision = next_revision();
+        let ClipEvents {
+            events,
+            controllers,
+            controller_events,
+            ..
+        } = &mut *clip_events;
+
+        for id in controller_events.drain(..) {
+            events.delete_event(id);
+        }
+
+        for event in lanes.iter().flat_map(|lane| lane.to_midi_events()) {
+            controller_events.push(events.insert_event(event));
+        }
+
+        *controllers = lanes;
+    }
+
+    pub fn end_time(&self) -> Time {
+        self.notes
+            .copy_of_whole_list()
+            .into_iter()
+            .map(|(_, note)| {
+                let note = note.get_copy();
+                note.start + note.length
+            })
+            .chain(
+                self.controllers()
+                    .iter()
+                    .filter_map(|lane| lane.points.last().map(|p| p.time)),
+            )
+            .fold(0., Time::max)
+    }
+}
+
+impl ClipEvents {
+    fn watch_note(events: &Rc<RefCell<Self>>, key: ReactiveListKey, note: &Reactive<Note>) {
+        let weak = Rc::downgrade(events);
+        let subscription = note.subscribe(Box::new(move |note: &Note| {
+            if let Some(events) = weak.upgrade() {
+                events.borrow_mut().update_note(key, note);
+            }
+        }));
+
+        let mut clip_events = events.borrow_mut();
+        let (on, off) = clip_events.insert_note(&note.get_copy());
+        clip_events.notes.insert(
+            key,
+            NoteEvents {
+                on,
+                off,
+                note: note.clone(),
+                subscription,
+            },
+        );
+    }
+
+    fn insert_note(&mut self, note: &Note) -> (EventId, EventId) {
+        self.revision = next_revision();
+
+        let [on, off]: [MidiEvent; 2] = match note.to_midi_events().try_into() {
+            Ok(events) => events,
+            Err(_) => unreachable!("A note is always a note on and a note off"),
+        };
+
+        (self.events.insert_event(on), self.events.insert_event(off))
+    }
+
+    fn update_note(&mut self, key: ReactiveListKey, note: &Note) {
+        let (on, off) = match self.notes.get(&key) {
+            Some(note_events) => (note_events.on, note_events.off),
+            None => return,
+        };
+
+        self.events.delete_event(on);
+        self.events.delete_event(off);
+        let (on, off) = self.insert_note(note);
+
+        if let Some(note_events) = self.notes.get_mut(&key) {
+            note_events.on = on;
+            note_events.off = off;
+        }
+    }
+
+    fn remove_note(&mut self, key: ReactiveListKey) {
+        if let Some(note_events) = self.notes.remove(&key) {
+            self.revision = next_revision();
+            self.events.delete_event(note_events.on);
+            self.events.delete_event(note_events.off);
+            note_events.note.unsubscribe(note_events.subscription);
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct MidiEvent {
     pub time: Time,
+    /// Offset into the block currently being processed. Filled in by the
+    /// engine just before the event is handed to a processor.
+    pub delta_frames: i32,
+    /// 0 based.
+    pub channel: u8,
     pub data: MidiEventData,
 }
 
@@ -42,6 +336,12 @@ pub struct MidiEvent {
 pub enum MidiEventData {
     NoteOn { note: NoteEvent },
     NoteOff { note: NoteEvent },
+    PolyAftertouch { note: u32, pressure: u32 },
+    ControlChange { controller: u32, value: u32 },
+    ProgramChange { program: u32 },
+    ChannelAftertouch { pressure: u32 },
+    /// -8192 to 8191, 0 is centred.
+    PitchBend { value: i32 },
 }
 
 #[derive(Clone, Copy)]
@@ -52,17 +352,97 @@ pub struct NoteEvent {
 
 impl MidiEvent {
     pub fn status_byte(&self) -> u8 {
-        match self.data {
+        let kind = match self.data {
             MidiEventData::NoteOn { note: _ } => 0x90,
             MidiEventData::NoteOff { note: _ } => 0x80,
-        }
+            MidiEventData::PolyAftertouch { .. } => 0xa0,
+            MidiEventData::ControlChange { .. } => 0xb0,
+            MidiEventData::ProgramChange { .. } => 0xc0,
+            MidiEventData::ChannelAftertouch { .. } => 0xd0,
+            MidiEventData::PitchBend { .. } => 0xe0,
+        };
+        kind | (self.channel & 0x0f)
     }
 
-    pub fn note(&self) -> Option<NoteEvent> {
-        match self.data {
-            MidiEventData::NoteOn { note } => Some(note),
-            MidiEventData::NoteOff { note } => Some(note),
-        }
+    /// The raw message. Messages with one data byte leave the last byte 0.
+    pub fn to_bytes(&self) -> [u8; 3] {
+        let data_byte = |value: u32| value.min(127) as u8;
+
+        let (first, second) = match self.data {
+            MidiEventData::NoteOn { note } | MidiEventData::NoteOff { note } => {
+                (data_byte(note.note), data_byte(note.velocity))
+            }
+            MidiEventData::PolyAftertouch { note, pressure } => (data_byte(note), data_byte(pressure)),
+            MidiEventData::ControlChange { controller, value } => {
+                (data_byte(controller), data_byte(value))
+            }
+            MidiEventData::ProgramChange { program } => (data_byte(program), 0),
+            MidiEventData::ChannelAftertouch { pressure } => (data_byte(pressure), 0),
+            MidiEventData::PitchBend { value } => {
+                let value = (value.clamp(-8192, 8191) + 8192) as u32;
+                ((value & 0x7f) as u8, (value >> 7) as u8)
+            }
+        };
+
+        [self.status_byte(), first, second]
+    }
+
+    /// Reads a channel message. A note on with no velocity is a note off.
+    pub fn from_bytes(bytes: [u8; 3]) -> Option<Self> {
+        let [status, first, second] = bytes;
+        let (first, second) = (first as u32 & 0x7f, second as u32 & 0x7f);
+
+        let data = match status & 0xf0 {
+            0x90 if second > 0 => MidiEventData::NoteOn {
+                note: NoteEvent {
+                    note: first,
+                    velocity: second,
+                },
+            },
+            0x80 | 0x90 => MidiEventData::NoteOff {
+                note: NoteEvent {
+                    note: first,
+                    velocity: second,
+                },
+            },
+            0xa0 => MidiEventData::PolyAftertouch {
+                note: first,
+                pressure: second,
+            },
+            0xb0 => MidiEventData::ControlChange {
+                controller: first,
+                value: second,
+            },
+            0xc0 => MidiEventData::ProgramChange { program: first },
+            0xd0 => MidiEventData::ChannelAftertouch { pressure: first },
+            0xe0 => MidiEventData::PitchBend {
+                value: (first | second << 7) as i32 - 8192,
+            },
+            _ => return None,
+        };
+
+        Some(MidiEvent {
+            time: 0.,
+            delta_frames: 0,
+            channel: status & 0x0f,
+            data,
+        })
+    }
+
+    pub fn is_note_on(&self) -> bool {
+        matches!(self.data, MidiEventData::NoteOn { .. })
+    }
+}
+
+/// Bytes in a message starting with `status`.
+pub fn message_length(status: u8) -> usize {
+    match status {
+        0xf6..=0xff => 1,
+        0xf1 | 0xf3 => 2,
+        _ => match status & 0xf0 {
+            0xc0 | 0xd0 => 2,
+            _ => 3,
+        },
     }
 }
 
@@ -71,6 +451,8 @@ impl Note {
         vec![
             MidiEvent {
                 time: self.start,
+                delta_frames: 0,
+                channel: 0,
                 data: MidiEventData::NoteOn {
                     note: NoteEvent {
                         note: self.note,
@@ -80,6 +462,8 @@ impl Note {
             },
             MidiEvent {
                 time: self.start + self.length,
+                delta_frames: 0,
+                channel: 0,
                 data: MidiEventData::NoteOff {
                     note: NoteEvent {
                         note: self.note,
@@ -94,14 +478,31 @@ impl Note {
 pub type BlockId = usize;
 pub type EventId = usize;
 
-struct MidiEventsBlockList {
-    pub blocks: HashMap<BlockId, MidiEventsBlock>,
+/// Length of the blocks events are bucketed into, in beats.
+const BLOCK_LENGTH: Time = 1.;
+
+/// Events bucketed by time, so the ones in a range can be found without
+/// looking at the rest.
+#[derive(Clone)]
+pub struct MidiEventsBlockList {
+    blocks: HashMap<BlockId, MidiEventsBlock>,
     event_locations: HashMap<EventId, BlockId>,
     id_counter: EventId,
 }
 
+/// Sorted by time, with note offs before note ons that happen at the same
+/// time so a repeated note isn't cut off by its predecessor.
+pub type MidiEventsBlock = Vec<(EventId, MidiEvent)>;
+
+/// Events before the start of the timeline go in the first block.
 fn get_block_id(time: Time) -> BlockId {
-    unimplemented!()
+    (time.max(0.) / BLOCK_LENGTH).floor() as BlockId
+}
+
+impl Default for MidiEventsBlockList {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MidiEventsBlockList {
@@ -116,18 +517,70 @@ impl MidiEventsBlockList {
     pub fn insert_event(&mut self, event: MidiEvent) -> EventId {
         let block_id = get_block_id(event.time);
         let event_id = self.id();
-        self.blocks
-            .entry(block_id)
-            .or_insert_with(HashMap::new)
-            .insert(event_id, event);
+
+        let block = self.blocks.entry(block_id).or_default();
+        let key = (event.time, event.is_note_on());
+        let i = block.partition_point(|(_, e)| (e.time, e.is_note_on()) <= key);
+        block.insert(i, (event_id, event));
+
         self.event_locations.insert(event_id, block_id);
         event_id
     }
 
     pub fn delete_event(&mut self, event_id: EventId) {
-        let block_id = self.event_locations[&event_id];
-        self.blocks.get_mut(&block_id).unwrap().remove(&event_id);
-        self.event_locations.remove(&event_id);
+        let block_id = match self.event_locations.remove(&event_id) {
+            Some(block_id) => block_id,
+            None => return,
+        };
+
+        if let Some(block) = self.blocks.get_mut(&block_id) {
+            block.retain(|(id, _)| *id != event_id);
+            if block.is_empty() {
+                self.blocks.remove(&block_id);
+            }
+        }
+    }
+
+    /// Events with `start <= time < end`, in order. Only looks at the
+    /// blocks the range touches.
+    pub fn events_in_range(&self, start: Time, end: Time) -> Vec<MidiEvent> {
+        let mut events = vec![];
+        self.events_in_range_into(start, end, &mut events);
+        events
+    }
+
+    /// Appends what `events_in_range` returns to `events`, so a buffer can be
+    /// reused instead of allocating.
+    pub fn events_in_range_into(&self, start: Time, end: Time, events: &mut Vec<MidiEvent>) {
+        if end <= start {
+            return;
+        }
+
+        for block_id in get_block_id(start)..=get_block_id(end) {
+            let block = match self.blocks.get(&block_id) {
+                Some(block) => block,
+                None => continue,
+            };
+
+            let first = block.partition_point(|(_, e)| e.time < start);
+            events.extend(
+                block[first..]
+                    .iter()
+                    .take_while(|(_, e)| e.time < end)
+                    .map(|(_, e)| e.clone()),
+            );
+        }
+    }
+
+    /// Every event, in order.
+    pub fn all_events(&self) -> Vec<MidiEvent> {
+        let mut block_ids: Vec<&BlockId> = self.blocks.keys().collect();
+        block_ids.sort();
+
+        block_ids
+            .into_iter()
+            .flat_map(|block_id| self.blocks[block_id].iter().map(|(_, e)| e.clone()))
+            .collect()
     }
 
     fn id(&mut self) -> EventId {
@@ -135,7 +588,216 @@ impl MidiEventsBlockList {
         self.id_counter += 1;
         id
     }
-
 }
 
-pub type MidiEventsBlock = HashMap<EventId, MidiEvent>;
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_event(time: Time, note: u32, on: bool) -> MidiEvent {
+        let note = NoteEvent { note, velocity: 100 };
+        MidiEvent {
+            time,
+            delta_frames: 0,
+            channel: 0,
+            data: if on {
+                MidiEventData::NoteOn { note }
+            } else {
+                MidiEventData::NoteOff { note }
+            },
+        }
+    }
+
+    /// `(time, is_note_on)` of each event.
+    fn summary(events: &[MidiEvent]) -> Vec<(Time, bool)> {
+        events.iter().map(|e| (e.time, e.is_note_on())).collect()
+    }
+
+    #[test]
+    fn events_on_block_boundaries_start_their_block() {
+        let mut list = MidiEventsBlockList::new();
+        for i in 0..4 {
+            list.insert_event(note_event(i as Time * BLOCK_LENGTH, 60, true));
+        }
+
+        assert_eq!(
+            summary(&list.events_in_range(BLOCK_LENGTH, 2. * BLOCK_LENGTH)),
+            vec![(BLOCK_LENGTH, true)]
+        );
+        assert_eq!(list.all_events().len(), 4);
+    }
+
+    #[test]
+    fn range_end_is_exclusive_on_a_block_edge() {
+        let mut list = MidiEventsBlockList::new();
+        list.insert_event(note_event(0.5, 60, true));
+        list.insert_event(note_event(1., 62, true));
+        list.insert_event(note_event(2., 64, true));
+
+        assert_eq!(summary(&list.events_in_range(0., 1.)), vec![(0.5, true)]);
+        assert_eq!(
+            summary(&list.events_in_range(0.5, 2.)),
+            vec![(0.5, true), (1., true)]
+        );
+        assert!(list.events_in_range(1., 1.).is_empty());
+    }
+
+    #[test]
+    fn long_note_ends_several_blocks_later() {
+        let clip = MidiClip::new();
+        clip.notes.clone().push(Reactive::new(Note {
+            note: 60,
+            velocity: 100,
+            start: 0.5,
+            length: 3.,
+        }));
+
+        let events = clip.events();
+        assert_eq!(summary(&events.events_in_range(0., 1.)), vec![(0.5, true)]);
+        assert!(events.events_in_range(1., 3.).is_empty());
+        assert_eq!(summary(&events.events_in_range(3., 4.)), vec![(3.5, false)]);
+    }
+
+    #[test]
+    fn note_off_comes_before_note_on_at_the_same_time() {
+        let mut list = MidiEventsBlockList::new();
+        list.insert_event(note_event(1., 60, true));
+        list.insert_event(note_event(1., 60, false));
+        list.insert_event(note_event(0.5, 60, true));
+
+        assert_eq!(
+            summary(&list.events_in_range(0., 2.)),
+            vec![(0.5, true), (1., false), (1., true)]
+        );
+    }
+
+    #[test]
+    fn negative_times_go_in_the_first_block() {
+        let mut list = MidiEventsBlockList::new();
+        list.insert_event(note_event(0.5, 60, true));
+        list.insert_event(note_event(-2., 60, true));
+
+        assert_eq!(
+            summary(&list.events_in_range(-3., 1.)),
+            vec![(-2., true), (0.5, true)]
+        );
+        assert_eq!(summary(&list.events_in_range(0., 1.)), vec![(0.5, true)]);
+        assert_eq!(summary(&list.events_in_range(-3., -1.)), vec![(-2., true)]);
+    }
+
+    #[test]
+    fn deleted_events_are_gone() {
+        let mut list = MidiEventsBlockList::new();
+        let id = list.insert_event(note_event(0.5, 60, true));
+        list.insert_event(note_event(0.75, 62, true));
+
+        list.delete_event(id);
+        list.delete_event(id);
+
+        assert_eq!(summary(&list.events_in_range(0., 1.)), vec![(0.75, true)]);
+    }
+
+    #[test]
+    fn clip_events_follow_its_notes() {
+        let clip = MidiClip::new();
+        let mut notes = clip.notes.clone();
+        let note = Reactive::new(Note {
+            note: 60,
+            velocity: 100,
+            start: 0.,
+            length: 1.,
+        });
+        let revision = clip.revision();
+        let key = notes.push(note.clone());
+        assert_ne!(clip.revision(), revision);
+
+        assert_eq!(
+            summary(&clip.events().all_events()),
+            vec![(0., true), (1., false)]
+        );
+
+        note.set(Note {
+            note: 60,
+            velocity: 100,
+            start: 4.,
+            length: 2.,
+        });
+        assert_eq!(
+            summary(&clip.events().all_events()),
+            vec![(4., true), (6., false)]
+        );
+
+        notes.remove(&key);
+        assert!(clip.events().all_events().is_empty());
+
+        // No longer watched once removed.
+        note.set(Note {
+            note: 60,
+            velocity: 100,
+            start: 0.,
+            length: 1.,
+        });
+        assert!(clip.events().all_events().is_empty());
+    }
+
+    #[test]
+    fn linked_clips_share_events() {
+        let clip = MidiClip::new();
+        let linked = clip.clone();
+        clip.notes.clone().push(Reactive::new(Note {
+            note: 60,
+            velocity: 100,
+            start: 0.,
+            length: 1.,
+        }));
+
+        assert_eq!(linked.events().all_events().len(), 2);
+        assert_eq!(clip.duplicate().events().all_events().len(), 2);
+        assert!(!clip.duplicate().shares_notes_with(&clip));
+    }
+
+    #[test]
+    fn controllers_replace_their_old_events() {
+        let mut clip = MidiClip::new();
+        let mut lane = ControllerLane::new(Controller::ControlChange(1), 0);
+        lane.insert(ControllerPoint { time: 1., value: 10 });
+        lane.insert(ControllerPoint { time: 2., value: 20 });
+        clip.set_controllers(vec![lane.clone()]);
+        assert_eq!(clip.events().all_events().len(), 2);
+
+        lane.points.pop();
+        clip.set_controllers(vec![lane]);
+        assert_eq!(summary(&clip.events().all_events()), vec![(1., false)]);
+    }
+
+    #[test]
+    fn controller_points_stay_sorted_and_replace_at_the_same_time() {
+        let mut lane = ControllerLane::new(Controller::ControlChange(7), 2);
+        lane.insert(ControllerPoint { time: 2., value: 20 });
+        lane.insert(ControllerPoint { time: 1., value: 10 });
+        lane.insert(ControllerPoint { time: 2., value: 30 });
+
+        let points: Vec<(Time, i32)> = lane.points.iter().map(|p| (p.time, p.value)).collect();
+        assert_eq!(points, vec![(1., 10), (2., 30)]);
+    }
+
+    #[test]
+    fn controller_lane_events_are_on_its_channel_and_clamped() {
+        let mut lane = ControllerLane::new(Controller::ControlChange(7), 2);
+        lane.insert(ControllerPoint { time: 0., value: 200 });
+        lane.insert(ControllerPoint { time: 1., value: 64 });
+
+        let events = lane.to_midi_events();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.channel == 2));
+        assert!(matches!(
+            events[0].data,
+            MidiEventData::ControlChange { controller: 7, value: 127 }
+        ));
+        assert_eq!(events[1].time, 1.);
+        assert!(matches!(
+            events[1].data,
+            MidiEventData::ControlChange { controller: 7, value: 64 }
+        ));
+    }
+}
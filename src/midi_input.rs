@@ -0,0 +1,192 @@
+use std::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Instant,
+};
+
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort};
+
+use crate::{
+    event_subscriptions::MidiInputEvent,
+    midi::MidiEvent,
+};
+
+const CLIENT_NAME: &str = "daw";
+
+/// Hands incoming messages to the engine for monitoring and queues them for
+/// the UI thread. Cloned into midir's callback, and usable on its own as a
+/// fake input source.
+#[derive(Clone)]
+pub struct InputSource {
+    events: Sender<MidiInputEvent>,
+    monitor: Option<Sender<MidiEvent>>,
+}
+
+impl InputSource {
+    /// Takes a raw message. Anything longer than three bytes, such as sysex,
+    /// is dropped.
+    pub fn send(&self, stamp: u64, bytes: &[u8]) {
+        let event = match MidiInputEvent::parse(stamp, bytes) {
+            Some(event) => event,
+            None => return,
+        };
+
+        if let (Some(monitor), Some(midi_event)) = (&self.monitor, event.to_midi_event()) {
+            let _ = monitor.send(midi_event);
+        }
+
+        let _ = self.events.send(event);
+    }
+}
+
+/// Listens to one MIDI input port at a time.
+pub struct MidiInputs {
+    connection: Option<MidiInputConnection<()>>,
+    port_name: Option<String>,
+    source: InputSource,
+    events: Receiver<MidiInputEvent>,
+}
+
+impl Default for MidiInputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiInputs {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+
+        Self {
+            connection: None,
+            port_name: None,
+            source: InputSource {
+                events: sender,
+                monitor: None,
+            },
+            events: receiver,
+        }
+    }
+
+    /// Where notes are sent to be heard straight away. Applies to
+    /// connections made afterwards.
+    pub fn set_monitor(&mut self, monitor: Option<Sender<MidiEvent>>) {
+        self.source.monitor = monitor;
+    }
+
+    /// A source feeding the same queue as the connected port.
+    pub fn source(&self) -> InputSource {
+        self.source.clone()
+    }
+
+    pub fn port_name(&self) -> Option<&str> {
+        self.port_name.as_deref()
+    }
+
+    pub fn port_names() -> Result<Vec<String>, String> {
+        let midi_in = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+
+        Ok(midi_in
+            .ports()
+            .iter()
+            .map(|port| midi_in.port_name(port).unwrap_or_else(|_| "?".to_string()))
+            .collect())
+    }
+
+    /// Connects to a port given by its 1 based position in `port_names` or
+    /// part of its name.
+    pub fn connect(&mut self, port: &str) -> Result<(), String> {
+        self.disconnect();
+
+        let mut midi_in = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+        midi_in.ignore(Ignore::None);
+
+        let ports = midi_in.ports();
+        let names: Vec<String> = ports
+            .iter()
+            .map(|p| midi_in.port_name(p).unwrap_or_default())
+            .collect();
+
+        let index = match port.parse::<usize>() {
+            Ok(position) if position >= 1 && position <= ports.len() => position - 1,
+            _ => names
+                .iter()
+                .position(|name| name.to_lowercase().contains(&port.to_lowercase()))
+                .ok_or_else(|| format!("No MIDI input port matches '{}'", port))?,
+        };
+
+        let port: &MidiInputPort = &ports[index];
+        let source = self.source.clone();
+        let connection = midi_in
+            .connect(
+                port,
+                "daw-input",
+                move |stamp, bytes, _| source.send(stamp, bytes),
+                (),
+            )
+            .map_err(|e| e.to_string())?;
+
+        self.connection = Some(connection);
+        self.port_name = Some(names[index].clone());
+        Ok(())
+    }
+
+    /// Creates a port other programs can connect to, e.g. to play into the
+    /// DAW from another sequencer.
+    #[cfg(unix)]
+    pub fn connect_virtual(&mut self) -> Result<(), String> {
+        use midir::os::unix::VirtualInput;
+
+        self.disconnect();
+
+        let mut midi_in = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+        midi_in.ignore(Ignore::None);
+
+        let source = self.source.clone();
+        let connection = midi_in
+            .create_virtual(
+                "daw-input",
+                move |stamp, bytes, _| source.send(stamp, bytes),
+                (),
+            )
+            .map_err(|e| e.to_string())?;
+
+        self.connection = Some(connection);
+        self.port_name = Some("daw-input (virtual)".to_string());
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            connection.close();
+        }
+        self.port_name = None;
+    }
+
+    /// Everything received since the last call.
+    pub fn poll(&self) -> Vec<MidiInputEvent> {
+        self.events.try_iter().collect()
+    }
+}
+
+impl MidiInputEvent {
+    fn parse(stamp: u64, bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() || bytes.len() > 3 {
+            return None;
+        }
+
+        let mut message = [0; 3];
+        message[..bytes.len()].copy_from_slice(bytes);
+
+        Some(Self {
+            channel: message[0] & 0x0f,
+            stamp: (stamp / 1000) as u32,
+            message,
+            received: Instant::now(),
+        })
+    }
+
+    /// Channel messages as engine events.
+    pub fn to_midi_event(&self) -> Option<MidiEvent> {
+        MidiEvent::from_bytes(self.message)
+    }
+}
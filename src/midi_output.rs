@@ -0,0 +1,174 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Instant,
+};
+
+use midir::{MidiOutput, MidiOutputConnection};
+use serde::{Deserialize, Serialize};
+
+use crate::{midi::message_length, track::TrackId};
+
+const CLIENT_NAME: &str = "daw";
+
+/// Where a track's notes are sent besides its instrument. Saved with the
+/// project.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct MidiOutputSettings {
+    /// Part of the name of the port, or the name of the port to create when
+    /// `virtual_port` is set.
+    pub port: String,
+    #[serde(default)]
+    pub virtual_port: bool,
+    /// 0 based.
+    #[serde(default)]
+    pub channel: u8,
+    /// Added on top of the engine's latency, e.g. to line up with a slow
+    /// synth. Negative values send earlier.
+    #[serde(default)]
+    pub offset_ms: f32,
+}
+
+/// What a connection on the output thread is used for.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum OutputPort {
+    Track(TrackId),
+    /// MIDI clock and time code.
+    Sync,
+}
+
+pub enum OutputMessage {
+    Connect {
+        port: OutputPort,
+        connection: MidiOutputConnection,
+    },
+    Disconnect(OutputPort),
+    /// Sends `bytes` on `port` once `due` is reached.
+    Send {
+        port: OutputPort,
+        due: Instant,
+        bytes: [u8; 3],
+    },
+}
+
+pub fn port_names() -> Result<Vec<String>, String> {
+    let midi_out = MidiOutput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+
+    Ok(midi_out
+        .ports()
+        .iter()
+        .map(|port| midi_out.port_name(port).unwrap_or_else(|_| "?".to_string()))
+        .collect())
+}
+
+/// Opens the port `settings` names, creating it first if it's virtual.
+pub fn connect(settings: &MidiOutputSettings) -> Result<MidiOutputConnection, String> {
+    let midi_out = MidiOutput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+
+    if settings.virtual_port {
+        return connect_virtual(midi_out, &settings.port);
+    }
+
+    let ports = midi_out.ports();
+    let port = ports
+        .iter()
+        .find(|port| {
+            midi_out
+                .port_name(port)
+                .map(|name| name.to_lowercase().contains(&settings.port.to_lowercase()))
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| format!("No MIDI output port matches '{}'", settings.port))?;
+
+    midi_out
+        .connect(port, "daw-output")
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(unix)]
+fn connect_virtual(midi_out: MidiOutput, name: &str) -> Result<MidiOutputConnection, String> {
+    use midir::os::unix::VirtualOutput;
+
+    midi_out.create_virtual(name).map_err(|e| e.to_string())
+}
+
+#[cfg(not(unix))]
+fn connect_virtual(_: MidiOutput, _: &str) -> Result<MidiOutputConnection, String> {
+    Err("Virtual MIDI ports aren't supported on this platform".to_string())
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Scheduled {
+    due: Instant,
+    /// Keeps messages due at the same time in the order they were sent.
+    order: u64,
+    port: OutputPort,
+    bytes: [u8; 3],
+}
+
+/// Starts the thread that holds the output connections and sends messages
+/// when they're due, so the audio thread can schedule them ahead of time
+/// without blocking. It stops once every sender has been dropped.
+pub fn spawn_output_thread() -> Sender<OutputMessage> {
+    let (sender, receiver) = channel();
+
+    thread::Builder::new()
+        .name("midi-output".to_string())
+        .spawn(move || run(receiver))
+        .expect("Failed to start the MIDI output thread");
+
+    sender
+}
+
+fn run(receiver: Receiver<OutputMessage>) {
+    let mut connections: HashMap<OutputPort, MidiOutputConnection> = HashMap::new();
+    let mut queue: BinaryHeap<Reverse<Scheduled>> = BinaryHeap::new();
+    let mut order = 0;
+
+    loop {
+        let message = match queue.peek() {
+            Some(Reverse(next)) => {
+                receiver.recv_timeout(next.due.saturating_duration_since(Instant::now()))
+            }
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match message {
+            Ok(OutputMessage::Connect { port, connection }) => {
+                if let Some(old) = connections.insert(port, connection) {
+                    old.close();
+                }
+            }
+            Ok(OutputMessage::Disconnect(port)) => {
+                if let Some(connection) = connections.remove(&port) {
+                    connection.close();
+                }
+            }
+            Ok(OutputMessage::Send { port, due, bytes }) => {
+                order += 1;
+                queue.push(Reverse(Scheduled {
+                    due,
+                    order,
+                    port,
+                    bytes,
+                }));
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let now = Instant::now();
+        while queue.peek().is_some_and(|Reverse(next)| next.due <= now) {
+            let Reverse(next) = queue.pop().unwrap();
+            if let Some(connection) = connections.get_mut(&next.port) {
+                let _ = connection.send(&next.bytes[..message_length(next.bytes[0])]);
+            }
+        }
+    }
+
+    for (_, connection) in connections {
+        connection.close();
+    }
+}
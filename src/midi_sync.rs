@@ -0,0 +1,292 @@
+use std::{cell::RefCell, rc::Rc, time::Instant};
+
+use crate::{
+    event_subscriptions::MidiInputEvent,
+    global::{Globals, PlayingState},
+    midi::Time,
+    midi_output::MidiOutputSettings,
+    tempo_map::{MAX_TEMPO, MIN_TEMPO},
+};
+
+pub const CLOCKS_PER_BEAT: f64 = 24.;
+
+pub const CLOCK: u8 = 0xf8;
+pub const START: u8 = 0xfa;
+pub const CONTINUE: u8 = 0xfb;
+pub const STOP: u8 = 0xfc;
+pub const SONG_POSITION: u8 = 0xf2;
+pub const QUARTER_FRAME: u8 = 0xf1;
+
+/// How much each new clock interval moves the smoothed one. Lower is
+/// steadier but slower to follow tempo changes.
+const SMOOTHING: f64 = 0.05;
+/// Gaps longer than this mean the clock stopped rather than slowed down.
+const MAX_CLOCK_INTERVAL: f64 = 0.25;
+/// Most the tempo is nudged by to catch up with the master, as a fraction.
+const MAX_CORRECTION: f64 = 0.05;
+
+/// MIDI Time Code frame rates.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MtcRate {
+    Fps24,
+    Fps25,
+    Fps30,
+}
+
+impl MtcRate {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "24" => Some(MtcRate::Fps24),
+            "25" => Some(MtcRate::Fps25),
+            "30" => Some(MtcRate::Fps30),
+            _ => None,
+        }
+    }
+
+    pub fn fps(&self) -> u64 {
+        match self {
+            MtcRate::Fps24 => 24,
+            MtcRate::Fps25 => 25,
+            MtcRate::Fps30 => 30,
+        }
+    }
+
+    /// The rate's bits in the last quarter frame.
+    fn code(&self) -> u8 {
+        match self {
+            MtcRate::Fps24 => 0,
+            MtcRate::Fps25 => 1,
+            MtcRate::Fps30 => 3,
+        }
+    }
+}
+
+/// Where clock is sent while this is the master, and whether time code goes
+/// with it.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ClockOutput {
+    pub port: MidiOutputSettings,
+    pub mtc: Option<MtcRate>,
+}
+
+/// Song position pointer for `t`, rounded down to a sixteenth note.
+pub fn song_position(t: Time) -> [u8; 3] {
+    let sixteenths = ((t * 4.).floor().max(0.) as u32).min(0x3fff);
+    [SONG_POSITION, (sixteenths & 0x7f) as u8, (sixteenths >> 7) as u8]
+}
+
+/// The time a song position pointer message points at.
+fn song_position_time(message: &[u8]) -> Time {
+    let sixteenths = message[1] as u32 | (message[2] as u32) << 7;
+    sixteenths as Time / 4.
+}
+
+/// Quarter frame number `quarter` since the start of the timeline. Each run
+/// of eight spells out the time at the first of them.
+pub fn quarter_frame(quarter: u64, rate: MtcRate) -> [u8; 3] {
+    let piece = (quarter % 8) as u8;
+    let frames = (quarter - piece as u64) / 4;
+
+    let fps = rate.fps();
+    let frame = (frames % fps) as u8;
+    let seconds = (frames / fps % 60) as u8;
+    let minutes = (frames / fps / 60 % 60) as u8;
+    let hours = (frames / fps / 3600 % 24) as u8;
+
+    let nibble = match piece {
+        0 => frame & 0x0f,
+        1 => frame >> 4,
+        2 => seconds & 0x0f,
+        3 => seconds >> 4,
+        4 => minutes & 0x0f,
+        5 => minutes >> 4,
+        6 => hours & 0x0f,
+        _ => (hours >> 4) | (rate.code() << 1),
+    };
+
+    [QUARTER_FRAME, (piece << 4) | nibble, 0]
+}
+
+/// Follows clock from the MIDI input, for running as a slave to a drum
+/// machine or another sequencer.
+pub struct ClockFollower {
+    last_clock: Option<Instant>,
+    /// Smoothed seconds between clocks.
+    interval: Option<f64>,
+    /// Where the master started or was last positioned.
+    start: Time,
+    /// Clocks since the first one after being positioned, which falls on
+    /// `start` itself.
+    clocks: Option<u64>,
+}
+
+impl Default for ClockFollower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockFollower {
+    pub fn new() -> Self {
+        Self {
+            last_clock: None,
+            interval: None,
+            start: 0.,
+            clocks: None,
+        }
+    }
+
+    fn locate(&mut self, t: Time) {
+        self.start = t;
+        self.clocks = None;
+        self.last_clock = None;
+    }
+
+    /// Where the master is, going by the clocks received since it was
+    /// positioned.
+    fn position(&self) -> Time {
+        self.start + self.clocks.unwrap_or(0) as f64 / CLOCKS_PER_BEAT
+    }
+
+    fn clock(&mut self, received: Instant) {
+        if let Some(last) = self.last_clock {
+            let interval = received.duration_since(last).as_secs_f64();
+            if interval < MAX_CLOCK_INTERVAL {
+                self.interval = Some(match self.interval {
+                    Some(smoothed) => smoothed + (interval - smoothed) * SMOOTHING,
+                    None => interval,
+                });
+            }
+        }
+
+        self.last_clock = Some(received);
+        self.clocks = Some(self.clocks.map_or(0, |clocks| clocks + 1));
+    }
+
+    /// The master's tempo, nudged so the playhead catches up with where the
+    /// master is when it's at `player_time`.
+    fn tempo(&self, player_time: Time) -> Option<f32> {
+        let tempo = 60. / (self.interval? * CLOCKS_PER_BEAT);
+        let correction = ((self.position() - player_time) * 0.5).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+
+        Some(((tempo * (1. + correction)) as f32).clamp(MIN_TEMPO, MAX_TEMPO))
+    }
+}
+
+/// Drives the transport and tempo from clock and song position messages
+/// while following is switched on.
+pub fn follow_midi_clock(globals: &mut Globals) {
+    globals.subscriptions.subscribe_midi_input(Rc::new(RefCell::new(
+        |event: &MidiInputEvent, globals: &mut Globals| {
+            let player_time = globals.loaded_project.player_time.get_copy();
+            let follower = match &mut globals.clock_follower {
+                Some(follower) => follower,
+                None => return,
+            };
+
+            match event.message[0] {
+                CLOCK => {
+                    // Clock keeps coming while the master is stopped.
+                    if !globals.playing_state.is_playing() {
+                        follower.last_clock = Some(event.received);
+                        return;
+                    }
+
+                    follower.clock(event.received);
+                    let tempo = follower.tempo(player_time);
+
+                    if let Some(engine) = &mut globals.engine {
+                        engine.set_external_tempo(tempo);
+                    }
+                }
+                START => {
+                    follower.locate(0.);
+                    globals.loaded_project.player_time <<= 0.;
+                    globals.playing_state = PlayingState::Playing;
+                }
+                CONTINUE => {
+                    follower.locate(player_time);
+                    globals.playing_state = PlayingState::Playing;
+                }
+                STOP => globals.playing_state = PlayingState::Stopped,
+                SONG_POSITION => {
+                    let t = song_position_time(&event.message);
+
+                    follower.locate(t);
+                    globals.loaded_project.player_time <<= t;
+                }
+                _ => (),
+            }
+        },
+    )));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Reads back the time a run of eight quarter frames spells out, in
+    /// frames, and the rate.
+    fn decode_quarter_frames(messages: &[[u8; 3]]) -> (u64, u8) {
+        let mut nibbles = [0u64; 8];
+        for message in messages {
+            assert_eq!(message[0], QUARTER_FRAME);
+            nibbles[(message[1] >> 4) as usize] = (message[1] & 0x0f) as u64;
+        }
+
+        let frame = nibbles[0] | nibbles[1] << 4;
+        let seconds = nibbles[2] | nibbles[3] << 4;
+        let minutes = nibbles[4] | nibbles[5] << 4;
+        let hours = nibbles[6] | (nibbles[7] & 1) << 4;
+        let rate = (nibbles[7] >> 1) as u8;
+
+        (((hours * 60 + minutes) * 60 + seconds) * 25 + frame, rate)
+    }
+
+    #[test]
+    fn song_position_round_trips_in_sixteenths() {
+        for t in [0., 0.25, 3.75, 1000., 4095.75] {
+            assert_eq!(song_position_time(&song_position(t)), t);
+        }
+        // Rounded down to a sixteenth.
+        assert_eq!(song_position_time(&song_position(1.3)), 1.25);
+        // Past the largest position the message can hold.
+        assert_eq!(song_position_time(&song_position(5000.)), 0x3fff as Time / 4.);
+    }
+
+    #[test]
+    fn quarter_frames_spell_out_the_time_at_the_first_of_eight() {
+        // 1 hour, 2 minutes, 3 seconds and 5 frames at 25 fps. A run of
+        // eight covers two frames, so runs start on even ones.
+        let frames = ((60 + 2) * 60 + 3) * 25 + 5;
+        let first = frames * 4;
+
+        let messages: Vec<[u8; 3]> = (first..first + 8)
+            .map(|quarter| quarter_frame(quarter, MtcRate::Fps25))
+            .collect();
+
+        assert_eq!(decode_quarter_frames(&messages), (frames, MtcRate::Fps25.code()));
+    }
+
+    #[test]
+    fn the_first_clock_after_start_is_on_the_beat() {
+        let mut follower = ClockFollower::new();
+        let t0 = Instant::now();
+        follower.locate(4.);
+
+        follower.clock(t0);
+        assert_eq!(follower.position(), 4.);
+
+        let interval = Duration::from_secs_f64(0.5 / CLOCKS_PER_BEAT);
+        for i in 1..=24 {
+            follower.clock(t0 + interval * i);
+        }
+        assert_eq!(follower.position(), 5.);
+
+        // 120 BPM, with the playhead already where the master is.
+        let tempo = follower.tempo(5.).unwrap();
+        assert!((tempo - 120.).abs() < 0.01);
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+pub const MIN_GAIN_DB: f32 = -60.;
+pub const MAX_GAIN_DB: f32 = 12.;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MixerSettings {
+    pub gain_db: f32,
+    /// -1 is hard left, 1 is hard right.
+    pub pan: f32,
+    pub mute: bool,
+    pub solo: bool,
+}
+
+impl Default for MixerSettings {
+    fn default() -> Self {
+        Self {
+            gain_db: 0.,
+            pan: 0.,
+            mute: false,
+            solo: false,
+        }
+    }
+}
+
+impl MixerSettings {
+    /// Left and right gains with gain and constant power panning applied.
+    pub fn channel_gains(&self) -> (f32, f32) {
+        let gain = db_to_linear(self.gain_db);
+        let angle = (self.pan.clamp(-1., 1.) + 1.) * std::f32::consts::FRAC_PI_4;
+
+        (gain * angle.cos(), gain * angle.sin())
+    }
+
+    /// Whether the track should be heard given whether any track is soloed.
+    pub fn is_audible(&self, any_solo: bool) -> bool {
+        !self.mute && (!any_solo || self.solo)
+    }
+}
+
+pub fn db_to_linear(db: f32) -> f32 {
+    if db <= MIN_GAIN_DB {
+        return 0.;
+    }
+
+    10f32.powf(db / 20.)
+}
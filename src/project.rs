@@ -1,30 +1,57 @@
 use core::panic;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    midi::{Note, Time},
-    track::{self, Track, TrackData, TrackGroup, TrackType},
+    audio::{audio_processor::ParamId, metronome::MetronomeSettings},
+    automation::{AutomationLane, Breakpoint},
+    clip::{ClipId, TrackClip},
+    midi::{ControllerLane, MidiClip, Note, Time},
+    midi_output::MidiOutputSettings,
+    mixer::{MixerSettings, MAX_GAIN_DB, MIN_GAIN_DB},
+    project_file::ProjectFile,
+    routing::{Bus, BusId, Send, StripId},
+    track::{Insert, InsertId, Instrument, Track, TrackGroup, TrackId, TrackType},
     ui::{reactive::Reactive, reactive_list::ReactiveListKey},
     utils::note_name, selection::Selection,
+    tempo_map::{MeterMap, TempoMap, DEFAULT_TEMPO},
 };
 
 pub struct Project {
     pub meta: ProjectMeta,
     pub selection: Reactive<Selection>,
-    pub tempo: Reactive<f32>,
+    pub tempo_map: Reactive<TempoMap>,
     pub tracks: TrackGroup,
+    pub buses: BTreeMap<BusId, Bus>,
     pub player_time: Reactive<Time>,
     pub key_signature: Reactive<KeySignature>,
-    pub time_signature: Reactive<TimeSignature>,
+    pub meter_map: Reactive<MeterMap>,
+    pub loop_region: Reactive<Region>,
+    /// Recording only takes notes inside this while it's enabled.
+    pub punch_region: Reactive<Region>,
+    pub metronome: Reactive<MetronomeSettings>,
+    pub path: Option<PathBuf>,
     undo_stack: Vec<Action>,
     redo_stack: Vec<Action>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ProjectMeta {
     pub name: String,
     pub description: String,
     pub version: String,
 }
 
+impl Default for Project {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Project {
     pub fn new() -> Self {
         let mut project = Project {
@@ -35,28 +62,216 @@ impl Project {
             },
             selection: Reactive::new(Selection::default()),
             key_signature: Reactive::new(KeySignature::new(0, KeyMode::Major)),
-            tempo: Reactive::new(120.),
+            tempo_map: Reactive::new(TempoMap::new(DEFAULT_TEMPO)),
             tracks: TrackGroup::new(),
+            buses: BTreeMap::new(),
             player_time: Reactive::new(0.),
-            time_signature: Reactive::new(TimeSignature::common()),
+            meter_map: Reactive::new(MeterMap::new(TimeSignature::common())),
+            loop_region: Reactive::new(Region::default()),
+            punch_region: Reactive::new(Region::default()),
+            metronome: Reactive::new(MetronomeSettings::default()),
+            path: None,
             undo_stack: vec![],
             redo_stack: vec![],
         };
 
         project.tracks.add_new(TrackType::Midi);
 
-        return project;
+        project
     }
 
-    pub fn undo(&mut self) {
-        loop {
-            if let Some(action) = self.undo_stack.pop() {
-                self.__perform_action(&action, ActionType::Undo);
-                if !action.undoes_automatically() {
-                    return;
+    pub fn save(&mut self, path: &Path) -> Result<(), String> {
+        ProjectFile::from_project(self).write(path)?;
+        self.path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Loads a project file into this project. Everything is updated in place
+    /// so that UI bound to the project's reactives stays valid.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let file = ProjectFile::read(path)?;
+
+        self.meta = file.meta;
+        self.tempo_map <<= file.tempo_map;
+        self.key_signature <<= file.key_signature;
+        self.meter_map <<= file.meter_map;
+        self.loop_region <<= file.loop_region;
+        self.punch_region <<= file.punch_region;
+        self.metronome <<= file.metronome;
+        self.selection <<= Selection::None;
+        self.player_time <<= 0.;
+
+        let pool: Vec<MidiClip> = file
+            .pool
+            .into_iter()
+            .map(|pooled| {
+                let mut midi = MidiClip::new();
+                for note in pooled.notes {
+                    midi.notes.push(Reactive::new(note));
                 }
-            } else {
-                break;
+                midi.set_controllers(pooled.controllers);
+                midi
+            })
+            .collect();
+
+        let loaded_ids: Vec<_> = file.tracks.iter().map(|t| t.uid).collect();
+        let stale_ids: Vec<_> = self
+            .tracks
+            .tracks
+            .keys()
+            .filter(|id| !loaded_ids.contains(id))
+            .cloned()
+            .collect();
+
+        for id in stale_ids {
+            self.tracks.delete(id);
+        }
+
+        for track_file in file.tracks {
+            let reuse = self
+                .tracks
+                .tracks
+                .get(&track_file.uid)
+                .map(|t| t.type_ == track_file.type_)
+                .unwrap_or(false);
+
+            if !reuse {
+                self.tracks
+                    .append(Track::with_uid(track_file.uid, track_file.type_));
+            }
+
+            let track = &mut self.tracks[track_file.uid];
+            track.name = track_file.name;
+            track.colour = track_file.colour;
+            track.mixer <<= track_file.mixer;
+            track.set_instrument(track_file.instrument);
+            track.inserts = track_file.inserts;
+            track.sends = track_file.sends;
+            track.automation = track_file
+                .automation
+                .iter()
+                .filter_map(|lane| lane.from_file(&track.inserts))
+                .collect();
+            track.midi_output = track_file.midi_output;
+
+            let mut clips = vec![];
+            for clip_file in track_file.clips {
+                let midi = pool
+                    .get(clip_file.source)
+                    .ok_or_else(|| format!("Clip refers to missing pool entry {}", clip_file.source))?;
+
+                clips.push(TrackClip {
+                    start: clip_file.start,
+                    offset: clip_file.offset,
+                    length: clip_file.length,
+                    loop_length: clip_file.loop_length,
+                    ..TrackClip::new(0., 0., midi.clone())
+                });
+            }
+            track.set_clips(clips);
+        }
+
+        self.buses = file.buses.into_iter().map(|bus| (bus.uid, bus)).collect();
+
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.path = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    /// Applies `f` to a track's mixer settings, returning whatever `f` does.
+    fn set_track_mixer(
+        &mut self,
+        track_id: TrackId,
+        f: impl FnOnce(&mut MixerSettings) -> Action,
+    ) -> Action {
+        let mixer = &self.tracks[track_id].mixer;
+        let mut settings = mixer.get_copy();
+        let inverse = f(&mut settings);
+        mixer.set(settings);
+        inverse
+    }
+
+    pub fn inserts(&self, strip: StripId) -> &Vec<Insert> {
+        match strip {
+            StripId::Track(track_id) => &self.tracks[track_id].inserts,
+            StripId::Bus(bus_id) => &self.buses[&bus_id].inserts,
+        }
+    }
+
+    fn inserts_mut(&mut self, strip: StripId) -> &mut Vec<Insert> {
+        match strip {
+            StripId::Track(track_id) => &mut self.tracks[track_id].inserts,
+            StripId::Bus(bus_id) => &mut self.buses.get_mut(&bus_id).expect("Bus not found").inserts,
+        }
+    }
+
+    /// The saved parameter values of the insert or instrument with id `id`
+    /// on any track or bus.
+    pub fn params_mut(&mut self, id: InsertId) -> Option<&mut BTreeMap<ParamId, f32>> {
+        for track in self.tracks.tracks.values_mut() {
+            if let Some(i) = track.inserts.iter().position(|insert| insert.id == id) {
+                return Some(&mut track.inserts[i].params);
+            }
+            match track.instrument_mut() {
+                Some(instrument) if instrument.id == id => return Some(&mut instrument.params),
+                _ => (),
+            }
+        }
+
+        self.buses
+            .values_mut()
+            .flat_map(|bus| bus.inserts.iter_mut())
+            .find(|insert| insert.id == id)
+            .map(|insert| &mut insert.params)
+    }
+
+    pub fn sends(&self, strip: StripId) -> &Vec<Send> {
+        match strip {
+            StripId::Track(track_id) => &self.tracks[track_id].sends,
+            StripId::Bus(bus_id) => &self.buses[&bus_id].sends,
+        }
+    }
+
+    fn sends_mut(&mut self, strip: StripId) -> &mut Vec<Send> {
+        match strip {
+            StripId::Track(track_id) => &mut self.tracks[track_id].sends,
+            StripId::Bus(bus_id) => &mut self.buses.get_mut(&bus_id).expect("Bus not found").sends,
+        }
+    }
+
+    pub fn next_bus_id(&self) -> BusId {
+        self.buses.keys().max().map(|id| id + 1).unwrap_or(0)
+    }
+
+    /// Time at which the last clip in the project ends.
+    pub fn end_time(&self) -> Time {
+        self.tracks
+            .tracks
+            .values()
+            .map(|track| track.end_time())
+            .fold(0., Time::max)
+    }
+
+    /// The first clip of the MIDI track with the lowest uid.
+    pub fn first_clip(&self) -> Option<(TrackId, ClipId)> {
+        let mut track_ids: Vec<&TrackId> = self.tracks.tracks.keys().collect();
+        track_ids.sort();
+
+        track_ids.into_iter().find_map(|track_id| {
+            self.tracks[*track_id]
+                .clips()
+                .first()
+                .map(|clip| (*track_id, clip.uid))
+        })
+    }
+
+    pub fn undo(&mut self) {
+        while let Some(action) = self.undo_stack.pop() {
+            self.__perform_action(&action, ActionType::Undo);
+            if !action.undoes_automatically() {
+                return;
             }
         }
     }
@@ -66,8 +281,6 @@ impl Project {
             self.__perform_action(&action, ActionType::Redo);
         }
 
-        println!("{}", self.redo_stack.len());
-
         loop {
             if let Some(action) = self.redo_stack.last() {
                 if !action.undoes_automatically() {
@@ -106,6 +319,7 @@ impl Project {
         let mut inverse: Option<Action> = None;
         match action {
             Action::Group(actions) => {
+                let first_inverse = self.undo_stack.len();
                 for action in actions {
                     // HACK: Hardcoded as normal action so that the inverse actions
                     //       are added to the undo stack.
@@ -116,40 +330,361 @@ impl Project {
                 // and put them in a group.
                 let mut inverse_actions = vec![];
 
+                // Not necessarily one per action, as some may have done
+                // nothing.
                 self.undo_stack
-                    .drain(self.undo_stack.len() - actions.len()..)
+                    .drain(first_inverse..)
                     .for_each(|action| inverse_actions.push(action));
 
+                // Undo in the opposite order so index based actions line up.
+                inverse_actions.reverse();
+
                 inverse = Some(Action::Group(inverse_actions));
             }
             Action::MoveTimeCursor(t) => {
                 inverse = Some(Action::MoveTimeCursor(self.player_time.get_copy()));
                 self.player_time <<= *t;
             }
-            Action::ChangeTempo(new_tempo) => {
-                inverse = Some(Action::ChangeTempo(self.tempo.get_copy()));
-                self.tempo <<= *new_tempo;
+            Action::SetTempoMap(tempo_map) => {
+                inverse = Some(Action::SetTempoMap(self.tempo_map.get_copy()));
+                self.tempo_map <<= tempo_map.clone();
+            }
+            Action::SetMeterMap(meter_map) => {
+                inverse = Some(Action::SetMeterMap(self.meter_map.get_copy()));
+                self.meter_map <<= meter_map.clone();
+            }
+            Action::SetLoopRegion(region) => {
+                inverse = Some(Action::SetLoopRegion(self.loop_region.get_copy()));
+                self.loop_region <<= *region;
             }
-            Action::AddMidiNote { track_id, note } => {
+            Action::SetPunchRegion(region) => {
+                inverse = Some(Action::SetPunchRegion(self.punch_region.get_copy()));
+                self.punch_region <<= *region;
+            }
+            Action::AddMidiNote { track_id, clip_id, note } => {
                 let note_id = self.tracks[*track_id]
-                    .push_note(*note)
-                    .expect("Tried to add MIDI note to non MIDI track");
+                    .push_note(*clip_id, *note)
+                    .expect("Tried to add MIDI note to a clip that was not found.");
                 inverse = Some(Action::RemoveMidiNote {
                     track_id: *track_id,
                     note_id,
                 })
             }
+            Action::SetControllerLanes { track_id, clip_id, lanes } => {
+                let track = &mut self.tracks[*track_id];
+                inverse = Some(Action::SetControllerLanes {
+                    track_id: *track_id,
+                    clip_id: *clip_id,
+                    lanes: track.controllers(*clip_id),
+                });
+                track.set_controllers(*clip_id, lanes.clone());
+            }
             Action::RemoveMidiNote { track_id, note_id } => {
-                let note = self.tracks[*track_id]
-                    .get_note_from_id(*note_id)
-                    .expect("Tried to delete MIDI note that was not found.")
-                    .get_copy();
+                let track = &self.tracks[*track_id];
+                let (note, clip_id) =
+                    match (track.get_note_from_id(*note_id), track.clip_of_note(*note_id)) {
+                        (Some(note), Some(clip_id)) => (note.get_copy(), clip_id),
+                        // Already gone, e.g. with its clip.
+                        _ => return,
+                    };
                 inverse = Some(Action::AddMidiNote {
                     track_id: *track_id,
+                    clip_id,
                     note,
                 });
                 self.tracks[*track_id].remove_note(*note_id);
             },
+            Action::AddClip { track_id, clip } => {
+                inverse = Some(Action::RemoveClip {
+                    track_id: *track_id,
+                    clip_id: clip.uid,
+                });
+                self.tracks[*track_id].set_clip(clip.clone());
+            }
+            Action::RemoveClip { track_id, clip_id } => {
+                let clip = match self.tracks[*track_id].remove_clip(*clip_id) {
+                    Some(clip) => clip,
+                    None => return,
+                };
+                inverse = Some(Action::AddClip {
+                    track_id: *track_id,
+                    clip,
+                });
+                self.prune_selection();
+            }
+            Action::ModifyClip { track_id, clip } => {
+                let old = match self.tracks[*track_id].clip(clip.uid) {
+                    Some(old) => old.clone(),
+                    None => return,
+                };
+                inverse = Some(Action::ModifyClip {
+                    track_id: *track_id,
+                    clip: old,
+                });
+                self.tracks[*track_id].set_clip(clip.clone());
+            }
+            Action::SetTrackGain { track_id, gain_db } => {
+                let gain_db = gain_db.clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+                inverse = Some(self.set_track_mixer(*track_id, |m| {
+                    let inverse = Action::SetTrackGain {
+                        track_id: *track_id,
+                        gain_db: m.gain_db,
+                    };
+                    m.gain_db = gain_db;
+                    inverse
+                }));
+            }
+            Action::SetTrackPan { track_id, pan } => {
+                let pan = pan.clamp(-1., 1.);
+                inverse = Some(self.set_track_mixer(*track_id, |m| {
+                    let inverse = Action::SetTrackPan {
+                        track_id: *track_id,
+                        pan: m.pan,
+                    };
+                    m.pan = pan;
+                    inverse
+                }));
+            }
+            Action::SetTrackMute { track_id, mute } => {
+                inverse = Some(self.set_track_mixer(*track_id, |m| {
+                    let inverse = Action::SetTrackMute {
+                        track_id: *track_id,
+                        mute: m.mute,
+                    };
+                    m.mute = *mute;
+                    inverse
+                }));
+            }
+            Action::SetTrackSolo { track_id, solo } => {
+                inverse = Some(self.set_track_mixer(*track_id, |m| {
+                    let inverse = Action::SetTrackSolo {
+                        track_id: *track_id,
+                        solo: m.solo,
+                    };
+                    m.solo = *solo;
+                    inverse
+                }));
+            }
+            Action::SetInstrument { track_id, instrument } => {
+                let track = &mut self.tracks[*track_id];
+                inverse = Some(Action::SetInstrument {
+                    track_id: *track_id,
+                    instrument: track.instrument().cloned(),
+                });
+                track.set_instrument(instrument.clone());
+            }
+            Action::SetInstrumentParam {
+                track_id,
+                param,
+                value,
+            } => {
+                let instrument = match self.tracks[*track_id].instrument_mut() {
+                    Some(instrument) => instrument,
+                    None => return,
+                };
+                inverse = Some(Action::SetInstrumentParam {
+                    track_id: *track_id,
+                    param: *param,
+                    value: instrument.params.get(param).cloned(),
+                });
+
+                match value {
+                    Some(value) => instrument.params.insert(*param, *value),
+                    None => instrument.params.remove(param),
+                };
+            }
+            Action::SetTrackMidiOutput { track_id, output } => {
+                let track = &mut self.tracks[*track_id];
+                inverse = Some(Action::SetTrackMidiOutput {
+                    track_id: *track_id,
+                    output: track.midi_output.clone(),
+                });
+                track.midi_output = output.clone();
+            }
+            Action::AddInsert {
+                strip,
+                index,
+                insert,
+            } => {
+                self.inserts_mut(*strip).insert(*index, insert.clone());
+                inverse = Some(Action::RemoveInsert {
+                    strip: *strip,
+                    index: *index,
+                });
+            }
+            Action::RemoveInsert { strip, index } => {
+                let insert = self.inserts_mut(*strip).remove(*index);
+                inverse = Some(Action::AddInsert {
+                    strip: *strip,
+                    index: *index,
+                    insert,
+                });
+            }
+            Action::MoveInsert { strip, from, to } => {
+                let inserts = self.inserts_mut(*strip);
+                let insert = inserts.remove(*from);
+                inserts.insert(*to, insert);
+                inverse = Some(Action::MoveInsert {
+                    strip: *strip,
+                    from: *to,
+                    to: *from,
+                });
+            }
+            Action::SetInsertBypass {
+                strip,
+                index,
+                bypass,
+            } => {
+                let insert = &mut self.inserts_mut(*strip)[*index];
+                inverse = Some(Action::SetInsertBypass {
+                    strip: *strip,
+                    index: *index,
+                    bypass: insert.bypass,
+                });
+                insert.bypass = *bypass;
+            }
+            Action::SetInsertSidechain {
+                strip,
+                index,
+                sidechain,
+            } => {
+                let insert = &mut self.inserts_mut(*strip)[*index];
+                inverse = Some(Action::SetInsertSidechain {
+                    strip: *strip,
+                    index: *index,
+                    sidechain: insert.sidechain,
+                });
+                insert.sidechain = *sidechain;
+            }
+            Action::SetInsertParam {
+                strip,
+                index,
+                param,
+                value,
+            } => {
+                let insert = &mut self.inserts_mut(*strip)[*index];
+                inverse = Some(Action::SetInsertParam {
+                    strip: *strip,
+                    index: *index,
+                    param: *param,
+                    value: insert.params.get(param).cloned(),
+                });
+
+                match value {
+                    Some(value) => insert.params.insert(*param, *value),
+                    None => insert.params.remove(param),
+                };
+            }
+            Action::AddAutomationLane {
+                track_id,
+                index,
+                lane,
+            } => {
+                self.tracks[*track_id].automation.insert(*index, lane.clone());
+                inverse = Some(Action::RemoveAutomationLane {
+                    track_id: *track_id,
+                    index: *index,
+                });
+            }
+            Action::RemoveAutomationLane { track_id, index } => {
+                let lane = self.tracks[*track_id].automation.remove(*index);
+                inverse = Some(Action::AddAutomationLane {
+                    track_id: *track_id,
+                    index: *index,
+                    lane,
+                });
+            }
+            Action::AddBreakpoint {
+                track_id,
+                lane,
+                point,
+            } => {
+                let index = self.tracks[*track_id].automation[*lane].add_point(*point);
+                inverse = Some(Action::RemoveBreakpoint {
+                    track_id: *track_id,
+                    lane: *lane,
+                    index,
+                });
+            }
+            Action::RemoveBreakpoint {
+                track_id,
+                lane,
+                index,
+            } => {
+                let point = self.tracks[*track_id].automation[*lane].points.remove(*index);
+                inverse = Some(Action::AddBreakpoint {
+                    track_id: *track_id,
+                    lane: *lane,
+                    point,
+                });
+            }
+            Action::ModifyBreakpoint {
+                track_id,
+                lane,
+                index,
+                point,
+            } => {
+                // Moving a point in time can change its place in the lane.
+                let automation = &mut self.tracks[*track_id].automation[*lane];
+                let old = automation.points.remove(*index);
+                let index = automation.add_point(*point);
+                inverse = Some(Action::ModifyBreakpoint {
+                    track_id: *track_id,
+                    lane: *lane,
+                    index,
+                    point: old,
+                });
+            }
+            Action::AddBus(bus) => {
+                inverse = Some(Action::RemoveBus(bus.uid));
+                self.buses.insert(bus.uid, bus.clone());
+            }
+            Action::RemoveBus(bus_id) => {
+                let bus = self
+                    .buses
+                    .remove(bus_id)
+                    .expect("Tried to remove a bus that doesn't exist");
+                inverse = Some(Action::AddBus(bus));
+            }
+            Action::SetBusMixer { bus_id, mixer } => {
+                let bus = self.buses.get_mut(bus_id).expect("Bus not found");
+                inverse = Some(Action::SetBusMixer {
+                    bus_id: *bus_id,
+                    mixer: bus.mixer,
+                });
+                bus.mixer = *mixer;
+            }
+            Action::AddSend {
+                source,
+                index,
+                send,
+            } => {
+                self.sends_mut(*source).insert(*index, *send);
+                inverse = Some(Action::RemoveSend {
+                    source: *source,
+                    index: *index,
+                });
+            }
+            Action::RemoveSend { source, index } => {
+                let send = self.sends_mut(*source).remove(*index);
+                inverse = Some(Action::AddSend {
+                    source: *source,
+                    index: *index,
+                    send,
+                });
+            }
+            Action::ModifySend {
+                source,
+                index,
+                send,
+            } => {
+                let old = std::mem::replace(&mut self.sends_mut(*source)[*index], *send);
+                inverse = Some(Action::ModifySend {
+                    source: *source,
+                    index: *index,
+                    send: old,
+                });
+            }
             Action::SetSelection(sel) => {
                 inverse = Some(Action::SetSelection(self.selection.get_copy()));
                 self.selection <<= sel.clone();
@@ -163,22 +698,40 @@ impl Project {
                     });
 
                     *note <<= *new_note;
-                } else {
-                    panic!("Tried to modify MIDI note that was not found.");
                 }
             }
         }
 
+        // Actions on things that are already gone do nothing and can't be
+        // undone.
         if let Some(inverse) = inverse {
             self.handle_inverse_action(inverse, type_);
-        } else {
-            panic!();
+        }
+    }
+
+    /// Forgets selected notes that are no longer in their track, e.g. because
+    /// their clip was removed.
+    fn prune_selection(&mut self) {
+        let mut selection = self.selection.get_copy();
+
+        if let Selection::MidiNotes(track_map) = &mut selection {
+            for (track_id, note_ids) in track_map.iter_mut() {
+                match self.tracks.tracks.get(track_id) {
+                    Some(track) => note_ids.retain(|id| track.get_note_from_id(*id).is_some()),
+                    None => note_ids.clear(),
+                }
+            }
+            track_map.retain(|_, note_ids| !note_ids.is_empty());
+        }
+
+        if selection != self.selection.get_copy() {
+            self.selection <<= selection;
         }
     }
 }
 
 #[derive(Clone, Copy)]
-enum ActionType {
+pub enum ActionType {
     Normal,
     Undo,
     Redo,
@@ -188,9 +741,14 @@ pub enum Action {
     Group(Vec<Action>),
     SetSelection(Selection),
     MoveTimeCursor(Time),
-    ChangeTempo(f32),
+    SetTempoMap(TempoMap),
+    SetMeterMap(MeterMap),
+    SetLoopRegion(Region),
+    SetPunchRegion(Region),
     AddMidiNote {
         track_id: u32,
+        clip_id: ClipId,
+        /// In the clip's own time.
         note: Note,
     },
     RemoveMidiNote {
@@ -202,46 +760,222 @@ pub enum Action {
         note_id: ReactiveListKey,
         new_note: Note,
     },
+    SetControllerLanes {
+        track_id: TrackId,
+        clip_id: ClipId,
+        lanes: Vec<ControllerLane>,
+    },
+    AddClip {
+        track_id: TrackId,
+        clip: TrackClip,
+    },
+    RemoveClip {
+        track_id: TrackId,
+        clip_id: ClipId,
+    },
+    /// Replaces the clip with the same uid, e.g. to move or resize it.
+    ModifyClip {
+        track_id: TrackId,
+        clip: TrackClip,
+    },
+    SetTrackGain {
+        track_id: TrackId,
+        gain_db: f32,
+    },
+    SetTrackPan {
+        track_id: TrackId,
+        pan: f32,
+    },
+    SetTrackMute {
+        track_id: TrackId,
+        mute: bool,
+    },
+    SetTrackSolo {
+        track_id: TrackId,
+        solo: bool,
+    },
+    SetInstrument {
+        track_id: TrackId,
+        instrument: Option<Instrument>,
+    },
+    /// `None` goes back to the plugin's default.
+    SetInstrumentParam {
+        track_id: TrackId,
+        param: ParamId,
+        value: Option<f32>,
+    },
+    SetTrackMidiOutput {
+        track_id: TrackId,
+        output: Option<MidiOutputSettings>,
+    },
+    AddInsert {
+        strip: StripId,
+        index: usize,
+        insert: Insert,
+    },
+    RemoveInsert {
+        strip: StripId,
+        index: usize,
+    },
+    MoveInsert {
+        strip: StripId,
+        from: usize,
+        to: usize,
+    },
+    SetInsertBypass {
+        strip: StripId,
+        index: usize,
+        bypass: bool,
+    },
+    SetInsertSidechain {
+        strip: StripId,
+        index: usize,
+        sidechain: Option<TrackId>,
+    },
+    /// `None` goes back to the plugin's default.
+    SetInsertParam {
+        strip: StripId,
+        index: usize,
+        param: ParamId,
+        value: Option<f32>,
+    },
+    AddAutomationLane {
+        track_id: TrackId,
+        index: usize,
+        lane: AutomationLane,
+    },
+    RemoveAutomationLane {
+        track_id: TrackId,
+        index: usize,
+    },
+    /// Breakpoints are kept sorted, so the index is worked out on adding.
+    AddBreakpoint {
+        track_id: TrackId,
+        lane: usize,
+        point: Breakpoint,
+    },
+    RemoveBreakpoint {
+        track_id: TrackId,
+        lane: usize,
+        index: usize,
+    },
+    ModifyBreakpoint {
+        track_id: TrackId,
+        lane: usize,
+        index: usize,
+        point: Breakpoint,
+    },
+    AddBus(Bus),
+    RemoveBus(BusId),
+    SetBusMixer {
+        bus_id: BusId,
+        mixer: MixerSettings,
+    },
+    AddSend {
+        source: StripId,
+        index: usize,
+        send: Send,
+    },
+    RemoveSend {
+        source: StripId,
+        index: usize,
+    },
+    ModifySend {
+        source: StripId,
+        index: usize,
+        send: Send,
+    },
 }
 
 impl Action {
     fn undoes_automatically(&self) -> bool {
-        match self {
-            Action::MoveTimeCursor(_) => true,
-            Action::SetSelection(_) => true,
-            _ => false,
+        matches!(self, Action::MoveTimeCursor(_) | Action::SetSelection(_))
+    }
+}
+
+/// A stretch of the timeline that can be switched on and off, such as the
+/// loop or punch range.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Region {
+    pub start: Time,
+    pub end: Time,
+    pub enabled: bool,
+}
+
+impl Default for Region {
+    fn default() -> Self {
+        Self {
+            start: 0.,
+            end: 16.,
+            enabled: false,
         }
     }
 }
 
-#[derive(Clone, Copy)]
+impl Region {
+    /// `(start, end)` if the region is enabled and not empty.
+    pub fn active(&self) -> Option<(Time, Time)> {
+        if self.enabled && self.end > self.start {
+            Some((self.start, self.end))
+        } else {
+            None
+        }
+    }
+
+    /// Whether `t` is inside the region, or the region is switched off.
+    pub fn allows(&self, t: Time) -> bool {
+        match self.active() {
+            Some((start, end)) => t >= start && t < end,
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub struct TimeSignature {
     numerator: u32,
     denominator: u32,
 }
 
 impl TimeSignature {
-    pub fn common() -> Self {
+    pub fn new(numerator: u32, denominator: u32) -> Self {
         Self {
-            numerator: 4,
-            denominator: 4,
+            numerator,
+            denominator,
         }
     }
 
-    pub fn beats_per_measure(&self) -> u32 {
-        // FIXME: This is probably very wrong but it works for now.
-        //        I guess this is quarter notes per bar?
-        self.numerator / (self.denominator / 4)
+    pub fn common() -> Self {
+        Self::new(4, 4)
+    }
+
+    pub fn numerator(&self) -> u32 {
+        self.numerator
+    }
+
+    pub fn denominator(&self) -> u32 {
+        self.denominator
+    }
+
+    /// Length of a bar in beats, which are always quarter notes.
+    pub fn beats_per_measure(&self) -> Time {
+        self.numerator as Time * self.beat_length()
+    }
+
+    /// Length in quarter note beats of the note value the signature counts,
+    /// e.g. 0.5 for 7/8.
+    pub fn beat_length(&self) -> Time {
+        4. / self.denominator.max(1) as Time
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct KeySignature {
     pub root: u32,
     pub mode: KeyMode,
 }
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub enum KeyMode {
     Major,
     Minor,
@@ -257,7 +991,7 @@ impl KeySignature {
     }
 
     pub fn from_degree(&self, degree: i32, octave: i32) -> u32 {
-        let degree: i32 = degree as i32
+        let degree: i32 = degree
             - match self.mode {
                 KeyMode::Major => 0,
                 KeyMode::Minor => 2,
@@ -272,7 +1006,7 @@ impl KeySignature {
             octave_offset -= 1;
         }
 
-        octave_offset += ((degree - 1) / 7) as i32;
+        octave_offset += (degree - 1) / 7;
         degree = (degree - 1) % 7 + 1;
 
         let mut note = match degree {
@@ -319,3 +1053,138 @@ pub struct Degree {
     pub degree: i32,
     pub octave: i32,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+
+    fn note(start: Time) -> Note {
+        Note {
+            note: 60,
+            velocity: 100,
+            start,
+            length: 1.,
+        }
+    }
+
+    #[test]
+    fn removing_a_clip_forgets_its_selected_notes() {
+        let mut project = Project::new();
+        let (track_id, clip_id) = project.first_clip().unwrap();
+        let note_id = project.tracks[track_id].push_note(clip_id, note(0.)).unwrap();
+
+        project.selection <<= Selection::MidiNotes(HashMap::from([(
+            track_id,
+            HashSet::from([note_id]),
+        )]));
+        project.perform_action(Action::RemoveClip { track_id, clip_id });
+
+        assert!(project.selection.get_copy() == Selection::MidiNotes(HashMap::new()));
+
+        // Acting on the note now does nothing rather than panicking.
+        project.perform_action(Action::Group(vec![
+            Action::RemoveMidiNote { track_id, note_id },
+            Action::ModifyMidiNote {
+                track_id,
+                note_id,
+                new_note: note(1.),
+            },
+        ]));
+
+        project.undo();
+        project.undo();
+        assert!(project.tracks[track_id].get_note_from_id(note_id).is_some());
+    }
+
+    #[test]
+    fn instrument_params_are_undoable_and_saved() {
+        let mut project = Project::new();
+        let (track_id, _) = project.first_clip().unwrap();
+
+        project.perform_action(Action::SetInstrumentParam {
+            track_id,
+            param: 2,
+            value: Some(0.5),
+        });
+        let instrument = project.tracks[track_id].instrument().unwrap().clone();
+        assert_eq!(instrument.params.get(&2), Some(&0.5));
+        assert!(project.params_mut(instrument.id).is_some());
+
+        let saved = serde_json::to_string(&instrument).unwrap();
+        let loaded: Instrument = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.params, instrument.params);
+
+        project.undo();
+        assert!(project.tracks[track_id].instrument().unwrap().params.is_empty());
+    }
+
+    #[test]
+    fn undoing_an_added_clip_forgets_its_selected_notes() {
+        let mut project = Project::new();
+        let (track_id, _) = project.first_clip().unwrap();
+
+        let clip = TrackClip::empty(16.);
+        let clip_id = clip.uid;
+        project.perform_action(Action::AddClip { track_id, clip });
+        let note_id = project.tracks[track_id].push_note(clip_id, note(0.)).unwrap();
+
+        project.selection <<= Selection::MidiNotes(HashMap::from([(
+            track_id,
+            HashSet::from([note_id]),
+        )]));
+        project.undo();
+
+        assert!(project.tracks[track_id].clip(clip_id).is_none());
+        assert!(!project.selection.get_copy().is_note_selected(track_id, note_id));
+    }
+
+    #[test]
+    fn regions_only_limit_time_while_enabled() {
+        let mut region = Region {
+            start: 4.,
+            end: 8.,
+            enabled: false,
+        };
+        assert_eq!(region.active(), None);
+        assert!(region.allows(0.));
+
+        region.enabled = true;
+        assert_eq!(region.active(), Some((4., 8.)));
+        assert!(!region.allows(3.9));
+        assert!(region.allows(4.));
+        assert!(!region.allows(8.));
+
+        region.end = region.start;
+        assert_eq!(region.active(), None);
+        assert!(region.allows(100.));
+    }
+
+    #[test]
+    fn regions_are_undoable_and_saved() {
+        let mut project = Project::new();
+        let loop_region = Region {
+            start: 2.,
+            end: 6.,
+            enabled: true,
+        };
+        let punch_region = Region {
+            start: 3.,
+            end: 5.,
+            enabled: false,
+        };
+        project.perform_action(Action::SetLoopRegion(loop_region));
+        project.perform_action(Action::SetPunchRegion(punch_region));
+
+        let saved = ProjectFile::from_project(&project).to_string().unwrap();
+        let loaded = ProjectFile::from_str(&saved).unwrap();
+        assert_eq!(loaded.loop_region, loop_region);
+        assert_eq!(loaded.punch_region, punch_region);
+
+        project.undo();
+        project.undo();
+        assert_eq!(project.loop_region.get_copy(), Region::default());
+        assert_eq!(project.punch_region.get_copy(), Region::default());
+    }
+}
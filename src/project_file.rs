@@ -0,0 +1,415 @@
+//! On-disk project format.
+//!
+//! Projects are saved as JSON with the following layout:
+//!
+//! ```text
+//! {
+//!     "version": 3,
+//!     "meta": { "name": "...", "description": "...", "version": "..." },
+//!     "tempo_map": { "changes": [ { "time": 0.0, "tempo": 120.0, "ramp": false } ] },
+//!     "key_signature": { "root": 0, "mode": "Major" },
+//!     "meter_map": { "changes": [ { "bar": 1, "time_signature": { "numerator": 4, "denominator": 4 } } ] },
+//!     "loop_region": { "start": 0.0, "end": 16.0, "enabled": false },
+//!     "punch_region": { "start": 0.0, "end": 16.0, "enabled": false },
+//!     "metronome": { "enabled": false, "level_db": -6.0, "sound": "Beep", "count_in_bars": 1 },
+//!     // Or a sample: "sound": { "Sample": "click.wav" }
+//!     "tracks": [
+//!         {
+//!             "uid": 0,
+//!             "name": "...",
+//!             "colour": { "r": 1.0, "g": 1.0, "b": 1.0, "a": 1.0 },
+//!             "type_": "Midi",
+//!             // "source" is the clip's notes' position in "pool". Linked
+//!             // copies share one.
+//!             "clips": [ { "source": 0, "start": 0.0, "offset": 0.0, "length": 16.0,
+//!                          "loop_length": 4.0 } ],
+//!             "mixer": { "gain_db": 0.0, "pan": 0.0, "mute": false, "solo": false },
+//!             "instrument": { "plugin": { "name": "...", "path": "...", "type_": "Vst2", "instrument": true } },
+//!             // The built-in sampler also saves its kit:
+//!             // "instrument": { "plugin": { ..., "type_": { "Builtin": "Sampler" } },
+//!             //                 "kit": { "pads": [ { "path": "kick.wav", "low_note": 36, ... } ] } },
+//!             "inserts": [ { "plugin": { ... }, "bypass": false } ],
+//!             // The instrument and inserts save parameters changed from their
+//!             // defaults, by id: "params": { "2": 0.5 }
+//!             // Effects keyed by another track also save its uid:
+//!             // "inserts": [ { "plugin": { ..., "type_": { "Builtin": "Compressor" } },
+//!             //                "bypass": false, "sidechain": 1 } ],
+//!             "sends": [ { "target": 0, "level_db": -6.0, "pre_fader": false } ],
+//!             // Param targets name the insert by its position in "inserts",
+//!             // "InstrumentParam": { "param": 2 } targets the instrument's:
+//!             "automation": [ { "target": { "Param": { "insert": 0, "param": 2 } },
+//!                               "points": [ { "time": 0.0, "value": 0.5, "curve": "Linear" } ] } ],
+//!             // Tracks driving external gear also save where to:
+//!             // "midi_output": { "port": "...", "virtual_port": false, "channel": 0, "offset_ms": 0.0 }
+//!         }
+//!     ],
+//!     "buses": [
+//!         { "uid": 0, "name": "...", "mixer": { ... }, "inserts": [ ... ], "sends": [ ... ] }
+//!     ],
+//!     "pool": [
+//!         {
+//!             // Times are from the start of the clip's notes.
+//!             "notes": [ { "note": 60, "velocity": 100, "start": 0.0, "length": 1.0 } ],
+//!             "controllers": [ { "controller": { "ControlChange": 1 }, "channel": 0,
+//!                                "points": [ { "time": 0.0, "value": 64 } ] } ]
+//!             // Other controllers: "PitchBend", "ChannelAftertouch",
+//!             // { "PolyAftertouch": 60 } and "Program".
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! `version` is bumped whenever the layout changes. Files are checked by
+//! `migrate` before being deserialised; upgrades from older layouts go there
+//! so loading never has to deal with more than the current layout.
+
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::metronome::MetronomeSettings,
+    automation::AutomationLane,
+    clip::TrackClip,
+    midi::{ControllerLane, MidiClip, Note, Time},
+    midi_output::MidiOutputSettings,
+    mixer::MixerSettings,
+    routing::{Bus, Send},
+    tempo_map::{MeterMap, TempoMap},
+    project::{KeySignature, Project, ProjectMeta, Region},
+    track::{Insert, Instrument, Track, TrackId, TrackType},
+    ui::style::Colour,
+};
+
+pub const PROJECT_FILE_VERSION: u32 = 3;
+pub const PROJECT_FILE_EXTENSION: &str = "dawproj";
+
+#[derive(Serialize, Deserialize)]
+pub struct ProjectFile {
+    pub version: u32,
+    pub meta: ProjectMeta,
+    pub tempo_map: TempoMap,
+    pub key_signature: KeySignature,
+    pub meter_map: MeterMap,
+    #[serde(default)]
+    pub loop_region: Region,
+    #[serde(default)]
+    pub punch_region: Region,
+    #[serde(default)]
+    pub metronome: MetronomeSettings,
+    pub tracks: Vec<TrackFile>,
+    #[serde(default)]
+    pub buses: Vec<Bus>,
+    #[serde(default)]
+    pub pool: Vec<PooledClip>,
+}
+
+/// Notes shared by every clip made from them.
+#[derive(Serialize, Deserialize)]
+pub struct PooledClip {
+    #[serde(default)]
+    pub notes: Vec<Note>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub controllers: Vec<ControllerLane>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClipFile {
+    /// Index into the pool.
+    pub source: usize,
+    pub start: Time,
+    #[serde(default)]
+    pub offset: Time,
+    pub length: Time,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_length: Option<Time>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrackFile {
+    pub uid: TrackId,
+    pub name: String,
+    pub colour: Colour,
+    pub type_: TrackType,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clips: Vec<ClipFile>,
+    #[serde(default)]
+    pub mixer: MixerSettings,
+    #[serde(default)]
+    pub instrument: Option<Instrument>,
+    #[serde(default)]
+    pub inserts: Vec<Insert>,
+    #[serde(default)]
+    pub sends: Vec<Send>,
+    #[serde(default)]
+    pub automation: Vec<AutomationLane>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub midi_output: Option<MidiOutputSettings>,
+}
+
+impl ProjectFile {
+    pub fn from_project(project: &Project) -> Self {
+        let mut track_ids: Vec<&TrackId> = project.tracks.tracks.keys().collect();
+        track_ids.sort();
+
+        let mut pool: Vec<MidiClip> = vec![];
+        let tracks = track_ids
+            .into_iter()
+            .map(|track_id| TrackFile::from_track(&project.tracks[*track_id], &mut pool))
+            .collect();
+
+        Self {
+            version: PROJECT_FILE_VERSION,
+            meta: project.meta.clone(),
+            tempo_map: project.tempo_map.get_copy(),
+            key_signature: project.key_signature.get_copy(),
+            meter_map: project.meter_map.get_copy(),
+            loop_region: project.loop_region.get_copy(),
+            punch_region: project.punch_region.get_copy(),
+            metronome: project.metronome.get_copy(),
+            tracks,
+            buses: project.buses.values().cloned().collect(),
+            pool: pool.iter().map(PooledClip::from_midi).collect(),
+        }
+    }
+
+    pub fn to_string(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+
+    pub fn from_str(s: &str) -> Result<Self, String> {
+        let value: serde_json::Value = serde_json::from_str(s).map_err(|e| e.to_string())?;
+        let value = migrate(value)?;
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let contents = self.to_string()?;
+
+        // Write to a temporary file first so a crash mid-save doesn't
+        // clobber the previous good copy.
+        let tmp_path = path.with_extension(format!("{}.tmp", PROJECT_FILE_EXTENSION));
+        fs::write(&tmp_path, contents).map_err(|e| e.to_string())?;
+        fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_str(&contents)
+    }
+}
+
+impl PooledClip {
+    fn from_midi(midi: &MidiClip) -> Self {
+        Self {
+            notes: midi
+                .notes
+                .copy_of_whole_list()
+                .into_iter()
+                .map(|(_, note)| note.get_copy())
+                .collect(),
+            controllers: midi.controllers(),
+        }
+    }
+}
+
+impl ClipFile {
+    /// Adds the clip's notes to `pool` unless a linked copy already did.
+    fn from_clip(clip: &TrackClip, pool: &mut Vec<MidiClip>) -> Self {
+        let source = match pool.iter().position(|midi| midi.shares_notes_with(&clip.midi)) {
+            Some(i) => i,
+            None => {
+                pool.push(clip.midi.clone());
+                pool.len() - 1
+            }
+        };
+
+        Self {
+            source,
+            start: clip.start,
+            offset: clip.offset,
+            length: clip.length,
+            loop_length: clip.loop_length,
+        }
+    }
+}
+
+impl TrackFile {
+    fn from_track(track: &Track, pool: &mut Vec<MidiClip>) -> Self {
+        Self {
+            uid: track.uid,
+            name: track.name.clone(),
+            colour: track.colour,
+            type_: track.type_,
+            clips: track
+                .clips()
+                .iter()
+                .map(|clip| ClipFile::from_clip(clip, pool))
+                .collect(),
+            mixer: track.mixer.get_copy(),
+            instrument: track.instrument().cloned(),
+            inserts: track.inserts.clone(),
+            sends: track.sends.clone(),
+            automation: track
+                .automation
+                .iter()
+                .filter_map(|lane| lane.to_file(&track.inserts))
+                .collect(),
+            midi_output: track.midi_output.clone(),
+        }
+    }
+}
+
+/// Upgrades a parsed project file of any supported version to the layout of
+/// `PROJECT_FILE_VERSION`.
+fn migrate(value: serde_json::Value) -> Result<serde_json::Value, String> {
+    let version = value
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| "Project file has no version".to_string())? as u32;
+
+    if version > PROJECT_FILE_VERSION {
+        return Err(format!(
+            "Project file version {} is newer than the supported version {}",
+            version, PROJECT_FILE_VERSION
+        ));
+    }
+
+    match version {
+        PROJECT_FILE_VERSION => Ok(value),
+        _ => Err(format!("Unsupported project file version {}", version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        audio::audio_processor::{BuiltinPlugin, PluginDescription},
+        project::{KeyMode, TimeSignature},
+        tempo_map::{MeterChange, TempoChange},
+    };
+
+    use super::*;
+
+    fn note(note: u32, velocity: u32, start: Time) -> Note {
+        Note {
+            note,
+            velocity,
+            start,
+            length: 0.5,
+        }
+    }
+
+    #[test]
+    fn a_saved_project_loads_back_the_same() {
+        let mut project = Project::new();
+        project.meta.name = "Round trip".to_string();
+        project.meta.description = "Everything a project saves".to_string();
+
+        let mut tempo_map = TempoMap::new(100.);
+        tempo_map.insert(TempoChange {
+            time: 8.,
+            tempo: 140.,
+            ramp: true,
+        });
+        project.tempo_map <<= tempo_map.clone();
+
+        let mut meter_map = MeterMap::new(TimeSignature::common());
+        meter_map.insert(MeterChange {
+            bar: 3,
+            time_signature: TimeSignature::new(7, 8),
+        });
+        project.meter_map <<= meter_map.clone();
+        project.key_signature <<= KeySignature::new(9, KeyMode::Minor);
+
+        let mut bus = Bus::new(0, "Reverb".to_string());
+        bus.inserts.push(Insert::new(PluginDescription::builtin(BuiltinPlugin::Reverb)));
+        project.buses.insert(bus.uid, bus);
+
+        let (track_id, clip_id) = project.first_clip().unwrap();
+        let track = &mut project.tracks[track_id];
+        track.name = "Lead".to_string();
+        track.mixer <<= MixerSettings {
+            gain_db: -3.,
+            pan: 0.25,
+            mute: false,
+            solo: true,
+        };
+        track.instrument_mut().unwrap().params.insert(4, 0.75);
+        let mut insert = Insert::new(PluginDescription::builtin(BuiltinPlugin::Delay));
+        insert.bypass = true;
+        insert.params.insert(1, 0.3);
+        track.inserts.push(insert);
+        track.sends.push(Send {
+            target: 0,
+            level_db: -6.,
+            pre_fader: true,
+        });
+        track.push_note(clip_id, note(60, 100, 0.));
+        track.push_note(clip_id, note(64, 80, 1.5));
+        let linked = track.clip(clip_id).unwrap().linked_copy(16.);
+        track.set_clip(linked);
+
+        let path = std::env::temp_dir().join(format!(
+            "round_trip_{}.{}",
+            std::process::id(),
+            PROJECT_FILE_EXTENSION
+        ));
+        project.save(&path).unwrap();
+        let mut loaded = Project::new();
+        let result = loaded.load(&path);
+        let _ = fs::remove_file(&path);
+        result.unwrap();
+
+        assert_eq!(loaded.meta.name, "Round trip");
+        assert_eq!(loaded.meta.description, "Everything a project saves");
+        assert_eq!(loaded.tempo_map.get_copy(), tempo_map);
+        assert_eq!(loaded.meter_map.get_copy(), meter_map);
+        assert!(loaded.key_signature.get_copy() == KeySignature::new(9, KeyMode::Minor));
+
+        let bus = &loaded.buses[&0];
+        assert_eq!(bus.name, "Reverb");
+        assert!(bus.inserts[0].plugin == PluginDescription::builtin(BuiltinPlugin::Reverb));
+
+        let track = &loaded.tracks[track_id];
+        assert_eq!(track.name, "Lead");
+        assert_eq!(track.mixer.get_copy().pan, 0.25);
+        assert!(track.mixer.get_copy().solo);
+        let instrument = track.instrument().unwrap();
+        assert!(instrument.plugin == PluginDescription::builtin(BuiltinPlugin::Synth));
+        assert_eq!(instrument.params.get(&4), Some(&0.75));
+        assert!(track.inserts[0].plugin == PluginDescription::builtin(BuiltinPlugin::Delay));
+        assert!(track.inserts[0].bypass);
+        assert_eq!(track.inserts[0].params.get(&1), Some(&0.3));
+        assert_eq!(
+            track.sends,
+            vec![Send {
+                target: 0,
+                level_db: -6.,
+                pre_fader: true,
+            }]
+        );
+
+        let clips = track.clips();
+        assert_eq!(clips.len(), 2);
+        assert_eq!(clips[1].start, 16.);
+        assert!(clips[0].midi.shares_notes_with(&clips[1].midi));
+        let notes: Vec<(u32, u32, Time)> = clips[0]
+            .midi
+            .notes
+            .copy_of_whole_list()
+            .into_iter()
+            .map(|(_, n)| {
+                let n = n.get_copy();
+                (n.note, n.velocity, n.start)
+            })
+            .collect();
+        assert_eq!(notes, vec![(60, 100, 0.), (64, 80, 1.5)]);
+
+        // Anything not checked above comes back too.
+        assert_eq!(
+            ProjectFile::from_project(&loaded).to_string(),
+            ProjectFile::from_project(&project).to_string()
+        );
+    }
+}
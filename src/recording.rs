@@ -0,0 +1,454 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    event_subscriptions::MidiInputEvent,
+    global::{Globals, PlayingState},
+    clip::{fitted_length, TrackClip},
+    midi::{Controller, ControllerLane, ControllerPoint, MidiEvent, MidiEventData, Note, Time},
+    project::{Action, Region},
+    track::{Track, TrackData, TrackId},
+};
+
+/// Shortest note a quick press and release is recorded as.
+const MIN_NOTE_LENGTH: Time = 1. / 64.;
+
+/// Notes played into the armed track during one pass of recording. They're
+/// added to the clip playing where recording started, or a new clip if there
+/// isn't one, in one undoable step once recording stops.
+pub struct Take {
+    track_id: TrackId,
+    /// Where recording started. Nothing lands before it, e.g. notes played
+    /// during the count-in.
+    start: Time,
+    /// The latest time anything was recorded at or played through. Unlike the
+    /// play position it doesn't go back when the loop wraps.
+    furthest: Time,
+    /// Notes still held down, with when they started and their velocity.
+    held: HashMap<u32, (Time, u32)>,
+    notes: Vec<Note>,
+    controllers: Vec<ControllerLane>,
+}
+
+/// The armed track, if it's a MIDI track.
+pub fn armed_track(globals: &Globals) -> Option<TrackId> {
+    let track_id = globals.armed_track?;
+
+    match &globals.loaded_project.tracks.tracks.get(&track_id)?.data {
+        TrackData::Midi(..) => Some(track_id),
+        _ => None,
+    }
+}
+
+/// Adds notes from MIDI input to the take while recording, inside the punch
+/// range if it's on.
+pub fn record_midi_input(globals: &mut Globals) {
+    globals.subscriptions.subscribe_midi_input(Rc::new(RefCell::new(
+        |event: &MidiInputEvent, globals: &mut Globals| {
+            let midi_event = match event.to_midi_event() {
+                Some(midi_event) => midi_event,
+                None => return,
+            };
+
+            let t = input_time(globals, event);
+            let punch_region = globals.loaded_project.punch_region.get_copy();
+            let loop_end = loop_end(globals);
+
+            if let Some(take) = &mut globals.take {
+                take.record(&midi_event, t, &punch_region, loop_end);
+            }
+        },
+    )));
+}
+
+/// Starts a take when recording starts and adds it to its track when it
+/// stops. Call once per frame, before MIDI input is handled so nothing
+/// played in the frame recording starts is missed.
+pub fn update_recording(globals: &mut Globals) {
+    let recording = globals.playing_state == PlayingState::Recording;
+    let now = globals.loaded_project.player_time.get_copy();
+
+    match (recording, &mut globals.take) {
+        (true, Some(take)) => take.furthest = take.furthest.max(now),
+        (true, None) => {
+            if let Some(track_id) = armed_track(globals) {
+                globals.take = Some(Take::new(track_id, now));
+            }
+        }
+        (false, Some(_)) => finish_take(globals),
+        (false, None) => (),
+    }
+}
+
+fn finish_take(globals: &mut Globals) {
+    let take = match globals.take.take() {
+        Some(take) => take,
+        None => return,
+    };
+
+    let end = globals.loaded_project.player_time.get_copy();
+    let loop_end = loop_end(globals);
+
+    let track = match globals.loaded_project.tracks.tracks.get(&take.track_id) {
+        Some(track) => track,
+        None => return,
+    };
+
+    let actions = take.finish(track, end, loop_end, globals.input_quantize);
+    if !actions.is_empty() {
+        globals.loaded_project.perform_action(Action::Group(actions));
+    }
+}
+
+fn loop_end(globals: &Globals) -> Option<Time> {
+    globals
+        .loaded_project
+        .loop_region
+        .get_copy()
+        .active()
+        .map(|(_, end)| end)
+}
+
+/// Adds the points of `recorded` to the lane in `lanes` for the same
+/// controller, replacing what was there over the time it covers.
+fn merge_lane(lanes: &mut Vec<ControllerLane>, recorded: ControllerLane) {
+    let lane = match lanes
+        .iter_mut()
+        .find(|lane| lane.controller == recorded.controller && lane.channel == recorded.channel)
+    {
+        Some(lane) => lane,
+        None => {
+            lanes.push(recorded);
+            return;
+        }
+    };
+
+    if let (Some(first), Some(last)) = (recorded.points.first(), recorded.points.last()) {
+        let (start, end) = (first.time, last.time);
+        lane.points.retain(|p| p.time < start || p.time > end);
+    }
+
+    for point in recorded.points {
+        lane.insert(point);
+    }
+}
+
+impl Take {
+    fn new(track_id: TrackId, start: Time) -> Self {
+        Self {
+            track_id,
+            start,
+            furthest: start,
+            held: HashMap::new(),
+            notes: vec![],
+            controllers: vec![],
+        }
+    }
+
+    /// Adds `event`, played at `t`. Anything played before the take started,
+    /// e.g. during the count-in, is dropped.
+    fn record(&mut self, event: &MidiEvent, t: Time, punch_region: &Region, loop_end: Option<Time>) {
+        if t < self.start {
+            return;
+        }
+        self.furthest = self.furthest.max(t);
+
+        if let Some((controller, value)) = Controller::of(&event.data) {
+            if punch_region.allows(t) {
+                self.record_controller(controller, event.channel, t, value);
+            }
+            return;
+        }
+
+        match event.data {
+            MidiEventData::NoteOn { note }
+                if punch_region.allows(t) => {
+                    self.held.insert(note.note, (t, note.velocity));
+                }
+            MidiEventData::NoteOff { note } => {
+                if let Some((start, velocity)) = self.held.remove(&note.note) {
+                    self.notes
+                        .push(note_between(note.note, velocity, start, t, loop_end));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Ends notes still held at `end` and returns the actions adding the take
+    /// to `track`, which it was recorded into. Empty if nothing was played.
+    fn finish(
+        mut self,
+        track: &Track,
+        end: Time,
+        loop_end: Option<Time>,
+        quantize: Option<Time>,
+    ) -> Vec<Action> {
+        for (note, (start, velocity)) in self.held.drain() {
+            self.notes
+                .push(note_between(note, velocity, start, end, loop_end));
+        }
+
+        if self.notes.is_empty() && self.controllers.is_empty() {
+            return vec![];
+        }
+
+        // Quantizing doesn't move notes before where recording started, so
+        // they all land in the clip.
+        if let Some(grid) = quantize {
+            for note in &mut self.notes {
+                note.start = ((note.start / grid).round() * grid).max(self.start);
+            }
+        }
+
+        let furthest = self
+            .notes
+            .iter()
+            .map(|note| note.start + note.length)
+            .fold(self.furthest.max(end), Time::max);
+
+        let track_id = self.track_id;
+        let mut actions = vec![];
+
+        let clip = match track.clip_at(self.start) {
+            Some(clip) => {
+                let local_end = clip.local_time(self.start) + furthest - self.start;
+                if let Some(grown) = clip.grown_to_fit(local_end) {
+                    actions.push(Action::ModifyClip {
+                        track_id,
+                        clip: grown,
+                    });
+                }
+                clip.clone()
+            }
+            None => {
+                let clip = TrackClip {
+                    length: fitted_length(furthest - self.start),
+                    ..TrackClip::empty(self.start)
+                };
+                actions.push(Action::AddClip {
+                    track_id,
+                    clip: clip.clone(),
+                });
+                clip
+            }
+        };
+
+        for mut note in self.notes {
+            note.start = clip.local_time(note.start);
+
+            actions.push(Action::AddMidiNote {
+                track_id,
+                clip_id: clip.uid,
+                note,
+            });
+        }
+
+        if !self.controllers.is_empty() {
+            let mut lanes = clip.midi.controllers();
+            for mut recorded in self.controllers {
+                for point in &mut recorded.points {
+                    point.time = clip.local_time(point.time);
+                }
+                merge_lane(&mut lanes, recorded);
+            }
+
+            actions.push(Action::SetControllerLanes {
+                track_id,
+                clip_id: clip.uid,
+                lanes,
+            });
+        }
+
+        actions
+    }
+
+    fn record_controller(&mut self, controller: Controller, channel: u8, time: Time, value: i32) {
+        let lane = match self
+            .controllers
+            .iter_mut()
+            .position(|lane| lane.controller == controller && lane.channel == channel)
+        {
+            Some(i) => &mut self.controllers[i],
+            None => {
+                self.controllers.push(ControllerLane::new(controller, channel));
+                self.controllers.last_mut().unwrap()
+            }
+        };
+
+        lane.insert(ControllerPoint { time, value });
+    }
+}
+
+/// Where on the timeline `event` was played, allowing for the time it took
+/// to reach the UI thread.
+fn input_time(globals: &Globals, event: &MidiInputEvent) -> Time {
+    let tempo_map = globals.loaded_project.tempo_map.get_copy();
+    let now = tempo_map.beats_to_seconds(globals.loaded_project.player_time.get_copy());
+    let age = event.received.elapsed().as_secs_f64();
+
+    tempo_map.seconds_to_beats(now - age)
+}
+
+/// A note held from `start` to `end`. If `end` comes first the loop wrapped
+/// while it was held, so it's cut off at the loop end.
+fn note_between(note: u32, velocity: u32, start: Time, end: Time, loop_end: Option<Time>) -> Note {
+    let length = if end > start {
+        end - start
+    } else {
+        loop_end.map(|loop_end| loop_end - start).unwrap_or(0.)
+    };
+
+    Note {
+        note,
+        velocity,
+        start,
+        length: length.max(MIN_NOTE_LENGTH),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        midi::{MidiClip, NoteEvent},
+        project::Region,
+        track::TrackType,
+    };
+
+    use super::*;
+
+    fn event(data: MidiEventData) -> MidiEvent {
+        MidiEvent {
+            time: 0.,
+            delta_frames: 0,
+            channel: 0,
+            data,
+        }
+    }
+
+    fn note_on(note: u32) -> MidiEvent {
+        event(MidiEventData::NoteOn {
+            note: NoteEvent { note, velocity: 100 },
+        })
+    }
+
+    fn note_off(note: u32) -> MidiEvent {
+        event(MidiEventData::NoteOff {
+            note: NoteEvent { note, velocity: 0 },
+        })
+    }
+
+    fn mod_wheel(value: u32) -> MidiEvent {
+        event(MidiEventData::ControlChange {
+            controller: 1,
+            value,
+        })
+    }
+
+    fn track_without_clips() -> Track {
+        let mut track = Track::new(TrackType::Midi);
+        track.set_clips(vec![]);
+        track
+    }
+
+    fn added_clip(actions: &[Action]) -> TrackClip {
+        actions
+            .iter()
+            .find_map(|action| match action {
+                Action::AddClip { clip, .. } => Some(clip.clone()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn added_notes(actions: &[Action]) -> Vec<Note> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::AddMidiNote { note, .. } => Some(*note),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn notes_in_the_count_in_are_dropped() {
+        let punch = Region::default();
+        let mut take = Take::new(0, 4.);
+
+        take.record(&note_on(60), 3.5, &punch, None);
+        take.record(&note_off(60), 4.2, &punch, None);
+        take.record(&note_on(62), 4., &punch, None);
+        take.record(&note_off(62), 5., &punch, None);
+
+        let notes = added_notes(&take.finish(&track_without_clips(), 6., None, None));
+        assert_eq!(notes.len(), 1);
+        assert_eq!((notes[0].note, notes[0].start, notes[0].length), (62, 0., 1.));
+    }
+
+    #[test]
+    fn a_loop_wrap_keeps_the_clip_long_enough() {
+        let punch = Region::default();
+        let mut take = Take::new(0, 0.);
+
+        take.record(&note_on(60), 30., &punch, Some(32.));
+        // As `update_recording` does while the play position passes by.
+        take.furthest = 31.9;
+        // The loop wrapped back to 0 while the note was held.
+        take.record(&note_off(60), 1., &punch, Some(32.));
+
+        let actions = take.finish(&track_without_clips(), 2., Some(32.), None);
+        assert_eq!(added_clip(&actions).length, 32.);
+
+        let notes = added_notes(&actions);
+        assert_eq!((notes[0].start, notes[0].length), (30., 2.));
+    }
+
+    #[test]
+    fn quantizing_keeps_notes_inside_the_clip() {
+        let punch = Region::default();
+        let mut take = Take::new(0, 4.25);
+
+        take.record(&note_on(60), 4.3, &punch, None);
+        take.record(&note_off(60), 4.6, &punch, None);
+        take.record(&note_on(62), 5.9, &punch, None);
+        take.record(&note_off(62), 6.2, &punch, None);
+
+        let actions = take.finish(&track_without_clips(), 7., None, Some(1.));
+        assert_eq!(added_clip(&actions).start, 4.25);
+
+        let starts: Vec<Time> = added_notes(&actions).iter().map(|n| n.start).collect();
+        assert_eq!(starts, vec![0., 1.75]);
+    }
+
+    #[test]
+    fn overdubbed_controllers_replace_only_the_time_they_cover() {
+        let mut existing = ControllerLane::new(Controller::ControlChange(1), 0);
+        for (time, value) in [(0., 10), (2., 20), (4., 30)] {
+            existing.insert(ControllerPoint { time, value });
+        }
+        let mut midi = MidiClip::new();
+        midi.set_controllers(vec![existing]);
+
+        let mut track = track_without_clips();
+        track.set_clips(vec![TrackClip::new(0., 16., midi)]);
+
+        let punch = Region::default();
+        let mut take = Take::new(0, 1.);
+        take.record(&mod_wheel(100), 1.5, &punch, None);
+        take.record(&mod_wheel(110), 2.5, &punch, None);
+
+        let actions = take.finish(&track, 3., None, None);
+        let lanes = actions
+            .iter()
+            .find_map(|action| match action {
+                Action::SetControllerLanes { lanes, .. } => Some(lanes.clone()),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(lanes.len(), 1);
+        let points: Vec<(Time, i32)> =
+            lanes[0].points.iter().map(|p| (p.time, p.value)).collect();
+        assert_eq!(points, vec![(0., 10), (1.5, 100), (2.5, 110), (4., 30)]);
+    }
+}
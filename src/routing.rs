@@ -0,0 +1,212 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::Hash,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mixer::MixerSettings,
+    track::{Insert, TrackId},
+};
+
+pub type BusId = u32;
+
+/// Something with a fader and an insert chain.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum StripId {
+    Track(TrackId),
+    Bus(BusId),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Send {
+    pub target: BusId,
+    pub level_db: f32,
+    /// Taken before the source's gain and pan are applied.
+    pub pre_fader: bool,
+}
+
+/// A return bus. Tracks and other buses send to it, and it feeds the master
+/// bus after its own inserts and fader.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Bus {
+    pub uid: BusId,
+    pub name: String,
+    #[serde(default)]
+    pub mixer: MixerSettings,
+    #[serde(default)]
+    pub inserts: Vec<Insert>,
+    #[serde(default)]
+    pub sends: Vec<Send>,
+}
+
+impl Bus {
+    pub fn new(uid: BusId, name: String) -> Self {
+        Self {
+            uid,
+            name,
+            mixer: MixerSettings::default(),
+            inserts: vec![],
+            sends: vec![],
+        }
+    }
+}
+
+/// Whether adding a send from `from` to `to` would make a bus feed itself.
+pub fn would_create_cycle(buses: &BTreeMap<BusId, Bus>, from: StripId, to: BusId) -> bool {
+    let from = match from {
+        StripId::Bus(from) => from,
+        // Nothing sends to tracks.
+        StripId::Track(_) => return false,
+    };
+
+    let mut stack = vec![to];
+    let mut visited = vec![];
+
+    while let Some(bus_id) = stack.pop() {
+        if bus_id == from {
+            return true;
+        }
+
+        if visited.contains(&bus_id) {
+            continue;
+        }
+        visited.push(bus_id);
+
+        if let Some(bus) = buses.get(&bus_id) {
+            stack.extend(bus.sends.iter().map(|s| s.target));
+        }
+    }
+
+    false
+}
+
+/// Buses that send to each other in a cycle. The engine mutes them.
+pub fn cyclic_buses(buses: &BTreeMap<BusId, Bus>) -> Vec<BusId> {
+    let edges: HashMap<BusId, Vec<BusId>> = buses
+        .values()
+        .map(|bus| (bus.uid, bus.sends.iter().map(|s| s.target).collect()))
+        .collect();
+
+    topological_order(&edges).1
+}
+
+/// Orders nodes so every node comes after all the nodes with edges to it, e.g.
+/// buses after the buses sending to them. Nodes that are part of a cycle are
+/// left out and returned separately. Nodes a cycle only feeds into are still
+/// ordered, ignoring their edges from the cycle.
+pub fn topological_order<Id: Copy + Ord + Hash>(edges: &HashMap<Id, Vec<Id>>) -> (Vec<Id>, Vec<Id>) {
+    let cyclic: BTreeSet<Id> = edges
+        .keys()
+        .filter(|id| in_cycle(edges, **id))
+        .cloned()
+        .collect();
+
+    let mut in_degree: BTreeMap<Id, usize> = edges
+        .keys()
+        .filter(|id| !cyclic.contains(id))
+        .map(|id| (*id, 0))
+        .collect();
+
+    for (_, targets) in edges.iter().filter(|(id, _)| !cyclic.contains(id)) {
+        for target in targets {
+            if let Some(degree) = in_degree.get_mut(target) {
+                *degree += 1;
+            }
+        }
+    }
+
+    let mut ready: Vec<Id> = in_degree
+        .iter()
+        .filter(|(_, degree)| **degree == 0)
+        .map(|(id, _)| *id)
+        .collect();
+
+    let mut order = vec![];
+
+    while let Some(id) = ready.pop() {
+        order.push(id);
+
+        for target in edges.get(&id).into_iter().flatten() {
+            if let Some(degree) = in_degree.get_mut(target) {
+                *degree -= 1;
+                if *degree == 0 {
+                    ready.push(*target);
+                }
+            }
+        }
+    }
+
+    (order, cyclic.into_iter().collect())
+}
+
+/// Whether following edges from `id` can lead back to it.
+fn in_cycle<Id: Copy + Hash + Eq>(edges: &HashMap<Id, Vec<Id>>, id: Id) -> bool {
+    let mut stack: Vec<Id> = edges.get(&id).cloned().unwrap_or_default();
+    let mut visited = HashSet::new();
+
+    while let Some(next) = stack.pop() {
+        if next == id {
+            return true;
+        }
+
+        if visited.insert(next) {
+            stack.extend(edges.get(&next).into_iter().flatten());
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bus(uid: BusId, targets: &[BusId]) -> (BusId, Bus) {
+        let mut bus = Bus::new(uid, format!("Bus {}", uid));
+        bus.sends = targets
+            .iter()
+            .map(|target| Send {
+                target: *target,
+                level_db: 0.,
+                pre_fader: false,
+            })
+            .collect();
+        (uid, bus)
+    }
+
+    #[test]
+    fn only_buses_in_a_cycle_are_cyclic() {
+        // 1 and 2 feed each other and 3, 4 feeds itself, 0 feeds 1.
+        let buses = BTreeMap::from([
+            bus(0, &[1]),
+            bus(1, &[2]),
+            bus(2, &[1, 3]),
+            bus(3, &[]),
+            bus(4, &[4]),
+        ]);
+
+        assert_eq!(cyclic_buses(&buses), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn nodes_come_after_everything_feeding_them() {
+        // 4 feeds itself and 3.
+        let edges = HashMap::from([
+            (0, vec![2]),
+            (1, vec![2]),
+            (2, vec![3]),
+            (3, vec![]),
+            (4, vec![4, 3]),
+        ]);
+
+        let (order, cyclic) = topological_order(&edges);
+        let position = |id| order.iter().position(|o| *o == id).unwrap();
+
+        assert_eq!(cyclic, vec![4]);
+        assert_eq!(order.len(), 4);
+        assert!(position(0) < position(2) && position(1) < position(2));
+        assert!(position(2) < position(3));
+    }
+}
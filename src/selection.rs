@@ -1,11 +1,8 @@
-use std::{
-    collections::{HashMap, HashSet},
-    default,
-};
+use std::collections::{HashMap, HashSet};
 
-use crate::{midi::Time, track::TrackId, ui::reactive_list::ReactiveListKey};
+use crate::{track::TrackId, ui::reactive_list::ReactiveListKey};
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, PartialEq)]
 pub enum Selection {
     #[default]
     None,
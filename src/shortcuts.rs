@@ -1,24 +1,24 @@
 use std::{
-    arch::global_asm,
     cell::RefCell,
     collections::{HashMap, HashSet},
     rc::Rc,
 };
 
-use sdl2::{
-    keyboard::Scancode,
-    libc::{creat, glob},
-    sys::{KeyCode, SDL_KeyCode},
-};
+use sdl2::sys::{KeyCode, SDL_KeyCode};
 
 use crate::{
+    commands::{open_command_palette, save_project},
     event_subscriptions::Key,
-    global::{self, EditingContext, Globals, PlayingState},
+    global::{EditingContext, Globals, PlayingState},
+    clip::ClipId,
     midi::{Note, Time},
-    project::{Action, TimeSignature},
+    project::{Action, Region},
     selection::Selection,
-    track::{self, TrackData, TrackId},
-    ui::{reactive::Reactive, reactive_list::ReactiveListKey},
+    track::{TrackData, TrackId},
+    ui::{
+        arrangement::open_arrangement, mixer::open_mixer, piano_roll::editing_clip,
+        reactive::Reactive, reactive_list::ReactiveListKey,
+    },
 };
 
 pub struct ShortcutsBuffer {
@@ -26,6 +26,12 @@ pub struct ShortcutsBuffer {
     pub amount_modifier: Reactive<Option<i32>>,
 }
 
+impl Default for ShortcutsBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ShortcutsBuffer {
     pub fn new() -> Self {
         Self {
@@ -40,10 +46,7 @@ impl ShortcutsBuffer {
     }
 
     pub fn get_amount(&self) -> i32 {
-        match self.amount_modifier.get_copy() {
-            Some(amount) => amount,
-            None => 1,
-        }
+        self.amount_modifier.get_copy().unwrap_or(1)
     }
 }
 
@@ -78,15 +81,35 @@ pub fn universal_shortcuts(globals: &mut Globals) {
         globals,
         k("^s"),
         Box::new(|globals| {
-            globals.loaded_project.perform_action(Action::AddMidiNote {
-                track_id: 0,
-                note: Note {
-                    start: 0.,
-                    length: 100.,
-                    note: 61,
-                    velocity: 100,
-                },
-            })
+            save_project(globals);
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("^o"),
+        Box::new(|globals| {
+            open_command_palette(globals, "open ");
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("^m"),
+        Box::new(|globals| {
+            open_mixer(globals);
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("^t"),
+        Box::new(|globals| {
+            open_arrangement(globals);
+            globals.shortcuts_buffer.clear();
         }),
     );
 
@@ -136,6 +159,89 @@ pub fn universal_shortcuts(globals: &mut Globals) {
         }),
     );
 
+    perma_bind(
+        globals,
+        k("m"),
+        Box::new(|globals| {
+            globals
+                .loaded_project
+                .metronome
+                .mutate(Box::new(|metronome| metronome.enabled = !metronome.enabled));
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("["),
+        Box::new(|globals| {
+            move_region_edge_to_cursor(globals, false, true);
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("]"),
+        Box::new(|globals| {
+            move_region_edge_to_cursor(globals, false, false);
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("{"),
+        Box::new(|globals| {
+            move_region_edge_to_cursor(globals, true, true);
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("}"),
+        Box::new(|globals| {
+            move_region_edge_to_cursor(globals, true, false);
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("\\"),
+        Box::new(|globals| {
+            let mut region = globals.loaded_project.loop_region.get_copy();
+            region.enabled = !region.enabled;
+            globals
+                .loaded_project
+                .perform_action(Action::SetLoopRegion(region));
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("^\\"),
+        Box::new(|globals| {
+            let mut region = globals.loaded_project.punch_region.get_copy();
+            region.enabled = !region.enabled;
+            globals
+                .loaded_project
+                .perform_action(Action::SetPunchRegion(region));
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
+    perma_bind(
+        globals,
+        k("|"),
+        Box::new(|globals| {
+            loop_selection(globals);
+            globals.shortcuts_buffer.clear();
+        }),
+    );
+
     perma_bind(globals, k("A"), Box::new(|globals| {
         globals.loaded_project.selection <<= Selection::None;
     }));
@@ -290,14 +396,8 @@ pub fn universal_shortcuts(globals: &mut Globals) {
         globals,
         k("o"),
         Box::new(|globals| {
-            let bar_length = globals
-                .loaded_project
-                .time_signature
-                .get_copy()
-                .beats_per_measure() as Time;
-
-            let num_modifier = globals.shortcuts_buffer.get_amount() as Time;
-            globals.loaded_project.player_time += bar_length * num_modifier;
+            let num_modifier = globals.shortcuts_buffer.get_amount();
+            move_by_bars(globals, num_modifier);
             globals.shortcuts_buffer.clear();
         }),
     );
@@ -306,14 +406,8 @@ pub fn universal_shortcuts(globals: &mut Globals) {
         globals,
         k("O"),
         Box::new(|globals| {
-            let bar_length = globals
-                .loaded_project
-                .time_signature
-                .get_copy()
-                .beats_per_measure() as Time;
-
-            let num_modifier = globals.shortcuts_buffer.get_amount() as Time;
-            globals.loaded_project.player_time -= bar_length * num_modifier;
+            let num_modifier = globals.shortcuts_buffer.get_amount();
+            move_by_bars(globals, -num_modifier);
             globals.shortcuts_buffer.clear();
         }),
     );
@@ -322,7 +416,7 @@ pub fn universal_shortcuts(globals: &mut Globals) {
         globals,
         k("c"),
         Box::new(|globals| {
-            if globals.shortcuts_buffer.keys.len() == 0
+            if globals.shortcuts_buffer.keys.is_empty()
                 && globals.editor_context.get_copy() == EditingContext::PianoRoll
             {
                 globals.shortcuts_buffer.keys.push(k("c"));
@@ -338,7 +432,7 @@ pub fn universal_shortcuts(globals: &mut Globals) {
 
             perform_actions_on_selected_notes(
                 globals,
-                Box::new(|globals, note_id, track_id| quantize_note(globals, note_id, track_id)),
+                Box::new(quantize_note),
             );
         }),
     );
@@ -393,15 +487,12 @@ pub fn universal_shortcuts(globals: &mut Globals) {
         globals,
         k("^a"),
         Box::new(|globals| {
-            // FIXME: hardcoded for piano roll and track 0
-
             globals.shortcuts_buffer.clear();
 
             let mut selection: HashMap<TrackId, HashSet<ReactiveListKey>> = HashMap::new();
 
-            let track_id: TrackId = 0;
-            if let TrackData::Midi(_, notes) = &globals.loaded_project.tracks[track_id].data {
-                let notes = notes.notes.copy_of_whole_list();
+            if let Some((track_id, clip)) = editing_clip(globals) {
+                let notes = clip.midi.notes.copy_of_whole_list();
                 let ids: HashSet<ReactiveListKey> = notes.into_iter().map(|(id, _)| id).collect();
                 selection.insert(track_id, ids);
             }
@@ -423,10 +514,83 @@ pub fn universal_shortcuts(globals: &mut Globals) {
     );
 }
 
+/// Moves the start or end of the loop, or the punch range with `punch`, to
+/// the time cursor and turns it on. Keeps the range at least a bar long.
+fn move_region_edge_to_cursor(globals: &mut Globals, punch: bool, start: bool) {
+    let project = &globals.loaded_project;
+    let t = project.player_time.get_copy();
+    let bar = project.meter_map.get_copy().time_signature_at(t).beats_per_measure();
+
+    let mut region = if punch {
+        project.punch_region.get_copy()
+    } else {
+        project.loop_region.get_copy()
+    };
+
+    if start {
+        region.start = t;
+    } else {
+        region.end = t;
+        if region.start >= t {
+            region.start = (t - bar).max(0.);
+        }
+    }
+
+    if region.end <= region.start {
+        region.end = region.start + bar;
+    }
+    region.enabled = true;
+
+    let action = if punch {
+        Action::SetPunchRegion(region)
+    } else {
+        Action::SetLoopRegion(region)
+    };
+    globals.loaded_project.perform_action(action);
+}
+
+/// Loops from the start of the first selected note to the end of the last,
+/// or the bar under the time cursor when nothing is selected.
+fn loop_selection(globals: &mut Globals) {
+    let project = &globals.loaded_project;
+
+    let mut range: Option<(Time, Time)> = None;
+    if let Selection::MidiNotes(map) = project.selection.get_copy() {
+        for (track_id, notes) in map {
+            let track = &project.tracks[track_id];
+            for note_id in notes {
+                let clip = track.clip_of_note(note_id).and_then(|id| track.clip(id));
+                if let (Some(clip), Some(note)) = (clip, track.get_note_from_id(note_id)) {
+                    let note = note.get_copy();
+                    let note_start = clip.timeline_time(note.start);
+                    let note_end = note_start + note.length;
+
+                    let (start, end) = range.unwrap_or((note_start, note_end));
+                    range = Some((start.min(note_start), end.max(note_end)));
+                }
+            }
+        }
+    }
+
+    let (start, end) = range.unwrap_or_else(|| {
+        let meter_map = project.meter_map.get_copy();
+        let bar = meter_map.bar_beat_tick(project.player_time.get_copy()).bar;
+        (meter_map.bar_start(bar), meter_map.bar_start(bar + 1))
+    });
+
+    globals
+        .loaded_project
+        .perform_action(Action::SetLoopRegion(Region {
+            start,
+            end,
+            enabled: true,
+        }));
+}
+
 fn delete_selected_notes(globals: &mut Globals) {
     perform_actions_on_selected_notes(
         globals,
-        Box::new(move |globals, note_id, track_id| {
+        Box::new(move |_globals, note_id, track_id| {
             vec![Action::RemoveMidiNote { track_id, note_id }]
         }),
     );
@@ -576,10 +740,11 @@ fn split_note(
     track_id: u32,
     times: i32,
 ) -> Vec<Action> {
-    let note = globals.loaded_project.tracks[track_id]
-        .get_note_from_id(note_id)
-        .unwrap()
-        .get_copy();
+    let track = &globals.loaded_project.tracks[track_id];
+    let (note, clip_id) = match (track.get_note_from_id(note_id), track.clip_of_note(note_id)) {
+        (Some(note), Some(clip_id)) => (note.get_copy(), clip_id),
+        _ => return vec![],
+    };
 
     let length = note.length / times as Time;
 
@@ -588,6 +753,7 @@ fn split_note(
     for i in 0..times {
         actions.push(Action::AddMidiNote {
             track_id,
+            clip_id,
             note: Note {
                 start: note.start + i as f64 * length,
                 length,
@@ -601,7 +767,7 @@ fn split_note(
 }
 
 fn handle_number_press(globals: &mut Globals, num: i32) {
-    if globals.shortcuts_buffer.keys.len() == 0 {
+    if globals.shortcuts_buffer.keys.is_empty() {
         globals
             .shortcuts_buffer
             .amount_modifier
@@ -627,18 +793,24 @@ fn handle_number_press(globals: &mut Globals, num: i32) {
             }
 
             handle_chord_drawing(globals, num);
-
-            return;
         }
     }
 }
 
+/// Moves the playhead by whole bars, following any meter changes.
+fn move_by_bars(globals: &mut Globals, bars: i32) {
+    let t = globals.loaded_project.player_time.get_copy();
+    let t = globals.loaded_project.meter_map.get_copy().offset_bars(t, bars);
+    globals.loaded_project.player_time <<= t;
+}
+
 fn handle_chord_drawing(globals: &mut Globals, num: i32) {
     // TODO: length modifiers
     let length = globals
         .loaded_project
-        .time_signature
+        .meter_map
         .get_copy()
+        .time_signature_at(globals.loaded_project.player_time.get_copy())
         .beats_per_measure();
 
     let mut degrees = vec![1, 3, 5];
@@ -654,32 +826,38 @@ fn handle_chord_drawing(globals: &mut Globals, num: i32) {
         }
     }
 
+    let t = globals.loaded_project.player_time.get_copy();
+    let (track_id, clip) = match editing_clip(globals) {
+        Some(editing) => editing,
+        None => return,
+    };
+
     let ks = globals.loaded_project.key_signature.get_copy();
     let chord = degrees
         .into_iter()
         .map(|d| Note {
             note: ks.from_degree(d - 1 + num, 5),
             velocity: 100,
-            start: globals.loaded_project.player_time.get_copy(),
+            start: clip.local_time(t).max(0.),
             length: length as Time,
         })
         .collect();
 
-    let t = globals.loaded_project.player_time.get_copy();
-
-    add_chord(globals, chord, 0);
+    add_chord(globals, chord, track_id, clip.uid);
 
     globals
         .loaded_project
         .perform_action(Action::MoveTimeCursor(t + 4.));
 }
 
+#[allow(clippy::cast_enum_truncation)]
 const LEFT: Key = Key {
     code: SDL_KeyCode::SDLK_LEFT as KeyCode,
     control: false,
     shift: false,
 };
 
+#[allow(clippy::cast_enum_truncation)]
 const RIGHT: Key = Key {
     code: SDL_KeyCode::SDLK_RIGHT as KeyCode,
     control: false,
@@ -713,14 +891,28 @@ pub fn perma_bind(globals: &mut Globals, key: Key, callback: Box<dyn Fn(&mut Glo
     )));
 }
 
-pub fn add_chord(globals: &mut Globals, notes: Vec<Note>, track_id: TrackId) {
-    let actions = notes
+/// Adds `notes` to the clip, lengthening it if they'd run past its end.
+pub fn add_chord(globals: &mut Globals, notes: Vec<Note>, track_id: TrackId, clip_id: ClipId) {
+    let mut actions: Vec<Action> = notes
         .iter()
         .map(|note| Action::AddMidiNote {
             track_id,
+            clip_id,
             note: *note,
         })
         .collect();
+
+    let end = notes
+        .iter()
+        .map(|note| note.start + note.length)
+        .fold(0., Time::max);
+    let grown = globals.loaded_project.tracks[track_id]
+        .clip(clip_id)
+        .and_then(|clip| clip.grown_to_fit(end));
+    if let Some(clip) = grown {
+        actions.push(Action::ModifyClip { track_id, clip });
+    }
+
     let action_group = Action::Group(actions);
     globals.loaded_project.perform_action(action_group);
 }
@@ -732,18 +924,18 @@ pub fn move_notes_vertically(
     offset: i32,
     by_scale_degree: bool,
 ) -> Vec<Action> {
-    let track = &mut globals.loaded_project.tracks[track_id];
-    let track_data = &mut track.data;
+    let track = &globals.loaded_project.tracks[track_id];
 
     let mut actions: Vec<Action> = vec![];
 
     let ks = globals.loaded_project.key_signature.get_copy();
 
-    if let TrackData::Midi(_, notes) = track_data {
-        let notes = &notes.notes;
+    if let TrackData::Midi(..) = &track.data {
         for note_id in note_ids {
-            let note = notes.get_copy_of_item(note_id).unwrap();
-            let note = note.get_copy();
+            let note = match track.get_note_from_id(note_id) {
+                Some(note) => note.get_copy(),
+                None => continue,
+            };
 
             let new_note = {
                 let non_scale_degree = Note {
@@ -784,14 +976,13 @@ pub fn k(symbol: &str) -> Key {
 }
 
 pub fn key_from_symbol(symbol: &str) -> Option<Key> {
-    let mut c: char = '_';
-    let mut shift = false;
+    let mut c: char;
     let mut ctrl = false;
 
     if symbol.len() == 1 {
         c = symbol.chars().next().unwrap();
     } else if symbol.len() == 2 {
-        if symbol.chars().next().unwrap() == '^' {
+        if symbol.starts_with('^') {
             c = symbol.chars().nth(1).unwrap();
             ctrl = true;
         } else {
@@ -801,7 +992,7 @@ pub fn key_from_symbol(symbol: &str) -> Option<Key> {
         return None;
     }
 
-    shift = true;
+    let mut shift = true;
     // Unshift
     match c {
         '!' => c = '1',
@@ -836,7 +1027,7 @@ pub fn key_from_symbol(symbol: &str) -> Option<Key> {
     }
 
     let c = c as u8;
-    if c >= 0x20 && c <= 0x7e {
+    if (0x20..=0x7e).contains(&c) {
         return Some(Key {
             code: c as KeyCode,
             control: ctrl,
@@ -0,0 +1,810 @@
+//! Standard MIDI File import and export.
+//!
+//! Export writes one `MTrk` per MIDI track (type 1) or everything merged into
+//! a single `MTrk` (type 0). Tempo and time signature changes and the key
+//! signature are written as meta events in the first track. Controller
+//! lanes go out on the same channel as their track's notes.
+
+use std::{fs, path::Path};
+
+use crate::{
+    clip::TrackClip,
+    midi::{
+        message_length, Controller, ControllerLane, ControllerPoint, MidiClip, MidiEvent,
+        MidiEventData, Note, Time,
+    },
+    project::{KeyMode, KeySignature, Project, TimeSignature},
+    tempo_map::{MeterChange, MeterMap, TempoChange, TempoMap, DEFAULT_TEMPO},
+    track::{Track, TrackType},
+    ui::reactive::Reactive,
+};
+
+/// Ticks per quarter note used when exporting.
+pub const EXPORT_PPQN: u16 = 480;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SmfFormat {
+    SingleTrack = 0,
+    MultiTrack = 1,
+}
+
+pub fn ticks_to_time(ticks: u64, ppqn: u16) -> Time {
+    ticks as Time / ppqn as Time
+}
+
+pub fn time_to_ticks(time: Time, ppqn: u16) -> u64 {
+    (time.max(0.) * ppqn as Time).round() as u64
+}
+
+// Exporting
+
+pub fn export(project: &Project, path: &Path, format: SmfFormat) -> Result<(), String> {
+    fs::write(path, export_to_bytes(project, format)).map_err(|e| e.to_string())
+}
+
+pub fn export_to_bytes(project: &Project, format: SmfFormat) -> Vec<u8> {
+    let mut midi_tracks: Vec<&Track> = project
+        .tracks
+        .tracks
+        .values()
+        .filter(|t| t.type_ == TrackType::Midi)
+        .collect();
+
+    midi_tracks.sort_by_key(|t| t.uid);
+
+    let mut conductor = tempo_events(&project.tempo_map.get_copy());
+    conductor.extend(meter_events(&project.meter_map.get_copy()));
+    conductor.push(TimedEvent::key_signature(project.key_signature.get_copy()));
+
+    let mut chunks: Vec<Vec<TimedEvent>> = match format {
+        SmfFormat::SingleTrack => {
+            for (channel, track) in midi_tracks.iter().enumerate() {
+                conductor.extend(track_events(track, channel_for_track(channel)));
+            }
+            vec![conductor]
+        }
+        SmfFormat::MultiTrack => {
+            let mut chunks = vec![conductor];
+            for (channel, track) in midi_tracks.iter().enumerate() {
+                let mut events = vec![TimedEvent::track_name(&track.name)];
+                events.extend(track_events(track, channel_for_track(channel)));
+                chunks.push(events);
+            }
+            chunks
+        }
+    };
+
+    let mut bytes = vec![];
+
+    bytes.extend_from_slice(b"MThd");
+    bytes.extend_from_slice(&6u32.to_be_bytes());
+    bytes.extend_from_slice(&(format as u16).to_be_bytes());
+    bytes.extend_from_slice(&(chunks.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&EXPORT_PPQN.to_be_bytes());
+
+    for events in chunks.iter_mut() {
+        write_track_chunk(&mut bytes, events);
+    }
+
+    bytes
+}
+
+fn channel_for_track(index: usize) -> u8 {
+    // Skip channel 10 so tracks don't get turned into drums by accident.
+    let channel = (index % 15) as u8;
+    if channel >= 9 {
+        channel + 1
+    } else {
+        channel
+    }
+}
+
+struct TimedEvent {
+    tick: u64,
+    /// Note offs sort before note ons on the same tick so that repeated notes
+    /// don't swallow each other.
+    order: u8,
+    data: Vec<u8>,
+}
+
+impl TimedEvent {
+    fn meta(type_: u8, payload: &[u8]) -> Self {
+        let mut data = vec![0xff, type_];
+        write_vlq(&mut data, payload.len() as u64);
+        data.extend_from_slice(payload);
+        Self {
+            tick: 0,
+            order: 0,
+            data,
+        }
+    }
+
+    fn tempo(bpm: f32) -> Self {
+        let micros_per_quarter = (60_000_000. / bpm.max(1.) as f64).round() as u32;
+        Self::meta(0x51, &micros_per_quarter.to_be_bytes()[1..])
+    }
+
+    fn time_signature(time_signature: TimeSignature) -> Self {
+        let denominator_power = (time_signature.denominator().max(1) as f32).log2() as u8;
+        Self::meta(
+            0x58,
+            &[time_signature.numerator() as u8, denominator_power, 24, 8],
+        )
+    }
+
+    fn key_signature(key_signature: KeySignature) -> Self {
+        let (sharps, minor) = key_signature_to_smf(key_signature);
+        Self::meta(0x59, &[sharps as u8, minor as u8])
+    }
+
+    fn track_name(name: &str) -> Self {
+        Self::meta(0x03, name.as_bytes())
+    }
+
+    fn at(mut self, time: Time) -> Self {
+        self.tick = time_to_ticks(time, EXPORT_PPQN);
+        self
+    }
+}
+
+/// MIDI files can't ramp the tempo, so ramps are written as a change every
+/// beat.
+fn tempo_events(tempo_map: &TempoMap) -> Vec<TimedEvent> {
+    let changes = tempo_map.changes();
+    let mut events = vec![];
+
+    for (i, change) in changes.iter().enumerate() {
+        events.push(TimedEvent::tempo(change.tempo).at(change.time));
+
+        if let (true, Some(next)) = (change.ramp, changes.get(i + 1)) {
+            let mut t = change.time + 1.;
+            while t < next.time {
+                events.push(TimedEvent::tempo(tempo_map.tempo_at(t)).at(t));
+                t += 1.;
+            }
+        }
+    }
+
+    events
+}
+
+fn meter_events(meter_map: &MeterMap) -> Vec<TimedEvent> {
+    meter_map
+        .changes()
+        .iter()
+        .map(|change| {
+            TimedEvent::time_signature(change.time_signature).at(meter_map.bar_start(change.bar))
+        })
+        .collect()
+}
+
+fn track_events(track: &Track, channel: u8) -> Vec<TimedEvent> {
+    track
+        .events()
+        .all_events()
+        .into_iter()
+        .map(|mut event| {
+            event.channel = channel;
+
+            let order = match &mut event.data {
+                MidiEventData::NoteOn { note } => {
+                    note.velocity = note.velocity.max(1);
+                    2
+                }
+                MidiEventData::NoteOff { note } => {
+                    note.velocity = 0;
+                    1
+                }
+                _ => 0,
+            };
+
+            let bytes = event.to_bytes();
+            TimedEvent {
+                tick: time_to_ticks(event.time, EXPORT_PPQN),
+                order,
+                data: bytes[..message_length(bytes[0])].to_vec(),
+            }
+        })
+        .collect()
+}
+
+fn write_track_chunk(bytes: &mut Vec<u8>, events: &mut [TimedEvent]) {
+    events.sort_by_key(|e| (e.tick, e.order));
+
+    let mut data = vec![];
+    let mut last_tick = 0;
+
+    for event in events.iter() {
+        write_vlq(&mut data, event.tick - last_tick);
+        data.extend_from_slice(&event.data);
+        last_tick = event.tick;
+    }
+
+    // End of track
+    data.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+
+    bytes.extend_from_slice(b"MTrk");
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&data);
+}
+
+fn write_vlq(bytes: &mut Vec<u8>, mut value: u64) {
+    let mut buf = vec![(value & 0x7f) as u8];
+    value >>= 7;
+
+    while value > 0 {
+        buf.push(((value & 0x7f) as u8) | 0x80);
+        value >>= 7;
+    }
+
+    buf.reverse();
+    bytes.extend_from_slice(&buf);
+}
+
+/// Number of sharps (positive) or flats (negative) and whether the key is
+/// minor, as stored in the key signature meta event.
+fn key_signature_to_smf(key_signature: KeySignature) -> (i8, bool) {
+    let major_root = match key_signature.mode {
+        KeyMode::Major => key_signature.root,
+        KeyMode::Minor => key_signature.root + 3,
+    } % 12;
+
+    let sharps = ((major_root * 7) % 12) as i8;
+    let sharps = if sharps > 6 { sharps - 12 } else { sharps };
+
+    (sharps, key_signature.mode == KeyMode::Minor)
+}
+
+fn key_signature_from_smf(sharps: i8, minor: bool) -> KeySignature {
+    let major_root = (sharps as i32 * 7).rem_euclid(12) as u32;
+
+    if minor {
+        KeySignature::new((major_root + 9) % 12, KeyMode::Minor)
+    } else {
+        KeySignature::new(major_root, KeyMode::Major)
+    }
+}
+
+// Importing
+
+pub struct ImportedSmf {
+    /// Sorted by time.
+    pub tempo_changes: Vec<(Time, f32)>,
+    pub time_signatures: Vec<(Time, TimeSignature)>,
+    pub key_signature: Option<KeySignature>,
+    pub tracks: Vec<ImportedTrack>,
+}
+
+pub struct ImportedTrack {
+    pub name: Option<String>,
+    pub notes: Vec<Note>,
+    pub controllers: Vec<ControllerLane>,
+}
+
+/// Reads a `.mid` file and adds a MIDI track to the project for every `MTrk`
+/// that contains notes or controller changes.
+pub fn import(project: &mut Project, path: &Path) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let smf = parse(&bytes)?;
+
+    if let Some(tempo_map) = imported_tempo_map(&smf) {
+        project.tempo_map <<= tempo_map;
+    }
+
+    if let Some(meter_map) = imported_meter_map(&smf) {
+        project.meter_map <<= meter_map;
+    }
+
+    if let Some(key_signature) = smf.key_signature {
+        project.key_signature <<= key_signature;
+    }
+
+    let file_name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "Imported".to_string());
+
+    for (i, imported) in smf.tracks.into_iter().enumerate() {
+        if imported.notes.is_empty() && imported.controllers.is_empty() {
+            continue;
+        }
+
+        let mut track = Track::new(TrackType::Midi);
+        track.name = imported
+            .name
+            .unwrap_or_else(|| format!("{} {}", file_name, i + 1));
+
+        let mut midi = MidiClip::new();
+        for note in imported.notes {
+            midi.notes.push(Reactive::new(note));
+        }
+        midi.set_controllers(imported.controllers);
+        track.set_clips(vec![TrackClip::fitted(0., midi)]);
+
+        project.tracks.append(track);
+    }
+
+    Ok(())
+}
+
+pub fn parse(bytes: &[u8]) -> Result<ImportedSmf, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(4)? != b"MThd" {
+        return Err("Not a MIDI file".to_string());
+    }
+
+    let header_len = reader.u32()? as usize;
+    let header = reader.take(header_len)?;
+    if header.len() < 6 {
+        return Err("MIDI header too short".to_string());
+    }
+
+    let format = u16::from_be_bytes([header[0], header[1]]);
+    let num_tracks = u16::from_be_bytes([header[2], header[3]]);
+    let division = u16::from_be_bytes([header[4], header[5]]);
+
+    if format > 1 {
+        return Err(format!("Unsupported MIDI file format {}", format));
+    }
+
+    if division & 0x8000 != 0 {
+        return Err("SMPTE time division is not supported".to_string());
+    }
+
+    let ppqn = division;
+
+    let mut smf = ImportedSmf {
+        tempo_changes: vec![],
+        time_signatures: vec![],
+        key_signature: None,
+        tracks: vec![],
+    };
+
+    for _ in 0..num_tracks {
+        let chunk_type = reader.take(4)?;
+        let chunk_len = reader.u32()? as usize;
+        let chunk = reader.take(chunk_len)?;
+
+        if chunk_type != b"MTrk" {
+            continue;
+        }
+
+        let track = parse_track(chunk, ppqn, &mut smf)?;
+        smf.tracks.push(track);
+    }
+
+    // Changes can be spread over several tracks.
+    smf.tempo_changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+    smf.time_signatures.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    Ok(smf)
+}
+
+/// The default tempo holds until the first tempo event, unless that's at the
+/// very start.
+fn imported_tempo_map(smf: &ImportedSmf) -> Option<TempoMap> {
+    if smf.tempo_changes.is_empty() {
+        return None;
+    }
+    let mut tempo_map = TempoMap::new(DEFAULT_TEMPO);
+
+    for (time, tempo) in &smf.tempo_changes {
+        tempo_map.insert(TempoChange {
+            time: *time,
+            tempo: *tempo,
+            ramp: false,
+        });
+    }
+
+    Some(tempo_map)
+}
+
+/// Time signature changes that don't land on a bar line are moved to the
+/// next one. Like the tempo, it's 4/4 until the first one.
+fn imported_meter_map(smf: &ImportedSmf) -> Option<MeterMap> {
+    if smf.time_signatures.is_empty() {
+        return None;
+    }
+    let mut meter_map = MeterMap::new(TimeSignature::common());
+
+    for (time, time_signature) in &smf.time_signatures {
+        let position = meter_map.bar_beat_tick(*time);
+        let on_bar_line = position.beat == 1 && position.tick == 0;
+
+        meter_map.insert(MeterChange {
+            bar: if on_bar_line { position.bar } else { position.bar + 1 },
+            time_signature: *time_signature,
+        });
+    }
+
+    Some(meter_map)
+}
+
+fn parse_track(chunk: &[u8], ppqn: u16, smf: &mut ImportedSmf) -> Result<ImportedTrack, String> {
+    let mut reader = Reader {
+        bytes: chunk,
+        pos: 0,
+    };
+
+    let mut track = ImportedTrack {
+        name: None,
+        notes: vec![],
+        controllers: vec![],
+    };
+
+    // (channel, note) -> (start tick, velocity)
+    let mut held: Vec<((u8, u8), (u64, u8))> = vec![];
+    let mut tick: u64 = 0;
+    let mut running_status: Option<u8> = None;
+
+    let finish_note = |track: &mut ImportedTrack,
+                           held: &mut Vec<((u8, u8), (u64, u8))>,
+                           channel: u8,
+                           key: u8,
+                           end: u64| {
+        if let Some(i) = held.iter().position(|(k, _)| *k == (channel, key)) {
+            let (_, (start, velocity)) = held.remove(i);
+            track.notes.push(Note {
+                note: key as u32,
+                velocity: velocity as u32,
+                start: ticks_to_time(start, ppqn),
+                length: ticks_to_time(end.saturating_sub(start), ppqn),
+            });
+        }
+    };
+
+    while !reader.is_empty() {
+        tick += reader.vlq()?;
+
+        let mut status = reader.u8()?;
+        if status < 0x80 {
+            // Running status, the byte we just read is the first data byte.
+            status = running_status.ok_or_else(|| "Invalid running status".to_string())?;
+            reader.pos -= 1;
+        }
+
+        match status {
+            0xff => {
+                let type_ = reader.u8()?;
+                let len = reader.vlq()? as usize;
+                let payload = reader.take(len)?;
+
+                match type_ {
+                    0x03 if track.name.is_none() => {
+                        track.name = Some(String::from_utf8_lossy(payload).to_string());
+                    }
+                    0x51 if payload.len() == 3 => {
+                        let micros =
+                            u32::from_be_bytes([0, payload[0], payload[1], payload[2]]);
+                        if micros > 0 {
+                            smf.tempo_changes.push((
+                                ticks_to_time(tick, ppqn),
+                                (60_000_000. / micros as f64) as f32,
+                            ));
+                        }
+                    }
+                    0x58 if payload.len() >= 2 => {
+                        smf.time_signatures.push((
+                            ticks_to_time(tick, ppqn),
+                            TimeSignature::new(payload[0] as u32, 1 << payload[1].min(6)),
+                        ));
+                    }
+                    0x59 if payload.len() >= 2 && smf.key_signature.is_none() => {
+                        smf.key_signature =
+                            Some(key_signature_from_smf(payload[0] as i8, payload[1] == 1));
+                    }
+                    0x2f => break,
+                    _ => {}
+                }
+            }
+            0xf0 | 0xf7 => {
+                let len = reader.vlq()? as usize;
+                reader.take(len)?;
+            }
+            _ => {
+                running_status = Some(status);
+                let channel = status & 0x0f;
+
+                match status & 0xf0 {
+                    0x90 => {
+                        let key = reader.u8()?;
+                        let velocity = reader.u8()?;
+
+                        finish_note(&mut track, &mut held, channel, key, tick);
+
+                        if velocity > 0 {
+                            held.push(((channel, key), (tick, velocity)));
+                        }
+                    }
+                    0x80 => {
+                        let key = reader.u8()?;
+                        reader.u8()?;
+                        finish_note(&mut track, &mut held, channel, key, tick);
+                    }
+                    0xa0 | 0xb0 | 0xc0 | 0xd0 | 0xe0 => {
+                        let mut bytes = [status, 0, 0];
+                        let data = reader.take(message_length(status) - 1)?;
+                        bytes[1..1 + data.len()].copy_from_slice(data);
+
+                        if let Some((controller, value)) =
+                            MidiEvent::from_bytes(bytes).and_then(|e| Controller::of(&e.data))
+                        {
+                            add_controller_point(&mut track, controller, tick, ppqn, value);
+                        }
+                    }
+                    _ => return Err(format!("Unexpected status byte {:#x}", status)),
+                }
+            }
+        }
+    }
+
+    // Close off anything left hanging at the end of the track.
+    let held_keys: Vec<(u8, u8)> = held.iter().map(|(k, _)| *k).collect();
+    for (channel, key) in held_keys {
+        finish_note(&mut track, &mut held, channel, key, tick);
+    }
+
+    Ok(track)
+}
+
+/// Notes are imported without their channel and play on channel 0, so
+/// controllers do too.
+fn add_controller_point(
+    track: &mut ImportedTrack,
+    controller: Controller,
+    tick: u64,
+    ppqn: u16,
+    value: i32,
+) {
+    let lane = match track
+        .controllers
+        .iter()
+        .position(|lane| lane.controller == controller)
+    {
+        Some(i) => &mut track.controllers[i],
+        None => {
+            track.controllers.push(ControllerLane::new(controller, 0));
+            track.controllers.last_mut().unwrap()
+        }
+    };
+
+    lane.insert(ControllerPoint {
+        time: ticks_to_time(tick, ppqn),
+        value,
+    });
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.bytes.len() {
+            return Err("Unexpected end of MIDI file".to_string());
+        }
+
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn vlq(&mut self) -> Result<u64, String> {
+        let mut value: u64 = 0;
+
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err("Variable length quantity too long".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smf_with_tempo_changes(tempo_changes: Vec<(Time, f32)>) -> ImportedSmf {
+        ImportedSmf {
+            tempo_changes,
+            time_signatures: vec![],
+            key_signature: None,
+            tracks: vec![],
+        }
+    }
+
+    #[test]
+    fn a_late_first_tempo_keeps_the_default_before_it() {
+        let tempo_map = imported_tempo_map(&smf_with_tempo_changes(vec![(4., 90.)])).unwrap();
+
+        assert_eq!(tempo_map.tempo_at(0.), DEFAULT_TEMPO);
+        assert_eq!(tempo_map.tempo_at(4.), 90.);
+    }
+
+    #[test]
+    fn a_tempo_at_the_start_replaces_the_default() {
+        let tempo_map =
+            imported_tempo_map(&smf_with_tempo_changes(vec![(0., 100.), (8., 140.)])).unwrap();
+
+        assert_eq!(tempo_map.changes().len(), 2);
+        assert_eq!(tempo_map.initial_tempo(), 100.);
+    }
+
+    /// A format 0 file with one track holding `events`, at 96 ticks per beat.
+    fn smf_bytes(events: &[u8]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 1, 0, 96]);
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(events.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(events);
+        bytes
+    }
+
+    #[test]
+    fn parses_notes_with_running_status_and_zero_velocity_note_offs() {
+        let smf = parse(&smf_bytes(&[
+            0x00, 0x90, 60, 100, // note on
+            0x00, 62, 80, // running status note on
+            0x60, 60, 0, // velocity 0 note off, one beat later
+            0x30, 0x80, 62, 0, // note off half a beat later
+            0x00, 0xff, 0x2f, 0x00,
+        ]))
+        .unwrap();
+
+        let notes: Vec<(u32, u32, Time, Time)> = smf.tracks[0]
+            .notes
+            .iter()
+            .map(|n| (n.note, n.velocity, n.start, n.length))
+            .collect();
+        assert_eq!(notes, vec![(60, 100, 0., 1.), (62, 80, 0., 1.5)]);
+    }
+
+    #[test]
+    fn parses_meta_events() {
+        let smf = parse(&smf_bytes(&[
+            0x00, 0xff, 0x03, 0x04, b'L', b'e', b'a', b'd', // track name
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 500000us per beat
+            0x00, 0xff, 0x58, 0x04, 3, 2, 24, 8, // 3/4
+            0x81, 0x40, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 1000000us at beat 2
+            0x00, 0xff, 0x2f, 0x00,
+        ]))
+        .unwrap();
+
+        assert_eq!(smf.tracks[0].name.as_deref(), Some("Lead"));
+        assert_eq!(smf.tempo_changes, vec![(0., 120.), (2., 60.)]);
+        assert_eq!(smf.time_signatures, vec![(0., TimeSignature::new(3, 4))]);
+    }
+
+    #[test]
+    fn controllers_go_into_lanes() {
+        let smf = parse(&smf_bytes(&[
+            0x00, 0xb3, 7, 100, // volume on channel 3
+            0x30, 7, 50, // running status
+            0x00, 0xb0, 10, 64, // pan
+            0x00, 0xff, 0x2f, 0x00,
+        ]))
+        .unwrap();
+
+        let lanes = &smf.tracks[0].controllers;
+        assert_eq!(lanes.len(), 2);
+        assert_eq!(lanes[0].controller, Controller::ControlChange(7));
+        assert_eq!(lanes[0].channel, 0);
+        assert_eq!(
+            lanes[0].points,
+            vec![
+                ControllerPoint { time: 0., value: 100 },
+                ControllerPoint { time: 0.5, value: 50 },
+            ]
+        );
+        assert_eq!(lanes[1].controller, Controller::ControlChange(10));
+    }
+
+    /// A project whose one MIDI track plays `notes`, with a tempo change, a
+    /// meter change and a key signature.
+    fn project_with_notes(notes: &[(u32, u32, Time, Time)]) -> Project {
+        let mut project = Project::new();
+
+        let mut tempo_map = TempoMap::new(100.);
+        tempo_map.insert(TempoChange {
+            time: 8.,
+            tempo: 150.,
+            ramp: false,
+        });
+        project.tempo_map <<= tempo_map;
+
+        let mut meter_map = MeterMap::new(TimeSignature::new(3, 4));
+        meter_map.insert(MeterChange {
+            bar: 3,
+            time_signature: TimeSignature::new(6, 8),
+        });
+        project.meter_map <<= meter_map;
+        project.key_signature <<= KeySignature::new(9, KeyMode::Minor);
+
+        let (track_id, clip_id) = project.first_clip().unwrap();
+        let track = &mut project.tracks[track_id];
+        track.name = "Lead".to_string();
+        for &(note, velocity, start, length) in notes {
+            track.push_note(
+                clip_id,
+                Note {
+                    note,
+                    velocity,
+                    start,
+                    length,
+                },
+            );
+        }
+
+        project
+    }
+
+    #[test]
+    fn exports_a_chunk_per_track_or_one_for_everything() {
+        let project = project_with_notes(&[(60, 100, 0., 1.)]);
+
+        let multi = export_to_bytes(&project, SmfFormat::MultiTrack);
+        assert_eq!(&multi[..4], b"MThd");
+        // Format 1, a conductor track and the MIDI track, 480 ticks a beat.
+        assert_eq!(&multi[8..14], &[0, 1, 0, 2, 0x01, 0xe0]);
+        assert_eq!(&multi[14..18], b"MTrk");
+
+        let single = export_to_bytes(&project, SmfFormat::SingleTrack);
+        assert_eq!(&single[8..14], &[0, 0, 0, 1, 0x01, 0xe0]);
+        // The note on, at tick 0 on the first channel.
+        assert!(single.windows(4).any(|w| w == [0x00, 0x90, 60, 100]));
+    }
+
+    #[test]
+    fn exported_files_import_back_the_same() {
+        let notes = vec![(60, 100, 0., 1.), (64, 80, 1.5, 0.25), (67, 1, 9.75, 2.)];
+        let project = project_with_notes(&notes);
+
+        for format in [SmfFormat::SingleTrack, SmfFormat::MultiTrack] {
+            let smf = parse(&export_to_bytes(&project, format)).unwrap();
+
+            assert_eq!(smf.tempo_changes, vec![(0., 100.), (8., 150.)]);
+            assert_eq!(
+                smf.time_signatures,
+                vec![(0., TimeSignature::new(3, 4)), (6., TimeSignature::new(6, 8))]
+            );
+            assert!(smf.key_signature == Some(KeySignature::new(9, KeyMode::Minor)));
+
+            let track = smf.tracks.iter().find(|t| !t.notes.is_empty()).unwrap();
+            let imported: Vec<(u32, u32, Time, Time)> = track
+                .notes
+                .iter()
+                .map(|n| (n.note, n.velocity, n.start, n.length))
+                .collect();
+            assert_eq!(imported, notes);
+            if format == SmfFormat::MultiTrack {
+                assert_eq!(track.name.as_deref(), Some("Lead"));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_midi() {
+        assert!(parse(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(parse(&smf_bytes(&[0x00, 0x90, 60])).is_err());
+    }
+}
@@ -0,0 +1,468 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{midi::Time, project::TimeSignature};
+
+pub const MIN_TEMPO: f32 = 20.;
+pub const MAX_TEMPO: f32 = 400.;
+/// Used before the first tempo change in a file that doesn't start with one.
+pub const DEFAULT_TEMPO: f32 = 120.;
+
+/// Subdivisions of a meter beat in bar:beat:tick positions.
+pub const TICKS_PER_BEAT: u32 = 960;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TempoChange {
+    /// In beats.
+    pub time: Time,
+    pub tempo: f32,
+    /// Whether the tempo moves linearly to the next change's instead of
+    /// jumping there.
+    #[serde(default)]
+    pub ramp: bool,
+}
+
+/// Tempo changes over the timeline. There is always one at beat 0.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(from = "SavedTempoMap")]
+pub struct TempoMap {
+    changes: Vec<TempoChange>,
+}
+
+/// A tempo map as saved, which may have been edited by hand.
+#[derive(Deserialize)]
+struct SavedTempoMap {
+    changes: Vec<TempoChange>,
+}
+
+impl From<SavedTempoMap> for TempoMap {
+    fn from(saved: SavedTempoMap) -> Self {
+        let mut tempo_map = TempoMap::new(DEFAULT_TEMPO);
+        for change in saved.changes {
+            if change.time.is_finite() && change.tempo.is_finite() {
+                tempo_map.insert(change);
+            }
+        }
+        tempo_map
+    }
+}
+
+impl TempoMap {
+    pub fn new(tempo: f32) -> Self {
+        Self {
+            changes: vec![TempoChange {
+                time: 0.,
+                tempo: tempo.clamp(MIN_TEMPO, MAX_TEMPO),
+                ramp: false,
+            }],
+        }
+    }
+
+    /// Sorted by time.
+    pub fn changes(&self) -> &[TempoChange] {
+        &self.changes
+    }
+
+    pub fn initial_tempo(&self) -> f32 {
+        self.changes[0].tempo
+    }
+
+    pub fn set_initial_tempo(&mut self, tempo: f32) {
+        self.changes[0].tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+    }
+
+    /// Adds a change, replacing any already at the same time. Returns its
+    /// index.
+    pub fn insert(&mut self, mut change: TempoChange) -> usize {
+        change.time = change.time.max(0.);
+        change.tempo = change.tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+
+        let index = self.changes.partition_point(|c| c.time < change.time);
+        match self.changes.get_mut(index) {
+            Some(existing) if existing.time == change.time => *existing = change,
+            _ => self.changes.insert(index, change),
+        }
+        index
+    }
+
+    /// Removes a change other than the first.
+    pub fn remove(&mut self, index: usize) -> Option<TempoChange> {
+        if index == 0 || index >= self.changes.len() {
+            return None;
+        }
+        Some(self.changes.remove(index))
+    }
+
+    pub fn tempo_at(&self, t: Time) -> f32 {
+        let i = self.segment_at(t);
+        let (tempo, slope, _) = self.segment(i);
+        tempo + (slope * (t - self.changes[i].time).max(0.)) as f32
+    }
+
+    pub fn beats_to_seconds(&self, t: Time) -> f64 {
+        let mut seconds = 0.;
+
+        for i in 0..self.changes.len() {
+            let (_, _, end) = self.segment(i);
+            match end {
+                Some(end) if end <= t => seconds += self.seconds_into_segment(i, end),
+                _ => return seconds + self.seconds_into_segment(i, t.max(0.)),
+            }
+        }
+
+        seconds
+    }
+
+    pub fn seconds_to_beats(&self, seconds: f64) -> Time {
+        let mut remaining = seconds.max(0.);
+
+        for i in 0..self.changes.len() {
+            let (_, _, end) = self.segment(i);
+            if let Some(end) = end {
+                let length = self.seconds_into_segment(i, end);
+                if length <= remaining {
+                    remaining -= length;
+                    continue;
+                }
+            }
+
+            return self.changes[i].time + self.beats_into_segment(i, remaining);
+        }
+
+        0.
+    }
+
+    fn segment_at(&self, t: Time) -> usize {
+        self.changes.partition_point(|c| c.time <= t).saturating_sub(1)
+    }
+
+    /// The tempo at the start of segment `i`, how fast it changes in BPM per
+    /// beat and when the segment ends.
+    fn segment(&self, i: usize) -> (f32, f64, Option<Time>) {
+        let change = &self.changes[i];
+
+        match self.changes.get(i + 1) {
+            Some(next) if change.ramp && next.time > change.time => {
+                let slope = (next.tempo - change.tempo) as f64 / (next.time - change.time);
+                (change.tempo, slope, Some(next.time))
+            }
+            Some(next) => (change.tempo, 0., Some(next.time)),
+            None => (change.tempo, 0., None),
+        }
+    }
+
+    /// Seconds from the start of segment `i` to `t`, which must be within it.
+    fn seconds_into_segment(&self, i: usize, t: Time) -> f64 {
+        let (tempo, slope, _) = self.segment(i);
+        let beats = t - self.changes[i].time;
+        let tempo = tempo as f64;
+
+        if slope == 0. {
+            beats * 60. / tempo
+        } else {
+            // Integral of 60 / (tempo + slope * b) over the segment.
+            60. / slope * ((tempo + slope * beats) / tempo).ln()
+        }
+    }
+
+    fn beats_into_segment(&self, i: usize, seconds: f64) -> Time {
+        let (tempo, slope, _) = self.segment(i);
+        let tempo = tempo as f64;
+
+        if slope == 0. {
+            seconds * tempo / 60.
+        } else {
+            tempo * ((slope * seconds / 60.).exp() - 1.) / slope
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MeterChange {
+    /// Counted from 1.
+    pub bar: u32,
+    pub time_signature: TimeSignature,
+}
+
+/// Time signature changes, which always fall on a bar line. There is always
+/// one at bar 1.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(from = "SavedMeterMap")]
+pub struct MeterMap {
+    changes: Vec<MeterChange>,
+}
+
+/// See `SavedTempoMap`.
+#[derive(Deserialize)]
+struct SavedMeterMap {
+    changes: Vec<MeterChange>,
+}
+
+impl From<SavedMeterMap> for MeterMap {
+    fn from(saved: SavedMeterMap) -> Self {
+        let mut meter_map = MeterMap::new(TimeSignature::common());
+        for change in saved.changes {
+            if change.time_signature.numerator() > 0 {
+                meter_map.insert(change);
+            }
+        }
+        meter_map
+    }
+}
+
+/// A position counted in bars, beats of the bar's time signature and
+/// `TICKS_PER_BEAT` ticks. Bars and beats count from 1.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BarBeatTick {
+    pub bar: u32,
+    pub beat: u32,
+    pub tick: u32,
+}
+
+impl std::fmt::Display for BarBeatTick {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{:03}", self.bar, self.beat, self.tick)
+    }
+}
+
+impl MeterMap {
+    pub fn new(time_signature: TimeSignature) -> Self {
+        Self {
+            changes: vec![MeterChange {
+                bar: 1,
+                time_signature,
+            }],
+        }
+    }
+
+    /// Sorted by bar.
+    pub fn changes(&self) -> &[MeterChange] {
+        &self.changes
+    }
+
+    /// Adds a change, replacing any already at the same bar. Returns its
+    /// index.
+    pub fn insert(&mut self, mut change: MeterChange) -> usize {
+        change.bar = change.bar.max(1);
+
+        let index = self.changes.partition_point(|c| c.bar < change.bar);
+        match self.changes.get_mut(index) {
+            Some(existing) if existing.bar == change.bar => *existing = change,
+            _ => self.changes.insert(index, change),
+        }
+        index
+    }
+
+    /// Removes a change other than the first.
+    pub fn remove(&mut self, index: usize) -> Option<MeterChange> {
+        if index == 0 || index >= self.changes.len() {
+            return None;
+        }
+        Some(self.changes.remove(index))
+    }
+
+    pub fn time_signature_at_bar(&self, bar: u32) -> TimeSignature {
+        let i = self.changes.partition_point(|c| c.bar <= bar).saturating_sub(1);
+        self.changes[i].time_signature
+    }
+
+    pub fn time_signature_at(&self, t: Time) -> TimeSignature {
+        self.time_signature_at_bar(self.bar_beat_tick(t).bar)
+    }
+
+    /// Time in beats at which `bar` starts.
+    pub fn bar_start(&self, bar: u32) -> Time {
+        let bar = bar.max(1);
+        let mut start = 0.;
+
+        for (i, change) in self.changes.iter().enumerate() {
+            let length = change.time_signature.beats_per_measure();
+            match self.changes.get(i + 1) {
+                Some(next) if next.bar <= bar => start += (next.bar - change.bar) as Time * length,
+                _ => return start + (bar - change.bar) as Time * length,
+            }
+        }
+
+        start
+    }
+
+    pub fn bar_beat_tick(&self, t: Time) -> BarBeatTick {
+        let t = t.max(0.);
+        let mut start = 0.;
+
+        for (i, change) in self.changes.iter().enumerate() {
+            let time_signature = change.time_signature;
+            let length = time_signature.beats_per_measure();
+
+            if let Some(next) = self.changes.get(i + 1) {
+                let end = start + (next.bar - change.bar) as Time * length;
+                if end <= t {
+                    start = end;
+                    continue;
+                }
+            }
+
+            let bars = ((t - start) / length).floor();
+            let into_bar = t - start - bars * length;
+            let unit = time_signature.beat_length();
+            let beats = (into_bar / unit).floor();
+            let tick = ((into_bar - beats * unit) / unit * TICKS_PER_BEAT as Time).floor();
+
+            return BarBeatTick {
+                bar: change.bar + bars as u32,
+                beat: beats as u32 + 1,
+                tick: (tick as u32).min(TICKS_PER_BEAT - 1),
+            };
+        }
+
+        BarBeatTick {
+            bar: 1,
+            beat: 1,
+            tick: 0,
+        }
+    }
+
+    pub fn beats_of(&self, position: BarBeatTick) -> Time {
+        let unit = self.time_signature_at_bar(position.bar).beat_length();
+        self.bar_start(position.bar)
+            + (position.beat.max(1) - 1) as Time * unit
+            + position.tick as Time / TICKS_PER_BEAT as Time * unit
+    }
+
+    /// Moves `t` by whole bars, keeping its place within the bar.
+    pub fn offset_bars(&self, t: Time, bars: i32) -> Time {
+        let mut position = self.bar_beat_tick(t);
+        position.bar = (position.bar as i32 + bars).max(1) as u32;
+        self.beats_of(position)
+    }
+
+    /// The first `count` beat lines at or after `from`, with whether each
+    /// starts a bar.
+    pub fn beat_lines(&self, from: Time, count: usize) -> Vec<(Time, bool)> {
+        let mut lines = Vec::with_capacity(count);
+        self.beat_lines_into(from, count, &mut lines);
+        lines
+    }
+
+    /// Like `beat_lines`, but into `lines`, which is cleared first.
+    pub fn beat_lines_into(&self, from: Time, count: usize, lines: &mut Vec<(Time, bool)>) {
+        lines.clear();
+        let mut bar = self.bar_beat_tick(from).bar;
+
+        while lines.len() < count {
+            let start = self.bar_start(bar);
+            let time_signature = self.time_signature_at_bar(bar);
+            let unit = time_signature.beat_length();
+
+            for beat in 0..time_signature.numerator().max(1) {
+                let t = start + beat as Time * unit;
+                if t >= from && lines.len() < count {
+                    lines.push((t, beat == 0));
+                }
+            }
+
+            bar += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tempo_map(json: &str) -> TempoMap {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn loading_no_tempo_changes_uses_the_default() {
+        let tempo_map = tempo_map(r#"{"changes": []}"#);
+        assert_eq!(tempo_map.initial_tempo(), DEFAULT_TEMPO);
+        assert_eq!(tempo_map.changes().len(), 1);
+    }
+
+    #[test]
+    fn loading_tempo_changes_sorts_them_and_starts_at_zero() {
+        let tempo_map = tempo_map(
+            r#"{"changes": [
+                {"time": 8.0, "tempo": 90.0},
+                {"time": 4.0, "tempo": 0.0}
+            ]}"#,
+        );
+
+        let changes: Vec<(Time, f32)> =
+            tempo_map.changes().iter().map(|c| (c.time, c.tempo)).collect();
+        assert_eq!(changes, vec![(0., DEFAULT_TEMPO), (4., MIN_TEMPO), (8., 90.)]);
+    }
+
+    #[test]
+    fn loading_meter_changes_starts_at_bar_one() {
+        let meter_map: MeterMap = serde_json::from_str(
+            r#"{"changes": [
+                {"bar": 5, "time_signature": {"numerator": 3, "denominator": 4}},
+                {"bar": 3, "time_signature": {"numerator": 0, "denominator": 4}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let bars: Vec<u32> = meter_map.changes().iter().map(|c| c.bar).collect();
+        assert_eq!(bars, vec![1, 5]);
+        assert_eq!(meter_map.time_signature_at_bar(1), TimeSignature::common());
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn converts_across_tempo_jumps() {
+        let mut tempo_map = TempoMap::new(120.);
+        tempo_map.insert(TempoChange {
+            time: 4.,
+            tempo: 60.,
+            ramp: false,
+        });
+
+        assert_eq!(tempo_map.tempo_at(3.9), 120.);
+        assert_eq!(tempo_map.tempo_at(4.), 60.);
+        assert_close(tempo_map.beats_to_seconds(2.), 1.);
+        assert_close(tempo_map.beats_to_seconds(4.), 2.);
+        assert_close(tempo_map.beats_to_seconds(6.), 4.);
+        assert_close(tempo_map.seconds_to_beats(1.), 2.);
+        assert_close(tempo_map.seconds_to_beats(4.), 6.);
+    }
+
+    #[test]
+    fn converts_through_tempo_ramps() {
+        let mut tempo_map = TempoMap::new(120.);
+        tempo_map.insert(TempoChange {
+            time: 0.,
+            tempo: 120.,
+            ramp: true,
+        });
+        tempo_map.insert(TempoChange {
+            time: 4.,
+            tempo: 60.,
+            ramp: false,
+        });
+
+        assert_eq!(tempo_map.tempo_at(2.), 90.);
+        assert_eq!(tempo_map.tempo_at(8.), 60.);
+
+        // Slower than 120 BPM throughout, faster than 60.
+        let ramp_seconds = tempo_map.beats_to_seconds(4.);
+        assert_close(ramp_seconds, 4. * 2f64.ln());
+        assert!(ramp_seconds > 2. && ramp_seconds < 4.);
+        assert_close(tempo_map.beats_to_seconds(5.), ramp_seconds + 1.);
+
+        for beats in [0., 0.5, 1., 3.25, 4., 7.5] {
+            assert_close(tempo_map.seconds_to_beats(tempo_map.beats_to_seconds(beats)), beats);
+        }
+    }
+
+    #[test]
+    fn negative_times_convert_to_zero() {
+        let tempo_map = TempoMap::new(120.);
+        assert_eq!(tempo_map.beats_to_seconds(-1.), 0.);
+        assert_eq!(tempo_map.seconds_to_beats(-1.), 0.);
+    }
+}
@@ -1,21 +1,117 @@
 use std::{
-    borrow::BorrowMut,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     ops::{Index, IndexMut},
-    sync::atomic::AtomicU32,
+    sync::atomic::{AtomicU32, AtomicU64},
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    midi::{MidiClip, Note},
+    audio::{
+        audio_processor::{AudioProcessor, BuiltinPlugin, ParamId, PluginDescription, PluginType},
+        sampler::{Kit, Sampler},
+        Audio,
+    },
+    automation::AutomationLane,
+    clip::{arrange, ClipId, TrackClip},
+    midi::{ControllerLane, MidiEventsBlockList, Note, Time},
+    midi_output::MidiOutputSettings,
+    mixer::MixerSettings,
+    routing::Send,
     ui::{reactive::Reactive, style::Colour, reactive_list::ReactiveListKey},
 };
 
 static TRACK_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
+static INSERT_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 pub type TrackId = u32;
+pub type InsertId = u64;
 
-#[derive(Clone)]
-pub struct Instrument {}
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Instrument {
+    /// Shares the insert ids, so parameter changes can name either.
+    #[serde(skip, default = "next_insert_id")]
+    pub id: InsertId,
+    pub plugin: PluginDescription,
+    /// Samples for the built-in sampler.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kit: Option<Kit>,
+    /// Parameters that have been changed from the plugin's defaults.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<ParamId, f32>,
+}
+
+impl Instrument {
+    pub fn new(plugin: PluginDescription) -> Self {
+        Self {
+            id: next_insert_id(),
+            plugin,
+            kit: None,
+            params: BTreeMap::new(),
+        }
+    }
+
+    /// The built-in synth, which new MIDI tracks start with.
+    pub fn synth() -> Self {
+        Self::new(PluginDescription::builtin(BuiltinPlugin::Synth))
+    }
+
+    pub fn sampler(kit: Kit) -> Self {
+        Self {
+            kit: Some(kit),
+            ..Self::new(PluginDescription::builtin(BuiltinPlugin::Sampler))
+        }
+    }
+
+    /// Samples missing from a kit are left out, with why added to `errors`.
+    pub fn load(
+        &self,
+        a: &Audio,
+        errors: &mut Vec<String>,
+    ) -> Result<Box<dyn AudioProcessor>, String> {
+        match (&self.plugin.type_, &self.kit) {
+            (PluginType::Builtin(BuiltinPlugin::Sampler), Some(kit)) => Ok(Box::new(Sampler::new(
+                kit,
+                a.sample_rate.get_copy(),
+                a.block_size.get_copy(),
+                errors,
+            ))),
+            _ => self.plugin.load(a),
+        }
+    }
+}
+
+/// An effect in a track's insert chain.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Insert {
+    /// Only unique within a session, so not saved.
+    #[serde(skip, default = "next_insert_id")]
+    pub id: InsertId,
+    pub plugin: PluginDescription,
+    pub bypass: bool,
+    /// Track whose output keys the effect, for effects that take one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sidechain: Option<TrackId>,
+    /// Parameters that have been changed from the plugin's defaults.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<ParamId, f32>,
+}
+
+impl Insert {
+    pub fn new(plugin: PluginDescription) -> Self {
+        Self {
+            id: next_insert_id(),
+            plugin,
+            bypass: false,
+            sidechain: None,
+            params: BTreeMap::new(),
+        }
+    }
+}
+
+fn next_insert_id() -> InsertId {
+    INSERT_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+}
 
 #[derive(Clone)]
 pub struct Track {
@@ -24,12 +120,29 @@ pub struct Track {
     pub colour: Colour,
     pub type_: TrackType,
     pub data: TrackData,
+    pub mixer: Reactive<MixerSettings>,
+    /// Effects applied after the instrument, in order.
+    pub inserts: Vec<Insert>,
+    pub sends: Vec<Send>,
+    pub automation: Vec<AutomationLane>,
+    /// External port the track's notes are also sent to.
+    pub midi_output: Option<MidiOutputSettings>,
 }
 
 impl Track {
     pub fn new(type_: TrackType) -> Self {
+        Self::with_uid(
+            TRACK_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+            type_,
+        )
+    }
+
+    /// Creates a track with a known id, e.g. one read from a project file.
+    pub fn with_uid(uid: TrackId, type_: TrackType) -> Self {
+        TRACK_ID_COUNTER.fetch_max(uid + 1, std::sync::atomic::Ordering::SeqCst);
+
         Track {
-            uid: TRACK_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+            uid,
             name: "Untitled".to_string(),
             colour: Colour {
                 r: 1.,
@@ -39,34 +152,134 @@ impl Track {
             },
             type_,
             data: TrackData::new(type_),
+            mixer: Reactive::new(MixerSettings::default()),
+            inserts: vec![],
+            sends: vec![],
+            automation: vec![],
+            midi_output: None,
+        }
+    }
+
+    pub fn clips(&self) -> &[TrackClip] {
+        match &self.data {
+            TrackData::Midi(_, clips) => clips,
+            _ => &[],
         }
     }
 
-    pub fn push_note(&mut self, note: Note) -> Option<ReactiveListKey> {
+    pub fn clip(&self, clip_id: ClipId) -> Option<&TrackClip> {
+        self.clips().iter().find(|clip| clip.uid == clip_id)
+    }
+
+    pub fn clip_mut(&mut self, clip_id: ClipId) -> Option<&mut TrackClip> {
         match &mut self.data {
-            TrackData::Midi(_, clip) => Some(clip.notes.push(Reactive::new(note))) ,
-            _ => None
+            TrackData::Midi(_, clips) => clips.iter_mut().find(|clip| clip.uid == clip_id),
+            _ => None,
         }
     }
 
-    pub fn remove_note(&mut self, note_id: ReactiveListKey) {
+    /// The first clip playing at `t`.
+    pub fn clip_at(&self, t: Time) -> Option<&TrackClip> {
+        self.clips().iter().find(|clip| clip.contains(t))
+    }
+
+    /// Adds `clip`, or replaces the clip with the same uid. Clips are kept in
+    /// order of where they start.
+    pub fn set_clip(&mut self, clip: TrackClip) {
+        if let TrackData::Midi(_, clips) = &mut self.data {
+            clips.retain(|c| c.uid != clip.uid);
+            let i = clips.partition_point(|c| c.start <= clip.start);
+            clips.insert(i, clip);
+        }
+    }
+
+    pub fn remove_clip(&mut self, clip_id: ClipId) -> Option<TrackClip> {
         match &mut self.data {
-            TrackData::Midi(_, clip) => {
-                clip.notes.remove(&note_id);
-            },
-            _ => {}
+            TrackData::Midi(_, clips) => {
+                let i = clips.iter().position(|clip| clip.uid == clip_id)?;
+                Some(clips.remove(i))
+            }
+            _ => None,
         }
     }
 
-    pub fn get_note_from_id(&self, note_id: ReactiveListKey) -> Option<Reactive<Note>> {
+    pub fn set_clips(&mut self, mut new_clips: Vec<TrackClip>) {
+        if let TrackData::Midi(_, clips) = &mut self.data {
+            new_clips.sort_by(|a, b| a.start.total_cmp(&b.start));
+            *clips = new_clips;
+        }
+    }
+
+    /// Everything the track's clips play, indexed by time on the timeline.
+    pub fn events(&self) -> MidiEventsBlockList {
+        arrange(self.clips())
+    }
+
+    /// Where the last clip ends.
+    pub fn end_time(&self) -> Time {
+        self.clips().iter().map(|clip| clip.end()).fold(0., Time::max)
+    }
+
+    pub fn push_note(&mut self, clip_id: ClipId, note: Note) -> Option<ReactiveListKey> {
+        let clip = self.clip_mut(clip_id)?;
+        Some(clip.midi.notes.push(Reactive::new(note)))
+    }
+
+    pub fn remove_note(&mut self, note_id: ReactiveListKey) {
+        if let Some(clip) = self.clip_of_note(note_id).and_then(|id| self.clip_mut(id)) {
+            clip.midi.notes.remove(&note_id);
+        }
+    }
+
+    /// A clip holding the note. Linked copies all do, so any of them may be
+    /// returned.
+    pub fn clip_of_note(&self, note_id: ReactiveListKey) -> Option<ClipId> {
+        self.clips()
+            .iter()
+            .find(|clip| clip.midi.get_note(note_id).is_some())
+            .map(|clip| clip.uid)
+    }
+
+    pub fn controllers(&self, clip_id: ClipId) -> Vec<ControllerLane> {
+        self.clip(clip_id)
+            .map(|clip| clip.midi.controllers())
+            .unwrap_or_default()
+    }
+
+    pub fn set_controllers(&mut self, clip_id: ClipId, lanes: Vec<ControllerLane>) {
+        if let Some(clip) = self.clip_mut(clip_id) {
+            clip.midi.set_controllers(lanes);
+        }
+    }
+
+    pub fn instrument(&self) -> Option<&Instrument> {
         match &self.data {
-            TrackData::Midi(_, clip) => clip.get_note(note_id),
-            _ => None
+            TrackData::Midi(instrument, _) => instrument.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn instrument_mut(&mut self) -> Option<&mut Instrument> {
+        match &mut self.data {
+            TrackData::Midi(instrument, _) => instrument.as_mut(),
+            _ => None,
         }
     }
+
+    pub fn set_instrument(&mut self, instrument: Option<Instrument>) {
+        if let TrackData::Midi(slot, _) = &mut self.data {
+            *slot = instrument;
+        }
+    }
+
+    pub fn get_note_from_id(&self, note_id: ReactiveListKey) -> Option<Reactive<Note>> {
+        self.clips()
+            .iter()
+            .find_map(|clip| clip.midi.get_note(note_id))
+    }
 }
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum TrackType {
     Midi,
     Audio,
@@ -74,14 +287,15 @@ pub enum TrackType {
 
 #[derive(Clone)]
 pub enum TrackData {
-    Midi(Option<Instrument>, MidiClip),
+    /// Clips in order of where they start.
+    Midi(Option<Instrument>, Vec<TrackClip>),
     Audio(Vec<f32>),
 }
 
 impl TrackData {
     pub fn new(type_: TrackType) -> Self {
         match type_ {
-            TrackType::Midi => TrackData::Midi(None, MidiClip::new()),
+            TrackType::Midi => TrackData::Midi(Some(Instrument::synth()), vec![TrackClip::empty(0.)]),
             TrackType::Audio => TrackData::Audio(vec![]),
         }
     }
@@ -91,6 +305,12 @@ pub struct TrackGroup {
     pub tracks: HashMap<TrackId, Track>,
 }
 
+impl Default for TrackGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TrackGroup {
     pub fn new() -> Self {
         TrackGroup {
@@ -114,13 +334,13 @@ impl TrackGroup {
 impl Index<TrackId> for TrackGroup {
     type Output = Track;
 
-    fn index<'a>(&'a self, index: TrackId) -> &'a Self::Output {
+    fn index(&self, index: TrackId) -> &Self::Output {
         self.tracks.get(&index).unwrap()
     }
 }
 
 impl IndexMut<TrackId> for TrackGroup {
-    fn index_mut<'a>(&'a mut self, index: TrackId) -> &'a mut Self::Output {
+    fn index_mut(&mut self, index: TrackId) -> &mut Self::Output {
         self.tracks.get_mut(&index).unwrap()
     }
 }
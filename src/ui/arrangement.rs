@@ -0,0 +1,440 @@
+use glow::Context;
+use sdl2::sys::{KeyCode, SDL_KeyCode};
+
+use crate::{
+    bind_reactives,
+    clip::TrackClip,
+    global::{EditingContext, Globals},
+    midi::Time,
+    shortcuts::k,
+    track::TrackId,
+    ui::{
+        style::{Colour, Style},
+        Coordinate, Dimensions, Position, Size,
+    },
+    utils::rc_ref_cell,
+};
+
+use super::{element::Element, frame_buf::FrameBuf, p, text::Text, ComputedDimensions};
+
+const TRACK_HEIGHT: f32 = 40.;
+const NAME_WIDTH: f32 = 180.;
+const BEAT_WIDTH: f32 = 8.;
+const CLIP_BORDER: f32 = 2.;
+const MAX_SHOWN_TRACKS: usize = 16;
+/// Clips in view past this many aren't drawn. The selected track's are
+/// drawn first.
+const MAX_SHOWN_CLIPS: usize = 128;
+
+/// What the arrangement shows, worked out by `refresh_arrangement`.
+#[derive(Clone, PartialEq, Default)]
+pub struct ArrangementView {
+    pub tracks: Vec<TrackView>,
+    pub clips: Vec<ClipView>,
+    /// Beat at the left edge of the clips.
+    pub scroll: Time,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct TrackView {
+    pub name: String,
+    pub colour: Colour,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct ClipView {
+    /// Which of the shown tracks it's on.
+    pub track: usize,
+    pub start: Time,
+    pub length: Time,
+    pub colour: Colour,
+    pub label: String,
+    pub selected: bool,
+}
+
+/// Lays out every track as a lane of its clips. While open it takes the
+/// keyboard: `j`/`k` pick a track, `h`/`l` a clip on it, `g`/`G` jump to the
+/// first or last track and `0`/`$` to the first or last clip. Enter opens the
+/// clip in the piano roll and Escape closes it.
+pub fn fb_arrangement(
+    gl: &Context,
+    globals: &mut Globals,
+    parent_dims: &ComputedDimensions,
+) -> FrameBuf {
+    let pos = p(0., 0.);
+    let dims = Dimensions {
+        width: Size::FractionOfParent(1.),
+        height: Size::FractionOfParentWithOffset(1., -globals.top_bar_size),
+    };
+
+    let mut frame_buf = FrameBuf::new(gl, None, pos, dims, *parent_dims);
+    let needs_rerender = frame_buf.children_need_rerender.clone();
+    let frame_bounding_box = frame_buf.bounding_box.clone();
+
+    let container_style = Style {
+        background_colour: globals.colour_palette.bg_primary,
+        ..Style::default()
+    };
+
+    let name_style = Style {
+        padding_left: 8.,
+        ..Style::default()
+    };
+
+    let clip_style = Style {
+        border_width: CLIP_BORDER,
+        padding_left: 4.,
+        ..Style::default()
+    };
+
+    let player_head_style = Style {
+        background_colour: globals.colour_palette.player_head,
+        ..Style::default()
+    };
+
+    let mut children: Vec<_> = (0..MAX_SHOWN_TRACKS)
+        .map(|i| {
+            let text = Text::new(
+                gl,
+                String::new(),
+                18.,
+                &globals.main_font,
+                globals.colour_palette.black,
+                Position::origin(),
+                needs_rerender.clone(),
+            );
+
+            let name = Element::new(
+                gl,
+                Position {
+                    x: Coordinate::Fixed(0.),
+                    y: lane_y(i),
+                },
+                Size::Fixed(NAME_WIDTH - CLIP_BORDER),
+                Size::Fixed(TRACK_HEIGHT - CLIP_BORDER),
+                Some(name_style.clone()),
+                Some(text),
+                needs_rerender.clone(),
+                frame_bounding_box.clone(),
+                vec![],
+            );
+
+            let view = globals.arrangement_view.clone();
+            bind_reactives! {
+                name {
+                    [view] => (|e: &mut Element, view: ArrangementView| {
+                        match view.tracks.get(i) {
+                            Some(track) => {
+                                let text = track.name.clone();
+                                e.style.visible = true;
+                                e.style.background_colour = track.colour;
+                                e.text_node.as_mut().unwrap().mutate(Box::new(move |t| {
+                                    t.text = text.clone();
+                                }));
+                            }
+                            None => e.style.visible = false,
+                        }
+                    }),
+                }
+            }
+
+            name
+        })
+        .collect();
+
+    children.extend((0..MAX_SHOWN_CLIPS).map(|i| {
+        let text = Text::new(
+            gl,
+            String::new(),
+            14.,
+            &globals.main_font,
+            globals.colour_palette.black,
+            Position::origin(),
+            needs_rerender.clone(),
+        );
+
+        let clip = Element::new(
+            gl,
+            Position::origin(),
+            Size::Fixed(0.),
+            Size::Fixed(TRACK_HEIGHT - CLIP_BORDER),
+            Some(clip_style.clone()),
+            Some(text),
+            needs_rerender.clone(),
+            frame_bounding_box.clone(),
+            vec![],
+        );
+
+        let selected = globals.colour_palette.selected;
+        let unselected = globals.colour_palette.black;
+        let view = globals.arrangement_view.clone();
+        bind_reactives! {
+            clip {
+                [view] => (|e: &mut Element, view: ArrangementView| {
+                    let clip = match view.clips.get(i) {
+                        Some(clip) => clip.clone(),
+                        None => {
+                            e.style.visible = false;
+                            return;
+                        }
+                    };
+
+                    let left = x_of_time(clip.start, view.scroll).max(NAME_WIDTH);
+                    let right = x_of_time(clip.start + clip.length, view.scroll);
+
+                    e.style.visible = right > left;
+                    e.position = Position {
+                        x: Coordinate::Fixed(left),
+                        y: lane_y(clip.track),
+                    };
+                    e.dimensions.width = Size::Fixed((right - left).max(1.));
+                    e.style.background_colour = clip.colour;
+                    e.style.border_colour = if clip.selected { selected } else { unselected };
+                    e.text_node.as_mut().unwrap().mutate(Box::new(move |t| {
+                        t.text = clip.label.clone();
+                    }));
+                }),
+            }
+        }
+
+        clip
+    }));
+
+    let player_head = Element::new(
+        gl,
+        Position::origin(),
+        Size::Fixed(1.),
+        Size::FractionOfParent(1.),
+        Some(player_head_style),
+        None,
+        needs_rerender.clone(),
+        frame_bounding_box.clone(),
+        vec![],
+    );
+
+    let view = globals.arrangement_view.clone();
+    let player_time = globals.loaded_project.player_time.clone();
+    bind_reactives! {
+        player_head {
+            [view, player_time] => (|e: &mut Element, view: ArrangementView, player_time| {
+                let x = x_of_time(player_time, view.scroll);
+                e.style.visible = x >= NAME_WIDTH;
+                e.position.x = Coordinate::Fixed(x);
+            }),
+        }
+    }
+
+    children.push(player_head);
+
+    let container = Element::new(
+        gl,
+        Position::origin(),
+        Size::FractionOfParent(1.),
+        Size::FractionOfParent(1.),
+        Some(container_style),
+        None,
+        needs_rerender.clone(),
+        frame_bounding_box.clone(),
+        children,
+    );
+
+    globals.subscriptions.subscribe_key(rc_ref_cell(|key, globals: &mut Globals| {
+        if globals.editor_context.get_copy() != EditingContext::Arrangement
+            || globals.arrangement_just_opened
+        {
+            return;
+        }
+
+        let num_tracks = arrangement_tracks(globals).len();
+        let num_clips = selected_track(globals)
+            .map(|track_id| globals.loaded_project.tracks[track_id].clips().len())
+            .unwrap_or(0);
+
+        if key.code == SDL_KeyCode::SDLK_ESCAPE as KeyCode {
+            globals.editor_context <<= EditingContext::PianoRoll;
+            return;
+        } else if key.code == SDL_KeyCode::SDLK_RETURN as KeyCode {
+            if let Some((track_id, clip)) = selected_clip(globals) {
+                globals.editing_clip = Some((track_id, clip.uid));
+                globals.editor_context <<= EditingContext::PianoRoll;
+                return;
+            }
+        } else if *key == k("j") {
+            select_track(globals, (globals.arrangement_track + 1).min(num_tracks.saturating_sub(1)));
+        } else if *key == k("k") {
+            select_track(globals, globals.arrangement_track.saturating_sub(1));
+        } else if *key == k("g") {
+            select_track(globals, 0);
+        } else if *key == k("G") {
+            select_track(globals, num_tracks.saturating_sub(1));
+        } else if *key == k("l") {
+            globals.arrangement_clip = (globals.arrangement_clip + 1).min(num_clips.saturating_sub(1));
+        } else if *key == k("h") {
+            globals.arrangement_clip = globals.arrangement_clip.saturating_sub(1);
+        } else if *key == k("0") {
+            globals.arrangement_clip = 0;
+        } else if *key == k("$") {
+            globals.arrangement_clip = num_clips.saturating_sub(1);
+        } else if *key == k("u") {
+            globals.loaded_project.undo();
+        } else if *key == k("^r") {
+            globals.loaded_project.redo();
+        }
+
+        refresh_arrangement(globals);
+    }));
+
+    frame_buf.root_node = Some(container);
+    frame_buf
+}
+
+/// Opens the arrangement with the clip open in the piano roll selected.
+pub fn open_arrangement(globals: &mut Globals) {
+    if let Some((track_id, clip_id)) = globals.editing_clip {
+        let track_ids = arrangement_tracks(globals);
+        if let Some(i) = track_ids.iter().position(|id| *id == track_id) {
+            globals.arrangement_track = i;
+            globals.arrangement_clip = globals.loaded_project.tracks[track_id]
+                .clips()
+                .iter()
+                .position(|clip| clip.uid == clip_id)
+                .unwrap_or(0);
+        }
+    }
+
+    refresh_arrangement(globals);
+    globals.editor_context <<= EditingContext::Arrangement;
+    globals.arrangement_just_opened = true;
+}
+
+/// Tracks in the order the arrangement lists them.
+fn arrangement_tracks(globals: &Globals) -> Vec<TrackId> {
+    let mut track_ids: Vec<TrackId> = globals.loaded_project.tracks.tracks.keys().cloned().collect();
+    track_ids.sort();
+    track_ids
+}
+
+fn selected_track(globals: &Globals) -> Option<TrackId> {
+    arrangement_tracks(globals).get(globals.arrangement_track).cloned()
+}
+
+fn selected_clip(globals: &Globals) -> Option<(TrackId, TrackClip)> {
+    let track_id = selected_track(globals)?;
+    let clip = globals.loaded_project.tracks[track_id]
+        .clips()
+        .get(globals.arrangement_clip)?;
+    Some((track_id, clip.clone()))
+}
+
+/// Moves to another track, picking the clip there that starts closest to
+/// the one selected now.
+fn select_track(globals: &mut Globals, index: usize) {
+    let t = selected_clip(globals).map(|(_, clip)| clip.start).unwrap_or(0.);
+    globals.arrangement_track = index;
+
+    let clips = match selected_track(globals) {
+        Some(track_id) => globals.loaded_project.tracks[track_id].clips(),
+        None => return,
+    };
+
+    globals.arrangement_clip = clips
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| (a.start - t).abs().total_cmp(&(b.start - t).abs()))
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+}
+
+fn lane_y(shown_index: usize) -> Coordinate {
+    Coordinate::FractionOfParentWithOffset(1., -TRACK_HEIGHT * (shown_index + 1) as f32)
+}
+
+fn x_of_time(t: Time, scroll: Time) -> f32 {
+    NAME_WIDTH + BEAT_WIDTH * (t - scroll) as f32
+}
+
+fn clip_label(index: usize, clip: &TrackClip, clips: &[TrackClip]) -> String {
+    let mut label = format!("{}", index + 1);
+    if clip.loop_length.is_some() {
+        label.push_str(" loop");
+    }
+    if clips
+        .iter()
+        .any(|other| other.uid != clip.uid && other.midi.shares_notes_with(&clip.midi))
+    {
+        label.push_str(" link");
+    }
+    label
+}
+
+/// Brings the arrangement up to date with the project, keeping the selection
+/// in view. Cheap when nothing has changed, so it's called every frame while
+/// the arrangement is open.
+pub fn refresh_arrangement(globals: &mut Globals) {
+    let track_ids = arrangement_tracks(globals);
+    globals.arrangement_track = globals
+        .arrangement_track
+        .min(track_ids.len().saturating_sub(1));
+
+    let num_clips = selected_track(globals)
+        .map(|track_id| globals.loaded_project.tracks[track_id].clips().len())
+        .unwrap_or(0);
+    globals.arrangement_clip = globals.arrangement_clip.min(num_clips.saturating_sub(1));
+
+    let shown_beats = ((globals.screen_dims.width - NAME_WIDTH) / BEAT_WIDTH).max(1.) as Time;
+    if let Some((_, clip)) = selected_clip(globals) {
+        if clip.start < globals.arrangement_scroll {
+            globals.arrangement_scroll = clip.start;
+        } else if clip.end() > globals.arrangement_scroll + shown_beats {
+            globals.arrangement_scroll = (clip.end() - shown_beats).min(clip.start);
+        }
+    }
+
+    // Keep the selection in view when there are more tracks than lanes.
+    let first = (globals.arrangement_track + 1).saturating_sub(MAX_SHOWN_TRACKS);
+
+    let mut view = ArrangementView {
+        scroll: globals.arrangement_scroll,
+        ..ArrangementView::default()
+    };
+    let (from, to) = (view.scroll, view.scroll + shown_beats);
+
+    for (shown, (i, track_id)) in track_ids
+        .iter()
+        .enumerate()
+        .skip(first)
+        .take(MAX_SHOWN_TRACKS)
+        .enumerate()
+    {
+        let track = &globals.loaded_project.tracks[*track_id];
+        let cursor = if i == globals.arrangement_track { "> " } else { "" };
+
+        view.tracks.push(TrackView {
+            name: format!("{}{}", cursor, track.name),
+            colour: track.colour,
+        });
+
+        let clips = track.clips();
+        for (j, clip) in clips.iter().enumerate() {
+            if clip.end() <= from || clip.start >= to {
+                continue;
+            }
+
+            view.clips.push(ClipView {
+                track: shown,
+                start: clip.start,
+                length: clip.length,
+                colour: track.colour,
+                label: clip_label(j, clip, clips),
+                selected: i == globals.arrangement_track && j == globals.arrangement_clip,
+            });
+        }
+    }
+
+    let selected = globals.arrangement_track - first;
+    view.clips.sort_by_key(|clip| clip.track != selected);
+
+    if view != globals.arrangement_view.get_copy() {
+        globals.arrangement_view <<= view;
+    }
+}
@@ -0,0 +1,362 @@
+use std::{cell::RefCell, rc::Rc};
+
+use glow::Context;
+use sdl2::mouse::MouseButton;
+
+use crate::{
+    audio::audio_processor::ParamId,
+    automation::{AutomationTarget, Breakpoint, Curve},
+    bind_reactives,
+    global::{EditingContext, Globals},
+    midi::Time,
+    project::Action,
+    track::{InsertId, TrackId},
+    ui::{style::Style, Coordinate, Dimensions, Position, Size},
+};
+
+use super::{
+    element::Element, frame_buf::FrameBuf, mixer::selected_track, p, text::Text,
+    ComputedDimensions,
+};
+
+pub const LANE_HEIGHT: f32 = 120.;
+const MAX_SHOWN_POINTS: usize = 64;
+const POINT_SIZE: f32 = 6.;
+/// How close in pixels a right click has to be to a point to remove it.
+const REMOVE_DISTANCE: f32 = 8.;
+
+/// What the lane view shows, worked out by `refresh_automation_view`.
+#[derive(Clone, PartialEq, Default)]
+pub struct AutomationView {
+    pub label: String,
+    /// Breakpoint times with their values scaled to 0..1 over the lane's
+    /// range.
+    pub points: Vec<(Time, f32)>,
+}
+
+/// Shows one automation lane of the selected track under the piano roll,
+/// lined up with its time axis. Left click adds a breakpoint and right click
+/// removes the nearest one. `lane-show` picks the lane.
+pub fn fb_automation(gl: &Context, globals: &mut Globals, parent_dims: &ComputedDimensions) -> FrameBuf {
+    let pos = p(0., 0.);
+    let dims = Dimensions {
+        width: Size::FractionOfParent(1.),
+        height: Size::Fixed(LANE_HEIGHT),
+    };
+
+    let mut frame_buf = FrameBuf::new(gl, None, pos, dims, *parent_dims);
+    let needs_rerender = frame_buf.children_need_rerender.clone();
+    let frame_bounding_box = frame_buf.bounding_box.clone();
+
+    let container_style = Style {
+        background_colour: globals.colour_palette.bg_primary,
+        ..Style::default()
+    };
+
+    let label_style = Style {
+        render_self: false,
+        padding_left: 8.,
+        ..Style::default()
+    };
+
+    let point_style = Style {
+        background_colour: globals.colour_palette.white,
+        ..Style::default()
+    };
+
+    let label_text = Text::new(
+        gl,
+        String::new(),
+        16.,
+        &globals.main_font,
+        globals.colour_palette.text_primary,
+        Position::origin(),
+        needs_rerender.clone(),
+    );
+
+    let label = Element::new(
+        gl,
+        p(0., LANE_HEIGHT - 22.),
+        Size::FractionOfParent(1.),
+        Size::Fixed(22.),
+        Some(label_style),
+        Some(label_text),
+        needs_rerender.clone(),
+        frame_bounding_box.clone(),
+        vec![],
+    );
+
+    let view = globals.automation_view.clone();
+    bind_reactives! {
+        label {
+            [view] => (|e: &mut Element, view: AutomationView| {
+                e.text_node.as_mut().unwrap().mutate(Box::new(move |text| {
+                    text.text = view.label.clone();
+                }));
+            }),
+        }
+    }
+
+    let keyboard_width = globals.piano_roll_keyboard_width;
+
+    let mut children: Vec<_> = (0..MAX_SHOWN_POINTS)
+        .map(|i| {
+            let point = Element::new(
+                gl,
+                Position::origin(),
+                Size::Fixed(POINT_SIZE),
+                Size::Fixed(POINT_SIZE),
+                Some(point_style.clone()),
+                None,
+                needs_rerender.clone(),
+                frame_bounding_box.clone(),
+                vec![],
+            );
+
+            let view = globals.automation_view.clone();
+            let ts = globals.viewport.time_scroll.clone();
+            let hz = globals.viewport.h_zoom.clone();
+            bind_reactives! {
+                point {
+                    [view, ts, hz] => (|e: &mut Element, view: AutomationView, ts, hz| {
+                        match view.points.get(i) {
+                            Some((t, value)) => {
+                                let x = keyboard_width + hz * (*t as f32 - ts);
+                                let y = value * (LANE_HEIGHT - POINT_SIZE);
+                                e.position.x = Coordinate::Fixed(x - POINT_SIZE / 2.);
+                                e.position.y = Coordinate::Fixed(y);
+                                e.style.visible = x >= keyboard_width;
+                            }
+                            None => e.style.visible = false,
+                        }
+                    }),
+                }
+            }
+
+            point
+        })
+        .collect();
+
+    children.push(label);
+
+    let container = Element::new(
+        gl,
+        Position::origin(),
+        Size::FractionOfParent(1.),
+        Size::FractionOfParent(1.),
+        Some(container_style),
+        None,
+        needs_rerender.clone(),
+        frame_bounding_box.clone(),
+        children,
+    );
+
+    let bounding_box = frame_bounding_box.clone();
+    globals.subscriptions.subscribe_click_in_area(
+        frame_bounding_box,
+        Rc::new(RefCell::new(move |mb: &MouseButton, globals: &mut Globals| {
+            if globals.editor_context.get_copy() != EditingContext::PianoRoll {
+                return;
+            }
+
+            let bb = match bounding_box.borrow().clone() {
+                Some(bb) => bb,
+                None => return,
+            };
+
+            let x = globals.mouse_pos.x - bb.top_left.x;
+            let y = globals.mouse_pos.y - bb.top_left.y;
+            if x < keyboard_width {
+                return;
+            }
+
+            match mb {
+                MouseButton::Left => add_point_at(globals, x - keyboard_width, y),
+                MouseButton::Right => remove_point_near(globals, x - keyboard_width),
+                _ => (),
+            }
+        })),
+    );
+
+    frame_buf.root_node = Some(container);
+    frame_buf
+}
+
+/// The lane `lane-show` picked on the selected track, if it has one.
+fn shown_lane(globals: &Globals) -> Option<(TrackId, usize)> {
+    let track_id = selected_track(globals)?;
+    let lanes = &globals.loaded_project.tracks[track_id].automation;
+    let lane = globals.automation_lane.min(lanes.len().checked_sub(1)?);
+    Some((track_id, lane))
+}
+
+fn time_at(globals: &Globals, x: f32) -> Time {
+    let time_scroll = globals.viewport.time_scroll.get_copy();
+    let h_zoom = globals.viewport.h_zoom.get_copy();
+    (time_scroll + x / h_zoom).max(0.) as Time
+}
+
+fn add_point_at(globals: &mut Globals, x: f32, y: f32) {
+    let (track_id, lane) = match shown_lane(globals) {
+        Some(shown) => shown,
+        None => return,
+    };
+
+    let target = globals.loaded_project.tracks[track_id].automation[lane].target;
+    let (min, max) = match lane_range(globals, track_id, target) {
+        Some(range) => range,
+        None => return,
+    };
+
+    let scaled = (y / LANE_HEIGHT).clamp(0., 1.);
+    let point = Breakpoint {
+        time: time_at(globals, x),
+        value: min + scaled * (max - min),
+        curve: Curve::Linear,
+    };
+
+    globals.loaded_project.perform_action(Action::AddBreakpoint {
+        track_id,
+        lane,
+        point,
+    });
+}
+
+fn remove_point_near(globals: &mut Globals, x: f32) {
+    let (track_id, lane) = match shown_lane(globals) {
+        Some(shown) => shown,
+        None => return,
+    };
+
+    let t = time_at(globals, x);
+    let max_distance = (REMOVE_DISTANCE / globals.viewport.h_zoom.get_copy()) as Time;
+
+    let nearest = globals.loaded_project.tracks[track_id].automation[lane]
+        .points
+        .iter()
+        .enumerate()
+        .map(|(i, point)| (i, (point.time - t).abs()))
+        .filter(|(_, distance)| *distance <= max_distance)
+        .min_by(|a, b| a.1.total_cmp(&b.1));
+
+    if let Some((index, _)) = nearest {
+        globals.loaded_project.perform_action(Action::RemoveBreakpoint {
+            track_id,
+            lane,
+            index,
+        });
+    }
+}
+
+/// The values a lane on `track_id` can take. `None` for parameters of
+/// inserts the engine hasn't loaded.
+pub fn lane_range(globals: &Globals, track_id: TrackId, target: AutomationTarget) -> Option<(f32, f32)> {
+    if let Some(range) = target.fixed_range() {
+        return Some(range);
+    }
+
+    let (processor, _, param) = lane_processor(globals, track_id, target)?;
+
+    let descriptor = globals
+        .engine
+        .as_ref()?
+        .params(processor)?
+        .iter()
+        .find(|d| d.id == param)?;
+
+    Some((descriptor.min, descriptor.max))
+}
+
+pub fn lane_name(globals: &Globals, track_id: TrackId, target: AutomationTarget) -> String {
+    match target {
+        AutomationTarget::Gain => return "Gain (dB)".to_string(),
+        AutomationTarget::Pan => return "Pan".to_string(),
+        _ => (),
+    }
+
+    let (processor, plugin_name, param) = match lane_processor(globals, track_id, target) {
+        Some(found) => found,
+        None => return "Removed plugin".to_string(),
+    };
+
+    let param_name = globals
+        .engine
+        .as_ref()
+        .and_then(|engine| engine.params(processor))
+        .and_then(|params| params.iter().find(|d| d.id == param))
+        .map(|d| d.name.clone())
+        .unwrap_or_else(|| format!("#{}", param + 1));
+
+    format!("{}: {}", plugin_name, param_name)
+}
+
+/// The id and name of the instrument or insert a param lane drives, and the
+/// param. `None` for other lanes, or if the plugin is gone.
+fn lane_processor(
+    globals: &Globals,
+    track_id: TrackId,
+    target: AutomationTarget,
+) -> Option<(InsertId, &str, ParamId)> {
+    let track = &globals.loaded_project.tracks[track_id];
+
+    match target {
+        AutomationTarget::Param { insert, param } => {
+            let insert = track.inserts.iter().find(|i| i.id == insert)?;
+            Some((insert.id, &insert.plugin.name, param))
+        }
+        AutomationTarget::InstrumentParam { param } => {
+            let instrument = track.instrument()?;
+            Some((instrument.id, &instrument.plugin.name, param))
+        }
+        _ => None,
+    }
+}
+
+/// Brings the lane view up to date with the selected track's lanes. Cheap
+/// when nothing has changed, so it's called every frame.
+pub fn refresh_automation_view(globals: &mut Globals) {
+    let view = match shown_lane(globals) {
+        None => AutomationView {
+            label: match selected_track(globals) {
+                Some(track_id) => format!(
+                    "{}: no automation lanes",
+                    globals.loaded_project.tracks[track_id].name
+                ),
+                None => "No track selected".to_string(),
+            },
+            points: vec![],
+        },
+        Some((track_id, index)) => {
+            let track = &globals.loaded_project.tracks[track_id];
+            let lane = &track.automation[index];
+            let range = lane_range(globals, track_id, lane.target);
+
+            let label = format!(
+                "{}: lane {}/{} {}{}",
+                track.name,
+                index + 1,
+                track.automation.len(),
+                lane_name(globals, track_id, lane.target),
+                range
+                    .map(|(min, max)| format!(" [{}..{}]", min, max))
+                    .unwrap_or_default(),
+            );
+
+            let points = match range {
+                Some((min, max)) => lane
+                    .points
+                    .iter()
+                    .take(MAX_SHOWN_POINTS)
+                    .map(|point| (point.time, ((point.value - min) / (max - min)).clamp(0., 1.)))
+                    .collect(),
+                None => vec![],
+            };
+
+            AutomationView { label, points }
+        }
+    };
+
+    if view != globals.automation_view.get_copy() {
+        globals.automation_view <<= view;
+    }
+}
@@ -2,11 +2,14 @@ use glow::Context;
 use sdl2::sys::{KeyCode, SDL_KeyCode};
 
 use crate::{
+    commands::run_command,
     global::{Globals, EditingContext},
-    ui::{style::Style, Coordinate, Dimensions, Position, Size}, bind_reactives, utils::{RcRefCell, rc_ref_cell},
+    ui::{style::Style, Coordinate, Dimensions, Position, Size}, bind_reactives, utils::rc_ref_cell,
 };
 
-use super::{element::Element, frame_buf::FrameBuf, ComputedDimensions};
+use super::{element::Element, frame_buf::FrameBuf, p, text::Text, ComputedDimensions};
+
+const MAX_LISTED_COMMANDS: usize = 12;
 
 pub fn fb_command_palette(
     gl: &Context,
@@ -16,6 +19,7 @@ pub fn fb_command_palette(
     const TOP_GAP: f32 = 30.;
     const WIDTH: f32 = 550.;
     const HEIGHT: f32 = 390.;
+    const LINE_HEIGHT: f32 = 28.;
 
     let pos = Position {
         x: Coordinate::FractionOfParentWithOffset(0.5, -WIDTH / 2.),
@@ -36,6 +40,78 @@ pub fn fb_command_palette(
         ..Style::default()
     };
 
+    let line_style = Style {
+        render_self: false,
+        padding_left: 8.,
+        ..Style::default()
+    };
+
+    let line = |y: f32, globals: &Globals| {
+        let text = Text::new(
+            gl,
+            String::new(),
+            20.,
+            &globals.main_font,
+            globals.colour_palette.text_primary,
+            Position::origin(),
+            needs_rerender.clone(),
+        );
+
+        Element::new(
+            gl,
+            p(0., y),
+            Size::FractionOfParent(1.),
+            Size::Fixed(LINE_HEIGHT),
+            Some(line_style.clone()),
+            Some(text),
+            needs_rerender.clone(),
+            frame_bounding_box.clone(),
+            vec![],
+        )
+    };
+
+    let input_line = line(HEIGHT - LINE_HEIGHT, globals);
+    let list_lines: Vec<_> = (0..MAX_LISTED_COMMANDS)
+        .map(|i| line(HEIGHT - LINE_HEIGHT * (i + 2) as f32, globals))
+        .collect();
+
+    let commands = globals.commands.list();
+
+    let input = globals.command_palette_input.clone();
+    bind_reactives! {
+        input_line {
+            [input] => (|e: &mut Element, input: String| {
+                e.text_node.as_mut().unwrap().mutate(Box::new(move |text| {
+                    text.text = format!(":{}|", input);
+                }));
+            })
+        }
+    }
+
+    for (i, list_line) in list_lines.iter().enumerate() {
+        let entry = palette_entry(&commands, &globals.command_palette_input.get_copy(), i);
+        list_line.mutate(Box::new(move |e: &mut Element| {
+            let entry = entry.clone();
+            e.text_node.as_mut().unwrap().mutate(Box::new(move |text| {
+                text.text = entry.clone();
+            }));
+        }));
+
+        let commands = commands.clone();
+        list_line.subscribe_mutation_to_reactive(
+            &globals.command_palette_input,
+            Box::new(move |e: &mut Element, input: &String| {
+                let entry = palette_entry(&commands, input, i);
+                e.text_node.as_mut().unwrap().mutate(Box::new(move |text| {
+                    text.text = entry.clone();
+                }));
+            }),
+        );
+    }
+
+    let mut children = vec![input_line];
+    children.extend(list_lines);
+
     let container = Element::new(
         gl,
         Position::origin(),
@@ -45,19 +121,60 @@ pub fn fb_command_palette(
         None,
         needs_rerender.clone(),
         frame_bounding_box.clone(),
-        vec![],
+        children,
     );
 
+    globals.subscriptions.subscribe_text_input(rc_ref_cell(|text: &String, globals: &mut Globals| {
+        if globals.editor_context.get_copy() != EditingContext::CommandPallet {
+            return;
+        }
+
+        let text = text.clone();
+        globals
+            .command_palette_input
+            .mutate(Box::new(move |input| input.push_str(&text)));
+    }));
+
     globals.subscriptions.subscribe_key(rc_ref_cell(|key, globals: &mut Globals| {
         if globals.editor_context.get_copy() != EditingContext::CommandPallet {
             return;
         }
 
         if key.code == SDL_KeyCode::SDLK_ESCAPE as KeyCode {
+            globals.command_palette_input <<= String::new();
+            globals.editor_context <<= EditingContext::PianoRoll;
+        }
+
+        if key.code == SDL_KeyCode::SDLK_BACKSPACE as KeyCode {
+            globals.command_palette_input.mutate(Box::new(|input| {
+                input.pop();
+            }));
+        }
+
+        if key.code == SDL_KeyCode::SDLK_RETURN as KeyCode {
+            let input = globals.command_palette_input.get_copy();
+            globals.command_palette_input <<= String::new();
             globals.editor_context <<= EditingContext::PianoRoll;
+            globals.status <<= String::new();
+
+            if let Err(e) = run_command(globals, &input) {
+                globals.status <<= e;
+            }
         }
     }));
 
     frame_buf.root_node = Some(container);
     frame_buf
 }
+
+/// The `i`th command whose name starts with what has been typed so far.
+fn palette_entry(commands: &Vec<(&'static str, &'static str)>, input: &str, i: usize) -> String {
+    let name = input.split(' ').next().unwrap_or("");
+
+    commands
+        .iter()
+        .filter(|(n, _)| n.starts_with(name))
+        .nth(i)
+        .map(|(n, d)| format!("{}  {}", n, d))
+        .unwrap_or_default()
+}
@@ -44,7 +44,7 @@ impl ElementRef {
         Self { ptr, ref_count }
     }
 
-    pub fn borrow<'a>(&'a self) -> &'a Element {
+    pub fn borrow(&self) -> &Element {
         unsafe { &*self.ptr }
     }
 
@@ -93,7 +93,7 @@ impl ElementRef {
     ) where
         T: Clone + 'static,
     {
-        let mut element = self.clone();
+        let element = self.clone();
 
         let id = {
             let element = element.clone();
@@ -120,7 +120,7 @@ impl ElementRef {
     ) where
         T: Clone + 'static,
     {
-        let mut element = self.clone();
+        let element = self.clone();
 
         let id = {
             let element = element.clone();
@@ -187,10 +187,7 @@ impl Element {
             position,
             dimensions: Dimensions { width, height },
             children,
-            style: match style {
-                Some(style) => style,
-                None => Style::default(),
-            },
+            style: style.unwrap_or_default(),
             text_node: text,
             needs_rerender,
             quad: unsafe { Quad::new(gl) },
@@ -277,9 +274,7 @@ impl Element {
     }
 
     pub fn cleanup(&self, gl: &glow::Context) {
-        unsafe {
-            self.quad.cleanup(gl);
-        }
+        self.quad.cleanup(gl);
 
         for child in self.children.iter() {
             child.borrow().cleanup(gl);
@@ -1,15 +1,15 @@
-use std::{sync::Mutex, cell::{Cell, OnceCell}};
+use std::cell::RefCell;
 
 use glow::Context;
 
 use crate::{global::Globals, ui::element::ElementRef};
 
-static mut QUEUE: Vec<CreateElementRequest> = Vec::new();
+thread_local! {
+    static QUEUE: RefCell<Vec<CreateElementRequest>> = const { RefCell::new(Vec::new()) };
+}
 
 pub fn queue_element(f: CreateElementFn, parent: ElementRef) {
-    unsafe {
-        QUEUE.push(CreateElementRequest { f, parent });
-    }
+    QUEUE.with_borrow_mut(|queue| queue.push(CreateElementRequest { f, parent }));
 }
 
 pub type CreateElementFn = Box<dyn FnOnce(&Context, &mut Globals) -> ElementRef>;
@@ -20,14 +20,11 @@ struct CreateElementRequest {
 }
 
 pub fn fulfil_queue(gl: &Context, globals: &mut Globals) {
-    unsafe {
-        let queue = std::mem::take(&mut QUEUE);
-        for request in queue {
-            let element = (request.f)(gl, globals);
-            let parent = request.parent;
-            parent.mutate(Box::new(move |parent| {
-                parent.children.push(element.clone());
-            }));
-        }
+    for request in QUEUE.take() {
+        let element = (request.f)(gl, globals);
+        let parent = request.parent;
+        parent.mutate(Box::new(move |parent| {
+            parent.children.push(element.clone());
+        }));
     }
 }
\ No newline at end of file
@@ -1,12 +1,11 @@
-use std::{sync::atomic::AtomicUsize, cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
 use crate::global::Globals;
 
 use super::{
     compute_dims,
     element::*,
-    gl::{create_quad, render_textured_quad, RENDER_MODE_SOLID, RENDER_MODE_TEXTURE, Quad},
-    p, ComputedDimensions, Dimensions, Position, ComputedPosition, BoundingBoxRef, ComputedBoundingBox, reactive::Reactive,
+    gl::{render_textured_quad, Quad}, ComputedDimensions, Dimensions, Position, ComputedPosition, BoundingBoxRef, ComputedBoundingBox,
 };
 use glow::*;
 
@@ -78,7 +77,7 @@ impl FrameBuf {
             };
 
             ret.replace_texture(gl, comped_dims);
-            return ret;
+            ret
         }
     }
 
@@ -181,8 +180,8 @@ impl FrameBuf {
             gl.viewport(0, 0, dims.width as i32, dims.height as i32);
             gl.uniform_2_f32(
                 Some(&globals.element_uniform_locations["window_size"]),
-                dims.width as f32,
-                dims.height as f32,
+                dims.width,
+                dims.height,
             );
 
             gl.clear(glow::COLOR_BUFFER_BIT);
@@ -203,8 +202,8 @@ impl FrameBuf {
             );
             gl.uniform_2_f32(
                 Some(&globals.element_uniform_locations["window_size"]),
-                globals.screen_dims.width as f32,
-                globals.screen_dims.height as f32,
+                globals.screen_dims.width,
+                globals.screen_dims.height,
             );
         }
     }
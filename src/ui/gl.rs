@@ -2,7 +2,7 @@ use glow::*;
 
 use crate::global::Globals;
 
-use super::{ComputedDimensions, Position, ComputedPosition};
+use super::{ComputedDimensions, ComputedPosition};
 
 pub const RENDER_MODE_SOLID: i32 = 0;
 pub const RENDER_MODE_TEXTURE: i32 = 1;
@@ -119,9 +119,9 @@ pub unsafe fn render_textured_quad(
     pos: ComputedPosition,
     dims: &ComputedDimensions,
 ) {
-    gl.bind_vertex_array(Some((*quad).vao));
+    gl.bind_vertex_array(Some(quad.vao));
 
-    create_quad(gl, &quad.vbo, pos, &dims);
+    create_quad(gl, &quad.vbo, pos, dims);
 
     gl.uniform_1_i32(
         Some(&globals.element_uniform_locations["mode"]),
@@ -1,10 +1,10 @@
-use std::{cell::RefCell, default, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
-use sdl2::{libc::glob, mouse::MouseButton};
+use sdl2::mouse::MouseButton;
 
 use crate::{
-    event_subscriptions::{Key, SubscriptionId},
-    global::{Globals, EditingContext}, utils::{RcRefCell, rc_ref_cell},
+    event_subscriptions::Key,
+    global::{Globals, EditingContext},
 };
 
 use super::{
@@ -12,7 +12,7 @@ use super::{
     reactive::Reactive,
     style::Style,
     text::Text,
-    BoundingBoxRef, Dimensions, Position, Size,
+    BoundingBoxRef, Dimensions, Position,
 };
 
 pub fn e_f32_field(
@@ -31,7 +31,6 @@ pub fn e_f32_field(
 
     let current = value.get().borrow().to_string();
     let typing_buf = Reactive::new(current.to_string());
-    let inserting_at = Reactive::new(0);
     let previous_editor_context = Reactive::new(globals.editor_context.get_copy());
 
     let text = Text::new(
@@ -70,7 +69,7 @@ pub fn e_f32_field(
         &typing_buf,
         Box::new(move |element: &mut Element, new_value: &String| {
             if let Some(text) = &mut element.text_node {
-                text.text = format!("{}|", new_value.to_string());
+                text.text = format!("{}|", new_value);
                 text.needs_glyphs_rerender = true;
             }
         }),
@@ -107,7 +106,7 @@ fn start_input(globals: &mut Globals, typing_buf: Reactive<String>, value: React
         globals
             .subscriptions
             .subscribe_text_input(Rc::new(RefCell::new(
-                move |text: &String, globals: &mut Globals| {
+                move |text: &String, _globals: &mut Globals| {
                     let typing_buf = typing_buf.clone();
                     let text = text.clone();
                     typing_buf.mutate(Box::new(move |v| *v = format!("{}{}", v, text.clone())));
@@ -125,10 +124,10 @@ fn start_input(globals: &mut Globals, typing_buf: Reactive<String>, value: React
         move |key: &Key, globals: &mut Globals| {
             let mut value = value.clone();
             let typing_buf = typing_buf.clone();
-            let key = key.code.clone();
+            let key = key.code;
             if key == sdl2::keyboard::Keycode::Backspace as u8 {
                 typing_buf.mutate(Box::new(|v| {
-                    if v.len() < 1 {
+                    if v.is_empty() {
                         return;
                     }
 
@@ -181,7 +180,7 @@ pub fn e_button(
         needs_rerender.clone(),
     );
 
-    let mut container = Element::new(
+    let container = Element::new(
         gl,
         pos,
         dims.width,
@@ -196,7 +195,7 @@ pub fn e_button(
     globals.subscriptions.subscribe_click_in_area(
         container.borrow().bounding_box.clone(),
         Rc::new(RefCell::new(
-            move |mb: &MouseButton, globals: &mut Globals| {
+            move |mb: &MouseButton, _globals: &mut Globals| {
                 if mb == &MouseButton::Left {
                     on_click();
                 }
@@ -16,7 +16,7 @@ macro_rules! bind_reactives {
         }
     ) => {
         {
-            let element: crate::ui::element::ElementRef = $element.clone();
+            let element: $crate::ui::element::ElementRef = $element.clone();
 
             $({
                 $(let $reactive_dependency = $reactive_dependency.clone().get_copy();)*
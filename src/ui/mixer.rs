@@ -0,0 +1,346 @@
+use glow::Context;
+use sdl2::sys::{KeyCode, SDL_KeyCode};
+
+use crate::{
+    global::{EditingContext, Globals},
+    mixer::{MixerSettings, MAX_GAIN_DB, MIN_GAIN_DB},
+    project::Action,
+    routing::{Send, StripId},
+    shortcuts::k,
+    track::{Insert, TrackId},
+    ui::{style::Style, Coordinate, Dimensions, Position, Size},
+    utils::rc_ref_cell,
+};
+
+use super::{element::Element, frame_buf::FrameBuf, p, text::Text, ComputedDimensions};
+
+const MAX_LISTED_TRACKS: usize = 16;
+const GAIN_STEP_DB: f32 = 1.;
+const PAN_STEP: f32 = 0.1;
+
+/// Lists every track and bus with its gain, pan, mute and solo. While open it
+/// takes the keyboard: `j`/`k` pick a strip, `+`/`-` change gain, `h`/`l` pan,
+/// `m` mutes and `s` solos. Escape closes it.
+pub fn fb_mixer(gl: &Context, globals: &mut Globals, parent_dims: &ComputedDimensions) -> FrameBuf {
+    const WIDTH: f32 = 800.;
+    const LINE_HEIGHT: f32 = 28.;
+    const HEIGHT: f32 = LINE_HEIGHT * (MAX_LISTED_TRACKS + 1) as f32;
+
+    let pos = Position {
+        x: Coordinate::FractionOfParentWithOffset(0.5, -WIDTH / 2.),
+        y: Coordinate::FractionOfParentWithOffset(0.5, -HEIGHT / 2.),
+    };
+
+    let dims = Dimensions {
+        width: Size::Fixed(WIDTH),
+        height: Size::Fixed(HEIGHT),
+    };
+
+    let mut frame_buf = FrameBuf::new(gl, None, pos, dims, *parent_dims);
+    let needs_rerender = frame_buf.children_need_rerender.clone();
+    let frame_bounding_box = frame_buf.bounding_box.clone();
+
+    let container_style = Style {
+        background_colour: globals.colour_palette.bg_primary,
+        ..Style::default()
+    };
+
+    let line_style = Style {
+        render_self: false,
+        padding_left: 8.,
+        ..Style::default()
+    };
+
+    let lines: Vec<_> = (0..=MAX_LISTED_TRACKS)
+        .map(|i| {
+            let text = Text::new(
+                gl,
+                String::new(),
+                20.,
+                &globals.main_font,
+                globals.colour_palette.text_primary,
+                Position::origin(),
+                needs_rerender.clone(),
+            );
+
+            let line = Element::new(
+                gl,
+                p(0., HEIGHT - LINE_HEIGHT * (i + 1) as f32),
+                Size::FractionOfParent(1.),
+                Size::Fixed(LINE_HEIGHT),
+                Some(line_style.clone()),
+                Some(text),
+                needs_rerender.clone(),
+                frame_bounding_box.clone(),
+                vec![],
+            );
+
+            line.subscribe_mutation_to_reactive(
+                &globals.mixer_lines,
+                Box::new(move |e: &mut Element, lines: &Vec<String>| {
+                    let line = lines.get(i).cloned().unwrap_or_default();
+                    e.text_node.as_mut().unwrap().mutate(Box::new(move |text| {
+                        text.text = line.clone();
+                    }));
+                }),
+            );
+
+            line
+        })
+        .collect();
+
+    let container = Element::new(
+        gl,
+        Position::origin(),
+        Size::FractionOfParent(1.),
+        Size::FractionOfParent(1.),
+        Some(container_style),
+        None,
+        needs_rerender.clone(),
+        frame_bounding_box.clone(),
+        lines,
+    );
+
+    globals.subscriptions.subscribe_key(rc_ref_cell(|key, globals: &mut Globals| {
+        if globals.editor_context.get_copy() != EditingContext::Mixer {
+            return;
+        }
+
+        let strips = mixer_strips(globals);
+        let strip = strips.get(globals.mixer_selected_track).cloned();
+
+        if key.code == SDL_KeyCode::SDLK_ESCAPE as KeyCode {
+            globals.editor_context <<= EditingContext::PianoRoll;
+            return;
+        } else if *key == k("j") {
+            globals.mixer_selected_track =
+                (globals.mixer_selected_track + 1).min(strips.len().saturating_sub(1));
+        } else if *key == k("k") {
+            globals.mixer_selected_track = globals.mixer_selected_track.saturating_sub(1);
+        } else if *key == k("u") {
+            globals.loaded_project.undo();
+        } else if *key == k("^r") {
+            globals.loaded_project.redo();
+        } else if let Some(strip) = strip {
+            let old = strip_mixer(globals, strip);
+            let mut new = old;
+
+            if *key == k("+") || *key == k("=") {
+                new.gain_db += GAIN_STEP_DB;
+            } else if *key == k("-") {
+                new.gain_db -= GAIN_STEP_DB;
+            } else if *key == k("0") {
+                new.gain_db = 0.;
+            } else if *key == k("h") {
+                new.pan -= PAN_STEP;
+            } else if *key == k("l") {
+                new.pan += PAN_STEP;
+            } else if *key == k("c") {
+                new.pan = 0.;
+            } else if *key == k("m") {
+                new.mute = !new.mute;
+            } else if *key == k("s") {
+                new.solo = !new.solo;
+            }
+
+            if let Some(action) = mixer_action(strip, &old, &new) {
+                globals.loaded_project.perform_action(action);
+            }
+        }
+
+        refresh_mixer(globals);
+    }));
+
+    frame_buf.root_node = Some(container);
+    frame_buf
+}
+
+pub fn open_mixer(globals: &mut Globals) {
+    refresh_mixer(globals);
+    globals.editor_context <<= EditingContext::Mixer;
+}
+
+/// The strip highlighted in the mixer, which insert and send commands act on.
+pub fn selected_strip(globals: &Globals) -> Option<StripId> {
+    mixer_strips(globals)
+        .get(globals.mixer_selected_track)
+        .cloned()
+}
+
+/// The highlighted track, if the mixer isn't on a bus.
+pub fn selected_track(globals: &Globals) -> Option<TrackId> {
+    match selected_strip(globals) {
+        Some(StripId::Track(track_id)) => Some(track_id),
+        _ => None,
+    }
+}
+
+/// Tracks followed by buses, in the order the mixer lists them.
+fn mixer_strips(globals: &Globals) -> Vec<StripId> {
+    let mut track_ids: Vec<TrackId> = globals.loaded_project.tracks.tracks.keys().cloned().collect();
+    track_ids.sort();
+
+    track_ids
+        .into_iter()
+        .map(StripId::Track)
+        .chain(globals.loaded_project.buses.keys().map(|id| StripId::Bus(*id)))
+        .collect()
+}
+
+fn strip_mixer(globals: &Globals, strip: StripId) -> MixerSettings {
+    match strip {
+        StripId::Track(track_id) => globals.loaded_project.tracks[track_id].mixer.get_copy(),
+        StripId::Bus(bus_id) => globals.loaded_project.buses[&bus_id].mixer,
+    }
+}
+
+/// The action taking a strip's mixer settings from `old` to `new`.
+fn mixer_action(strip: StripId, old: &MixerSettings, new: &MixerSettings) -> Option<Action> {
+    if old == new {
+        return None;
+    }
+
+    let track_id = match strip {
+        StripId::Track(track_id) => track_id,
+        StripId::Bus(bus_id) => {
+            let mut mixer = *new;
+            mixer.gain_db = mixer.gain_db.clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+            mixer.pan = mixer.pan.clamp(-1., 1.);
+            // Buses aren't affected by solo.
+            mixer.solo = old.solo;
+
+            if mixer == *old {
+                return None;
+            }
+            return Some(Action::SetBusMixer { bus_id, mixer });
+        }
+    };
+
+    Some(if old.gain_db != new.gain_db {
+        Action::SetTrackGain {
+            track_id,
+            gain_db: new.gain_db,
+        }
+    } else if old.pan != new.pan {
+        Action::SetTrackPan {
+            track_id,
+            pan: new.pan,
+        }
+    } else if old.mute != new.mute {
+        Action::SetTrackMute {
+            track_id,
+            mute: new.mute,
+        }
+    } else {
+        Action::SetTrackSolo {
+            track_id,
+            solo: new.solo,
+        }
+    })
+}
+
+fn format_chain(instrument: Option<String>, inserts: &[Insert], sends: &[Send], globals: &Globals) -> String {
+    let mut chain = instrument.unwrap_or_else(|| "-".to_string());
+
+    for (i, insert) in inserts.iter().enumerate() {
+        chain.push_str(if i == 0 { " | " } else { ", " });
+        chain.push_str(&format!("{}:{}", i + 1, insert.plugin.name));
+        if insert.bypass {
+            chain.push_str("(b)");
+        }
+        if let Some(track) = insert.sidechain.and_then(|id| globals.loaded_project.tracks.tracks.get(&id)) {
+            chain.push_str(&format!("(<{})", track.name));
+        }
+    }
+
+    for (i, send) in sends.iter().enumerate() {
+        let target = globals
+            .loaded_project
+            .buses
+            .get(&send.target)
+            .map(|b| b.name.as_str())
+            .unwrap_or("?");
+
+        chain.push_str(if i == 0 { " > " } else { ", " });
+        chain.push_str(&format!("{}:{} {:.1}", i + 1, target, send.level_db));
+        if send.pre_fader {
+            chain.push_str("(pre)");
+        }
+    }
+
+    chain
+}
+
+fn format_pan(pan: f32) -> String {
+    match (pan * 100.).round() as i32 {
+        0 => "C".to_string(),
+        p if p < 0 => format!("L{}", -p),
+        p => format!("R{}", p),
+    }
+}
+
+/// Rebuilds the text of every line from the project's mixer settings.
+pub fn refresh_mixer(globals: &mut Globals) {
+    let strips = mixer_strips(globals);
+
+    if globals.mixer_selected_track >= strips.len() {
+        globals.mixer_selected_track = strips.len().saturating_sub(1);
+    }
+
+    let any_solo = globals
+        .loaded_project
+        .tracks
+        .tracks
+        .values()
+        .any(|t| t.mixer.get_copy().solo);
+
+    let mut lines = vec![format!(
+        "  {:<20} {:>9} {:>5}  {}  {}",
+        "Strip", "Gain", "Pan", "M S", "Chain"
+    )];
+
+    // Keep the selection in view when there are more strips than lines.
+    let first = (globals.mixer_selected_track + 1).saturating_sub(MAX_LISTED_TRACKS);
+
+    for (i, strip) in strips.iter().enumerate().skip(first).take(MAX_LISTED_TRACKS) {
+        let project = &globals.loaded_project;
+
+        let (name, mixer, chain) = match strip {
+            StripId::Track(track_id) => {
+                let track = &project.tracks[*track_id];
+                let mixer = track.mixer.get_copy();
+                let name = if mixer.is_audible(any_solo) {
+                    track.name.clone()
+                } else {
+                    format!("({})", track.name)
+                };
+                let instrument = track.instrument().map(|i| i.plugin.name.clone());
+                (name, mixer, format_chain(instrument, &track.inserts, &track.sends, globals))
+            }
+            StripId::Bus(bus_id) => {
+                let bus = &project.buses[bus_id];
+                let name = if bus.mixer.mute {
+                    format!("[({})]", bus.name)
+                } else {
+                    format!("[{}]", bus.name)
+                };
+                (name, bus.mixer, format_chain(None, &bus.inserts, &bus.sends, globals))
+            }
+        };
+
+        let cursor = if i == globals.mixer_selected_track { ">" } else { " " };
+
+        lines.push(format!(
+            "{} {:<20} {:>6.1} dB {:>5}  {} {}  {}",
+            cursor,
+            name,
+            mixer.gain_db,
+            format_pan(mixer.pan),
+            if mixer.mute { "M" } else { "-" },
+            if mixer.solo { "S" } else { "-" },
+            chain,
+        ));
+    }
+
+    globals.mixer_lines <<= lines;
+}
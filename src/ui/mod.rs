@@ -1,7 +1,5 @@
 use std::{ops::{Add, AddAssign}, cell::RefCell, rc::Rc};
 
-use crate::global::Globals;
-
 pub mod element;
 pub mod frame_buf;
 pub mod gl;
@@ -17,6 +15,10 @@ pub mod misc_elements;
 pub mod piano_roll;
 pub mod top_bar;
 pub mod command_palette;
+pub mod mixer;
+pub mod automation;
+pub mod time_header;
+pub mod arrangement;
 
 #[derive(Copy, Clone, Debug)]
 pub enum Coordinate {
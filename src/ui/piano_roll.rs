@@ -1,32 +1,77 @@
-use std::{borrow::BorrowMut, cell::RefCell, collections::HashMap, ops::ControlFlow, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 use glow::Context;
-use midi_control::note;
-use sdl2::{keyboard, libc::glob};
 
 use crate::{
     bind_reactives,
-    element_creation_queue::{queue_element, CreateElementFn},
-    global::{Globals, Viewport},
+    clip::{ClipId, TrackClip},
+    global::Globals,
     midi::*,
-    project::TimeSignature,
     selection::Selection,
-    track::{TrackData, TrackId},
-    ui::{element::*, scroll_window::e_scroll_window},
-    ui::{macros::ElementInitDeps, reactive_list::ReactiveListKey, style::*, text::Text},
+    tempo_map::MeterMap,
+    track::TrackId,
+    ui::element_creation_queue::{queue_element, CreateElementFn},
+    ui::{element::*, frame_buf::FrameBuf, scroll_window::e_scroll_window},
+    ui::{reactive_list::ReactiveListKey, style::*, text::Text},
     ui::{reactive::Reactive, *},
     utils::{note_name, rc_ref_cell, RcRefCell},
 };
 
+/// The clip the piano roll shows, if it still exists.
+pub fn editing_clip(globals: &Globals) -> Option<(TrackId, TrackClip)> {
+    let (track_id, clip_id) = globals.editing_clip?;
+    let clip = globals.loaded_project.tracks.tracks.get(&track_id)?.clip(clip_id)?;
+    Some((track_id, clip.clone()))
+}
+
+/// Rebuilds the piano roll in `frame` when another clip is opened, falling
+/// back to the first clip in the project when the open one goes away.
+/// `shown` is the clip it was last built for. Called every frame.
+pub fn refresh_piano_roll(
+    gl: &glow::Context,
+    globals: &mut Globals,
+    frame: &mut FrameBuf,
+    shown: &mut Option<(TrackId, ClipId)>,
+) {
+    if editing_clip(globals).is_none() {
+        globals.editing_clip = globals.loaded_project.first_clip();
+    }
+
+    if globals.editing_clip == *shown {
+        return;
+    }
+    *shown = globals.editing_clip;
+
+    if let Some(root) = frame.root_node.take() {
+        root.cleanup(gl);
+    }
+
+    frame.root_node = shown.map(|(track_id, clip_id)| {
+        e_piano_roll(
+            gl,
+            globals,
+            track_id,
+            clip_id,
+            frame.children_need_rerender.clone(),
+            frame.bounding_box.clone(),
+        )
+    });
+    frame.children_need_rerender.replace(true);
+}
+
+/// Shows the notes of one clip, in the clip's own time.
 pub fn e_piano_roll(
     gl: &glow::Context,
     globals: &mut Globals,
     track_id: TrackId,
+    clip_id: ClipId,
     needs_rerender: Rc<RefCell<bool>>,
     frame_bounding_box: BoundingBoxRef,
 ) -> ElementRef {
-    globals.viewport.h_zoom.set(35.);
-    globals.viewport.v_zoom.set(12.);
+    let clip = globals.loaded_project.tracks[track_id]
+        .clip(clip_id)
+        .cloned()
+        .expect("Clip not found");
 
     let style = Style {
         render_self: false,
@@ -80,28 +125,25 @@ pub fn e_piano_roll(
 
         let needs_rerender = needs_rerender.clone();
         let frame_bounding_box = frame_bounding_box.clone();
-        let midi = match &globals.loaded_project.tracks[track_id].data {
-            TrackData::Midi(_, midi) => midi,
-            _ => panic!("Track is not midi"),
-        };
+        let midi = &clip.midi;
         let roll_cpy = roll.clone();
 
         let element_note_id_map_cpy = element_note_id_map.clone();
 
-        midi.notes.subscribe_to_push(Box::new(move |key, note| {
+        let push_id = midi.notes.subscribe_to_push(Box::new(move |key, note| {
             let needs_rerender = needs_rerender.clone();
             let note = note.clone();
             let frame_bounding_box = frame_bounding_box.clone();
             let roll = roll_cpy.clone();
 
             let element_note_id_map = element_note_id_map_cpy.clone();
-            let key = key.clone();
+            let key = *key;
 
             let create_note_element: CreateElementFn =
                 Box::new(move |gl: &Context, globals: &mut Globals| {
                     let note_element = e_midi_note(
                         &note,
-                        key.clone(),
+                        key,
                         globals,
                         gl,
                         &needs_rerender,
@@ -111,7 +153,7 @@ pub fn e_piano_roll(
                     element_note_id_map
                         .as_ref()
                         .borrow_mut()
-                        .insert(key.clone(), note_element.clone());
+                        .insert(key, note_element.clone());
 
                     note_element
                 });
@@ -120,11 +162,11 @@ pub fn e_piano_roll(
         }));
 
         let element_note_id_map = element_note_id_map.clone();
-        let roll = roll.clone();
-        midi.notes.subscribe_to_remove(Box::new(move |note_id, ()| {
+        let roll_cpy = roll.clone();
+        let remove_id = midi.notes.subscribe_to_remove(Box::new(move |note_id, ()| {
             let element_note_id_map = element_note_id_map.clone();
-            let roll = roll.clone();
-            let note_id = note_id.clone();
+            let roll = roll_cpy.clone();
+            let note_id = *note_id;
             roll.mutate(Box::new(move |element| {
                 let element_note_id_map = element_note_id_map.clone();
                 let note_element = element_note_id_map
@@ -137,15 +179,17 @@ pub fn e_piano_roll(
                 element.children.retain(|c| c.uid() != element_uid);
             }))
         }));
+
+        // The notes outlive the roll when another clip is opened.
+        let notes = midi.notes.clone();
+        roll.add_cleanup_callback(Box::new(move || {
+            notes.unsubscribe_to_push(push_id);
+            notes.unsubscribe_to_remove(remove_id);
+        }));
     }
 
     {
-        let midi = match &globals.loaded_project.tracks[track_id].data {
-            TrackData::Midi(_, midi) => midi,
-            _ => panic!("Track is not midi"),
-        };
-
-        let notes_ = midi.notes.copy_of_whole_list();
+        let notes_ = clip.midi.notes.copy_of_whole_list();
         let roll = roll.clone();
         for n in notes_.iter() {
             let needs_rerender = needs_rerender.clone();
@@ -161,7 +205,7 @@ pub fn e_piano_roll(
 
                     let note_element = e_midi_note(
                         &note.clone(),
-                        note_id.clone(),
+                        note_id,
                         globals,
                         gl,
                         &needs_rerender,
@@ -171,7 +215,7 @@ pub fn e_piano_roll(
                     element_note_id_map
                         .as_ref()
                         .borrow_mut()
-                        .insert(n.0.clone(), note_element.clone());
+                        .insert(n.0, note_element.clone());
 
                     note_element
                 });
@@ -183,12 +227,13 @@ pub fn e_piano_roll(
     let player_head = e_player_head(
         gl,
         globals,
+        clip,
         needs_rerender.clone(),
         frame_bounding_box.clone(),
         keyboard_width,
     );
 
-    let scroll_win = e_scroll_window(
+    e_scroll_window(
         gl,
         globals,
         needs_rerender.clone(),
@@ -198,9 +243,7 @@ pub fn e_piano_roll(
         false,
         0.,
         vec![roll, player_head],
-    );
-
-    return scroll_win;
+    )
 }
 
 // fn mark_selected(
@@ -236,80 +279,85 @@ fn e_time_grid(
     needs_rerender: Rc<RefCell<bool>>,
     frame_bounding_box: BoundingBoxRef,
 ) -> ElementRef {
+    const MAX_LINES: usize = 500;
+
     let style = Style {
         render_self: false,
         ..Default::default()
     };
 
-    let mut children = vec![];
+    // x of every beat line from the left edge on, and whether it starts a
+    // bar. Shared by all the line elements so the meter map is only walked
+    // once per change.
+    let lines: Reactive<Vec<(f32, bool)>> = Reactive::new(vec![]);
 
-    // let width = {
-    //     let bb = frame_bounding_box.borrow().clone().unwrap_or_default();
-    //     bb.dimensions().width - base_x
-    // };
-
-    let beats_per_division = 1;
-    let divisions_count = 500;
-    let total_width = {
+    let update_lines = {
+        let lines = lines.clone();
+        let time_scroll = globals.viewport.time_scroll.clone();
         let h_zoom = globals.viewport.h_zoom.clone();
+        let meter_map = globals.loaded_project.meter_map.clone();
+
         Rc::new(move || {
-            time_to_width(
-                (divisions_count * beats_per_division) as Time,
-                h_zoom.get_copy(),
-            )
+            let (ts, hz) = (time_scroll.get_copy(), h_zoom.get_copy());
+            let beat_lines = meter_map
+                .get_copy()
+                .beat_lines(ts.max(0.) as Time, MAX_LINES)
+                .into_iter()
+                .map(|(t, bar)| (x_of_time_no_global_access(t, 0., ts, hz), bar))
+                .collect();
+            lines.set(beat_lines);
         })
     };
 
-    for i in 0..divisions_count {
-        // let bar = e_bar(
-        //     gl,
-        //     globals,
-        //     needs_rerender.clone(),
-        //     frame_bounding_box.clone(),
-        //     x(),
-        //     1.,
-        // );
-
-        let time_scroll = globals.viewport.time_scroll.clone();
-        let h_zoom = globals.viewport.h_zoom.clone();
-
-        let deps = ElementInitDeps {
-            gl,
-            globals,
-            needs_rerender: needs_rerender.clone(),
-            frame_bounding_box: frame_bounding_box.clone(),
-        };
-
-        let bar = e_bar(
-            gl,
-            globals,
-            needs_rerender.clone(),
-            frame_bounding_box.clone(),
-        );
-
-        bind_reactives! {
-            bar {
-                [time_scroll, h_zoom] => (|e: &mut Element, ts, hz| {
-                    let total_width = time_to_width(
-                        (divisions_count * beats_per_division) as Time,
-                        hz,
-                    );
+    update_lines();
 
-                    let mut x = x_of_time_no_global_access(i as Time, 0., ts, hz);
-
-                    while x < 0. {
-                        x += total_width;
-                    }
-
-                    x = x % total_width;
-
-                    e.position.x = Coordinate::Fixed(x);
-                }),
-            }
-        };
+    let time_scroll_id = {
+        let update_lines = update_lines.clone();
+        globals
+            .viewport
+            .time_scroll
+            .subscribe(Box::new(move |_| update_lines()))
+    };
+    let h_zoom_id = {
+        let update_lines = update_lines.clone();
+        globals
+            .viewport
+            .h_zoom
+            .subscribe(Box::new(move |_| update_lines()))
+    };
+    let meter_map_id = globals
+        .loaded_project
+        .meter_map
+        .subscribe(Box::new(move |_| update_lines()));
+
+    let children = (0..MAX_LINES)
+        .map(|i| {
+            let line = e_bar(
+                gl,
+                globals,
+                needs_rerender.clone(),
+                frame_bounding_box.clone(),
+            );
+
+            let lines = lines.clone();
+            bind_reactives! {
+                line {
+                    [lines] => (|e: &mut Element, lines: Vec<(f32, bool)>| {
+                        match lines.get(i) {
+                            Some((x, bar)) => {
+                                e.style.visible = true;
+                                e.position.x = Coordinate::Fixed(*x);
+                                e.dimensions.width = Size::Fixed(if *bar { 2. } else { 1. });
+                            }
+                            None => e.style.visible = false,
+                        }
+                    }),
+                }
+            };
 
-        children.push(bar);
-    }
+            line
+        })
+        .collect();
 
     let grid = Element::new(
         gl,
@@ -323,12 +371,25 @@ fn e_time_grid(
         children,
     );
 
+    {
+        let time_scroll = globals.viewport.time_scroll.clone();
+        let h_zoom = globals.viewport.h_zoom.clone();
+        let meter_map = globals.loaded_project.meter_map.clone();
+        grid.add_cleanup_callback(Box::new(move || {
+            time_scroll.unsubscribe(time_scroll_id);
+            h_zoom.unsubscribe(h_zoom_id);
+            meter_map.unsubscribe(meter_map_id);
+        }));
+    }
+
     grid
 }
 
+/// Follows the player through the clip, wrapping round when it loops.
 fn e_player_head(
     gl: &glow::Context,
     globals: &Globals,
+    clip: TrackClip,
     needs_rerender: Rc<RefCell<bool>>,
     frame_bounding_box: BoundingBoxRef,
     x_offset: f32,
@@ -338,8 +399,9 @@ fn e_player_head(
         ..Default::default()
     };
 
+    let player_time = &globals.loaded_project.player_time;
     let x = x_of_time(
-        globals.loaded_project.player_time.get_copy(),
+        clip.local_time(player_time.get_copy()),
         globals,
         globals.piano_roll_keyboard_width,
     );
@@ -356,13 +418,23 @@ fn e_player_head(
         vec![],
     );
 
+    let local_time = Reactive::new(clip.local_time(player_time.get_copy()));
+    let id = {
+        let local_time = local_time.clone();
+        player_time.subscribe(Box::new(move |t| local_time.set(clip.local_time(*t))))
+    };
+    {
+        let player_time = player_time.clone();
+        head.add_cleanup_callback(Box::new(move || player_time.unsubscribe(id)));
+    }
+
     let ts = globals.viewport.time_scroll.clone();
-    let pt = globals.loaded_project.player_time.clone();
+    let lt = local_time;
     let hz = globals.viewport.h_zoom.clone();
     bind_reactives! {
         head {
-            [ts, pt, hz] => (|e: &mut Element, ts, pt, hz| {
-                let x = x_of_time_no_global_access(pt, x_offset, ts, hz);
+            [ts, lt, hz] => (|e: &mut Element, ts, lt, hz| {
+                let x = x_of_time_no_global_access(lt, x_offset, ts, hz);
                 e.position.x = Coordinate::Fixed(x);
             }),
         }
@@ -406,17 +478,13 @@ fn x_of_time(t: Time, globals: &Globals, x_offset: f32) -> f32 {
 }
 
 fn x_of_time_no_global_access(t: Time, x_offset: f32, time_scroll: f32, h_zoom: f32) -> f32 {
-    x_offset + h_zoom * (t as f32 - time_scroll) as f32
+    x_offset + h_zoom * (t as f32 - time_scroll)
 }
 
 fn time_to_width(duration: Time, h_zoom: f32) -> f32 {
     duration as f32 * h_zoom
 }
 
-fn width_to_time(width: f32, globals: &Globals) -> Time {
-    (width / globals.viewport.h_zoom.get_copy()) as Time
-}
-
 fn e_note(
     gl: &glow::Context,
     note: i32,
@@ -427,7 +495,7 @@ fn e_note(
 ) -> ElementRef {
     let key = e_key(globals, note, gl, &needs_rerender, &frame_bounding_box);
 
-    let mut children = vec![key.clone()];
+    let children = vec![key.clone()];
 
     // es_notes(
     //     notes,
@@ -440,15 +508,7 @@ fn e_note(
 
     let row_border_width = 1.;
 
-    let mut row_style = Style {
-        border_width: row_border_width,
-        border_colour: globals.colour_palette.time_grid,
-        ..Default::default()
-    };
-
-    let mut alt_col = BLACK;
-
-    (row_style.background_colour, alt_col) = if is_black_key(note) {
+    let (background_colour, alt_col) = if is_black_key(note) {
         (
             globals.colour_palette.black_key_piano_roll_row,
             globals.colour_palette.black_key_piano_roll_row_alt,
@@ -460,7 +520,14 @@ fn e_note(
         )
     };
 
-    let note_height = globals.viewport.v_zoom.get().borrow().clone();
+    let row_style = Style {
+        background_colour,
+        border_width: row_border_width,
+        border_colour: globals.colour_palette.time_grid,
+        ..Default::default()
+    };
+
+    let note_height = *globals.viewport.v_zoom.get().borrow();
 
     let y = note_height * note as f32;
 
@@ -479,17 +546,31 @@ fn e_note(
     let vz = globals.viewport.v_zoom.clone();
     let hz = globals.viewport.h_zoom.clone();
     let ts = globals.viewport.time_scroll.clone();
-    let t_sig = globals.loaded_project.time_signature.clone();
+    let meter_map = globals.loaded_project.meter_map.clone();
 
     let keyboard_width = globals.piano_roll_keyboard_width;
     bind_reactives! {
         key_row {
             [vz] => (|e: &mut Element, vz| { e.dimensions.height = Size::Fixed(vz);}),
-            [hz, ts, t_sig] => (move |e: &mut Element, hz, ts, t_sig: TimeSignature| {
-                let beats_per_measure = t_sig.beats_per_measure();
-                let bar_width = time_to_width(beats_per_measure as Time, hz);
-
-                let x_offset = x_of_time_no_global_access(0., keyboard_width, ts, hz);
+            [hz, ts, meter_map] => (move |e: &mut Element, hz: f32, ts: f32, meter_map: MeterMap| {
+                // Shading alternates evenly, so it follows whichever time
+                // signature is at the left edge, counted from where it starts.
+                let bar = meter_map.bar_beat_tick(ts.max(0.) as Time).bar;
+                let change = meter_map
+                    .changes()
+                    .iter()
+                    .rev()
+                    .find(|c| c.bar <= bar)
+                    .cloned()
+                    .unwrap_or(meter_map.changes()[0]);
+
+                let bar_width = time_to_width(change.time_signature.beats_per_measure(), hz);
+                let x_offset = x_of_time_no_global_access(
+                    meter_map.bar_start(change.bar),
+                    keyboard_width,
+                    ts,
+                    hz,
+                );
 
                 e.style.alt = Some((bar_width, x_offset, alt_col));
             })
@@ -507,10 +588,12 @@ fn e_midi_note(
     needs_rerender: &Rc<RefCell<bool>>,
     frame_bounding_box: &Rc<RefCell<Option<ComputedBoundingBox>>>,
 ) -> ElementRef {
-    let mut note_style = Style::default();
-    note_style.background_colour = globals.colour_palette.white;
-    note_style.border_width = 1.;
-    note_style.border_colour = globals.colour_palette.time_grid;
+    let note_style = Style {
+        background_colour: globals.colour_palette.white,
+        border_width: 1.,
+        border_colour: globals.colour_palette.time_grid,
+        ..Default::default()
+    };
 
     let label = Text::new(
         gl,
@@ -565,7 +648,7 @@ fn e_midi_note(
                     }));
             }),
             [selected] => (|e: &mut Element, s: Selection| {
-                if s.is_note_selected(0, note_id.clone()) {
+                if s.is_note_selected(0, note_id) {
                     e.style.border_colour = selected_colour;
                     e.style.border_width = 2.;
                 } else {
@@ -586,17 +669,17 @@ fn e_key(
     needs_rerender: &Rc<RefCell<bool>>,
     frame_bounding_box: &Rc<RefCell<Option<ComputedBoundingBox>>>,
 ) -> ElementRef {
-    let mut key_style = Style::default();
-
-    key_style.border_width = 1.;
-    key_style.border_colour = globals.colour_palette.time_grid;
-    key_style.padding_left = 5.;
-    key_style.padding_top = -1.;
-
-    key_style.background_colour = if is_black_key(note) {
-        globals.colour_palette.black_key
-    } else {
-        globals.colour_palette.white_key
+    let key_style = Style {
+        background_colour: if is_black_key(note) {
+            globals.colour_palette.black_key
+        } else {
+            globals.colour_palette.white_key
+        },
+        border_width: 1.,
+        border_colour: globals.colour_palette.time_grid,
+        padding_left: 5.,
+        padding_top: -1.,
+        ..Default::default()
     };
 
     let text_colour = if is_black_key(note) {
@@ -605,8 +688,6 @@ fn e_key(
         globals.colour_palette.black
     };
 
-    let note_height = globals.viewport.v_zoom.get().borrow().clone();
-
     let label = Text::new(
         gl,
         note_name(note as u8, true),
@@ -618,7 +699,7 @@ fn e_key(
     );
 
     let keyboard_width = globals.piano_roll_keyboard_width;
-    let key = Element::new(
+    Element::new(
         gl,
         Position::origin(),
         Size::Fixed(keyboard_width),
@@ -628,8 +709,7 @@ fn e_key(
         needs_rerender.clone(),
         frame_bounding_box.clone(),
         vec![],
-    );
-    key
+    )
 }
 
 fn is_black_key(note: i32) -> bool {
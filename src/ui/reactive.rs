@@ -1,8 +1,5 @@
 use std::{cell::RefCell, rc::Rc, ops::{ShlAssign, AddAssign, SubAssign, Add, Sub, Mul, Div, DivAssign, MulAssign}};
 
-use sdl2::libc::PACKET_ADD_MEMBERSHIP;
-use serde::Serializer;
-
 static ID_COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
 
 pub struct Reactive<T>
@@ -37,7 +34,7 @@ impl<T> Clone for Reactive<T>
         }
 
         Self {
-            ref_count: self.ref_count.clone(),
+            ref_count: self.ref_count,
             value: self.value.clone(),
             dependants: self.dependants.clone(),
             delete_listeners: self.delete_listeners.clone(),
@@ -1,13 +1,12 @@
 use std::{
     cell::RefCell,
-    ops::Index,
     rc::Rc,
-    sync::atomic::{AtomicU32, AtomicUsize},
+    sync::atomic::AtomicUsize,
 };
 
 use crate::utils::{fetch_ptr, free, leak, malloc};
 
-use super::reactive::{ReactiveSubscription, ReactiveSubscriptionId};
+use super::reactive::ReactiveSubscriptionId;
 
 /// Allows adding and removing items to be subscribed to.
 pub struct ReactiveList<T> {
@@ -35,8 +34,8 @@ impl<T> Clone for ReactiveList<T> {
         }
 
         Self {
-            ref_count: self.ref_count.clone(),
-            items: self.items.clone(),
+            ref_count: self.ref_count,
+            items: self.items,
             push_subscriptions: self.push_subscriptions.clone(),
             remove_subscriptions: self.remove_subscriptions.clone(),
         }
@@ -59,6 +58,12 @@ fn new_key() -> ReactiveListKey {
     ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
 }
 
+impl<T> Default for ReactiveList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> ReactiveList<T> {
     pub fn new() -> Self {
         let ref_count = malloc(1);
@@ -72,10 +77,6 @@ impl<T> ReactiveList<T> {
         }
     }
 
-    unsafe fn items_ptr(&self) -> *mut Vec<(ReactiveListKey, T)> {
-        return self.items;
-    }
-
     pub fn push(&mut self, item: T) -> ReactiveListKey {
         let key = new_key();
 
@@ -1,7 +1,6 @@
 use std::{cell::RefCell, rc::Rc};
 
 use glow::Context;
-use sdl2::libc::glob;
 
 use crate::{global::Globals, ui::style::Style, bind_reactives};
 
@@ -42,22 +41,22 @@ pub fn e_scroll_window(
 
     {
         let scroll = scroll.clone();
-        let sub = globals.subscriptions.subscribe_scroll_in_area(
+        globals.subscriptions.subscribe_scroll_in_area(
             frame_bounding_box.clone(),
             Rc::new(RefCell::new(
-                move |wheel: &(f32, f32), globals: &mut Globals| {
+                move |wheel: &(f32, f32), _globals: &mut Globals| {
                     let scroll = scroll.clone();
-                    let wheel = wheel.clone();
+                    let wheel = *wheel;
                     scroll.mutate(Box::new(move |scroll: &mut WindowScroll| {
                         let (s_x, s_y) = wheel;
-                        const scroll_speed: f32 = 10.;
+                        const SCROLL_SPEED: f32 = 10.;
 
                         if h_scroll {
-                            scroll.scroll_x += s_x * scroll_speed;
+                            scroll.scroll_x += s_x * SCROLL_SPEED;
                         }
 
                         if v_scroll {
-                            scroll.scroll_y += s_y * scroll_speed;
+                            scroll.scroll_y += s_y * SCROLL_SPEED;
                         }
                     }));
                 },
@@ -82,7 +81,7 @@ pub fn e_scroll_window(
         }
     }
 
-    return element;
+    element
 }
 
 #[derive(Debug, Clone)]
@@ -1,5 +1,5 @@
 use glow::*;
-use serde::de;
+use serde::{Deserialize, Serialize};
 
 use crate::global::Globals;
 
@@ -30,6 +30,8 @@ pub struct ColourPalette {
     pub time_grid: Colour,
     pub selected: Colour,
     pub player_head: Colour,
+    pub loop_region: Colour,
+    pub punch_region: Colour,
 }
 
 impl Default for ColourPalette {
@@ -49,6 +51,8 @@ impl Default for ColourPalette {
             time_grid: c("444444"),
             selected: c("ff0000"),
             player_head: c("ff4444"),
+            loop_region: c("4a7fc0"),
+            punch_region: c("c04a4a"),
         }
     }
 }
@@ -94,7 +98,7 @@ impl Style {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Colour {
     pub r: f32,
     pub g: f32,
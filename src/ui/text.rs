@@ -6,7 +6,6 @@ use rusttype::PositionedGlyph;
 use crate::global::Globals;
 
 use super::{
-    compute_dims,
     gl::{render_textured_quad, Quad},
     style::Colour,
     ComputedDimensions, Position, ComputedPosition,
@@ -52,8 +51,8 @@ impl Text {
         };
 
         let dims = ComputedDimensions {
-            width: 512. as f32,
-            height: 512. as f32,
+            width: 512.,
+            height: 512.,
         };
 
 
@@ -71,7 +70,7 @@ impl Text {
             let bb = glyph.pixel_bounding_box().unwrap();
             let mut data = vec![0; bb.width() as usize * bb.height() as usize * 4];
             glyph.draw(|x, y, v| {
-                let y = bb.height() as u32 - y as u32 - 1;
+                let y = bb.height() as u32 - y - 1;
                 let pix_index = (x + y * bb.width() as u32) as usize * 4;
                 let alpha = (v * 255.) as u8;
                 data[pix_index] = (self.colour.r * 255.) as u8;
@@ -85,10 +84,10 @@ impl Text {
                 gl.tex_sub_image_2d(
                     glow::TEXTURE_2D,
                     0,
-                    bb.min.x as i32,
-                    0 as i32,
-                    bb.width() as i32,
-                    bb.height() as i32,
+                    bb.min.x,
+                    0,
+                    bb.width(),
+                    bb.height(),
                     glow::RGBA,
                     glow::UNSIGNED_BYTE,
                     PixelUnpackData::Slice(&data),
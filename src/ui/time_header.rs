@@ -0,0 +1,113 @@
+use glow::Context;
+
+use crate::{
+    bind_reactives,
+    global::Globals,
+    project::Region,
+    ui::{style::Style, Coordinate, Dimensions, Position, Size},
+};
+
+use super::{element::Element, frame_buf::FrameBuf, style::Colour, ComputedDimensions};
+
+pub const HEADER_HEIGHT: f32 = 16.;
+
+/// Shows the loop range on the top half and the punch range on the bottom
+/// half, lined up with the piano roll's time axis. Ranges that are switched
+/// off are drawn darker.
+pub fn fb_time_header(gl: &Context, globals: &mut Globals, parent_dims: &ComputedDimensions) -> FrameBuf {
+    let pos = Position {
+        x: Coordinate::Fixed(0.),
+        y: Coordinate::FractionOfParentWithOffset(1., -globals.top_bar_size - HEADER_HEIGHT),
+    };
+    let dims = Dimensions {
+        width: Size::FractionOfParent(1.),
+        height: Size::Fixed(HEADER_HEIGHT),
+    };
+
+    let mut frame_buf = FrameBuf::new(gl, None, pos, dims, *parent_dims);
+    let needs_rerender = frame_buf.children_need_rerender.clone();
+    let frame_bounding_box = frame_buf.bounding_box.clone();
+
+    let container_style = Style {
+        background_colour: globals.colour_palette.bg_primary,
+        ..Style::default()
+    };
+
+    let keyboard_width = globals.piano_roll_keyboard_width;
+
+    let regions = [
+        (
+            globals.loaded_project.loop_region.clone(),
+            globals.colour_palette.loop_region,
+            HEADER_HEIGHT / 2.,
+        ),
+        (
+            globals.loaded_project.punch_region.clone(),
+            globals.colour_palette.punch_region,
+            0.,
+        ),
+    ];
+
+    let children = regions
+        .into_iter()
+        .map(|(region, colour, y)| {
+            let element = Element::new(
+                gl,
+                Position {
+                    x: Coordinate::Fixed(0.),
+                    y: Coordinate::Fixed(y),
+                },
+                Size::Fixed(0.),
+                Size::Fixed(HEADER_HEIGHT / 2.),
+                Some(Style {
+                    background_colour: colour,
+                    ..Style::default()
+                }),
+                None,
+                needs_rerender.clone(),
+                frame_bounding_box.clone(),
+                vec![],
+            );
+
+            let ts = globals.viewport.time_scroll.clone();
+            let hz = globals.viewport.h_zoom.clone();
+            bind_reactives! {
+                element {
+                    [region, ts, hz] => (move |e: &mut Element, region: Region, ts: f32, hz: f32| {
+                        let start = keyboard_width + hz * (region.start as f32 - ts);
+                        let end = keyboard_width + hz * (region.end as f32 - ts);
+                        let start = start.max(keyboard_width);
+
+                        e.style.visible = end > start;
+                        e.position.x = Coordinate::Fixed(start);
+                        e.dimensions.width = Size::Fixed((end - start).max(0.));
+                        let shade = if region.enabled { 1. } else { 0.4 };
+                        e.style.background_colour = Colour {
+                            r: colour.r * shade,
+                            g: colour.g * shade,
+                            b: colour.b * shade,
+                            a: colour.a,
+                        };
+                    }),
+                }
+            }
+
+            element
+        })
+        .collect();
+
+    let container = Element::new(
+        gl,
+        Position::origin(),
+        Size::FractionOfParent(1.),
+        Size::FractionOfParent(1.),
+        Some(container_style),
+        None,
+        needs_rerender.clone(),
+        frame_bounding_box.clone(),
+        children,
+    );
+
+    frame_buf.root_node = Some(container);
+    frame_buf
+}
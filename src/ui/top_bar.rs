@@ -1,19 +1,22 @@
-use std::{borrow::Borrow, cell::RefCell, rc::Rc};
+use std::{
+    cell::Cell,
+    rc::Rc,
+};
 
 use crate::{
+    audio::metronome::MetronomeSettings,
     bind_reactives,
     global::Globals,
     ui::{
         d,
-        element::{self, Element},
+        element::Element,
         frame_buf::FrameBuf,
         input::{e_button, e_f32_field},
         misc_elements::e_text,
         p,
         reactive::Reactive,
-        style::{c, Style},
-        text::Text,
-        BoundingBoxRef, ComputedDimensions, Coordinate, Dimensions, Position, Size,
+        style::Style,
+        text::Text, ComputedDimensions, Coordinate, Dimensions, Position, Size,
     },
 };
 use glow::*;
@@ -41,7 +44,37 @@ pub fn fb_topbar(
         ..Style::default()
     };
 
-    let tempo = globals.loaded_project.tempo.clone();
+    // The field edits the tempo at the start of the song. Later changes are
+    // made with the tempo commands.
+    let tempo_map = globals.loaded_project.tempo_map.clone();
+    let tempo = Reactive::new(tempo_map.get_copy().initial_tempo());
+    // Stops each side's update echoing back into the other while it's still
+    // being set.
+    let syncing = Rc::new(Cell::new(false));
+
+    {
+        let tempo = tempo.clone();
+        let syncing = syncing.clone();
+        tempo_map.subscribe(Box::new(move |tempo_map| {
+            if !syncing.get() && tempo.get_copy() != tempo_map.initial_tempo() {
+                syncing.set(true);
+                tempo.set(tempo_map.initial_tempo());
+                syncing.set(false);
+            }
+        }));
+    }
+
+    {
+        let tempo_map = tempo_map.clone();
+        tempo.subscribe(Box::new(move |tempo| {
+            if !syncing.get() {
+                let tempo = *tempo;
+                syncing.set(true);
+                tempo_map.mutate(Box::new(move |tempo_map| tempo_map.set_initial_tempo(tempo)));
+                syncing.set(false);
+            }
+        }));
+    }
 
     let tempo = e_f32_field(
         gl,
@@ -66,7 +99,70 @@ pub fn fb_topbar(
         key_mod_element {
             [am] => (|e: &mut Element, am: Option<i32>| {
                 e.text_node.as_mut().unwrap().mutate(Box::new(move |text| {
-                    text.text = am.map(|a| a.to_string()).unwrap_or_else(|| String::new());
+                    text.text = am.map(|a| a.to_string()).unwrap_or_default();
+                }));
+            })
+        }
+    }
+
+    let metronome = globals.loaded_project.metronome.clone();
+    let metronome_button = {
+        let metronome = metronome.clone();
+        e_button(
+            gl,
+            globals,
+            p(140., 0.),
+            d(90., globals.top_bar_size),
+            String::new(),
+            needs_rerender.clone(),
+            frame_bounding_box.clone(),
+            Box::new(move || {
+                metronome.mutate(Box::new(|metronome| metronome.enabled = !metronome.enabled));
+            }),
+        )
+    };
+
+    bind_reactives! {
+        metronome_button {
+            [metronome] => (|e: &mut Element, metronome: MetronomeSettings| {
+                e.text_node.as_mut().unwrap().mutate(Box::new(move |text| {
+                    text.text = format!("Click {}", if metronome.enabled { "on" } else { "off" });
+                }));
+            })
+        }
+    }
+
+    let status_text = Text::new(
+        gl,
+        String::new(),
+        20.,
+        &globals.main_font,
+        globals.colour_palette.text_primary,
+        Position::origin(),
+        needs_rerender.clone(),
+    );
+
+    let status_element = Element::new(
+        gl,
+        p(250., 0.),
+        Size::Fixed(10.),
+        Size::Fixed(10.),
+        Some(Style {
+            render_self: false,
+            ..Style::default()
+        }),
+        Some(status_text),
+        needs_rerender.clone(),
+        frame_bounding_box.clone(),
+        vec![],
+    );
+
+    let status = globals.status.clone();
+    bind_reactives! {
+        status_element {
+            [status] => (|e: &mut Element, status: String| {
+                e.text_node.as_mut().unwrap().mutate(Box::new(move |text| {
+                    text.text = status.clone();
                 }));
             })
         }
@@ -81,7 +177,7 @@ pub fn fb_topbar(
         None,
         needs_rerender.clone(),
         frame_bounding_box.clone(),
-        vec![tempo, key_mod_element],
+        vec![tempo, key_mod_element, metronome_button, status_element],
     );
 
     frame_buf.root_node = Some(container);